@@ -0,0 +1,16 @@
+PRAGMA foreign_keys = ON;
+
+-- Stored push responses keyed by client-supplied idempotency keys, so a retry
+-- after a lost response replays the original result instead of re-running it.
+CREATE TABLE IF NOT EXISTS push_idempotency (
+  user_id INTEGER NOT NULL,
+  idem_key TEXT NOT NULL,
+  request_hash TEXT NOT NULL,
+  response_json TEXT NOT NULL,
+  created_at_ms_utc INTEGER NOT NULL,
+  PRIMARY KEY (user_id, idem_key),
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_push_idempotency_user_created
+  ON push_idempotency (user_id, created_at_ms_utc);
@@ -0,0 +1,19 @@
+PRAGMA foreign_keys = ON;
+
+-- Devices seen on the sync API, keyed by the client's HLC device id.
+--
+-- `last_ack_server_seq` is the highest `serverSeq` the device confirmed it has
+-- applied (explicitly via `/v1/devices/{id}/ack`, or implicitly by pulling
+-- with `since`). `session_id` is the refresh session it last authenticated with.
+CREATE TABLE IF NOT EXISTS devices (
+  user_id INTEGER NOT NULL,
+  device_id TEXT NOT NULL,
+  name TEXT,
+  first_seen_at_ms_utc INTEGER NOT NULL,
+  last_seen_at_ms_utc INTEGER NOT NULL,
+  last_ack_server_seq INTEGER NOT NULL DEFAULT 0,
+  session_id INTEGER,
+  PRIMARY KEY (user_id, device_id),
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE,
+  FOREIGN KEY(session_id) REFERENCES refresh_tokens(id) ON DELETE SET NULL
+);
@@ -0,0 +1,18 @@
+-- Highest `serverSeq` whose tombstone was physically purged. Pulls from a
+-- cursor below this may have missed deletes and must resync from scratch.
+ALTER TABLE server_seq ADD COLUMN purge_horizon_seq INTEGER NOT NULL DEFAULT 0;
+
+-- What tombstone GC leaves of a purged delete: the key and its HLC. Push
+-- compares against it when the record has no row, so a device that missed
+-- the delete can't bring the record back with an older write. The row stays
+-- when the key is written again; the live record is compared instead then.
+CREATE TABLE IF NOT EXISTS purged_tombstones (
+  user_id INTEGER NOT NULL,
+  type TEXT NOT NULL,
+  record_id TEXT NOT NULL,
+  hlc_wall_ms_utc INTEGER NOT NULL,
+  hlc_counter INTEGER NOT NULL,
+  hlc_device_id TEXT NOT NULL,
+  PRIMARY KEY (user_id, type, record_id),
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,26 @@
+PRAGMA foreign_keys = ON;
+
+-- Superseded versions of committed records (newest `RECORD_HISTORY_LIMIT` per
+-- record). Columns mirror `records`; `server_seq` is the seq the version had
+-- while it was current. Attachment metadata and chunks are not versioned.
+CREATE TABLE IF NOT EXISTS record_history (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id INTEGER NOT NULL,
+  type TEXT NOT NULL,
+  record_id TEXT NOT NULL,
+  hlc_wall_ms_utc INTEGER NOT NULL,
+  hlc_counter INTEGER NOT NULL,
+  hlc_device_id TEXT NOT NULL,
+  deleted_at_ms_utc INTEGER,
+  schema_version INTEGER NOT NULL,
+  dek_id TEXT NOT NULL,
+  algo TEXT NOT NULL,
+  nonce TEXT NOT NULL,
+  ciphertext TEXT NOT NULL,
+  server_seq INTEGER NOT NULL,
+  superseded_at_ms_utc INTEGER NOT NULL,
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_record_history_record
+  ON record_history (user_id, type, record_id, id);
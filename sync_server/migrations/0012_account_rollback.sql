@@ -0,0 +1,13 @@
+-- Bookkeeping for point-in-time rollback:
+--
+-- - `records.created_server_seq`: seq of the write that created the row, so a
+--   rollback can tombstone records that didn't exist yet at the target.
+-- - `record_history.updated_at_ms_utc`: when the archived version was written,
+--   to map a timestamp onto a `serverSeq`.
+-- - `record_history.superseded_by_seq`: seq of the write that replaced the
+--   version, i.e. it was current for `server_seq <= s < superseded_by_seq`.
+--
+-- All three are NULL for rows written before this migration.
+ALTER TABLE records ADD COLUMN created_server_seq INTEGER;
+ALTER TABLE record_history ADD COLUMN updated_at_ms_utc INTEGER;
+ALTER TABLE record_history ADD COLUMN superseded_by_seq INTEGER;
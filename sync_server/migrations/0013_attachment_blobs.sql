@@ -0,0 +1,21 @@
+PRAGMA foreign_keys = ON;
+
+-- Raw ciphertext of attachment chunks uploaded through `/v1/blobs`.
+--
+-- The chunk's `todo_attachment_chunk` record still carries its HLC, nonce and
+-- staging/commit state; it is pushed with an empty `ciphertext` and the bytes
+-- live here, keyed by `(attachment_id, chunk_index)`. A blob is complete once
+-- `LENGTH(data) = total_bytes`; until then uploads resume at `LENGTH(data)`.
+CREATE TABLE IF NOT EXISTS attachment_blobs (
+  user_id INTEGER NOT NULL,
+  attachment_id TEXT NOT NULL,
+  chunk_index INTEGER NOT NULL,
+  total_bytes INTEGER NOT NULL,
+  data BLOB NOT NULL,
+  updated_at_ms_utc INTEGER NOT NULL,
+  PRIMARY KEY (user_id, attachment_id, chunk_index),
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_attachment_blobs_updated
+  ON attachment_blobs (updated_at_ms_utc);
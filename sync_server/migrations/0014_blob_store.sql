@@ -0,0 +1,92 @@
+PRAGMA foreign_keys = ON;
+
+-- External blob storage (`BLOB_STORE=fs|s3`).
+--
+-- When a blob store is configured, attachment chunk ciphertext is written to it
+-- and the row keeps only a pointer (`ciphertext_ref`, the object key) and the
+-- ciphertext length (`ciphertext_len`, so storage accounting doesn't need the
+-- bytes). `ciphertext` is then stored as ''. Completed `/v1/blobs` uploads move
+-- out of `attachment_blobs.data` the same way, via `object_key`.
+ALTER TABLE records ADD COLUMN ciphertext_ref TEXT;
+ALTER TABLE records ADD COLUMN ciphertext_len INTEGER;
+ALTER TABLE staged_records ADD COLUMN ciphertext_ref TEXT;
+ALTER TABLE staged_records ADD COLUMN ciphertext_len INTEGER;
+ALTER TABLE attachment_blobs ADD COLUMN object_key TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_records_ciphertext_ref
+  ON records (ciphertext_ref) WHERE ciphertext_ref IS NOT NULL;
+CREATE INDEX IF NOT EXISTS idx_staged_records_ciphertext_ref
+  ON staged_records (ciphertext_ref) WHERE ciphertext_ref IS NOT NULL;
+
+-- Objects no row points at anymore; the sweeper deletes them from the store.
+--
+-- Keys are queued by the triggers below, so every path that drops or replaces a
+-- pointer (push, compaction, ghost/tombstone/staged GC, account deletion via
+-- `ON DELETE CASCADE`) is covered without knowing about the store. A staged
+-- chunk and its committed copy share a key while the attachment commits, so
+-- the record triggers only queue a key the other table no longer references.
+CREATE TABLE IF NOT EXISTS blob_trash (
+  object_key TEXT PRIMARY KEY,
+  queued_at_ms_utc INTEGER NOT NULL
+);
+
+CREATE TRIGGER IF NOT EXISTS trg_records_ciphertext_ref_delete
+AFTER DELETE ON records
+WHEN OLD.ciphertext_ref IS NOT NULL
+  AND NOT EXISTS (SELECT 1 FROM staged_records WHERE ciphertext_ref = OLD.ciphertext_ref)
+BEGIN
+  INSERT INTO blob_trash (object_key, queued_at_ms_utc)
+  VALUES (OLD.ciphertext_ref, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+  ON CONFLICT(object_key) DO NOTHING;
+END;
+
+CREATE TRIGGER IF NOT EXISTS trg_records_ciphertext_ref_update
+AFTER UPDATE OF ciphertext_ref ON records
+WHEN OLD.ciphertext_ref IS NOT NULL
+  AND OLD.ciphertext_ref IS NOT NEW.ciphertext_ref
+  AND NOT EXISTS (SELECT 1 FROM staged_records WHERE ciphertext_ref = OLD.ciphertext_ref)
+BEGIN
+  INSERT INTO blob_trash (object_key, queued_at_ms_utc)
+  VALUES (OLD.ciphertext_ref, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+  ON CONFLICT(object_key) DO NOTHING;
+END;
+
+CREATE TRIGGER IF NOT EXISTS trg_staged_records_ciphertext_ref_delete
+AFTER DELETE ON staged_records
+WHEN OLD.ciphertext_ref IS NOT NULL
+  AND NOT EXISTS (SELECT 1 FROM records WHERE ciphertext_ref = OLD.ciphertext_ref)
+BEGIN
+  INSERT INTO blob_trash (object_key, queued_at_ms_utc)
+  VALUES (OLD.ciphertext_ref, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+  ON CONFLICT(object_key) DO NOTHING;
+END;
+
+CREATE TRIGGER IF NOT EXISTS trg_staged_records_ciphertext_ref_update
+AFTER UPDATE OF ciphertext_ref ON staged_records
+WHEN OLD.ciphertext_ref IS NOT NULL
+  AND OLD.ciphertext_ref IS NOT NEW.ciphertext_ref
+  AND NOT EXISTS (SELECT 1 FROM records WHERE ciphertext_ref = OLD.ciphertext_ref)
+BEGIN
+  INSERT INTO blob_trash (object_key, queued_at_ms_utc)
+  VALUES (OLD.ciphertext_ref, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+  ON CONFLICT(object_key) DO NOTHING;
+END;
+
+CREATE TRIGGER IF NOT EXISTS trg_attachment_blobs_object_key_delete
+AFTER DELETE ON attachment_blobs
+WHEN OLD.object_key IS NOT NULL
+BEGIN
+  INSERT INTO blob_trash (object_key, queued_at_ms_utc)
+  VALUES (OLD.object_key, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+  ON CONFLICT(object_key) DO NOTHING;
+END;
+
+CREATE TRIGGER IF NOT EXISTS trg_attachment_blobs_object_key_update
+AFTER UPDATE OF object_key ON attachment_blobs
+WHEN OLD.object_key IS NOT NULL
+  AND OLD.object_key IS NOT NEW.object_key
+BEGIN
+  INSERT INTO blob_trash (object_key, queued_at_ms_utc)
+  VALUES (OLD.object_key, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+  ON CONFLICT(object_key) DO NOTHING;
+END;
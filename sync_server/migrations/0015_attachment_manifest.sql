@@ -0,0 +1,15 @@
+PRAGMA foreign_keys = ON;
+
+-- Plaintext manifest of an attachment, carried by its `todo_attachment`
+-- envelope (`chunkCount`, `totalCiphertextLen`), so a commit can be refused
+-- until every chunk `0..chunk_count-1` has arrived.
+ALTER TABLE records ADD COLUMN chunk_count INTEGER;
+ALTER TABLE records ADD COLUMN total_ciphertext_len INTEGER;
+ALTER TABLE staged_records ADD COLUMN chunk_count INTEGER;
+ALTER TABLE staged_records ADD COLUMN total_ciphertext_len INTEGER;
+
+-- Decoded byte length of a `todo_attachment_chunk` ciphertext, to check the
+-- chunks against `total_ciphertext_len`. NULL for chunks sent as a raw blob
+-- (the blob's `total_bytes` counts instead) and for rows written before this.
+ALTER TABLE records ADD COLUMN ciphertext_bytes INTEGER;
+ALTER TABLE staged_records ADD COLUMN ciphertext_bytes INTEGER;
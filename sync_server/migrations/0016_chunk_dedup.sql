@@ -0,0 +1,105 @@
+PRAGMA foreign_keys = ON;
+
+-- Deduplicated attachment chunk ciphertext.
+--
+-- A `todo_attachment_chunk` pushed with a `contentHash` (a keyed hash the
+-- client computes over the chunk, so it reveals nothing to the server) keeps
+-- its bytes here, once per account; the chunk row stores the hash in
+-- `content_hash` and an empty `ciphertext`. `ciphertext` / `ciphertext_ref` /
+-- `ciphertext_len` work as on `records`; `ciphertext_bytes` is the decoded
+-- length, copied onto every row that references the chunk. The ciphertext
+-- only decrypts with the `nonce` (and DEK) of the push that first uploaded
+-- it, which a later row pointing at the same hash can differ from, so those
+-- are kept next to the bytes and served for every referencing row.
+CREATE TABLE IF NOT EXISTS chunk_blobs (
+  user_id INTEGER NOT NULL,
+  content_hash TEXT NOT NULL,
+  nonce TEXT NOT NULL,
+  dek_id TEXT NOT NULL,
+  ciphertext TEXT NOT NULL,
+  ciphertext_ref TEXT,
+  ciphertext_len INTEGER,
+  ciphertext_bytes INTEGER NOT NULL,
+  ref_count INTEGER NOT NULL DEFAULT 0,
+  created_at_ms_utc INTEGER NOT NULL,
+  PRIMARY KEY (user_id, content_hash),
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+ALTER TABLE records ADD COLUMN content_hash TEXT;
+ALTER TABLE staged_records ADD COLUMN content_hash TEXT;
+
+-- `ref_count` is the number of `records` + `staged_records` rows pointing at
+-- the chunk, kept by the triggers below so every path that drops or replaces a
+-- row (tombstones, compaction, ghost/staged GC, account deletion) releases its
+-- reference. Writers insert the `chunk_blobs` row before the row pointing at
+-- it, and a commit copies staged rows before deleting them, so the count only
+-- reaches 0 once the last reference is gone; the chunk is dropped then.
+CREATE TRIGGER IF NOT EXISTS trg_records_content_hash_insert
+AFTER INSERT ON records
+WHEN NEW.content_hash IS NOT NULL
+BEGIN
+  UPDATE chunk_blobs SET ref_count = ref_count + 1
+  WHERE user_id = NEW.user_id AND content_hash = NEW.content_hash;
+END;
+
+CREATE TRIGGER IF NOT EXISTS trg_records_content_hash_update
+AFTER UPDATE OF content_hash ON records
+WHEN OLD.content_hash IS NOT NEW.content_hash
+BEGIN
+  UPDATE chunk_blobs SET ref_count = ref_count + 1
+  WHERE user_id = NEW.user_id AND content_hash = NEW.content_hash;
+  UPDATE chunk_blobs SET ref_count = ref_count - 1
+  WHERE user_id = OLD.user_id AND content_hash = OLD.content_hash;
+END;
+
+CREATE TRIGGER IF NOT EXISTS trg_records_content_hash_delete
+AFTER DELETE ON records
+WHEN OLD.content_hash IS NOT NULL
+BEGIN
+  UPDATE chunk_blobs SET ref_count = ref_count - 1
+  WHERE user_id = OLD.user_id AND content_hash = OLD.content_hash;
+END;
+
+CREATE TRIGGER IF NOT EXISTS trg_staged_records_content_hash_insert
+AFTER INSERT ON staged_records
+WHEN NEW.content_hash IS NOT NULL
+BEGIN
+  UPDATE chunk_blobs SET ref_count = ref_count + 1
+  WHERE user_id = NEW.user_id AND content_hash = NEW.content_hash;
+END;
+
+CREATE TRIGGER IF NOT EXISTS trg_staged_records_content_hash_update
+AFTER UPDATE OF content_hash ON staged_records
+WHEN OLD.content_hash IS NOT NEW.content_hash
+BEGIN
+  UPDATE chunk_blobs SET ref_count = ref_count + 1
+  WHERE user_id = NEW.user_id AND content_hash = NEW.content_hash;
+  UPDATE chunk_blobs SET ref_count = ref_count - 1
+  WHERE user_id = OLD.user_id AND content_hash = OLD.content_hash;
+END;
+
+CREATE TRIGGER IF NOT EXISTS trg_staged_records_content_hash_delete
+AFTER DELETE ON staged_records
+WHEN OLD.content_hash IS NOT NULL
+BEGIN
+  UPDATE chunk_blobs SET ref_count = ref_count - 1
+  WHERE user_id = OLD.user_id AND content_hash = OLD.content_hash;
+END;
+
+CREATE TRIGGER IF NOT EXISTS trg_chunk_blobs_unreferenced
+AFTER UPDATE OF ref_count ON chunk_blobs
+WHEN NEW.ref_count <= 0
+BEGIN
+  DELETE FROM chunk_blobs
+  WHERE user_id = NEW.user_id AND content_hash = NEW.content_hash;
+END;
+
+CREATE TRIGGER IF NOT EXISTS trg_chunk_blobs_ciphertext_ref_delete
+AFTER DELETE ON chunk_blobs
+WHEN OLD.ciphertext_ref IS NOT NULL
+BEGIN
+  INSERT INTO blob_trash (object_key, queued_at_ms_utc)
+  VALUES (OLD.ciphertext_ref, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+  ON CONFLICT(object_key) DO NOTHING;
+END;
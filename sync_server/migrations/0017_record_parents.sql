@@ -0,0 +1,27 @@
+PRAGMA foreign_keys = ON;
+
+-- Plaintext parent of a record (`parentType` / `parentId` on its envelope),
+-- e.g. the todo a pomodoro session or statistics entry belongs to.
+--
+-- Like `attachment_refs`, this lets the server clean up after a deleted parent
+-- without decrypting payloads: live children of a parent that has been
+-- tombstoned (or is gone) for `GHOST_GC_MIN_PARENT_AGE_MS` are tombstoned by
+-- the ghost collector. `updated_at_ms_utc` is when the link was last pushed.
+CREATE TABLE IF NOT EXISTS record_parents (
+  user_id INTEGER NOT NULL,
+  type TEXT NOT NULL,
+  record_id TEXT NOT NULL,
+  parent_type TEXT NOT NULL,
+  parent_id TEXT NOT NULL,
+  updated_at_ms_utc INTEGER NOT NULL,
+  PRIMARY KEY (user_id, type, record_id),
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- A link goes away with its record (tombstone purge, ghost GC).
+CREATE TRIGGER IF NOT EXISTS trg_records_parent_delete
+AFTER DELETE ON records
+BEGIN
+  DELETE FROM record_parents
+  WHERE user_id = OLD.user_id AND type = OLD.type AND record_id = OLD.record_id;
+END;
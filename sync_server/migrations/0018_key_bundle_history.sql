@@ -0,0 +1,15 @@
+PRAGMA foreign_keys = ON;
+
+-- Bundles replaced by `PUT /v1/key-bundle`, so a client rotating its data
+-- key can still unwrap DEKs that older records were encrypted with. The
+-- newest `KEY_BUNDLE_HISTORY_LIMIT` versions per account are kept.
+CREATE TABLE IF NOT EXISTS key_bundle_history (
+  user_id INTEGER NOT NULL,
+  bundle_version INTEGER NOT NULL,
+  bundle_json TEXT NOT NULL,
+  updated_at_ms_utc INTEGER NOT NULL,
+  superseded_at_ms_utc INTEGER NOT NULL,
+  PRIMARY KEY (user_id, bundle_version),
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
@@ -0,0 +1,18 @@
+PRAGMA foreign_keys = ON;
+
+-- Named wrap slots for the account's data key: each holds the DEK wrapped
+-- under one secret (a passphrase, a printable recovery code, a device key),
+-- so losing one secret doesn't lose the data. `slot_json` is opaque to the
+-- server; `kind` is the only part it reads. Every slot has its own
+-- `slot_version` for optimistic concurrency, like `key_bundles`.
+CREATE TABLE IF NOT EXISTS key_slots (
+  user_id INTEGER NOT NULL,
+  slot_id TEXT NOT NULL,
+  kind TEXT NOT NULL,
+  slot_version INTEGER NOT NULL,
+  slot_json TEXT NOT NULL,
+  created_at_ms_utc INTEGER NOT NULL,
+  updated_at_ms_utc INTEGER NOT NULL,
+  PRIMARY KEY (user_id, slot_id),
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,26 @@
+PRAGMA foreign_keys = ON;
+
+-- Device pairing mailbox: a signed-in device opens a session under a short
+-- code, the new device drops an ephemeral public key into it, and the old
+-- device answers with the data key encrypted to that key. The server only
+-- relays the two opaque blobs. `code_hash` is hashed like `auth_tickets`;
+-- `consumed_at_ms_utc` is set (and the blobs cleared) when the new device
+-- picks up the payload, which can happen once.
+CREATE TABLE IF NOT EXISTS pairing_sessions (
+  code_hash TEXT PRIMARY KEY,
+  user_id INTEGER NOT NULL,
+  created_at_ms_utc INTEGER NOT NULL,
+  expires_at_ms_utc INTEGER NOT NULL,
+  public_key TEXT,
+  public_key_at_ms_utc INTEGER,
+  payload TEXT,
+  payload_at_ms_utc INTEGER,
+  consumed_at_ms_utc INTEGER,
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_pairing_sessions_user
+  ON pairing_sessions (user_id);
+
+CREATE INDEX IF NOT EXISTS idx_pairing_sessions_expires
+  ON pairing_sessions (expires_at_ms_utc);
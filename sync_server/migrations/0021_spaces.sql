@@ -0,0 +1,54 @@
+PRAGMA foreign_keys = ON;
+
+-- Shared spaces: a record stream several accounts sync together.
+--
+-- Every space is backed by its own `users` row (`oauth_provider = '_space'`,
+-- `oauth_sub = space_id`) so it gets its own records, `server_seq`, key
+-- bundle, history and blobs through the existing per-user tables; requests
+-- pick it with `spaceId` and `AuthedUser` swaps in `user_id` after checking
+-- `space_members`. Storage and outbound traffic are billed to the owner.
+CREATE TABLE IF NOT EXISTS spaces (
+  space_id TEXT PRIMARY KEY,
+  user_id INTEGER NOT NULL UNIQUE,
+  owner_user_id INTEGER NOT NULL,
+  created_at_ms_utc INTEGER NOT NULL,
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE,
+  FOREIGN KEY(owner_user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_spaces_owner
+  ON spaces (owner_user_id);
+
+CREATE TABLE IF NOT EXISTS space_members (
+  space_id TEXT NOT NULL,
+  user_id INTEGER NOT NULL,
+  role TEXT NOT NULL CHECK (role IN ('owner', 'editor', 'viewer')),
+  added_at_ms_utc INTEGER NOT NULL,
+  PRIMARY KEY (space_id, user_id),
+  FOREIGN KEY(space_id) REFERENCES spaces(space_id) ON DELETE CASCADE,
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_space_members_user
+  ON space_members (user_id);
+
+-- Dropping a space (or its owner's account) drops the backing row, and with
+-- it every record; blob store objects are queued by the existing triggers.
+CREATE TRIGGER IF NOT EXISTS trg_spaces_delete
+AFTER DELETE ON spaces
+BEGIN
+  DELETE FROM users WHERE id = OLD.user_id;
+END;
+
+-- Outbound bytes charged to a space move to its owner, so every handler can
+-- keep charging the `user_id` it works on.
+CREATE TRIGGER IF NOT EXISTS trg_space_outbound_to_owner
+AFTER UPDATE OF api_outbound_bytes ON users
+WHEN NEW.api_outbound_bytes > OLD.api_outbound_bytes
+  AND EXISTS (SELECT 1 FROM spaces WHERE user_id = NEW.id)
+BEGIN
+  UPDATE users
+  SET api_outbound_bytes = api_outbound_bytes + (NEW.api_outbound_bytes - OLD.api_outbound_bytes)
+  WHERE id = (SELECT owner_user_id FROM spaces WHERE user_id = NEW.id);
+  UPDATE users SET api_outbound_bytes = OLD.api_outbound_bytes WHERE id = NEW.id;
+END;
@@ -0,0 +1,27 @@
+PRAGMA foreign_keys = ON;
+
+-- Public share links: one todo (with its attachments) encrypted on the
+-- device under a throwaway key that only travels in the link's fragment.
+-- The server keeps the opaque snapshot under a random `share_id` and hands
+-- it to anyone with the link until it expires, runs out of views or is
+-- revoked; at that point the snapshot is cleared and the row stays behind
+-- (for the owner's list) until it expires.
+CREATE TABLE IF NOT EXISTS shares (
+  share_id TEXT PRIMARY KEY,
+  user_id INTEGER NOT NULL,
+  nonce TEXT,
+  ciphertext TEXT,
+  size_b64 INTEGER NOT NULL,
+  created_at_ms_utc INTEGER NOT NULL,
+  expires_at_ms_utc INTEGER NOT NULL,
+  max_views INTEGER,
+  view_count INTEGER NOT NULL DEFAULT 0,
+  revoked_at_ms_utc INTEGER,
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_shares_user
+  ON shares (user_id, created_at_ms_utc);
+
+CREATE INDEX IF NOT EXISTS idx_shares_expires
+  ON shares (expires_at_ms_utc);
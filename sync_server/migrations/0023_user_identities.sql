@@ -0,0 +1,31 @@
+PRAGMA foreign_keys = ON;
+
+-- OAuth identities that sign in to an account. An account can link several
+-- (e.g. linux.do and GitHub) so logging in with either lands in the same
+-- data. `users.oauth_provider` / `users.oauth_sub` keep naming one of them
+-- (the one the account was created with, unless it was unlinked).
+CREATE TABLE IF NOT EXISTS user_identities (
+  provider TEXT NOT NULL,
+  sub TEXT NOT NULL,
+  user_id INTEGER NOT NULL,
+  linked_at_ms_utc INTEGER NOT NULL,
+  PRIMARY KEY (provider, sub),
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_identities_user
+  ON user_identities (user_id);
+
+-- Space rows aren't accounts and never sign in.
+INSERT OR IGNORE INTO user_identities (provider, sub, user_id, linked_at_ms_utc)
+SELECT oauth_provider, oauth_sub, id, created_at_ms_utc
+FROM users
+WHERE oauth_provider != '_space';
+
+-- Set when the login attempt links a provider to an already signed-in
+-- account instead of signing in.
+ALTER TABLE auth_login_attempts ADD COLUMN link_user_id INTEGER;
+
+-- Hash of the nonce cookie set on the browser that started a link attempt;
+-- the callback only links when the same browser (and session) finishes it.
+ALTER TABLE auth_login_attempts ADD COLUMN link_nonce_hash TEXT;
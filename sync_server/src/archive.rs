@@ -0,0 +1,480 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use axum::body::Body;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite};
+use tokio::sync::mpsc;
+use tracing::error;
+
+use crate::blob_store::{self, BlobStore};
+use crate::{
+    current_server_seq, record_envelope_from_row, set_attachment_fields_from_row,
+    set_parent_from_row, SyncRecordEnvelope,
+};
+
+pub(crate) const ARCHIVE_FORMAT: &str = "easy_todo_export";
+pub(crate) const ARCHIVE_VERSION: i64 = 1;
+
+/// Flush the export stream to the client roughly every this many bytes.
+const EXPORT_CHUNK_BYTES: usize = 64 * 1024;
+/// Rows read per query; each page is its own short read, so a slow download
+/// never keeps a transaction (and the WAL) pinned.
+const EXPORT_PAGE_ROWS: i64 = 500;
+/// Blob rows carry up to a whole chunk each, so they are read in smaller pages.
+const EXPORT_BLOB_PAGE_ROWS: i64 = 16;
+
+/// One line of the JSONL account archive.
+///
+/// An archive is a `header`, an optional `keyBundle`, every `keySlot`, every
+/// `record` (in `serverSeq` order, tombstones included), every
+/// `attachmentRef`, every complete chunk `blob` (base64url), and an `end`
+/// trailer whose counts let an importer detect truncation.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub(crate) enum ArchiveLine {
+    #[serde(rename = "header")]
+    Header {
+        format: String,
+        version: i64,
+        #[serde(rename = "exportedAtMsUtc")]
+        exported_at_ms_utc: i64,
+        #[serde(rename = "serverSeq")]
+        server_seq: i64,
+    },
+    #[serde(rename = "keyBundle")]
+    KeyBundle {
+        #[serde(rename = "bundleVersion")]
+        bundle_version: i64,
+        bundle: serde_json::Value,
+    },
+    #[serde(rename = "keySlot")]
+    KeySlot {
+        #[serde(rename = "slotId")]
+        slot_id: String,
+        #[serde(rename = "slotKind")]
+        kind: String,
+        #[serde(rename = "slotVersion")]
+        slot_version: i64,
+        slot: serde_json::Value,
+    },
+    #[serde(rename = "record")]
+    Record(Box<SyncRecordEnvelope>),
+    #[serde(rename = "attachmentRef")]
+    AttachmentRef {
+        #[serde(rename = "attachmentId")]
+        attachment_id: String,
+        #[serde(rename = "todoId")]
+        todo_id: String,
+    },
+    #[serde(rename = "blob")]
+    Blob {
+        #[serde(rename = "attachmentId")]
+        attachment_id: String,
+        #[serde(rename = "chunkIndex")]
+        chunk_index: i64,
+        data: String,
+    },
+    #[serde(rename = "end")]
+    End {
+        records: i64,
+        #[serde(rename = "attachmentRefs")]
+        attachment_refs: i64,
+        #[serde(default)]
+        blobs: i64,
+    },
+}
+
+/// Stream the user's archive as the response body.
+///
+/// Rows are read in pages keyed by `serverSeq` (or by id for refs and blobs)
+/// rather than in one long transaction. A record written while the export
+/// runs moves to a higher `serverSeq`; it is written once, in whichever
+/// version is read first. Bytes actually sent are charged to `api_outbound_bytes`
+/// once the stream ends (or the client goes away). Bytes kept in the blob
+/// store are read back into the archive, so it is the same whichever backend
+/// is used.
+pub(crate) fn export_body(
+    db: Pool<Sqlite>,
+    store: Option<Arc<dyn BlobStore>>,
+    user_id: i64,
+    now_ms: i64,
+) -> Body {
+    let (tx, rx) = mpsc::channel::<Result<Vec<u8>, std::io::Error>>(8);
+    tokio::spawn(async move {
+        let mut sink = ExportSink {
+            tx,
+            buf: Vec::with_capacity(EXPORT_CHUNK_BYTES),
+            sent: 0,
+        };
+        if let Err(e) = write_archive(&db, store.as_deref(), user_id, now_ms, &mut sink).await {
+            error!(error = %e, user_id, "account export failed");
+            // Abort the body so the client sees a broken transfer rather than
+            // an archive that merely lacks its `end` line.
+            let _ = sink
+                .tx
+                .send(Err(std::io::Error::other("export failed")))
+                .await;
+        }
+        if let Err(e) = sqlx::query(
+            r#"UPDATE users SET api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#,
+        )
+        .bind(sink.sent)
+        .bind(user_id)
+        .execute(&db)
+        .await
+        {
+            error!(error = %e, "charge export outbound bytes failed");
+        }
+    });
+    Body::from_stream(tokio_stream::wrappers::ReceiverStream::new(rx))
+}
+
+struct ExportSink {
+    tx: mpsc::Sender<Result<Vec<u8>, std::io::Error>>,
+    buf: Vec<u8>,
+    sent: i64,
+}
+
+impl ExportSink {
+    async fn line(&mut self, line: &ArchiveLine) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.buf, line)?;
+        self.buf.push(b'\n');
+        if self.buf.len() >= EXPORT_CHUNK_BYTES {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(EXPORT_CHUNK_BYTES));
+        let len = chunk.len() as i64;
+        self.tx
+            .send(Ok(chunk))
+            .await
+            .map_err(|_| anyhow::anyhow!("client disconnected"))?;
+        self.sent += len;
+        Ok(())
+    }
+}
+
+async fn write_archive(
+    db: &Pool<Sqlite>,
+    store: Option<&dyn BlobStore>,
+    user_id: i64,
+    now_ms: i64,
+    sink: &mut ExportSink,
+) -> anyhow::Result<()> {
+    let server_seq = current_server_seq(db, user_id).await?;
+    sink.line(&ArchiveLine::Header {
+        format: ARCHIVE_FORMAT.to_string(),
+        version: ARCHIVE_VERSION,
+        exported_at_ms_utc: now_ms,
+        server_seq,
+    })
+    .await?;
+
+    if let Some(line) = key_bundle_line(db, user_id).await? {
+        sink.line(&line).await?;
+    }
+    {
+        let rows = sqlx::query(
+            r#"SELECT slot_id, kind, slot_version, slot_json
+               FROM key_slots WHERE user_id = ? ORDER BY slot_id"#,
+        )
+        .bind(user_id)
+        .fetch_all(db)
+        .await?;
+        for row in rows {
+            let slot_json: String = row.try_get("slot_json")?;
+            sink.line(&ArchiveLine::KeySlot {
+                slot_id: row.try_get("slot_id")?,
+                kind: row.try_get("kind")?,
+                slot_version: row.try_get("slot_version")?,
+                slot: serde_json::from_str(&slot_json)?,
+            })
+            .await?;
+        }
+    }
+
+    let mut records: i64 = 0;
+    let mut after_seq: i64 = 0;
+    let mut written = HashSet::new();
+    loop {
+        let rows = sqlx::query(
+            r#"SELECT
+                 r.type, r.record_id,
+                 r.hlc_wall_ms_utc, r.hlc_counter, r.hlc_device_id,
+                 r.deleted_at_ms_utc, r.schema_version,
+                 IFNULL(c.dek_id, r.dek_id) AS dek_id, r.algo,
+                 IFNULL(c.nonce, r.nonce) AS nonce,
+                 IFNULL(c.ciphertext, r.ciphertext) AS ciphertext,
+                 IFNULL(c.ciphertext_ref, r.ciphertext_ref) AS ciphertext_ref,
+                 r.chunk_count, r.total_ciphertext_len, r.content_hash,
+                 p.parent_type, p.parent_id,
+                 r.server_seq
+               FROM records r
+               LEFT JOIN chunk_blobs c
+                 ON c.user_id = r.user_id AND c.content_hash = r.content_hash
+               LEFT JOIN record_parents p
+                 ON p.user_id = r.user_id AND p.type = r.type AND p.record_id = r.record_id
+               WHERE r.user_id = ? AND r.server_seq > ?
+               ORDER BY r.server_seq ASC
+               LIMIT ?"#,
+        )
+        .bind(user_id)
+        .bind(after_seq)
+        .bind(EXPORT_PAGE_ROWS)
+        .fetch_all(db)
+        .await?;
+        let page_len = rows.len() as i64;
+        for row in rows {
+            after_seq = row.try_get("server_seq")?;
+            let key: (String, String) = (row.try_get("type")?, row.try_get("record_id")?);
+            if !written.insert(key) {
+                continue;
+            }
+            let mut envelope = record_envelope_from_row(&row)?;
+            set_attachment_fields_from_row(&mut envelope, &row)?;
+            set_parent_from_row(&mut envelope, &row)?;
+            if let Some(key) = row.try_get::<Option<String>, _>("ciphertext_ref")? {
+                envelope.ciphertext = blob_store::load_ciphertext(store, &key).await?;
+            }
+            sink.line(&ArchiveLine::Record(Box::new(envelope))).await?;
+            records += 1;
+        }
+        if page_len < EXPORT_PAGE_ROWS {
+            break;
+        }
+    }
+
+    let mut attachment_refs: i64 = 0;
+    let mut after_attachment = String::new();
+    loop {
+        let rows = sqlx::query(
+            r#"SELECT attachment_id, todo_id
+               FROM attachment_refs
+               WHERE user_id = ? AND attachment_id > ?
+               ORDER BY attachment_id
+               LIMIT ?"#,
+        )
+        .bind(user_id)
+        .bind(&after_attachment)
+        .bind(EXPORT_PAGE_ROWS)
+        .fetch_all(db)
+        .await?;
+        let page_len = rows.len() as i64;
+        for row in rows {
+            after_attachment = row.try_get("attachment_id")?;
+            sink.line(&ArchiveLine::AttachmentRef {
+                attachment_id: after_attachment.clone(),
+                todo_id: row.try_get("todo_id")?,
+            })
+            .await?;
+            attachment_refs += 1;
+        }
+        if page_len < EXPORT_PAGE_ROWS {
+            break;
+        }
+    }
+
+    let mut blobs: i64 = 0;
+    let mut after_blob = (String::new(), -1_i64);
+    loop {
+        let rows = sqlx::query(
+            r#"SELECT attachment_id, chunk_index, data, object_key
+               FROM attachment_blobs
+               WHERE user_id = ?
+                 AND (attachment_id, chunk_index) > (?, ?)
+                 AND (object_key IS NOT NULL OR LENGTH(data) = total_bytes)
+               ORDER BY attachment_id, chunk_index
+               LIMIT ?"#,
+        )
+        .bind(user_id)
+        .bind(&after_blob.0)
+        .bind(after_blob.1)
+        .bind(EXPORT_BLOB_PAGE_ROWS)
+        .fetch_all(db)
+        .await?;
+        let page_len = rows.len() as i64;
+        for row in rows {
+            after_blob = (row.try_get("attachment_id")?, row.try_get("chunk_index")?);
+            let mut data: Vec<u8> = row.try_get("data")?;
+            if let Some(key) = row.try_get::<Option<String>, _>("object_key")? {
+                let Some(store) = store else {
+                    anyhow::bail!(
+                        "blob {key} is in the blob store, but BLOB_STORE is not configured"
+                    );
+                };
+                data = store
+                    .get(&key, None)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("blob store object {key} is missing"))?;
+            }
+            sink.line(&ArchiveLine::Blob {
+                attachment_id: after_blob.0.clone(),
+                chunk_index: after_blob.1,
+                data: URL_SAFE_NO_PAD.encode(data),
+            })
+            .await?;
+            blobs += 1;
+        }
+        if page_len < EXPORT_BLOB_PAGE_ROWS {
+            break;
+        }
+    }
+
+    sink.line(&ArchiveLine::End {
+        records,
+        attachment_refs,
+        blobs,
+    })
+    .await?;
+    sink.flush().await
+}
+
+async fn key_bundle_line(db: &Pool<Sqlite>, user_id: i64) -> anyhow::Result<Option<ArchiveLine>> {
+    let row =
+        sqlx::query(r#"SELECT bundle_version, bundle_json FROM key_bundles WHERE user_id = ?"#)
+            .bind(user_id)
+            .fetch_optional(db)
+            .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let bundle_json: String = row.try_get("bundle_json")?;
+    Ok(Some(ArchiveLine::KeyBundle {
+        bundle_version: row.try_get("bundle_version")?,
+        bundle: serde_json::from_str(&bundle_json)?,
+    }))
+}
+
+/// A parsed archive, checked for a supported header and a matching trailer.
+pub(crate) struct ParsedArchive {
+    pub key_bundle: Option<(i64, serde_json::Value)>,
+    /// `(slot_id, kind, slot_version, slot)`
+    pub key_slots: Vec<(String, String, i64, serde_json::Value)>,
+    pub records: Vec<SyncRecordEnvelope>,
+    pub attachment_refs: Vec<(String, String)>,
+    /// `(attachment_id, chunk_index, bytes)`
+    pub blobs: Vec<(String, i64, Vec<u8>)>,
+}
+
+/// Parse a complete JSONL archive. Errors are short codes for the client.
+pub(crate) fn parse_archive(bytes: &[u8]) -> Result<ParsedArchive, &'static str> {
+    let mut lines = bytes
+        .split(|b| *b == b'\n')
+        .filter(|l| !l.iter().all(u8::is_ascii_whitespace));
+
+    match lines.next().map(serde_json::from_slice::<ArchiveLine>) {
+        Some(Ok(ArchiveLine::Header {
+            format, version, ..
+        })) if format == ARCHIVE_FORMAT => {
+            if version != ARCHIVE_VERSION {
+                return Err("unsupported_archive_version");
+            }
+        }
+        _ => return Err("invalid_archive"),
+    }
+
+    let mut parsed = ParsedArchive {
+        key_bundle: None,
+        key_slots: Vec::new(),
+        records: Vec::new(),
+        attachment_refs: Vec::new(),
+        blobs: Vec::new(),
+    };
+    let mut ended = false;
+    for line in lines {
+        if ended {
+            return Err("invalid_archive");
+        }
+        match serde_json::from_slice::<ArchiveLine>(line).map_err(|_| "invalid_archive")? {
+            ArchiveLine::Header { .. } => return Err("invalid_archive"),
+            ArchiveLine::KeyBundle {
+                bundle_version,
+                bundle,
+            } => parsed.key_bundle = Some((bundle_version, bundle)),
+            ArchiveLine::KeySlot {
+                slot_id,
+                kind,
+                slot_version,
+                slot,
+            } => parsed.key_slots.push((slot_id, kind, slot_version, slot)),
+            ArchiveLine::Record(record) => parsed.records.push(*record),
+            ArchiveLine::AttachmentRef {
+                attachment_id,
+                todo_id,
+            } => parsed.attachment_refs.push((attachment_id, todo_id)),
+            ArchiveLine::Blob {
+                attachment_id,
+                chunk_index,
+                data,
+            } => {
+                let data = URL_SAFE_NO_PAD
+                    .decode(data)
+                    .map_err(|_| "invalid_archive")?;
+                parsed.blobs.push((attachment_id, chunk_index, data));
+            }
+            ArchiveLine::End {
+                records,
+                attachment_refs,
+                blobs,
+            } => {
+                if records != parsed.records.len() as i64
+                    || attachment_refs != parsed.attachment_refs.len() as i64
+                    || blobs != parsed.blobs.len() as i64
+                {
+                    return Err("truncated_archive");
+                }
+                ended = true;
+            }
+        }
+    }
+    if !ended {
+        return Err("truncated_archive");
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_requires_header_and_matching_trailer() {
+        let header = r#"{"kind":"header","format":"easy_todo_export","version":1,"exportedAtMsUtc":1,"serverSeq":1}"#;
+        let record = r#"{"kind":"record","type":"todo","recordId":"a","hlc":{"wallTimeMsUtc":1,"counter":0,"deviceId":"d"},"deletedAtMsUtc":null,"schemaVersion":1,"dekId":"k","payloadAlgo":"x","nonce":"AA","ciphertext":"AA"}"#;
+
+        let ok = format!(
+            "{header}\n{record}\n{}\n",
+            r#"{"kind":"end","records":1,"attachmentRefs":0}"#
+        );
+        let parsed = parse_archive(ok.as_bytes()).unwrap();
+        assert_eq!(parsed.records.len(), 1);
+        assert_eq!(parsed.records[0].record_id, "a");
+
+        let short = format!(
+            "{header}\n{}\n",
+            r#"{"kind":"end","records":1,"attachmentRefs":0}"#
+        );
+        assert_eq!(
+            parse_archive(short.as_bytes()).err(),
+            Some("truncated_archive")
+        );
+        let no_end = format!("{header}\n{record}\n");
+        assert_eq!(
+            parse_archive(no_end.as_bytes()).err(),
+            Some("truncated_archive")
+        );
+        assert_eq!(
+            parse_archive(record.as_bytes()).err(),
+            Some("invalid_archive")
+        );
+    }
+}
@@ -6,7 +6,7 @@ use anyhow::Context;
 use async_trait::async_trait;
 use axum::extract::{ConnectInfo, FromRequestParts, Query, State};
 use axum::http::request::Parts;
-use axum::http::{header, HeaderMap, StatusCode};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
 use axum::response::{Html, IntoResponse, Redirect, Response};
 use axum::routing::{get, post};
 use axum::{Json, Router};
@@ -20,10 +20,12 @@ use sha2::{Digest, Sha256};
 use sqlx::{Pool, Row, Sqlite, Transaction};
 use url::Url;
 
-use crate::{json_error, now_ms_utc, AppState, ErrorBody, RateLimiter};
+use crate::{identities, json_error, now_ms_utc, spaces, AppState, ErrorBody, RateLimiter};
 
 pub(crate) const WEB_ACCESS_COOKIE: &str = "easy_todo_access";
 pub(crate) const WEB_REFRESH_COOKIE: &str = "easy_todo_refresh";
+/// Ties a link attempt to the browser that started it.
+const WEB_LINK_COOKIE: &str = "easy_todo_link";
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct OAuthProviderConfig {
@@ -201,7 +203,7 @@ impl AuthService {
         URL_SAFE_NO_PAD.encode(bytes)
     }
 
-    fn hash_token(&self, token: &str) -> String {
+    pub(crate) fn hash_token(&self, token: &str) -> String {
         let mut h = Sha256::new();
         h.update(self.config.token_pepper.as_bytes());
         h.update(b":");
@@ -428,7 +430,7 @@ impl AuthService {
             }
         }
 
-        let user_id = self
+        let (user_id, session_id) = self
             .verify_access_token(pool, &token)
             .await
             .map_err(|_| json_error(StatusCode::UNAUTHORIZED, "invalid access token"))?;
@@ -438,10 +440,20 @@ impl AuthService {
                 return Err(json_error(StatusCode::TOO_MANY_REQUESTS, "rate limited"));
             }
         }
-        Ok(AuthedUser { user_id })
+        Ok(AuthedUser {
+            user_id,
+            account_id: user_id,
+            session_id,
+            space_role: None,
+        })
     }
 
-    async fn verify_access_token(&self, pool: &Pool<Sqlite>, jwt: &str) -> anyhow::Result<i64> {
+    /// Returns `(user_id, session_id)`, where the session is the `refresh_tokens` row.
+    async fn verify_access_token(
+        &self,
+        pool: &Pool<Sqlite>,
+        jwt: &str,
+    ) -> anyhow::Result<(i64, i64)> {
         #[derive(Debug, Serialize, Deserialize)]
         struct Claims {
             sub: String,
@@ -452,7 +464,7 @@ impl AuthService {
         }
 
         let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
-        validation.set_issuer(&[self.config.jwt_issuer.clone()]);
+        validation.set_issuer(std::slice::from_ref(&self.config.jwt_issuer));
         let data = jsonwebtoken::decode::<Claims>(
             jwt,
             &jsonwebtoken::DecodingKey::from_secret(self.config.jwt_secret.as_bytes()),
@@ -491,7 +503,7 @@ impl AuthService {
             anyhow::bail!("session expired");
         }
 
-        Ok(user_id)
+        Ok((user_id, session_id))
     }
 
     fn sign_access_token(&self, user_id: i64, session_id: i64) -> anyhow::Result<(String, i64)> {
@@ -655,6 +667,31 @@ pub(crate) struct IssuedTokens {
 
 #[derive(Debug, Clone)]
 pub struct AuthedUser {
+    /// Whose data the request works on: the caller's own account, or the
+    /// backing row of the space picked with `spaceId`.
+    pub user_id: i64,
+    /// The signed-in account, even when `user_id` is a space.
+    pub account_id: i64,
+    pub session_id: i64,
+    /// The caller's role when the request targets a space.
+    pub space_role: Option<spaces::Role>,
+}
+
+impl AuthedUser {
+    /// Key material and account-level operations (key bundle and slots,
+    /// pairing, import, restores) stay with the space owner; editors only
+    /// get the sync side.
+    pub(crate) fn require_owner(&self) -> Result<(), (StatusCode, Json<ErrorBody>)> {
+        match self.space_role {
+            None | Some(spaces::Role::Owner) => Ok(()),
+            Some(_) => Err(json_error(StatusCode::FORBIDDEN, "owner only")),
+        }
+    }
+}
+
+/// The signed-in account, ignoring `spaceId`; for endpoints that manage
+/// spaces rather than work inside one.
+pub struct AuthedAccount {
     pub user_id: i64,
 }
 
@@ -670,10 +707,51 @@ impl FromRequestParts<AppState> for AuthedUser {
             .extensions
             .get::<ConnectInfo<SocketAddr>>()
             .map(|ci| ci.0.ip());
-        state
+        let mut user = state
             .auth
             .authenticate_request(&state.db, &state.limiter, &parts.headers, remote_ip)
+            .await?;
+
+        let Some(space_id) = spaces::space_id_from_request(parts) else {
+            return Ok(user);
+        };
+        if !spaces::is_valid_space_id(&space_id) {
+            return Err(json_error(StatusCode::NOT_FOUND, "space not found"));
+        }
+        let (space_user_id, role) = spaces::resolve(&state.db, &space_id, user.account_id)
             .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+            .ok_or_else(|| json_error(StatusCode::NOT_FOUND, "space not found"))?;
+        // Viewers get the read side of every endpoint (pull, stream, history,
+        // export, ...) and nothing else.
+        if !role.can_write() && !matches!(parts.method, Method::GET | Method::HEAD) {
+            return Err(json_error(StatusCode::FORBIDDEN, "read only"));
+        }
+        user.user_id = space_user_id;
+        user.space_role = Some(role);
+        Ok(user)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AuthedAccount {
+    type Rejection = (StatusCode, Json<ErrorBody>);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let remote_ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ci| ci.0.ip());
+        let user = state
+            .auth
+            .authenticate_request(&state.db, &state.limiter, &parts.headers, remote_ip)
+            .await?;
+        Ok(AuthedAccount {
+            user_id: user.account_id,
+        })
     }
 }
 
@@ -690,7 +768,7 @@ fn extract_bearer(headers: &HeaderMap) -> Option<String> {
     }
 }
 
-fn hex_encode(bytes: &[u8]) -> String {
+pub(crate) fn hex_encode(bytes: &[u8]) -> String {
     const LUT: &[u8; 16] = b"0123456789abcdef";
     let mut out = String::with_capacity(bytes.len() * 2);
     for &b in bytes {
@@ -926,9 +1004,7 @@ impl ParsedAppRedirectUrl {
         }
 
         let rest = after_colon.get(2..)?;
-        let authority_end = rest
-            .find(|c| matches!(c, '/' | '?' | '#'))
-            .unwrap_or(rest.len());
+        let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
         let authority = rest.get(..authority_end)?;
         let after_authority = rest.get(authority_end..)?;
 
@@ -1029,6 +1105,9 @@ struct StartQuery {
 struct WebStartQuery {
     provider: String,
     return_to: String,
+    /// `link` adds the provider to the signed-in dashboard account instead
+    /// of signing in.
+    intent: Option<String>,
 }
 
 async fn auth_start(
@@ -1108,8 +1187,9 @@ fn is_allowed_web_return_to(return_to: &str) -> bool {
 async fn auth_web_start(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Query(q): Query<WebStartQuery>,
-) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+) -> Result<Response, (StatusCode, Json<ErrorBody>)> {
     {
         let mut limiter = state.auth_limiter.lock().await;
         if !limiter.check(&format!("auth_web_start:{}", addr.ip())) {
@@ -1133,14 +1213,32 @@ async fn auth_web_start(
         return Err(json_error(StatusCode::BAD_REQUEST, "return_to not allowed"));
     }
 
+    let mut set_cookies = Vec::new();
+    let (link_user_id, link_nonce) = match q.intent.as_deref().unwrap_or("login") {
+        "login" => (None, None),
+        "link" => {
+            let (user_id, set) =
+                crate::web::authenticate_web(&state, &headers, Some(addr.ip())).await?;
+            set_cookies.extend(set.unwrap_or_default());
+            (Some(user_id), Some(state.auth.random_token_b64(24)))
+        }
+        _ => return Err(json_error(StatusCode::BAD_REQUEST, "invalid intent")),
+    };
+
     let state_token = state.auth.random_token_b64(24);
     let now_ms = now_ms_utc();
     let expires_at_ms = now_ms + state.auth.config.login_attempt_ttl.as_millis() as i64;
 
+    if let Some(nonce) = &link_nonce {
+        let max_age = state.auth.config.login_attempt_ttl.as_secs() as i64;
+        set_cookies.push(link_cookie(&state, nonce, max_age)?);
+    }
+
     sqlx::query(
         r#"INSERT INTO auth_login_attempts
-           (state, provider, app_redirect, client, created_at_ms_utc, expires_at_ms_utc)
-           VALUES (?, ?, ?, ?, ?, ?)"#,
+           (state, provider, app_redirect, client, created_at_ms_utc, expires_at_ms_utc,
+            link_user_id, link_nonce_hash)
+           VALUES (?, ?, ?, ?, ?, ?, ?, ?)"#,
     )
     .bind(&state_token)
     .bind(&provider)
@@ -1148,6 +1246,8 @@ async fn auth_web_start(
     .bind("web")
     .bind(now_ms)
     .bind(expires_at_ms)
+    .bind(link_user_id)
+    .bind(link_nonce.map(|nonce| state.auth.hash_token(&nonce)))
     .execute(&state.db)
     .await
     .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
@@ -1158,7 +1258,31 @@ async fn auth_web_start(
         .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "oauth config error"))?;
 
-    Ok(Redirect::temporary(&url))
+    let mut resp = Redirect::temporary(&url).into_response();
+    crate::web::apply_set_cookie_headers(resp.headers_mut(), set_cookies);
+    Ok(resp)
+}
+
+/// Scoped to the callback, which is the only place that reads it.
+fn link_cookie(
+    state: &AppState,
+    value: &str,
+    max_age_secs: i64,
+) -> Result<HeaderValue, (StatusCode, Json<ErrorBody>)> {
+    let secure = state
+        .auth
+        .config
+        .base_url
+        .trim_start()
+        .to_lowercase()
+        .starts_with("https://");
+    format!(
+        "{name}={value}; Path=/v1/auth/callback; HttpOnly; SameSite=Lax; Max-Age={max_age_secs}{secure}",
+        name = WEB_LINK_COOKIE,
+        secure = if secure { "; Secure" } else { "" }
+    )
+    .parse()
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "cookie error"))
 }
 
 #[derive(Debug, Deserialize)]
@@ -1172,6 +1296,7 @@ struct CallbackQuery {
 async fn auth_callback(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Query(q): Query<CallbackQuery>,
 ) -> Result<Response, (StatusCode, Json<ErrorBody>)> {
     {
@@ -1205,7 +1330,8 @@ async fn auth_callback(
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
     let row = sqlx::query(
-        r#"SELECT provider, app_redirect, client, expires_at_ms_utc
+        r#"SELECT provider, app_redirect, client, expires_at_ms_utc, link_user_id,
+             link_nonce_hash
            FROM auth_login_attempts WHERE state = ?"#,
     )
     .bind(&q.state)
@@ -1233,6 +1359,12 @@ async fn auth_callback(
     let expires_at_ms_utc: i64 = row
         .try_get("expires_at_ms_utc")
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let link_user_id: Option<i64> = row
+        .try_get("link_user_id")
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let link_nonce_hash: Option<String> = row
+        .try_get("link_nonce_hash")
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
     let now_ms = now_ms_utc();
     if expires_at_ms_utc <= now_ms {
@@ -1259,6 +1391,43 @@ async fn auth_callback(
         .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
+    // A link only completes in the browser that started it, still signed in
+    // to the same account; otherwise someone could start a link on their
+    // account and have a victim finish it with the victim's identity.
+    let mut link_set_cookies = Vec::new();
+    if let Some(link_user_id) = link_user_id {
+        let nonce_matches = link_nonce_hash.as_deref().is_some_and(|stored| {
+            crate::web::cookie_value(&headers, WEB_LINK_COOKIE)
+                .is_some_and(|nonce| state.auth.hash_token(&nonce) == stored)
+        });
+        let session = if nonce_matches {
+            crate::web::authenticate_web(&state, &headers, Some(addr.ip()))
+                .await
+                .ok()
+        } else {
+            None
+        };
+        match session {
+            Some((user_id, set)) if user_id == link_user_id => {
+                link_set_cookies.extend(set.unwrap_or_default());
+            }
+            _ => {
+                let mut resp = state
+                    .auth
+                    .html_result_page(
+                        "Link failed",
+                        "start linking again from the dashboard in this browser",
+                        None,
+                    )
+                    .into_response();
+                resp.headers_mut()
+                    .append(header::SET_COOKIE, link_cookie(&state, "", 0)?);
+                return Ok(resp);
+            }
+        }
+        link_set_cookies.push(link_cookie(&state, "", 0)?);
+    }
+
     let access_token = match state.auth.oauth_exchange_code(&provider, &code).await {
         Ok(t) => t,
         Err(_) => {
@@ -1283,6 +1452,13 @@ async fn auth_callback(
         }
     };
 
+    if let Some(link_user_id) = link_user_id {
+        let mut resp =
+            link_identity(&state, link_user_id, &provider, &sub, &app_redirect, now_ms).await?;
+        crate::web::apply_set_cookie_headers(resp.headers_mut(), link_set_cookies);
+        return Ok(resp);
+    }
+
     let mut tx = state
         .db
         .begin()
@@ -1390,6 +1566,56 @@ async fn auth_callback(
         .into_response())
 }
 
+/// Finish a dashboard "link another provider" flow: attach the identity to
+/// the account that started it and go back with `link=<outcome>`. The
+/// session stays as it is; no tokens are issued.
+async fn link_identity(
+    state: &AppState,
+    user_id: i64,
+    provider: &str,
+    sub: &str,
+    return_to: &str,
+    now_ms: i64,
+) -> Result<Response, (StatusCode, Json<ErrorBody>)> {
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let exists: bool = sqlx::query_scalar(r#"SELECT EXISTS (SELECT 1 FROM users WHERE id = ?)"#)
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if !exists {
+        tx.rollback().await.ok();
+        return Ok(state
+            .auth
+            .html_result_page("Link failed", "account not found", None)
+            .into_response());
+    }
+    let outcome = identities::link(&mut tx, user_id, provider, sub, now_ms)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    let result = match outcome {
+        identities::LinkOutcome::Linked => "linked",
+        identities::LinkOutcome::AlreadyLinked => "already_linked",
+        identities::LinkOutcome::OtherAccount => "other_account",
+    };
+    let return_to = if is_allowed_web_return_to(return_to) {
+        return_to
+    } else {
+        "/dashboard"
+    };
+    let sep = if return_to.contains('?') { '&' } else { '?' };
+    let base = state.auth.config.base_url.trim_end_matches('/');
+    Ok(Redirect::temporary(&format!("{base}{return_to}{sep}link={result}")).into_response())
+}
+
 #[derive(Debug, Deserialize)]
 struct ExchangeRequest {
     ticket: String,
@@ -1642,6 +1868,22 @@ mod tests {
         AuthService::new(cfg).expect("service")
     }
 
+    #[test]
+    fn only_space_owners_pass_require_owner() {
+        let user = |space_role| AuthedUser {
+            user_id: 2,
+            account_id: 1,
+            session_id: 1,
+            space_role,
+        };
+        assert!(user(None).require_owner().is_ok());
+        assert!(user(Some(spaces::Role::Owner)).require_owner().is_ok());
+        for role in [spaces::Role::Editor, spaces::Role::Viewer] {
+            let err = user(Some(role)).require_owner().unwrap_err();
+            assert_eq!(err.0, StatusCode::FORBIDDEN);
+        }
+    }
+
     #[test]
     fn app_redirect_allowlist_scheme_only() {
         let svc = make_service("easy_todo://");
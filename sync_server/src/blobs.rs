@@ -0,0 +1,503 @@
+use sqlx::{Executor, Pool, Row, Sqlite, Transaction};
+
+use crate::blob_store::{self, BlobStore};
+use crate::TYPE_TODO_ATTACHMENT_CHUNK;
+
+pub(crate) const UPLOAD_OFFSET_HEADER: &str = "upload-offset";
+pub(crate) const UPLOAD_LENGTH_HEADER: &str = "upload-length";
+
+/// A chunk blob: the bytes of chunk record `<attachment_id>:<chunk_index>`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BlobKey<'a> {
+    pub user_id: i64,
+    pub attachment_id: &'a str,
+    pub chunk_index: i64,
+}
+
+impl BlobKey<'_> {
+    /// Id of the `todo_attachment_chunk` record the blob belongs to.
+    pub(crate) fn record_id(&self) -> String {
+        format!("{}:{}", self.attachment_id, self.chunk_index)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BlobStatus {
+    pub total_bytes: i64,
+    pub received_bytes: i64,
+}
+
+impl BlobStatus {
+    pub(crate) fn is_complete(&self) -> bool {
+        self.received_bytes == self.total_bytes
+    }
+}
+
+/// Parse `Content-Range: bytes <start>-<end>/<total>` of a resumable upload
+/// part into `(start, end_inclusive, total)`.
+pub(crate) fn parse_content_range(value: &str) -> Option<(i64, i64, i64)> {
+    let rest = value.trim().strip_prefix("bytes ")?;
+    let (range, total) = rest.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let start: i64 = start.trim().parse().ok()?;
+    let end: i64 = end.trim().parse().ok()?;
+    let total: i64 = total.trim().parse().ok()?;
+    (0 <= start && start <= end && end < total).then_some((start, end, total))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RangeRequest {
+    Full,
+    /// `(start, end_inclusive)`
+    Partial(i64, i64),
+    Unsatisfiable,
+}
+
+/// Resolve a `Range` header against a blob of `len` bytes.
+///
+/// Only a single `bytes=` range is supported; anything else is ignored and
+/// the whole blob is served, as RFC 9110 allows.
+pub(crate) fn parse_range(value: &str, len: i64) -> RangeRequest {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RangeRequest::Full;
+    };
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let Ok(n) = suffix.parse::<i64>() else {
+                return RangeRequest::Full;
+            };
+            if n <= 0 || len == 0 {
+                return RangeRequest::Unsatisfiable;
+            }
+            ((len - n).max(0), len - 1)
+        }
+        (start, "") => {
+            let Ok(start) = start.parse::<i64>() else {
+                return RangeRequest::Full;
+            };
+            (start, len - 1)
+        }
+        (start, end) => {
+            let (Ok(start), Ok(end)) = (start.parse::<i64>(), end.parse::<i64>()) else {
+                return RangeRequest::Full;
+            };
+            if end < start {
+                return RangeRequest::Full;
+            }
+            (start, end.min(len - 1))
+        }
+    };
+    if start >= len {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Partial(start, end)
+}
+
+/// Whether the chunk record for `key` is committed. Devices may already have
+/// its bytes, so the blob is frozen until a new version of the chunk is
+/// staged by a push.
+pub(crate) async fn chunk_is_committed<'e, E>(
+    executor: E,
+    key: BlobKey<'_>,
+) -> Result<bool, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query_scalar(
+        r#"SELECT EXISTS (
+             SELECT 1 FROM records WHERE user_id = ? AND type = ? AND record_id = ?
+           ) AND NOT EXISTS (
+             SELECT 1 FROM staged_records WHERE user_id = ? AND type = ? AND record_id = ?
+           )"#,
+    )
+    .bind(key.user_id)
+    .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+    .bind(key.record_id())
+    .bind(key.user_id)
+    .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+    .bind(key.record_id())
+    .fetch_one(executor)
+    .await
+}
+
+pub(crate) async fn blob_status<'e, E>(
+    executor: E,
+    key: BlobKey<'_>,
+) -> Result<Option<BlobStatus>, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let row = sqlx::query(
+        r#"SELECT
+             total_bytes,
+             CASE WHEN object_key IS NULL THEN LENGTH(data) ELSE total_bytes END AS received_bytes
+           FROM attachment_blobs
+           WHERE user_id = ? AND attachment_id = ? AND chunk_index = ?"#,
+    )
+    .bind(key.user_id)
+    .bind(key.attachment_id)
+    .bind(key.chunk_index)
+    .fetch_optional(executor)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    Ok(Some(BlobStatus {
+        total_bytes: row.try_get("total_bytes")?,
+        received_bytes: row.try_get("received_bytes")?,
+    }))
+}
+
+/// Write `bytes` at `start` of a blob of `total_bytes`.
+///
+/// `start == 0` (re)starts the blob; any other offset must equal the bytes
+/// already received, which the caller checks against `blob_status`, and is
+/// appended in place. Uploads accumulate in SQLite; once the transaction has
+/// committed, a completed blob can be moved to the blob store with
+/// `offload_blob`.
+pub(crate) async fn write_blob(
+    tx: &mut Transaction<'_, Sqlite>,
+    key: BlobKey<'_>,
+    start: i64,
+    total_bytes: i64,
+    bytes: &[u8],
+    now_ms: i64,
+) -> Result<BlobStatus, sqlx::Error> {
+    if start != 0 {
+        // `||` yields text; the cast keeps the bytes and the BLOB type.
+        let received_bytes: i64 = sqlx::query_scalar(
+            r#"UPDATE attachment_blobs
+               SET data = CAST(data || ? AS BLOB), updated_at_ms_utc = ?
+               WHERE user_id = ? AND attachment_id = ? AND chunk_index = ?
+               RETURNING LENGTH(data)"#,
+        )
+        .bind(bytes)
+        .bind(now_ms)
+        .bind(key.user_id)
+        .bind(key.attachment_id)
+        .bind(key.chunk_index)
+        .fetch_one(&mut **tx)
+        .await?;
+        return Ok(BlobStatus {
+            total_bytes,
+            received_bytes,
+        });
+    }
+
+    sqlx::query(
+        r#"INSERT INTO attachment_blobs (
+             user_id, attachment_id, chunk_index, total_bytes, data, object_key,
+             updated_at_ms_utc
+           ) VALUES (?, ?, ?, ?, ?, ?, ?)
+           ON CONFLICT(user_id, attachment_id, chunk_index) DO UPDATE SET
+             total_bytes = excluded.total_bytes,
+             data = excluded.data,
+             object_key = excluded.object_key,
+             updated_at_ms_utc = excluded.updated_at_ms_utc"#,
+    )
+    .bind(key.user_id)
+    .bind(key.attachment_id)
+    .bind(key.chunk_index)
+    .bind(total_bytes)
+    .bind(bytes)
+    .bind(None::<String>)
+    .bind(now_ms)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(BlobStatus {
+        total_bytes,
+        received_bytes: bytes.len() as i64,
+    })
+}
+
+/// Record a complete blob whose bytes are already in the store under
+/// `object_key` (a pending upload, see `blob_store::upload_pending`), and
+/// claim it.
+pub(crate) async fn insert_stored_blob(
+    tx: &mut Transaction<'_, Sqlite>,
+    key: BlobKey<'_>,
+    total_bytes: i64,
+    object_key: &str,
+    now_ms: i64,
+) -> Result<(), sqlx::Error> {
+    blob_store::claim(tx, object_key).await?;
+    sqlx::query(
+        r#"INSERT INTO attachment_blobs (
+             user_id, attachment_id, chunk_index, total_bytes, data, object_key,
+             updated_at_ms_utc
+           ) VALUES (?, ?, ?, ?, ?, ?, ?)
+           ON CONFLICT(user_id, attachment_id, chunk_index) DO UPDATE SET
+             total_bytes = excluded.total_bytes,
+             data = excluded.data,
+             object_key = excluded.object_key,
+             updated_at_ms_utc = excluded.updated_at_ms_utc"#,
+    )
+    .bind(key.user_id)
+    .bind(key.attachment_id)
+    .bind(key.chunk_index)
+    .bind(total_bytes)
+    .bind(Vec::<u8>::new())
+    .bind(object_key)
+    .bind(now_ms)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Move a completed blob from SQLite into the store. Runs outside any
+/// transaction: the bytes are uploaded first, then the row switches to the
+/// object only if it still holds those bytes. If anything fails the blob
+/// simply stays inline (and an unused upload is swept from the trash).
+pub(crate) async fn offload_blob(
+    db: &Pool<Sqlite>,
+    store: &dyn BlobStore,
+    key: BlobKey<'_>,
+) -> anyhow::Result<()> {
+    let data: Option<Vec<u8>> = sqlx::query_scalar(
+        r#"SELECT data FROM attachment_blobs
+           WHERE user_id = ? AND attachment_id = ? AND chunk_index = ?
+             AND object_key IS NULL AND LENGTH(data) = total_bytes"#,
+    )
+    .bind(key.user_id)
+    .bind(key.attachment_id)
+    .bind(key.chunk_index)
+    .fetch_optional(db)
+    .await?;
+    let Some(data) = data else {
+        return Ok(());
+    };
+    let object_key = blob_store::upload_pending(db, store, key.user_id, data.clone()).await?;
+
+    let mut tx = db.begin().await?;
+    let updated = sqlx::query(
+        r#"UPDATE attachment_blobs
+           SET data = ?, object_key = ?
+           WHERE user_id = ? AND attachment_id = ? AND chunk_index = ?
+             AND object_key IS NULL AND data = ?"#,
+    )
+    .bind(Vec::<u8>::new())
+    .bind(&object_key)
+    .bind(key.user_id)
+    .bind(key.attachment_id)
+    .bind(key.chunk_index)
+    .bind(data)
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() > 0 {
+        blob_store::claim(&mut tx, &object_key).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// `len` bytes of a blob starting at `start`.
+pub(crate) async fn read_blob(
+    db: &Pool<Sqlite>,
+    store: Option<&dyn BlobStore>,
+    key: BlobKey<'_>,
+    start: i64,
+    len: i64,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    // `substr` is byte-based on BLOBs, so only the requested range is loaded.
+    let row: Option<(Vec<u8>, Option<String>)> = sqlx::query_as(
+        r#"SELECT substr(data, ? + 1, ?), object_key
+           FROM attachment_blobs
+           WHERE user_id = ? AND attachment_id = ? AND chunk_index = ?"#,
+    )
+    .bind(start)
+    .bind(len)
+    .bind(key.user_id)
+    .bind(key.attachment_id)
+    .bind(key.chunk_index)
+    .fetch_optional(db)
+    .await?;
+    match row {
+        None => Ok(None),
+        Some((data, None)) => Ok(Some(data)),
+        Some((_, Some(object_key))) => {
+            let Some(store) = store else {
+                anyhow::bail!(
+                    "blob {object_key} is in the blob store, but BLOB_STORE is not configured"
+                );
+            };
+            store
+                .get(&object_key, Some((start as u64, len as u64)))
+                .await
+        }
+    }
+}
+
+/// Drop every blob of an attachment. Returns the bytes freed.
+pub(crate) async fn delete_attachment_blobs(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    attachment_id: &str,
+) -> Result<i64, sqlx::Error> {
+    let bytes: i64 = sqlx::query_scalar(
+        r#"SELECT IFNULL(SUM(CASE WHEN object_key IS NULL THEN LENGTH(data) ELSE total_bytes END), 0)
+           FROM attachment_blobs
+           WHERE user_id = ? AND attachment_id = ?"#,
+    )
+    .bind(user_id)
+    .bind(attachment_id)
+    .fetch_one(&mut **tx)
+    .await?;
+    sqlx::query(r#"DELETE FROM attachment_blobs WHERE user_id = ? AND attachment_id = ?"#)
+        .bind(user_id)
+        .bind(attachment_id)
+        .execute(&mut **tx)
+        .await?;
+    Ok(bytes)
+}
+
+/// Drop the blob behind a chunk record (`<attachmentId>:<index>`), if any.
+/// Returns the bytes freed.
+pub(crate) async fn delete_chunk_blob(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    chunk_record_id: &str,
+) -> Result<i64, sqlx::Error> {
+    let Some((attachment_id, index)) = chunk_record_id.rsplit_once(':') else {
+        return Ok(0);
+    };
+    let Ok(chunk_index) = index.parse::<i64>() else {
+        return Ok(0);
+    };
+    let bytes: Option<i64> = sqlx::query_scalar(
+        r#"DELETE FROM attachment_blobs
+           WHERE user_id = ? AND attachment_id = ? AND chunk_index = ?
+           RETURNING CASE WHEN object_key IS NULL THEN LENGTH(data) ELSE total_bytes END"#,
+    )
+    .bind(user_id)
+    .bind(attachment_id)
+    .bind(chunk_index)
+    .fetch_optional(&mut **tx)
+    .await?;
+    Ok(bytes.unwrap_or(0))
+}
+
+/// Delete blobs untouched since `cutoff` whose chunk record was never pushed
+/// (or has since been removed). Returns the affected users.
+pub(crate) async fn gc_orphan_blobs(
+    tx: &mut Transaction<'_, Sqlite>,
+    cutoff: i64,
+) -> Result<Vec<i64>, sqlx::Error> {
+    let user_ids: Vec<i64> = sqlx::query_scalar(
+        r#"DELETE FROM attachment_blobs AS b
+           WHERE b.updated_at_ms_utc < ?
+             AND NOT EXISTS (
+               SELECT 1 FROM records r
+               WHERE r.user_id = b.user_id AND r.type = ?
+                 AND r.record_id = b.attachment_id || ':' || b.chunk_index
+             )
+             AND NOT EXISTS (
+               SELECT 1 FROM staged_records s
+               WHERE s.user_id = b.user_id AND s.type = ?
+                 AND s.record_id = b.attachment_id || ':' || b.chunk_index
+             )
+           RETURNING user_id"#,
+    )
+    .bind(cutoff)
+    .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+    .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+    .fetch_all(&mut **tx)
+    .await?;
+    let mut user_ids = user_ids;
+    user_ids.sort_unstable();
+    user_ids.dedup();
+    Ok(user_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_content_range("bytes 0-99/1000"), Some((0, 99, 1000)));
+        assert_eq!(parse_content_range("bytes 900-1000/1000"), None);
+        assert_eq!(parse_content_range("bytes */1000"), None);
+
+        assert_eq!(
+            parse_range("bytes=0-99", 1000),
+            RangeRequest::Partial(0, 99)
+        );
+        assert_eq!(
+            parse_range("bytes=900-", 1000),
+            RangeRequest::Partial(900, 999)
+        );
+        assert_eq!(
+            parse_range("bytes=-100", 1000),
+            RangeRequest::Partial(900, 999)
+        );
+        assert_eq!(
+            parse_range("bytes=500-5000", 1000),
+            RangeRequest::Partial(500, 999)
+        );
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("items=0-1", 1000), RangeRequest::Full);
+    }
+
+    const CHUNK_COLUMNS: &str = "user_id, type, record_id, hlc_wall_ms_utc, hlc_counter, \
+        hlc_device_id, schema_version, dek_id, algo, nonce, ciphertext, updated_at_ms_utc";
+
+    async fn insert_chunk(db: &Pool<Sqlite>, sql: &str) {
+        sqlx::query(sql)
+            .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn committed_chunk_blocks_overwrite() {
+        let db = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!("./migrations").run(&db).await.unwrap();
+        sqlx::query(
+            "INSERT INTO users (id, oauth_provider, oauth_sub, created_at_ms_utc) VALUES (1, 'p', 's', 0)",
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        let key = BlobKey {
+            user_id: 1,
+            attachment_id: "att",
+            chunk_index: 0,
+        };
+
+        assert!(!chunk_is_committed(&db, key).await.unwrap());
+        insert_chunk(
+            &db,
+            &format!(
+                "INSERT INTO records ({CHUNK_COLUMNS}, server_seq) \
+                 VALUES (1, ?, 'att:0', 1, 0, 'd', 1, 'k', 'a', 'n', 'c', 1, 1)"
+            ),
+        )
+        .await;
+        assert!(chunk_is_committed(&db, key).await.unwrap());
+        insert_chunk(
+            &db,
+            &format!(
+                "INSERT INTO staged_records ({CHUNK_COLUMNS}) \
+                 VALUES (1, ?, 'att:0', 2, 0, 'd', 1, 'k', 'a', 'n', 'c', 2)"
+            ),
+        )
+        .await;
+        assert!(!chunk_is_committed(&db, key).await.unwrap());
+    }
+}
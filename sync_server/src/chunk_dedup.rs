@@ -0,0 +1,99 @@
+use sqlx::{Executor, Sqlite, Transaction};
+
+use crate::blob_store;
+
+/// Longest accepted `contentHash` (a base64/hex keyed hash is far shorter).
+pub(crate) const MAX_CONTENT_HASH_LEN: usize = 128;
+
+pub(crate) fn is_valid_content_hash(hash: &str) -> bool {
+    !hash.is_empty()
+        && hash.len() <= MAX_CONTENT_HASH_LEN
+        && hash
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'+' | b'/' | b'='))
+}
+
+/// Decoded length of the stored chunk with this hash, if the account has one.
+pub(crate) async fn lookup<'e, E>(
+    executor: E,
+    user_id: i64,
+    content_hash: &str,
+) -> Result<Option<i64>, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query_scalar(
+        r#"SELECT ciphertext_bytes FROM chunk_blobs
+           WHERE user_id = ? AND content_hash = ?"#,
+    )
+    .bind(user_id)
+    .bind(content_hash)
+    .fetch_optional(executor)
+    .await
+}
+
+/// A chunk stored for the first time under its hash.
+pub(crate) struct NewChunk<'a> {
+    pub content_hash: &'a str,
+    /// The `nonce` and `dek_id` the bytes were encrypted with; reads serve
+    /// these instead of the referencing row's own.
+    pub nonce: &'a str,
+    pub dek_id: &'a str,
+    pub ciphertext: &'a str,
+    pub ciphertext_bytes: i64,
+}
+
+/// Store a chunk's ciphertext under its hash with no references yet; the
+/// chunk row written next takes the first one. The caller checks `lookup`
+/// first. `offloaded` is the ciphertext's pending blob store upload, if any
+/// (see `blob_store::offload_ciphertext`); it is claimed here.
+pub(crate) async fn insert(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    chunk: &NewChunk<'_>,
+    offloaded: Option<&(String, i64)>,
+    now_ms: i64,
+) -> Result<(), sqlx::Error> {
+    let (ciphertext, ciphertext_ref, ciphertext_len) = match offloaded {
+        Some((key, len)) => {
+            blob_store::claim(tx, key).await?;
+            ("", Some(key.as_str()), Some(*len))
+        }
+        None => (chunk.ciphertext, None, None),
+    };
+    sqlx::query(
+        r#"INSERT INTO chunk_blobs (
+             user_id, content_hash, nonce, dek_id, ciphertext, ciphertext_ref,
+             ciphertext_len, ciphertext_bytes, ref_count, created_at_ms_utc
+           ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, 0, ?)"#,
+    )
+    .bind(user_id)
+    .bind(chunk.content_hash)
+    .bind(chunk.nonce)
+    .bind(chunk.dek_id)
+    .bind(ciphertext)
+    .bind(ciphertext_ref)
+    .bind(ciphertext_len)
+    .bind(chunk.ciphertext_bytes)
+    .bind(now_ms)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_hashes() {
+        assert!(is_valid_content_hash("3q2-7w_AbC="));
+        assert!(is_valid_content_hash(&"a".repeat(MAX_CONTENT_HASH_LEN)));
+        assert!(!is_valid_content_hash(""));
+        assert!(!is_valid_content_hash(
+            &"a".repeat(MAX_CONTENT_HASH_LEN + 1)
+        ));
+        assert!(!is_valid_content_hash("abc def"));
+        assert!(!is_valid_content_hash("abc%"));
+    }
+}
@@ -0,0 +1,128 @@
+use axum::http::HeaderMap;
+use serde::Serialize;
+use sqlx::{Pool, Row, Sqlite};
+
+use crate::history::SERVER_HLC_DEVICE_ID;
+
+pub(crate) const MAX_DEVICE_ID_LEN: usize = 128;
+const MAX_DEVICE_NAME_CHARS: usize = 64;
+const MAX_DEVICES_PER_USER: i64 = 100;
+
+pub(crate) const DEVICE_ID_HEADER: &str = "x-device-id";
+pub(crate) const DEVICE_NAME_HEADER: &str = "x-device-name";
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Device {
+    #[serde(rename = "deviceId")]
+    pub device_id: String,
+    pub name: Option<String>,
+    #[serde(rename = "firstSeenAtMsUtc")]
+    pub first_seen_at_ms_utc: i64,
+    #[serde(rename = "lastSeenAtMsUtc")]
+    pub last_seen_at_ms_utc: i64,
+    #[serde(rename = "lastAckServerSeq")]
+    pub last_ack_server_seq: i64,
+    #[serde(rename = "sessionId")]
+    pub session_id: Option<i64>,
+}
+
+pub(crate) fn normalize_device_id(raw: &str) -> Option<String> {
+    let id = raw.trim();
+    if id.is_empty() || id.len() > MAX_DEVICE_ID_LEN || id == SERVER_HLC_DEVICE_ID {
+        return None;
+    }
+    Some(id.to_string())
+}
+
+fn header_utf8<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|v| std::str::from_utf8(v.as_bytes()).ok())
+}
+
+pub(crate) fn device_id_from_headers(headers: &HeaderMap) -> Option<String> {
+    header_utf8(headers, DEVICE_ID_HEADER).and_then(normalize_device_id)
+}
+
+pub(crate) fn device_name_from_headers(headers: &HeaderMap) -> Option<String> {
+    let name = header_utf8(headers, DEVICE_NAME_HEADER)?.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some(name.chars().take(MAX_DEVICE_NAME_CHARS).collect())
+}
+
+/// Upsert a device, bumping `last_seen` and (monotonically) its ack cursor.
+///
+/// New devices beyond `MAX_DEVICES_PER_USER` are ignored rather than rejected,
+/// so a client with an unstable device id can't break sync for the account.
+pub(crate) async fn record_sighting(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    device_id: &str,
+    name: Option<&str>,
+    session_id: i64,
+    ack_server_seq: Option<i64>,
+    now_ms: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"INSERT INTO devices (
+             user_id, device_id, name,
+             first_seen_at_ms_utc, last_seen_at_ms_utc,
+             last_ack_server_seq, session_id
+           )
+           SELECT ?, ?, ?, ?, ?, ?, ?
+           WHERE (SELECT COUNT(*) FROM devices WHERE user_id = ?) < ?
+              OR EXISTS (SELECT 1 FROM devices WHERE user_id = ? AND device_id = ?)
+           ON CONFLICT(user_id, device_id) DO UPDATE SET
+             name = COALESCE(excluded.name, devices.name),
+             last_seen_at_ms_utc = excluded.last_seen_at_ms_utc,
+             last_ack_server_seq = MAX(devices.last_ack_server_seq, excluded.last_ack_server_seq),
+             session_id = excluded.session_id"#,
+    )
+    .bind(user_id)
+    .bind(device_id)
+    .bind(name)
+    .bind(now_ms)
+    .bind(now_ms)
+    .bind(ack_server_seq.unwrap_or(0).max(0))
+    .bind(session_id)
+    .bind(user_id)
+    .bind(MAX_DEVICES_PER_USER)
+    .bind(user_id)
+    .bind(device_id)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub(crate) async fn list_devices(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+) -> Result<Vec<Device>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"SELECT
+             device_id, name,
+             first_seen_at_ms_utc, last_seen_at_ms_utc,
+             last_ack_server_seq, session_id
+           FROM devices
+           WHERE user_id = ?
+           ORDER BY last_seen_at_ms_utc DESC"#,
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await?;
+
+    rows.into_iter()
+        .map(|row| {
+            Ok(Device {
+                device_id: row.try_get("device_id")?,
+                name: row.try_get("name")?,
+                first_seen_at_ms_utc: row.try_get("first_seen_at_ms_utc")?,
+                last_seen_at_ms_utc: row.try_get("last_seen_at_ms_utc")?,
+                last_ack_server_seq: row.try_get("last_ack_server_seq")?,
+                session_id: row.try_get("session_id")?,
+            })
+        })
+        .collect()
+}
@@ -1,10 +1,39 @@
 use std::collections::HashSet;
 
 use anyhow::anyhow;
-use sqlx::{Pool, Sqlite, Transaction};
+use sqlx::{Pool, Row, Sqlite, Transaction};
+
+use crate::{blobs, history};
 
 const TYPE_TODO: &str = "todo";
 
+/// Cap on children tombstoned per user and run; the rest follow next run.
+const MAX_ORPHAN_CHILDREN_PER_RUN: i64 = 1000;
+
+/// Live children (`record_parents`) whose parent has been a tombstone since
+/// before the cutoff, or whose parent doesn't exist (not even staged) and whose
+/// link predates the cutoff. Binds: cutoff, cutoff.
+const ORPHAN_CHILDREN_SQL: &str = r#"SELECT p.user_id, p.type, p.record_id
+     FROM record_parents p
+     JOIN records c
+       ON c.user_id = p.user_id AND c.type = p.type AND c.record_id = p.record_id
+     LEFT JOIN records parent
+       ON parent.user_id = p.user_id
+      AND parent.type = p.parent_type
+      AND parent.record_id = p.parent_id
+     WHERE c.deleted_at_ms_utc IS NULL
+       AND (
+         (parent.deleted_at_ms_utc IS NOT NULL AND parent.updated_at_ms_utc <= ?)
+         OR (
+           parent.record_id IS NULL
+           AND p.updated_at_ms_utc <= ?
+           AND NOT EXISTS (
+             SELECT 1 FROM staged_records s
+             WHERE s.user_id = p.user_id AND s.type = p.parent_type AND s.record_id = p.parent_id
+           )
+         )
+       )"#;
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct GhostGcOptions {
     pub include_unreferenced_when_no_live_todo: bool,
@@ -19,7 +48,7 @@ pub(crate) struct GhostGcStats {
     pub stored_after: i64,
 }
 
-fn escape_like_prefix(value: &str) -> String {
+pub(crate) fn escape_like_prefix(value: &str) -> String {
     value
         .replace('\\', "\\\\")
         .replace('%', "\\%")
@@ -158,9 +187,6 @@ pub(crate) async fn gc_ghost_files_for_user_with_stored_before(
             continue;
         }
 
-        let escaped = escape_like_prefix(&attachment_id);
-        let chunk_pattern = format!("{escaped}:%");
-
         let mut deleted_any = 0_i64;
 
         deleted_any +=
@@ -172,16 +198,8 @@ pub(crate) async fn gc_ghost_files_for_user_with_stored_before(
                 .await?
                 .rows_affected() as i64;
 
-        deleted_any += sqlx::query(
-            r#"DELETE FROM records
-               WHERE user_id = ? AND type = ? AND record_id LIKE ? ESCAPE '\'"#,
-        )
-        .bind(user_id)
-        .bind(crate::TYPE_TODO_ATTACHMENT_CHUNK)
-        .bind(&chunk_pattern)
-        .execute(&mut **tx)
-        .await?
-        .rows_affected() as i64;
+        deleted_any +=
+            crate::delete_attachment_chunk_rows(tx, "records", user_id, &attachment_id).await?;
 
         deleted_any += sqlx::query(
             r#"DELETE FROM staged_records WHERE user_id = ? AND type = ? AND record_id = ?"#,
@@ -193,16 +211,11 @@ pub(crate) async fn gc_ghost_files_for_user_with_stored_before(
         .await?
         .rows_affected() as i64;
 
-        deleted_any += sqlx::query(
-            r#"DELETE FROM staged_records
-               WHERE user_id = ? AND type = ? AND record_id LIKE ? ESCAPE '\'"#,
-        )
-        .bind(user_id)
-        .bind(crate::TYPE_TODO_ATTACHMENT_CHUNK)
-        .bind(&chunk_pattern)
-        .execute(&mut **tx)
-        .await?
-        .rows_affected() as i64;
+        deleted_any +=
+            crate::delete_attachment_chunk_rows(tx, "staged_records", user_id, &attachment_id)
+                .await?;
+
+        crate::blobs::delete_attachment_blobs(tx, user_id, &attachment_id).await?;
 
         let _ =
             sqlx::query(r#"DELETE FROM attachment_refs WHERE user_id = ? AND attachment_id = ?"#)
@@ -280,3 +293,93 @@ pub(crate) async fn select_users_with_orphan_attachment_refs(
 
     Ok(user_ids)
 }
+
+pub(crate) async fn select_users_with_orphan_children(
+    db: &Pool<Sqlite>,
+    min_parent_age_ms: i64,
+    max_users: i64,
+) -> anyhow::Result<Vec<i64>> {
+    let max_users = max_users.clamp(1, 10_000);
+    let cutoff = crate::now_ms_utc().saturating_sub(min_parent_age_ms.max(0));
+    let user_ids: Vec<i64> = sqlx::query_scalar(&format!(
+        "SELECT DISTINCT user_id FROM ({ORPHAN_CHILDREN_SQL}) LIMIT ?"
+    ))
+    .bind(cutoff)
+    .bind(cutoff)
+    .bind(max_users)
+    .fetch_all(db)
+    .await?;
+    Ok(user_ids)
+}
+
+/// Tombstone the user's live children of deleted parents, like a client
+/// deleting them: each gets a server HLC and a fresh `serverSeq`, and its
+/// previous version goes to history. The parent link goes, and attachment
+/// bytes are compacted as for a deleted attachment. Returns how many were
+/// tombstoned.
+pub(crate) async fn tombstone_orphan_children_for_user(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    min_parent_age_ms: i64,
+    history_limit: i64,
+) -> anyhow::Result<i64> {
+    let now_ms = crate::now_ms_utc();
+    let cutoff = now_ms.saturating_sub(min_parent_age_ms.max(0));
+    let children: Vec<(i64, String, String)> = sqlx::query_as(&format!(
+        "SELECT * FROM ({ORPHAN_CHILDREN_SQL}) WHERE user_id = ? LIMIT ?"
+    ))
+    .bind(cutoff)
+    .bind(cutoff)
+    .bind(user_id)
+    .bind(MAX_ORPHAN_CHILDREN_PER_RUN)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    let mut tombstoned = 0_i64;
+    for (_, record_type, record_id) in children {
+        let row = sqlx::query(
+            r#"SELECT
+                 type, record_id,
+                 hlc_wall_ms_utc, hlc_counter, hlc_device_id,
+                 deleted_at_ms_utc, schema_version, dek_id, algo,
+                 nonce, ciphertext,
+                 ciphertext_ref IS NOT NULL OR content_hash IS NOT NULL AS stored_elsewhere
+               FROM records
+               WHERE user_id = ? AND type = ? AND record_id = ?"#,
+        )
+        .bind(user_id)
+        .bind(&record_type)
+        .bind(&record_id)
+        .fetch_one(&mut **tx)
+        .await?;
+        let mut record = crate::record_envelope_from_row(&row)?;
+        let hlc = record.hlc.clone();
+        record.deleted_at_ms_utc = Some(now_ms);
+        if record_type == crate::TYPE_TODO_ATTACHMENT_CHUNK || row.try_get("stored_elsewhere")? {
+            record.nonce.clear();
+            record.ciphertext.clear();
+        }
+        history::republish(tx, user_id, &mut record, Some(&hlc), history_limit, now_ms).await?;
+
+        sqlx::query(
+            r#"DELETE FROM record_parents WHERE user_id = ? AND type = ? AND record_id = ?"#,
+        )
+        .bind(user_id)
+        .bind(&record_type)
+        .bind(&record_id)
+        .execute(&mut **tx)
+        .await?;
+        if record_type == crate::TYPE_TODO_ATTACHMENT {
+            crate::delete_staged_attachment(tx, user_id, &record_id).await?;
+            crate::compact_committed_attachment_chunks(tx, user_id, &record_id, now_ms).await?;
+        } else if record_type == crate::TYPE_TODO_ATTACHMENT_CHUNK {
+            blobs::delete_chunk_blob(tx, user_id, &record_id).await?;
+        }
+        tombstoned += 1;
+    }
+
+    if tombstoned > 0 {
+        crate::recompute_and_store_user_b64(tx, user_id).await?;
+    }
+    Ok(tombstoned)
+}
@@ -0,0 +1,344 @@
+use serde::Serialize;
+use sqlx::{Pool, Row, Sqlite, Transaction};
+
+use crate::{
+    alloc_server_seq, is_attachment_staged_type, record_envelope_from_row, Hlc, SyncRecordEnvelope,
+};
+
+/// Device id used in HLCs the server mints itself (e.g. when restoring a version).
+pub(crate) const SERVER_HLC_DEVICE_ID: &str = "server";
+
+#[derive(Debug, Serialize)]
+pub(crate) struct HistoryVersion {
+    #[serde(rename = "versionId")]
+    pub version_id: i64,
+    #[serde(rename = "supersededAtMsUtc")]
+    pub superseded_at_ms_utc: i64,
+    pub record: SyncRecordEnvelope,
+}
+
+/// Attachment metadata and chunks are excluded: chunks are large and a
+/// deleted attachment's chunks are compacted, so old metadata can't be revived.
+pub(crate) fn is_versioned_type(t: &str) -> bool {
+    !is_attachment_staged_type(t)
+}
+
+async fn record_history_b64(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    record_type: &str,
+    record_id: &str,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext)), 0)
+           FROM record_history
+           WHERE user_id = ? AND type = ? AND record_id = ?"#,
+    )
+    .bind(user_id)
+    .bind(record_type)
+    .bind(record_id)
+    .fetch_one(&mut **tx)
+    .await
+}
+
+/// Copy the committed row into history before the write allocated
+/// `superseded_by_seq` overwrites it, keeping the newest `limit` versions.
+/// Returns the change in history bytes.
+pub(crate) async fn archive_current(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    record_type: &str,
+    record_id: &str,
+    superseded_by_seq: i64,
+    limit: i64,
+    now_ms: i64,
+) -> Result<i64, sqlx::Error> {
+    if limit <= 0 || !is_versioned_type(record_type) {
+        return Ok(0);
+    }
+    let before = record_history_b64(tx, user_id, record_type, record_id).await?;
+
+    sqlx::query(
+        r#"INSERT INTO record_history (
+             user_id, type, record_id,
+             hlc_wall_ms_utc, hlc_counter, hlc_device_id,
+             deleted_at_ms_utc, schema_version, dek_id,
+             algo, nonce, ciphertext,
+             server_seq, superseded_at_ms_utc,
+             updated_at_ms_utc, superseded_by_seq
+           )
+           SELECT
+             user_id, type, record_id,
+             hlc_wall_ms_utc, hlc_counter, hlc_device_id,
+             deleted_at_ms_utc, schema_version, dek_id,
+             algo, nonce, ciphertext,
+             server_seq, ?,
+             updated_at_ms_utc, ?
+           FROM records
+           WHERE user_id = ? AND type = ? AND record_id = ?"#,
+    )
+    .bind(now_ms)
+    .bind(superseded_by_seq)
+    .bind(user_id)
+    .bind(record_type)
+    .bind(record_id)
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query(
+        r#"DELETE FROM record_history
+           WHERE user_id = ? AND type = ? AND record_id = ?
+             AND id NOT IN (
+               SELECT id FROM record_history
+               WHERE user_id = ? AND type = ? AND record_id = ?
+               ORDER BY id DESC
+               LIMIT ?
+             )"#,
+    )
+    .bind(user_id)
+    .bind(record_type)
+    .bind(record_id)
+    .bind(user_id)
+    .bind(record_type)
+    .bind(record_id)
+    .bind(limit)
+    .execute(&mut **tx)
+    .await?;
+
+    let after = record_history_b64(tx, user_id, record_type, record_id).await?;
+    Ok(after - before)
+}
+
+/// Write `record` as the current version with a server HLC ahead of
+/// `current_hlc` and a fresh `serverSeq`, archiving whatever it replaces.
+/// Sets `record.hlc` / `record.server_seq` and returns the new seq.
+///
+/// The ciphertext is stored inline; a tombstone also drops the manifest and
+/// any offloaded or deduplicated bytes the replaced row pointed at.
+pub(crate) async fn republish(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    record: &mut SyncRecordEnvelope,
+    current_hlc: Option<&Hlc>,
+    limit: i64,
+    now_ms: i64,
+) -> anyhow::Result<i64> {
+    let server_seq = alloc_server_seq(tx, user_id).await?;
+    if current_hlc.is_some() {
+        archive_current(
+            tx,
+            user_id,
+            &record.r#type,
+            &record.record_id,
+            server_seq,
+            limit,
+            now_ms,
+        )
+        .await?;
+    }
+    record.hlc = server_hlc_after(current_hlc, now_ms);
+    record.server_seq = Some(server_seq);
+    let ((chunk_count, total_ciphertext_len), content_hash) = if record.deleted_at_ms_utc.is_some()
+    {
+        ((None, None), None)
+    } else {
+        (record.stored_manifest(), record.content_hash.clone())
+    };
+
+    sqlx::query(
+        r#"INSERT INTO records (
+             user_id, type, record_id,
+             hlc_wall_ms_utc, hlc_counter, hlc_device_id,
+             deleted_at_ms_utc,
+             schema_version, dek_id,
+             algo, nonce, ciphertext,
+             ciphertext_ref, ciphertext_len, ciphertext_bytes,
+             chunk_count, total_ciphertext_len, content_hash,
+             server_seq, updated_at_ms_utc, created_server_seq
+           ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, NULL, NULL, NULL, ?, ?, ?, ?, ?, ?)
+           ON CONFLICT(user_id, type, record_id) DO UPDATE SET
+             hlc_wall_ms_utc = excluded.hlc_wall_ms_utc,
+             hlc_counter = excluded.hlc_counter,
+             hlc_device_id = excluded.hlc_device_id,
+             deleted_at_ms_utc = excluded.deleted_at_ms_utc,
+             schema_version = excluded.schema_version,
+             dek_id = excluded.dek_id,
+             algo = excluded.algo,
+             nonce = excluded.nonce,
+             ciphertext = excluded.ciphertext,
+             ciphertext_ref = excluded.ciphertext_ref,
+             ciphertext_len = excluded.ciphertext_len,
+             ciphertext_bytes = excluded.ciphertext_bytes,
+             chunk_count = excluded.chunk_count,
+             total_ciphertext_len = excluded.total_ciphertext_len,
+             content_hash = excluded.content_hash,
+             server_seq = excluded.server_seq,
+             updated_at_ms_utc = excluded.updated_at_ms_utc"#,
+    )
+    .bind(user_id)
+    .bind(&record.r#type)
+    .bind(&record.record_id)
+    .bind(record.hlc.wall_time_ms_utc)
+    .bind(record.hlc.counter)
+    .bind(&record.hlc.device_id)
+    .bind(record.deleted_at_ms_utc)
+    .bind(record.schema_version)
+    .bind(&record.dek_id)
+    .bind(&record.payload_algo)
+    .bind(&record.nonce)
+    .bind(&record.ciphertext)
+    .bind(chunk_count)
+    .bind(total_ciphertext_len)
+    .bind(content_hash)
+    .bind(server_seq)
+    .bind(now_ms)
+    .bind(server_seq)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(server_seq)
+}
+
+/// Stored versions of one record, newest first.
+pub(crate) async fn list_versions(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    record_type: &str,
+    record_id: &str,
+) -> Result<Vec<HistoryVersion>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"SELECT
+             id, superseded_at_ms_utc,
+             type, record_id,
+             hlc_wall_ms_utc, hlc_counter, hlc_device_id,
+             deleted_at_ms_utc, schema_version, dek_id, algo,
+             nonce, ciphertext, server_seq
+           FROM record_history
+           WHERE user_id = ? AND type = ? AND record_id = ?
+           ORDER BY id DESC"#,
+    )
+    .bind(user_id)
+    .bind(record_type)
+    .bind(record_id)
+    .fetch_all(db)
+    .await?;
+
+    rows.iter().map(version_from_row).collect()
+}
+
+/// The version that was current at `server_seq`, if history still has it.
+pub(crate) async fn version_at(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    record_type: &str,
+    record_id: &str,
+    server_seq: i64,
+) -> Result<Option<HistoryVersion>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"SELECT
+             id, superseded_at_ms_utc, superseded_by_seq,
+             type, record_id,
+             hlc_wall_ms_utc, hlc_counter, hlc_device_id,
+             deleted_at_ms_utc, schema_version, dek_id, algo,
+             nonce, ciphertext, server_seq
+           FROM record_history
+           WHERE user_id = ? AND type = ? AND record_id = ? AND server_seq <= ?
+           ORDER BY server_seq DESC
+           LIMIT 1"#,
+    )
+    .bind(user_id)
+    .bind(record_type)
+    .bind(record_id)
+    .bind(server_seq)
+    .fetch_optional(&mut **tx)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    // Superseded before `server_seq` by a write history doesn't have (a
+    // purged tombstone, or the current row of a recreated record).
+    let superseded_by: Option<i64> = row.try_get("superseded_by_seq")?;
+    if superseded_by.is_some_and(|seq| seq <= server_seq) {
+        return Ok(None);
+    }
+    version_from_row(&row).map(Some)
+}
+
+pub(crate) async fn fetch_version(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    record_type: &str,
+    record_id: &str,
+    version_id: i64,
+) -> Result<Option<HistoryVersion>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"SELECT
+             id, superseded_at_ms_utc,
+             type, record_id,
+             hlc_wall_ms_utc, hlc_counter, hlc_device_id,
+             deleted_at_ms_utc, schema_version, dek_id, algo,
+             nonce, ciphertext, server_seq
+           FROM record_history
+           WHERE user_id = ? AND type = ? AND record_id = ? AND id = ?"#,
+    )
+    .bind(user_id)
+    .bind(record_type)
+    .bind(record_id)
+    .bind(version_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    row.as_ref().map(version_from_row).transpose()
+}
+
+fn version_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<HistoryVersion, sqlx::Error> {
+    let mut record = record_envelope_from_row(row)?;
+    record.server_seq = Some(row.try_get("server_seq")?);
+    Ok(HistoryVersion {
+        version_id: row.try_get("id")?,
+        superseded_at_ms_utc: row.try_get("superseded_at_ms_utc")?,
+        record,
+    })
+}
+
+/// An HLC that beats `current` (if any) while staying as close to `now_ms` as possible.
+pub(crate) fn server_hlc_after(current: Option<&Hlc>, now_ms: i64) -> Hlc {
+    match current {
+        Some(cur) if cur.wall_time_ms_utc >= now_ms => Hlc {
+            wall_time_ms_utc: cur.wall_time_ms_utc,
+            counter: cur.counter + 1,
+            device_id: SERVER_HLC_DEVICE_ID.to_string(),
+        },
+        _ => Hlc {
+            wall_time_ms_utc: now_ms,
+            counter: 0,
+            device_id: SERVER_HLC_DEVICE_ID.to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hlc_is_newer;
+
+    #[test]
+    fn server_hlc_beats_current() {
+        let ahead = Hlc {
+            wall_time_ms_utc: 2_000,
+            counter: 7,
+            device_id: "zzz".to_string(),
+        };
+        let hlc = server_hlc_after(Some(&ahead), 1_000);
+        assert!(hlc_is_newer(&hlc, &ahead));
+        assert_eq!(hlc.wall_time_ms_utc, 2_000);
+
+        let behind = Hlc {
+            wall_time_ms_utc: 500,
+            ..ahead
+        };
+        let hlc = server_hlc_after(Some(&behind), 1_000);
+        assert_eq!((hlc.wall_time_ms_utc, hlc.counter), (1_000, 0));
+    }
+}
@@ -0,0 +1,124 @@
+use serde::Serialize;
+use sqlx::{Pool, Row, Sqlite, Transaction};
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Identity {
+    pub provider: String,
+    pub sub: String,
+    #[serde(rename = "linkedAtMsUtc")]
+    pub linked_at_ms_utc: i64,
+}
+
+pub(crate) enum LinkOutcome {
+    Linked,
+    AlreadyLinked,
+    /// The identity signs in to a different account.
+    OtherAccount,
+}
+
+pub(crate) enum UnlinkOutcome {
+    Unlinked,
+    NotFound,
+    LastIdentity,
+}
+
+pub(crate) async fn find_user(
+    tx: &mut Transaction<'_, Sqlite>,
+    provider: &str,
+    sub: &str,
+) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar(r#"SELECT user_id FROM user_identities WHERE provider = ? AND sub = ?"#)
+        .bind(provider)
+        .bind(sub)
+        .fetch_optional(&mut **tx)
+        .await
+}
+
+pub(crate) async fn list(db: &Pool<Sqlite>, user_id: i64) -> Result<Vec<Identity>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"SELECT provider, sub, linked_at_ms_utc
+           FROM user_identities
+           WHERE user_id = ?
+           ORDER BY linked_at_ms_utc, provider, sub"#,
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            Ok(Identity {
+                provider: row.try_get("provider")?,
+                sub: row.try_get("sub")?,
+                linked_at_ms_utc: row.try_get("linked_at_ms_utc")?,
+            })
+        })
+        .collect()
+}
+
+pub(crate) async fn link(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    provider: &str,
+    sub: &str,
+    now_ms: i64,
+) -> Result<LinkOutcome, sqlx::Error> {
+    match find_user(tx, provider, sub).await? {
+        Some(owner) if owner == user_id => return Ok(LinkOutcome::AlreadyLinked),
+        Some(_) => return Ok(LinkOutcome::OtherAccount),
+        None => {}
+    }
+    sqlx::query(
+        r#"INSERT INTO user_identities (provider, sub, user_id, linked_at_ms_utc)
+           VALUES (?, ?, ?, ?)"#,
+    )
+    .bind(provider)
+    .bind(sub)
+    .bind(user_id)
+    .bind(now_ms)
+    .execute(&mut **tx)
+    .await?;
+    Ok(LinkOutcome::Linked)
+}
+
+/// Remove one identity, keeping at least one. If it was the one named on the
+/// `users` row, the oldest remaining identity takes its place there.
+pub(crate) async fn unlink(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    provider: &str,
+    sub: &str,
+) -> Result<UnlinkOutcome, sqlx::Error> {
+    if find_user(tx, provider, sub).await? != Some(user_id) {
+        return Ok(UnlinkOutcome::NotFound);
+    }
+    let count: i64 =
+        sqlx::query_scalar(r#"SELECT COUNT(*) FROM user_identities WHERE user_id = ?"#)
+            .bind(user_id)
+            .fetch_one(&mut **tx)
+            .await?;
+    if count <= 1 {
+        return Ok(UnlinkOutcome::LastIdentity);
+    }
+
+    sqlx::query(r#"DELETE FROM user_identities WHERE provider = ? AND sub = ?"#)
+        .bind(provider)
+        .bind(sub)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query(
+        r#"UPDATE users
+           SET (oauth_provider, oauth_sub) = (
+             SELECT provider, sub FROM user_identities
+             WHERE user_id = users.id
+             ORDER BY linked_at_ms_utc, provider, sub
+             LIMIT 1
+           )
+           WHERE id = ? AND oauth_provider = ? AND oauth_sub = ?"#,
+    )
+    .bind(user_id)
+    .bind(provider)
+    .bind(sub)
+    .execute(&mut **tx)
+    .await?;
+    Ok(UnlinkOutcome::Unlinked)
+}
@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use sqlx::{Pool, Row, Sqlite, Transaction};
+
+use crate::TYPE_TODO_ATTACHMENT_CHUNK;
+
+#[derive(Debug, Serialize)]
+pub(crate) struct BundleVersion {
+    #[serde(rename = "bundleVersion")]
+    pub bundle_version: i64,
+    #[serde(rename = "updatedAtMsUtc")]
+    pub updated_at_ms_utc: i64,
+    #[serde(rename = "supersededAtMsUtc")]
+    pub superseded_at_ms_utc: i64,
+    pub bundle: serde_json::Value,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct TypeUsage {
+    #[serde(rename = "type")]
+    pub record_type: String,
+    pub records: i64,
+    #[serde(rename = "stagedRecords")]
+    pub staged_records: i64,
+    #[serde(rename = "historyVersions")]
+    pub history_versions: i64,
+    pub bytes: i64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct DekUsage {
+    #[serde(rename = "dekId")]
+    pub dek_id: String,
+    pub records: i64,
+    pub tombstones: i64,
+    #[serde(rename = "stagedRecords")]
+    pub staged_records: i64,
+    #[serde(rename = "historyVersions")]
+    pub history_versions: i64,
+    pub bytes: i64,
+    pub types: Vec<TypeUsage>,
+}
+
+/// Copy the current bundle into history before it is replaced, keeping the
+/// newest `limit` versions.
+pub(crate) async fn archive_current(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    limit: i64,
+    now_ms: i64,
+) -> Result<(), sqlx::Error> {
+    if limit <= 0 {
+        return Ok(());
+    }
+
+    sqlx::query(
+        r#"INSERT INTO key_bundle_history (
+             user_id, bundle_version, bundle_json, updated_at_ms_utc, superseded_at_ms_utc
+           )
+           SELECT user_id, bundle_version, bundle_json, updated_at_ms_utc, ?
+           FROM key_bundles
+           WHERE user_id = ?
+           ON CONFLICT(user_id, bundle_version) DO NOTHING"#,
+    )
+    .bind(now_ms)
+    .bind(user_id)
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query(
+        r#"DELETE FROM key_bundle_history
+           WHERE user_id = ?
+             AND bundle_version NOT IN (
+               SELECT bundle_version FROM key_bundle_history
+               WHERE user_id = ?
+               ORDER BY bundle_version DESC
+               LIMIT ?
+             )"#,
+    )
+    .bind(user_id)
+    .bind(user_id)
+    .bind(limit)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Superseded bundles, newest first. Rows whose JSON no longer parses are skipped.
+pub(crate) async fn list_versions(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+) -> Result<Vec<BundleVersion>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"SELECT bundle_version, bundle_json, updated_at_ms_utc, superseded_at_ms_utc
+           FROM key_bundle_history
+           WHERE user_id = ?
+           ORDER BY bundle_version DESC"#,
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await?;
+
+    let mut out = Vec::with_capacity(rows.len());
+    for row in rows {
+        let bundle_version: i64 = row.try_get("bundle_version")?;
+        let bundle_json: String = row.try_get("bundle_json")?;
+        let Ok(mut bundle) = serde_json::from_str::<serde_json::Value>(&bundle_json) else {
+            continue;
+        };
+        bundle["bundleVersion"] = serde_json::Value::from(bundle_version);
+        out.push(BundleVersion {
+            bundle_version,
+            updated_at_ms_utc: row.try_get("updated_at_ms_utc")?,
+            superseded_at_ms_utc: row.try_get("superseded_at_ms_utc")?,
+            bundle,
+        });
+    }
+    Ok(out)
+}
+
+/// Rows still holding data encrypted under each DEK, per record type.
+///
+/// Only rows with a payload count: compacted tombstones carry no ciphertext
+/// and don't pin their DEK. Bytes are stored base64 like `stored_b64`, with a
+/// deduplicated chunk counted once per row that references it; a chunk sent
+/// as a raw blob counts the blob's bytes toward the chunk row's DEK.
+pub(crate) async fn dek_usage(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+) -> Result<Vec<DekUsage>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"SELECT dek_id, type,
+             SUM(src = 0) AS records,
+             SUM(src = 0 AND deleted) AS tombstones,
+             SUM(src = 1) AS staged_records,
+             SUM(src = 2) AS history_versions,
+             SUM(bytes) AS bytes
+           FROM (
+             SELECT 0 AS src, IFNULL(c.dek_id, r.dek_id) AS dek_id, r.type, r.deleted_at_ms_utc IS NOT NULL AS deleted,
+               LENGTH(r.nonce) + LENGTH(r.ciphertext) + IFNULL(r.ciphertext_len, 0)
+                 + IFNULL(LENGTH(c.ciphertext) + IFNULL(c.ciphertext_len, 0), 0)
+                 + IFNULL(CASE WHEN b.object_key IS NULL THEN LENGTH(b.data) ELSE b.total_bytes END, 0) AS bytes
+             FROM records r
+             LEFT JOIN chunk_blobs c
+               ON c.user_id = r.user_id AND c.content_hash = r.content_hash
+             LEFT JOIN attachment_blobs b
+               ON b.user_id = r.user_id AND r.type = ?
+              AND r.record_id = b.attachment_id || ':' || b.chunk_index
+             WHERE r.user_id = ? AND r.nonce <> ''
+             UNION ALL
+             SELECT 1, IFNULL(c.dek_id, s.dek_id), s.type, 0,
+               LENGTH(s.nonce) + LENGTH(s.ciphertext) + IFNULL(s.ciphertext_len, 0)
+                 + IFNULL(LENGTH(c.ciphertext) + IFNULL(c.ciphertext_len, 0), 0)
+                 + IFNULL(CASE WHEN b.object_key IS NULL THEN LENGTH(b.data) ELSE b.total_bytes END, 0)
+             FROM staged_records s
+             LEFT JOIN chunk_blobs c
+               ON c.user_id = s.user_id AND c.content_hash = s.content_hash
+             LEFT JOIN attachment_blobs b
+               ON b.user_id = s.user_id AND s.type = ?
+              AND s.record_id = b.attachment_id || ':' || b.chunk_index
+             WHERE s.user_id = ? AND s.nonce <> ''
+             UNION ALL
+             SELECT 2, dek_id, type, 0, LENGTH(nonce) + LENGTH(ciphertext)
+             FROM record_history
+             WHERE user_id = ? AND nonce <> ''
+           )
+           GROUP BY dek_id, type
+           ORDER BY dek_id, type"#,
+    )
+    .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+    .bind(user_id)
+    .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_all(db)
+    .await?;
+
+    let mut by_dek: BTreeMap<String, DekUsage> = BTreeMap::new();
+    for row in rows {
+        let dek_id: String = row.try_get("dek_id")?;
+        let usage = TypeUsage {
+            record_type: row.try_get("type")?,
+            records: row.try_get("records")?,
+            staged_records: row.try_get("staged_records")?,
+            history_versions: row.try_get("history_versions")?,
+            bytes: row.try_get("bytes")?,
+        };
+        let tombstones: i64 = row.try_get("tombstones")?;
+
+        let dek = by_dek.entry(dek_id.clone()).or_insert_with(|| DekUsage {
+            dek_id,
+            ..Default::default()
+        });
+        dek.records += usage.records;
+        dek.tombstones += tombstones;
+        dek.staged_records += usage.staged_records;
+        dek.history_versions += usage.history_versions;
+        dek.bytes += usage.bytes;
+        dek.types.push(usage);
+    }
+    Ok(by_dek.into_values().collect())
+}
@@ -0,0 +1,232 @@
+use serde::Serialize;
+use sqlx::{Pool, Row, Sqlite, Transaction};
+
+pub(crate) const KIND_PASSPHRASE: &str = "passphrase";
+pub(crate) const KIND_RECOVERY_CODE: &str = "recovery_code";
+pub(crate) const KIND_DEVICE: &str = "device";
+
+const MAX_SLOT_ID_LEN: usize = 64;
+/// Wrapped keys and KDF parameters are tiny; this only stops abuse.
+pub(crate) const MAX_SLOT_JSON_BYTES: usize = 16 * 1024;
+const MAX_SLOTS_PER_USER: i64 = 32;
+
+#[derive(Debug, Serialize)]
+pub(crate) struct KeySlot {
+    #[serde(rename = "slotId")]
+    pub slot_id: String,
+    pub kind: String,
+    #[serde(rename = "slotVersion")]
+    pub slot_version: i64,
+    #[serde(rename = "createdAtMsUtc")]
+    pub created_at_ms_utc: i64,
+    #[serde(rename = "updatedAtMsUtc")]
+    pub updated_at_ms_utc: i64,
+    pub slot: serde_json::Value,
+}
+
+pub(crate) enum PutOutcome {
+    Stored(KeySlot),
+    VersionMismatch,
+    KindMismatch,
+    TooManySlots,
+}
+
+pub(crate) enum DeleteOutcome {
+    Deleted,
+    NotFound,
+    VersionMismatch,
+    LastSlot,
+}
+
+pub(crate) fn is_valid_kind(kind: &str) -> bool {
+    matches!(kind, KIND_PASSPHRASE | KIND_RECOVERY_CODE | KIND_DEVICE)
+}
+
+/// Slot ids are client-chosen names such as `passphrase` or `device:<id>`.
+pub(crate) fn is_valid_slot_id(slot_id: &str) -> bool {
+    !slot_id.is_empty()
+        && slot_id.len() <= MAX_SLOT_ID_LEN
+        && slot_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+fn slot_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<KeySlot, sqlx::Error> {
+    let slot_json: String = row.try_get("slot_json")?;
+    Ok(KeySlot {
+        slot_id: row.try_get("slot_id")?,
+        kind: row.try_get("kind")?,
+        slot_version: row.try_get("slot_version")?,
+        created_at_ms_utc: row.try_get("created_at_ms_utc")?,
+        updated_at_ms_utc: row.try_get("updated_at_ms_utc")?,
+        slot: serde_json::from_str(&slot_json).unwrap_or(serde_json::Value::Null),
+    })
+}
+
+pub(crate) async fn list_slots(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+) -> Result<Vec<KeySlot>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"SELECT slot_id, kind, slot_version, slot_json, created_at_ms_utc, updated_at_ms_utc
+           FROM key_slots
+           WHERE user_id = ?
+           ORDER BY slot_id"#,
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await?;
+    rows.iter().map(slot_from_row).collect()
+}
+
+/// `(kind, count)` for the dashboard; never touches key material.
+pub(crate) async fn kind_counts(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+) -> Result<Vec<(String, i64)>, sqlx::Error> {
+    sqlx::query_as(
+        r#"SELECT kind, COUNT(*) FROM key_slots
+           WHERE user_id = ?
+           GROUP BY kind
+           ORDER BY kind"#,
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await
+}
+
+/// Create (`expected_version == 0`) or replace one slot, leaving the others
+/// untouched. A slot keeps the kind it was created with.
+pub(crate) async fn put_slot(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    slot_id: &str,
+    kind: &str,
+    expected_version: i64,
+    slot: serde_json::Value,
+    now_ms: i64,
+) -> Result<PutOutcome, sqlx::Error> {
+    let current = sqlx::query(
+        r#"SELECT kind, slot_version, created_at_ms_utc FROM key_slots
+           WHERE user_id = ? AND slot_id = ?"#,
+    )
+    .bind(user_id)
+    .bind(slot_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    let (current_version, created_at_ms_utc) = match &current {
+        Some(row) => {
+            let current_kind: String = row.try_get("kind")?;
+            let version: i64 = row.try_get("slot_version")?;
+            if version != expected_version {
+                return Ok(PutOutcome::VersionMismatch);
+            }
+            if current_kind != kind {
+                return Ok(PutOutcome::KindMismatch);
+            }
+            (version, row.try_get("created_at_ms_utc")?)
+        }
+        None => {
+            if expected_version != 0 {
+                return Ok(PutOutcome::VersionMismatch);
+            }
+            let count: i64 =
+                sqlx::query_scalar(r#"SELECT COUNT(*) FROM key_slots WHERE user_id = ?"#)
+                    .bind(user_id)
+                    .fetch_one(&mut **tx)
+                    .await?;
+            if count >= MAX_SLOTS_PER_USER {
+                return Ok(PutOutcome::TooManySlots);
+            }
+            (0, now_ms)
+        }
+    };
+
+    let slot_version = current_version + 1;
+    sqlx::query(
+        r#"INSERT INTO key_slots (
+             user_id, slot_id, kind, slot_version, slot_json, created_at_ms_utc, updated_at_ms_utc
+           ) VALUES (?, ?, ?, ?, ?, ?, ?)
+           ON CONFLICT(user_id, slot_id) DO UPDATE SET
+             slot_version = excluded.slot_version,
+             slot_json = excluded.slot_json,
+             updated_at_ms_utc = excluded.updated_at_ms_utc"#,
+    )
+    .bind(user_id)
+    .bind(slot_id)
+    .bind(kind)
+    .bind(slot_version)
+    .bind(slot.to_string())
+    .bind(created_at_ms_utc)
+    .bind(now_ms)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(PutOutcome::Stored(KeySlot {
+        slot_id: slot_id.to_string(),
+        kind: kind.to_string(),
+        slot_version,
+        created_at_ms_utc,
+        updated_at_ms_utc: now_ms,
+        slot,
+    }))
+}
+
+/// Remove one slot. The last slot can't be removed: that would leave no way
+/// to unwrap the data key.
+pub(crate) async fn delete_slot(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    slot_id: &str,
+    expected_version: i64,
+) -> Result<DeleteOutcome, sqlx::Error> {
+    let version: Option<i64> = sqlx::query_scalar(
+        r#"SELECT slot_version FROM key_slots WHERE user_id = ? AND slot_id = ?"#,
+    )
+    .bind(user_id)
+    .bind(slot_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+    let Some(version) = version else {
+        return Ok(DeleteOutcome::NotFound);
+    };
+    if version != expected_version {
+        return Ok(DeleteOutcome::VersionMismatch);
+    }
+
+    let count: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM key_slots WHERE user_id = ?"#)
+        .bind(user_id)
+        .fetch_one(&mut **tx)
+        .await?;
+    if count <= 1 {
+        return Ok(DeleteOutcome::LastSlot);
+    }
+
+    sqlx::query(r#"DELETE FROM key_slots WHERE user_id = ? AND slot_id = ?"#)
+        .bind(user_id)
+        .bind(slot_id)
+        .execute(&mut **tx)
+        .await?;
+    Ok(DeleteOutcome::Deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slot_ids_and_kinds() {
+        assert!(is_valid_slot_id("passphrase"));
+        assert!(is_valid_slot_id("device:3f2a-Pixel_8.main"));
+        assert!(is_valid_slot_id(&"a".repeat(MAX_SLOT_ID_LEN)));
+        assert!(!is_valid_slot_id(""));
+        assert!(!is_valid_slot_id(&"a".repeat(MAX_SLOT_ID_LEN + 1)));
+        assert!(!is_valid_slot_id("my slot"));
+        assert!(!is_valid_slot_id("a/b"));
+
+        assert!(is_valid_kind(KIND_RECOVERY_CODE));
+        assert!(!is_valid_kind("Passphrase"));
+        assert!(!is_valid_kind(""));
+    }
+}
@@ -1,34 +1,61 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context};
-use axum::extract::{Query, State};
+use axum::extract::{Path, Query, State};
 use axum::http::{header, HeaderValue, Method, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{Json, Router};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::sqlite::SqliteConnectOptions;
 use sqlx::sqlite::SqlitePoolOptions;
-use sqlx::{Executor, Pool, Row, Sqlite, Transaction};
+use sqlx::{Executor, Pool, QueryBuilder, Row, Sqlite, Transaction};
 use tower_http::cors::{AllowOrigin, CorsLayer};
 use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::trace::TraceLayer;
 use tracing::{error, info};
 use tracing_subscriber::EnvFilter;
 
+mod archive;
 mod auth;
+mod blob_store;
+mod blobs;
+mod chunk_dedup;
+mod devices;
 mod ghost_gc;
+mod history;
+mod identities;
+mod key_rotation;
+mod key_slots;
 mod metrics;
+mod notify;
+mod pairing;
+mod rollback;
+mod shares;
+mod spaces;
+mod tombstone_gc;
 mod web;
+mod wire;
 
 const MAX_RECORD_B64_LEN: usize = 512 * 1024; // per-field b64 string length cap
 const MAX_PULL_LIMIT: i64 = 500;
+const MAX_PULL_WAIT_MS: i64 = 30_000;
+const MAX_PULL_TYPE_FILTERS: usize = 32;
 const DEFAULT_BODY_LIMIT_BYTES: usize = 5 * 1024 * 1024;
+const DEFAULT_IMPORT_BODY_LIMIT_BYTES: usize = 256 * 1024 * 1024;
+const DEFAULT_BLOB_MAX_CHUNK_BYTES: usize = 16 * 1024 * 1024;
+const DEFAULT_SHARE_MAX_B64: usize = 32 * 1024 * 1024;
+const DEFAULT_SHARE_TTL_SECS: i64 = 7 * 24 * 60 * 60;
 const DEFAULT_MAX_PUSH_RECORDS: usize = 500;
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 128;
+const MAX_PARENT_REF_LEN: usize = 256;
+const SERVER_TIME_HEADER: &str = "x-server-time-ms";
+const CLIENT_VERSION_HEADER: &str = "x-client-version";
 
 const TYPE_TODO_ATTACHMENT: &str = "todo_attachment";
 const TYPE_TODO_ATTACHMENT_CHUNK: &str = "todo_attachment_chunk";
@@ -159,6 +186,69 @@ impl AdminConfig {
     }
 }
 
+/// Server-side rules for what `push_sync` accepts, independent of quotas.
+#[derive(Debug, Clone)]
+struct SyncPolicy {
+    /// Reject records whose HLC wall time is further than this ahead of server time.
+    max_clock_skew_ms: Option<i64>,
+    /// Per record type minimum `schemaVersion`; older writes are rejected.
+    min_schema_versions: HashMap<String, i64>,
+    /// Raw `MIN_CLIENT_VERSION`, plus its parsed numeric components.
+    min_client_version: Option<(String, Vec<u64>)>,
+}
+
+impl SyncPolicy {
+    fn load_from_env() -> anyhow::Result<Self> {
+        let max_clock_skew_ms = env_i64("HLC_MAX_SKEW_MS")
+            .unwrap_or(24 * 60 * 60 * 1000)
+            .max(0);
+
+        let raw = std::env::var("MIN_SCHEMA_VERSIONS_JSON").unwrap_or_else(|_| "{}".to_string());
+        let min_schema_versions: HashMap<String, i64> =
+            serde_json::from_str(&unquote_env_json(&raw))
+                .context("parse MIN_SCHEMA_VERSIONS_JSON")?;
+
+        let min_client_version = match std::env::var("MIN_CLIENT_VERSION") {
+            Ok(raw) if !raw.trim().is_empty() => {
+                let raw = raw.trim().to_string();
+                let parsed = parse_client_version(&raw)
+                    .with_context(|| format!("parse MIN_CLIENT_VERSION: {raw}"))?;
+                Some((raw, parsed))
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            max_clock_skew_ms: (max_clock_skew_ms > 0).then_some(max_clock_skew_ms),
+            min_schema_versions,
+            min_client_version,
+        })
+    }
+
+    fn is_schema_too_old(&self, record_type: &str, schema_version: i64) -> bool {
+        self.min_schema_versions
+            .get(record_type)
+            .is_some_and(|min| schema_version < *min)
+    }
+
+    /// A missing or unparseable version counts as too old: builds that predate
+    /// the header are exactly the ones the gate is meant to stop.
+    fn is_client_too_old(&self, client_version: Option<&str>) -> bool {
+        let Some((_, min)) = &self.min_client_version else {
+            return false;
+        };
+        match client_version.and_then(|v| parse_client_version(v).ok()) {
+            Some(v) => compare_versions(&v, min).is_lt(),
+            None => true,
+        }
+    }
+
+    fn is_clock_skewed(&self, hlc: &Hlc, now_ms: i64) -> bool {
+        self.max_clock_skew_ms
+            .is_some_and(|max| hlc.wall_time_ms_utc > now_ms.saturating_add(max))
+    }
+}
+
 #[derive(Clone)]
 struct AppState {
     db: Pool<Sqlite>,
@@ -169,8 +259,24 @@ struct AppState {
     max_push_records: usize,
     max_records_per_user: Option<i64>,
     billing: Arc<BillingConfig>,
+    sync_policy: Arc<SyncPolicy>,
     admin: AdminConfig,
     metrics: Arc<metrics::Metrics>,
+    notifier: Arc<notify::SyncNotifier>,
+    body_limit_bytes: usize,
+    import_body_limit_bytes: usize,
+    blob_max_chunk_bytes: usize,
+    /// Where attachment chunk bytes go; `None` keeps them inline in SQLite.
+    blob_store: Option<Arc<dyn blob_store::BlobStore>>,
+    push_idempotency_ttl_ms: i64,
+    record_history_limit: i64,
+    key_bundle_history_limit: i64,
+    pairing_ttl_ms: i64,
+    /// Cap on one share snapshot (nonce + ciphertext, base64).
+    share_max_b64: usize,
+    share_max_ttl_ms: i64,
+    /// How long staged attachment rows live; `None` when the cleanup is off.
+    staged_record_ttl_ms: Option<i64>,
     started_at: Instant,
     site_created_at_ms_utc: Option<i64>,
 }
@@ -313,6 +419,46 @@ async fn track_api_metrics(
     resp
 }
 
+/// Stamp `/v1` responses with server time so clients can correct their HLC clock.
+async fn add_server_time_header(
+    req: axum::http::Request<axum::body::Body>,
+    next: axum::middleware::Next,
+) -> Response {
+    let is_api = req.uri().path().starts_with("/v1/");
+    let mut resp = next.run(req).await;
+    if is_api {
+        resp.headers_mut()
+            .insert(SERVER_TIME_HEADER, HeaderValue::from(now_ms_utc()));
+    }
+    resp
+}
+
+/// Answer `426 upgrade_required` to clients below `MIN_CLIENT_VERSION`.
+///
+/// Health and auth stay reachable so an outdated app can still sign in and
+/// show the user why sync stopped.
+async fn require_min_client_version(
+    State(state): State<AppState>,
+    req: axum::http::Request<axum::body::Body>,
+    next: axum::middleware::Next,
+) -> Response {
+    let path = req.uri().path();
+    let gated = path.starts_with("/v1/")
+        && path != "/v1/health"
+        && !path.starts_with("/v1/auth/")
+        && req.method() != Method::OPTIONS;
+    if gated {
+        let client_version = req
+            .headers()
+            .get(CLIENT_VERSION_HEADER)
+            .and_then(|v| v.to_str().ok());
+        if state.sync_policy.is_client_too_old(client_version) {
+            return json_error(StatusCode::UPGRADE_REQUIRED, "upgrade_required").into_response();
+        }
+    }
+    next.run(req).await
+}
+
 fn json_bytes<T: Serialize>(value: &T) -> Result<(Response, i64), (StatusCode, Json<ErrorBody>)> {
     let bytes = serde_json::to_vec(value)
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "serialize error"))?;
@@ -334,6 +480,9 @@ struct UserBillingRow {
     banned_at_ms_utc: Option<i64>,
     stored_b64: i64,
     api_outbound_bytes: i64,
+    /// Stored bytes billed to the same owner outside this account or space;
+    /// they come off the storage allowance.
+    other_stored_b64: i64,
 }
 
 #[derive(Debug, Clone)]
@@ -385,7 +534,10 @@ fn compute_effective_quota(
         }
     }
 
-    let allowed_storage_b64 = base_storage_b64.map(|v| v.saturating_add(bonus_storage_b64));
+    let allowed_storage_b64 = base_storage_b64.map(|v| {
+        v.saturating_add(bonus_storage_b64)
+            .saturating_sub(user.other_stored_b64)
+    });
     let allowed_outbound_bytes =
         base_outbound_bytes.map(|v| v.saturating_add(bonus_outbound_bytes));
 
@@ -444,19 +596,79 @@ where
     Ok(updated.rows_affected() > 0)
 }
 
+/// Billing columns for `user_id`. A space is billed to its owner: plan, ban
+/// and outbound counter come from the owner's row (`billing_user_id`), while
+/// `stored_b64` stays the space's own and everything else the owner stores is
+/// `other_stored_b64`.
+const BILLING_ROW_SQL: &str = r#"SELECT
+     o.id AS billing_user_id,
+     o.base_storage_b64,
+     o.base_outbound_bytes,
+     o.subscription_plan_id,
+     o.subscription_expires_at_ms_utc,
+     o.banned_at_ms_utc,
+     u.stored_b64,
+     o.api_outbound_bytes,
+     (SELECT IFNULL(SUM(x.stored_b64), 0) FROM users x
+      WHERE x.id != u.id
+        AND (x.id = o.id
+             OR x.id IN (SELECT user_id FROM spaces WHERE owner_user_id = o.id))
+     ) AS other_stored_b64
+   FROM users u
+   JOIN users o
+     ON o.id = IFNULL((SELECT owner_user_id FROM spaces WHERE user_id = u.id), u.id)
+   WHERE u.id = ?"#;
+
+/// Billing row for `user_id`, with an expired subscription already cleared.
+async fn load_user_billing(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    now_ms: i64,
+) -> Result<Option<UserBillingRow>, sqlx::Error> {
+    let row = sqlx::query(BILLING_ROW_SQL)
+        .bind(user_id)
+        .fetch_optional(&mut **tx)
+        .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let mut subscription_plan_id: Option<String> = row.try_get("subscription_plan_id")?;
+    let mut subscription_expires_at_ms_utc: Option<i64> =
+        row.try_get("subscription_expires_at_ms_utc")?;
+    let billing_user_id: i64 = row.try_get("billing_user_id")?;
+    if clear_subscription_if_expired(
+        &mut **tx,
+        billing_user_id,
+        &subscription_plan_id,
+        subscription_expires_at_ms_utc,
+        now_ms,
+    )
+    .await?
+    {
+        subscription_plan_id = None;
+        subscription_expires_at_ms_utc = None;
+    }
+
+    Ok(Some(UserBillingRow {
+        base_storage_b64: row.try_get("base_storage_b64")?,
+        base_outbound_bytes: row.try_get("base_outbound_bytes")?,
+        subscription_plan_id,
+        subscription_expires_at_ms_utc,
+        banned_at_ms_utc: row.try_get("banned_at_ms_utc")?,
+        stored_b64: row.try_get("stored_b64")?,
+        api_outbound_bytes: row.try_get("api_outbound_bytes")?,
+        other_stored_b64: row.try_get("other_stored_b64")?,
+    }))
+}
+
 async fn ensure_user(
     tx: &mut Transaction<'_, Sqlite>,
     oauth_provider: &str,
     oauth_sub: &str,
     now_ms_utc: i64,
 ) -> anyhow::Result<(i64, bool)> {
-    let existing: Option<i64> =
-        sqlx::query_scalar(r#"SELECT id FROM users WHERE oauth_provider = ? AND oauth_sub = ?"#)
-            .bind(oauth_provider)
-            .bind(oauth_sub)
-            .fetch_optional(&mut **tx)
-            .await?;
-    if let Some(id) = existing {
+    if let Some(id) = identities::find_user(tx, oauth_provider, oauth_sub).await? {
         return Ok((id, false));
     }
 
@@ -469,8 +681,10 @@ async fn ensure_user(
     .bind(now_ms_utc)
     .execute(&mut **tx)
     .await?;
+    let user_id = created.last_insert_rowid();
+    identities::link(tx, user_id, oauth_provider, oauth_sub, now_ms_utc).await?;
 
-    Ok((created.last_insert_rowid(), true))
+    Ok((user_id, true))
 }
 
 fn now_ms_utc() -> i64 {
@@ -490,15 +704,17 @@ where
     E: Executor<'e, Database = Sqlite>,
 {
     // `api_outbound_bytes` is tracked per UTC month. When the month rolls over, reset usage to 0.
+    // A space's traffic lands on its owner, so the owner's month rolls over too.
     sqlx::query(
         r#"UPDATE users
            SET api_outbound_bytes = 0,
                api_outbound_month_utc = CAST(strftime('%Y%m', ? / 1000, 'unixepoch') AS INTEGER)
-           WHERE id = ?
+           WHERE (id = ? OR id = (SELECT owner_user_id FROM spaces WHERE user_id = ?))
              AND api_outbound_month_utc != CAST(strftime('%Y%m', ? / 1000, 'unixepoch') AS INTEGER)"#,
     )
     .bind(now_ms_utc)
     .bind(user_id)
+    .bind(user_id)
     .bind(now_ms_utc)
     .execute(executor)
     .await?;
@@ -559,6 +775,36 @@ fn env_i64(key: &str) -> Option<i64> {
     std::env::var(key).ok().and_then(|s| s.trim().parse().ok())
 }
 
+/// Parse `1.2.3`, `v1.2`, `1.2.3+45` or `1.2.3-beta` into numeric components
+/// (build metadata and pre-release suffixes are ignored).
+fn parse_client_version(raw: &str) -> anyhow::Result<Vec<u64>> {
+    let core = raw
+        .trim()
+        .trim_start_matches(['v', 'V'])
+        .split(['+', '-', ' '])
+        .next()
+        .unwrap_or("");
+    if core.is_empty() {
+        bail!("empty version");
+    }
+    core.split('.')
+        .map(|part| part.parse::<u64>().context("non-numeric version component"))
+        .collect()
+}
+
+fn compare_versions(a: &[u64], b: &[u64]) -> std::cmp::Ordering {
+    let len = a.len().max(b.len());
+    (0..len)
+        .map(|i| {
+            a.get(i)
+                .copied()
+                .unwrap_or(0)
+                .cmp(&b.get(i).copied().unwrap_or(0))
+        })
+        .find(|o| o.is_ne())
+        .unwrap_or(std::cmp::Ordering::Equal)
+}
+
 fn unquote_env_json(raw: &str) -> String {
     let trimmed = raw.trim();
     trimmed
@@ -569,6 +815,32 @@ fn unquote_env_json(raw: &str) -> String {
         .to_string()
 }
 
+fn cors_allowed_headers() -> Vec<header::HeaderName> {
+    vec![
+        header::AUTHORIZATION,
+        header::CONTENT_TYPE,
+        header::CONTENT_ENCODING,
+        header::CONTENT_RANGE,
+        header::RANGE,
+        header::ACCEPT,
+        header::HeaderName::from_static("idempotency-key"),
+        header::HeaderName::from_static(devices::DEVICE_ID_HEADER),
+        header::HeaderName::from_static(devices::DEVICE_NAME_HEADER),
+        header::HeaderName::from_static(CLIENT_VERSION_HEADER),
+        header::HeaderName::from_static(spaces::SPACE_ID_HEADER),
+    ]
+}
+
+fn cors_exposed_headers() -> Vec<header::HeaderName> {
+    vec![
+        header::HeaderName::from_static(SERVER_TIME_HEADER),
+        header::HeaderName::from_static("idempotent-replayed"),
+        header::CONTENT_RANGE,
+        header::HeaderName::from_static(blobs::UPLOAD_OFFSET_HEADER),
+        header::HeaderName::from_static(blobs::UPLOAD_LENGTH_HEADER),
+    ]
+}
+
 fn cors_layer_from_env() -> Option<CorsLayer> {
     let allow_any = std::env::var("CORS_ALLOW_ANY")
         .ok()
@@ -579,12 +851,15 @@ fn cors_layer_from_env() -> Option<CorsLayer> {
         return Some(
             CorsLayer::new()
                 .allow_origin(tower_http::cors::Any)
-                .allow_methods([Method::GET, Method::POST, Method::PUT, Method::OPTIONS])
-                .allow_headers([
-                    header::AUTHORIZATION,
-                    header::CONTENT_TYPE,
-                    header::ACCEPT,
-                ]),
+                .allow_methods([
+                    Method::GET,
+                    Method::POST,
+                    Method::PUT,
+                    Method::DELETE,
+                    Method::OPTIONS,
+                ])
+                .allow_headers(cors_allowed_headers())
+                .expose_headers(cors_exposed_headers()),
         );
     }
 
@@ -603,12 +878,15 @@ fn cors_layer_from_env() -> Option<CorsLayer> {
     Some(
         CorsLayer::new()
             .allow_origin(AllowOrigin::list(origins))
-            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::OPTIONS])
-            .allow_headers([
-                header::AUTHORIZATION,
-                header::CONTENT_TYPE,
-                header::ACCEPT,
-            ]),
+            .allow_methods([
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::DELETE,
+                Method::OPTIONS,
+            ])
+            .allow_headers(cors_allowed_headers())
+            .expose_headers(cors_exposed_headers()),
     )
 }
 
@@ -691,7 +969,7 @@ async fn get_key_bundle(
             .await
             .ok();
 
-            state.metrics.record_active_user(now_ms, user.user_id);
+            state.metrics.record_active_user(now_ms, user.account_id);
             Ok(resp)
         }
     }
@@ -702,6 +980,7 @@ async fn put_key_bundle(
     user: auth::AuthedUser,
     Json(req): Json<PutKeyBundleRequest>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    user.require_owner()?;
     let now_ms = now_ms_utc();
 
     let mut tx = state
@@ -722,6 +1001,15 @@ async fn put_key_bundle(
         return Err(json_error(StatusCode::CONFLICT, "bundle version mismatch"));
     }
 
+    key_rotation::archive_current(
+        &mut tx,
+        user.user_id,
+        state.key_bundle_history_limit,
+        now_ms,
+    )
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
     let new_version = current_version + 1;
     let mut bundle = req.bundle;
     bundle["bundleVersion"] = serde_json::Value::from(new_version);
@@ -760,772 +1048,2358 @@ async fn put_key_bundle(
         .await
         .ok();
 
-    state.metrics.record_active_user(now_ms, user.user_id);
+    state.metrics.record_active_user(now_ms, user.account_id);
     Ok(resp)
 }
 
-async fn upsert_attachment_refs(
+#[derive(Debug, Serialize)]
+struct KeyBundleVersionsResponse {
+    #[serde(rename = "bundleVersion")]
+    bundle_version: Option<i64>,
+    versions: Vec<key_rotation::BundleVersion>,
+}
+
+async fn current_bundle_version(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar(r#"SELECT bundle_version FROM key_bundles WHERE user_id = ?"#)
+        .bind(user_id)
+        .fetch_optional(db)
+        .await
+}
+
+async fn list_key_bundle_versions(
     State(state): State<AppState>,
     user: auth::AuthedUser,
-    Json(req): Json<UpsertAttachmentRefsRequest>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
-    if req.refs.len() > 5000 {
-        return Err(json_error(StatusCode::BAD_REQUEST, "too many refs"));
-    }
-
     let now_ms = now_ms_utc();
-
-    let mut tx = state
-        .db
-        .begin()
+    let bundle_version = current_bundle_version(&state.db, user.user_id)
         .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-
-    for r in req.refs {
-        let attachment_id = r.attachment_id.trim();
-        let todo_id = r.todo_id.trim();
-        if attachment_id.is_empty() || todo_id.is_empty() {
-            continue;
-        }
-
-        sqlx::query(
-            r#"INSERT INTO attachment_refs (user_id, attachment_id, todo_id, updated_at_ms_utc)
-               VALUES (?, ?, ?, ?)
-               ON CONFLICT(user_id, attachment_id) DO UPDATE SET
-                 todo_id = excluded.todo_id,
-                 updated_at_ms_utc = excluded.updated_at_ms_utc"#,
-        )
-        .bind(user.user_id)
-        .bind(attachment_id)
-        .bind(todo_id)
-        .bind(now_ms)
-        .execute(&mut *tx)
+    let versions = key_rotation::list_versions(&state.db, user.user_id)
         .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-    }
 
-    tx.commit()
+    let (resp, bytes_len) = json_bytes(&KeyBundleVersionsResponse {
+        bundle_version,
+        versions,
+    })?;
+    reset_user_api_outbound_if_new_month(&state.db, user.user_id, now_ms)
         .await
-        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        .ok();
+    sqlx::query(r#"UPDATE users SET api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#)
+        .bind(bytes_len)
+        .bind(user.user_id)
+        .execute(&state.db)
+        .await
+        .ok();
 
-    Ok(Json(OkResponse { ok: true }))
+    state.metrics.record_active_user(now_ms, user.account_id);
+    Ok(resp)
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-struct Hlc {
-    #[serde(rename = "wallTimeMsUtc")]
-    wall_time_ms_utc: i64,
-    counter: i64,
-    #[serde(rename = "deviceId")]
-    device_id: String,
+#[derive(Debug, Serialize)]
+struct KeyRotationStatusResponse {
+    #[serde(rename = "bundleVersion")]
+    bundle_version: Option<i64>,
+    deks: Vec<key_rotation::DekUsage>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-struct SyncRecordEnvelope {
-    r#type: String,
-    #[serde(rename = "recordId")]
-    record_id: String,
-    hlc: Hlc,
-    #[serde(rename = "deletedAtMsUtc")]
-    deleted_at_ms_utc: Option<i64>,
-    #[serde(rename = "schemaVersion")]
-    schema_version: i64,
-    #[serde(rename = "dekId")]
-    dek_id: String,
-    #[serde(rename = "payloadAlgo")]
-    payload_algo: String,
-    nonce: String,
-    ciphertext: String,
-}
+async fn get_key_rotation_status(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let now_ms = now_ms_utc();
+    let bundle_version = current_bundle_version(&state.db, user.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let deks = key_rotation::dek_usage(&state.db, user.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-#[derive(Debug, Deserialize)]
-struct PushRequest {
-    records: Vec<SyncRecordEnvelope>,
-}
+    let (resp, bytes_len) = json_bytes(&KeyRotationStatusResponse {
+        bundle_version,
+        deks,
+    })?;
+    reset_user_api_outbound_if_new_month(&state.db, user.user_id, now_ms)
+        .await
+        .ok();
+    sqlx::query(r#"UPDATE users SET api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#)
+        .bind(bytes_len)
+        .bind(user.user_id)
+        .execute(&state.db)
+        .await
+        .ok();
 
-#[derive(Debug, Serialize)]
-struct PushAccepted {
-    r#type: String,
-    #[serde(rename = "recordId")]
-    record_id: String,
-    #[serde(rename = "serverSeq")]
-    server_seq: i64,
+    state.metrics.record_active_user(now_ms, user.account_id);
+    Ok(resp)
 }
 
 #[derive(Debug, Serialize)]
-struct PushRejected {
-    r#type: String,
-    #[serde(rename = "recordId")]
-    record_id: String,
-    reason: String,
+struct KeySlotsResponse {
+    slots: Vec<key_slots::KeySlot>,
 }
 
-#[derive(Debug, Serialize)]
-struct PushResponse {
-    accepted: Vec<PushAccepted>,
-    rejected: Vec<PushRejected>,
-}
+async fn list_key_slots(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let now_ms = now_ms_utc();
+    let slots = key_slots::list_slots(&state.db, user.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-#[derive(Debug, Serialize)]
-struct OkResponse {
-    ok: bool,
-}
+    let (resp, bytes_len) = json_bytes(&KeySlotsResponse { slots })?;
+    reset_user_api_outbound_if_new_month(&state.db, user.user_id, now_ms)
+        .await
+        .ok();
+    sqlx::query(r#"UPDATE users SET api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#)
+        .bind(bytes_len)
+        .bind(user.user_id)
+        .execute(&state.db)
+        .await
+        .ok();
 
-#[derive(Debug, Deserialize)]
-struct AttachmentRefIn {
-    #[serde(rename = "attachmentId")]
-    attachment_id: String,
-    #[serde(rename = "todoId")]
-    todo_id: String,
+    state.metrics.record_active_user(now_ms, user.account_id);
+    Ok(resp)
 }
 
 #[derive(Debug, Deserialize)]
-struct UpsertAttachmentRefsRequest {
-    refs: Vec<AttachmentRefIn>,
-}
-
-fn hlc_is_newer(a: &Hlc, b: &Hlc) -> bool {
-    (a.wall_time_ms_utc, a.counter, &a.device_id) > (b.wall_time_ms_utc, b.counter, &b.device_id)
+struct PutKeySlotRequest {
+    kind: String,
+    #[serde(rename = "expectedSlotVersion")]
+    expected_slot_version: i64,
+    slot: serde_json::Value,
 }
 
-fn is_attachment_staged_type(t: &str) -> bool {
-    t == TYPE_TODO_ATTACHMENT || t == TYPE_TODO_ATTACHMENT_CHUNK
-}
-
-fn parse_chunk_index(record_id: &str) -> Option<i64> {
-    record_id.rsplit_once(':')?.1.parse().ok()
-}
+async fn put_key_slot(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+    Path(slot_id): Path<String>,
+    Json(req): Json<PutKeySlotRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    user.require_owner()?;
+    if !key_slots::is_valid_slot_id(&slot_id) {
+        return Err(json_error(StatusCode::BAD_REQUEST, "invalid slot id"));
+    }
+    if !key_slots::is_valid_kind(&req.kind) {
+        return Err(json_error(StatusCode::BAD_REQUEST, "invalid slot kind"));
+    }
+    if !req.slot.is_object() {
+        return Err(json_error(StatusCode::BAD_REQUEST, "invalid slot"));
+    }
+    if req.slot.to_string().len() > key_slots::MAX_SLOT_JSON_BYTES {
+        return Err(json_error(StatusCode::BAD_REQUEST, "slot too large"));
+    }
+    let now_ms = now_ms_utc();
 
-async fn recompute_and_store_user_b64(
-    tx: &mut Transaction<'_, Sqlite>,
-    user_id: i64,
-) -> anyhow::Result<i64> {
-    let total_b64: i64 = sqlx::query_scalar(
-        r#"SELECT
-             (SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext)), 0) FROM records WHERE user_id = ?)
-           + (SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext)), 0) FROM staged_records WHERE user_id = ?)"#,
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let outcome = key_slots::put_slot(
+        &mut tx,
+        user.user_id,
+        &slot_id,
+        &req.kind,
+        req.expected_slot_version,
+        req.slot,
+        now_ms,
     )
-    .bind(user_id)
-    .bind(user_id)
-    .fetch_one(&mut **tx)
-    .await?;
-
-    sqlx::query(r#"UPDATE users SET stored_b64 = ? WHERE id = ?"#)
-        .bind(total_b64)
-        .bind(user_id)
-        .execute(&mut **tx)
-        .await?;
-
-    Ok(total_b64)
-}
-
-async fn gc_staged_records(db: &Pool<Sqlite>, ttl_ms: i64) -> anyhow::Result<i64> {
-    if ttl_ms <= 0 {
-        return Ok(0);
-    }
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let slot = match outcome {
+        key_slots::PutOutcome::Stored(slot) => slot,
+        key_slots::PutOutcome::VersionMismatch => {
+            tx.rollback().await.ok();
+            return Err(json_error(StatusCode::CONFLICT, "slot version mismatch"));
+        }
+        key_slots::PutOutcome::KindMismatch => {
+            tx.rollback().await.ok();
+            return Err(json_error(StatusCode::CONFLICT, "slot kind mismatch"));
+        }
+        key_slots::PutOutcome::TooManySlots => {
+            tx.rollback().await.ok();
+            return Err(json_error(StatusCode::CONFLICT, "too many key slots"));
+        }
+    };
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-    let now_ms = now_ms_utc();
-    let cutoff = now_ms.saturating_sub(ttl_ms);
+    let (resp, bytes_len) = json_bytes(&slot)?;
+    reset_user_api_outbound_if_new_month(&state.db, user.user_id, now_ms)
+        .await
+        .ok();
+    sqlx::query(r#"UPDATE users SET api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#)
+        .bind(bytes_len)
+        .bind(user.user_id)
+        .execute(&state.db)
+        .await
+        .ok();
 
-    let mut tx = db.begin().await?;
+    state.metrics.record_active_user(now_ms, user.account_id);
+    Ok(resp)
+}
 
-    let user_ids: Vec<i64> = sqlx::query_scalar(
-        r#"SELECT DISTINCT user_id
-           FROM staged_records
-           WHERE updated_at_ms_utc < ?"#,
-    )
-    .bind(cutoff)
-    .fetch_all(&mut *tx)
-    .await?;
+#[derive(Debug, Deserialize)]
+struct DeleteKeySlotQuery {
+    #[serde(rename = "expectedSlotVersion")]
+    expected_slot_version: i64,
+}
 
-    if user_ids.is_empty() {
+async fn delete_key_slot(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+    Path(slot_id): Path<String>,
+    Query(q): Query<DeleteKeySlotQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    user.require_owner()?;
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let outcome = key_slots::delete_slot(&mut tx, user.user_id, &slot_id, q.expected_slot_version)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let err = match outcome {
+        key_slots::DeleteOutcome::Deleted => None,
+        key_slots::DeleteOutcome::NotFound => {
+            Some(json_error(StatusCode::NOT_FOUND, "key slot not found"))
+        }
+        key_slots::DeleteOutcome::VersionMismatch => {
+            Some(json_error(StatusCode::CONFLICT, "slot version mismatch"))
+        }
+        key_slots::DeleteOutcome::LastSlot => {
+            Some(json_error(StatusCode::CONFLICT, "last key slot"))
+        }
+    };
+    if let Some(err) = err {
         tx.rollback().await.ok();
-        return Ok(0);
+        return Err(err);
     }
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-    let deleted = sqlx::query(
-        r#"DELETE FROM staged_records
-           WHERE updated_at_ms_utc < ?"#,
-    )
-    .bind(cutoff)
-    .execute(&mut *tx)
-    .await?
-    .rows_affected() as i64;
+    state
+        .metrics
+        .record_active_user(now_ms_utc(), user.account_id);
+    Ok(Json(OkResponse { ok: true }))
+}
 
-    for user_id in user_ids {
-        let _ = recompute_and_store_user_b64(&mut tx, user_id).await?;
+#[derive(Debug, Serialize)]
+struct CreatePairingResponse {
+    #[serde(rename = "pairingCode")]
+    pairing_code: String,
+    #[serde(rename = "expiresAtMsUtc")]
+    expires_at_ms_utc: i64,
+}
+
+fn pairing_found<T>(lookup: pairing::Lookup<T>) -> Result<T, (StatusCode, Json<ErrorBody>)> {
+    match lookup {
+        pairing::Lookup::Found(v) => Ok(v),
+        pairing::Lookup::NotFound => Err(json_error(StatusCode::NOT_FOUND, "pairing not found")),
+        pairing::Lookup::Expired => Err(json_error(StatusCode::GONE, "pairing expired")),
+        pairing::Lookup::Conflict => {
+            Err(json_error(StatusCode::CONFLICT, "pairing state conflict"))
+        }
     }
+}
 
-    tx.commit().await?;
-    Ok(deleted)
+fn pairing_code_hash(state: &AppState, raw: &str) -> Result<String, (StatusCode, Json<ErrorBody>)> {
+    let code = pairing::normalize_code(raw)
+        .ok_or_else(|| json_error(StatusCode::NOT_FOUND, "pairing not found"))?;
+    Ok(state.auth.hash_token(&code))
 }
 
-async fn delete_staged_attachment(
-    tx: &mut Transaction<'_, Sqlite>,
-    user_id: i64,
-    attachment_id: &str,
-) -> anyhow::Result<(i64, i64)> {
-    let pattern = format!("{attachment_id}:%");
-    let (count, bytes): (i64, i64) = sqlx::query_as(
-        r#"SELECT
-             COUNT(*) AS c,
-             IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext)), 0) AS b
-           FROM staged_records
-           WHERE user_id = ?
-             AND (
-               (type = ? AND record_id = ?)
-               OR (type = ? AND record_id LIKE ?)
-             )"#,
-    )
-    .bind(user_id)
-    .bind(TYPE_TODO_ATTACHMENT)
-    .bind(attachment_id)
-    .bind(TYPE_TODO_ATTACHMENT_CHUNK)
-    .bind(&pattern)
-    .fetch_one(&mut **tx)
-    .await?;
+#[derive(Debug, Deserialize)]
+struct CreateShareRequest {
+    nonce: String,
+    ciphertext: String,
+    #[serde(rename = "expiresInSecs")]
+    expires_in_secs: Option<i64>,
+    #[serde(rename = "maxViews")]
+    max_views: Option<i64>,
+}
 
-    if count > 0 {
-        sqlx::query(
-            r#"DELETE FROM staged_records
-               WHERE user_id = ?
-                 AND (
-                   (type = ? AND record_id = ?)
-                   OR (type = ? AND record_id LIKE ?)
-                 )"#,
-        )
-        .bind(user_id)
-        .bind(TYPE_TODO_ATTACHMENT)
-        .bind(attachment_id)
-        .bind(TYPE_TODO_ATTACHMENT_CHUNK)
-        .bind(pattern)
-        .execute(&mut **tx)
-        .await?;
+/// Store a todo snapshot the device encrypted under a key that only goes into
+/// the link's `#fragment`; the server never sees it.
+async fn create_share(
+    State(state): State<AppState>,
+    account: auth::AuthedAccount,
+    Json(req): Json<CreateShareRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    if !pairing::is_valid_blob(&req.nonce, shares::MAX_NONCE_LEN) {
+        return Err(json_error(StatusCode::BAD_REQUEST, "invalid nonce"));
     }
+    if !pairing::is_valid_blob(&req.ciphertext, state.share_max_b64) {
+        return Err(json_error(StatusCode::BAD_REQUEST, "invalid ciphertext"));
+    }
+    if req.max_views.is_some_and(|v| v < 1) {
+        return Err(json_error(StatusCode::BAD_REQUEST, "invalid maxViews"));
+    }
+    let ttl_ms = match req.expires_in_secs {
+        Some(secs) if secs < 1 => {
+            return Err(json_error(StatusCode::BAD_REQUEST, "invalid expiresInSecs"));
+        }
+        Some(secs) => secs.saturating_mul(1000),
+        None => DEFAULT_SHARE_TTL_SECS * 1000,
+    }
+    .min(state.share_max_ttl_ms);
 
-    Ok((count, bytes))
-}
-
-async fn compact_committed_attachment_chunks(
-    tx: &mut Transaction<'_, Sqlite>,
-    user_id: i64,
-    attachment_id: &str,
-    deleted_at_ms_utc: i64,
-) -> anyhow::Result<i64> {
     let now_ms = now_ms_utc();
-    let pattern = format!("{attachment_id}:%");
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let Some(user_billing) = load_user_billing(&mut tx, account.user_id, now_ms)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+    else {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::UNAUTHORIZED, "unauthorized"));
+    };
+    if user_billing.banned_at_ms_utc.is_some_and(|ms| ms > 0) {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::FORBIDDEN, "banned"));
+    }
+    let quota = compute_effective_quota(&state.billing, &user_billing, now_ms);
 
-    let rows = sqlx::query(
-        r#"SELECT
-             record_id,
-             hlc_wall_ms_utc,
-             deleted_at_ms_utc,
-             LENGTH(nonce) AS nonce_len,
-             LENGTH(ciphertext) AS ciphertext_len
-           FROM records
-           WHERE user_id = ? AND type = ? AND record_id LIKE ?"#,
+    let share = shares::create(
+        &mut tx,
+        account.user_id,
+        &req.nonce,
+        &req.ciphertext,
+        now_ms,
+        now_ms.saturating_add(ttl_ms),
+        req.max_views,
     )
-    .bind(user_id)
-    .bind(TYPE_TODO_ATTACHMENT_CHUNK)
-    .bind(pattern)
-    .fetch_all(&mut **tx)
-    .await?;
-
-    let mut compacted = 0_i64;
-
-    for row in rows {
-        let record_id: String = row.try_get("record_id")?;
-        let existing_wall: i64 = row.try_get("hlc_wall_ms_utc")?;
-        let existing_deleted: Option<i64> = row.try_get("deleted_at_ms_utc")?;
-        let nonce_len: i64 = row.try_get("nonce_len")?;
-        let ciphertext_len: i64 = row.try_get("ciphertext_len")?;
-
-        // Already compacted tombstone; no need to bump server_seq repeatedly.
-        if existing_deleted.is_some() && nonce_len == 0 && ciphertext_len == 0 {
-            continue;
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let Some(share) = share else {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::TOO_MANY_REQUESTS, "too many shares"));
+    };
+    // Snapshots count toward storage until they're revoked, used up or expire.
+    let total_b64 = recompute_and_store_user_b64(&mut tx, account.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if let Some(max) = quota.allowed_storage_b64 {
+        if total_b64 > max && total_b64 > user_billing.stored_b64 {
+            tx.rollback().await.ok();
+            return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
         }
-
-        let server_seq = alloc_server_seq(tx, user_id).await?;
-        let new_wall = std::cmp::max(existing_wall, now_ms).saturating_add(1);
-
-        sqlx::query(
-            r#"UPDATE records
-               SET hlc_wall_ms_utc = ?,
-                   hlc_counter = 0,
-                   hlc_device_id = 'server',
-                   deleted_at_ms_utc = ?,
-                   nonce = '',
-                   ciphertext = '',
-                   server_seq = ?,
-                   updated_at_ms_utc = ?
-               WHERE user_id = ? AND type = ? AND record_id = ?"#,
-        )
-        .bind(new_wall)
-        .bind(deleted_at_ms_utc)
-        .bind(server_seq)
-        .bind(now_ms)
-        .bind(user_id)
-        .bind(TYPE_TODO_ATTACHMENT_CHUNK)
-        .bind(record_id)
-        .execute(&mut **tx)
-        .await?;
-
-        compacted += 1;
     }
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-    Ok(compacted)
+    state.metrics.record_active_user(now_ms, account.user_id);
+    Ok(Json(share))
 }
 
-async fn alloc_server_seq(tx: &mut Transaction<'_, Sqlite>, user_id: i64) -> anyhow::Result<i64> {
-    sqlx::query(
-        r#"INSERT INTO server_seq (user_id, next_seq)
-       VALUES (?, 0)
-       ON CONFLICT(user_id) DO NOTHING"#,
-    )
-    .bind(user_id)
-    .execute(&mut **tx)
-    .await?;
+#[derive(Debug, Serialize)]
+struct SharesResponse {
+    shares: Vec<shares::Share>,
+}
 
-    sqlx::query(r#"UPDATE server_seq SET next_seq = next_seq + 1 WHERE user_id = ?"#)
-        .bind(user_id)
-        .execute(&mut **tx)
-        .await?;
+async fn list_shares(
+    State(state): State<AppState>,
+    account: auth::AuthedAccount,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let now_ms = now_ms_utc();
+    let shares = shares::list(&state.db, account.user_id, now_ms)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-    let next_seq: i64 = sqlx::query_scalar(r#"SELECT next_seq FROM server_seq WHERE user_id = ?"#)
-        .bind(user_id)
-        .fetch_one(&mut **tx)
-        .await?;
+    let (resp, bytes_len) = json_bytes(&SharesResponse { shares })?;
+    reset_user_api_outbound_if_new_month(&state.db, account.user_id, now_ms)
+        .await
+        .ok();
+    sqlx::query(r#"UPDATE users SET api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#)
+        .bind(bytes_len)
+        .bind(account.user_id)
+        .execute(&state.db)
+        .await
+        .ok();
 
-    Ok(next_seq)
+    state.metrics.record_active_user(now_ms, account.user_id);
+    Ok(resp)
 }
 
-#[derive(Debug, Clone)]
-struct StoredRow {
-    r#type: String,
-    record_id: String,
-    hlc: Hlc,
-    deleted_at_ms_utc: Option<i64>,
-    schema_version: i64,
-    dek_id: String,
-    payload_algo: String,
-    nonce: String,
-    ciphertext: String,
+async fn revoke_share(
+    State(state): State<AppState>,
+    account: auth::AuthedAccount,
+    Path(share_id): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let revoked = shares::revoke(&state.db, account.user_id, &share_id, now_ms_utc())
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if !revoked {
+        return Err(json_error(StatusCode::NOT_FOUND, "share not found"));
+    }
+    Ok(Json(OkResponse { ok: true }))
 }
 
-async fn commit_staged_attachment(
-    tx: &mut Transaction<'_, Sqlite>,
-    user_id: i64,
-    attachment_id: &str,
-) -> anyhow::Result<()> {
-    let staged_meta = sqlx::query(
-        r#"SELECT
-         type,
-         record_id,
-         hlc_wall_ms_utc,
-         hlc_counter,
-         hlc_device_id,
-         deleted_at_ms_utc,
-         schema_version,
-         dek_id,
-         algo,
-         nonce,
-         ciphertext
-       FROM staged_records
-       WHERE user_id = ? AND type = ? AND record_id = ?"#,
-    )
-    .bind(user_id)
-    .bind(TYPE_TODO_ATTACHMENT)
-    .bind(attachment_id)
-    .fetch_optional(&mut **tx)
-    .await?;
+/// Open a pairing session on the signed-in (old) device.
+async fn create_pairing(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    user.require_owner()?;
+    let now_ms = now_ms_utc();
+    let code = pairing::new_code();
+    let code_hash = state.auth.hash_token(&code);
 
-    let pattern = format!("{attachment_id}:%");
-    let staged_chunks = sqlx::query(
-        r#"SELECT
-         type,
-         record_id,
-         hlc_wall_ms_utc,
-         hlc_counter,
-         hlc_device_id,
-         deleted_at_ms_utc,
-         schema_version,
-         dek_id,
-         algo,
-         nonce,
-         ciphertext
-       FROM staged_records
-       WHERE user_id = ? AND type = ? AND record_id LIKE ?"#,
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let expires_at_ms_utc = pairing::create(
+        &mut tx,
+        user.user_id,
+        &code_hash,
+        now_ms,
+        state.pairing_ttl_ms,
     )
-    .bind(user_id)
-    .bind(TYPE_TODO_ATTACHMENT_CHUNK)
-    .bind(pattern)
-    .fetch_all(&mut **tx)
-    .await?;
-
-    let mut rows: Vec<StoredRow> = Vec::with_capacity(staged_chunks.len() + 1);
-    if let Some(row) = staged_meta {
-        rows.push(StoredRow {
-            r#type: row.try_get("type")?,
-            record_id: row.try_get("record_id")?,
-            hlc: Hlc {
-                wall_time_ms_utc: row.try_get("hlc_wall_ms_utc")?,
-                counter: row.try_get("hlc_counter")?,
-                device_id: row.try_get("hlc_device_id")?,
-            },
-            deleted_at_ms_utc: row.try_get("deleted_at_ms_utc")?,
-            schema_version: row.try_get("schema_version")?,
-            dek_id: row.try_get("dek_id")?,
-            payload_algo: row.try_get("algo")?,
-            nonce: row.try_get("nonce")?,
-            ciphertext: row.try_get("ciphertext")?,
-        });
-    }
-
-    for row in staged_chunks {
-        rows.push(StoredRow {
-            r#type: row.try_get("type")?,
-            record_id: row.try_get("record_id")?,
-            hlc: Hlc {
-                wall_time_ms_utc: row.try_get("hlc_wall_ms_utc")?,
-                counter: row.try_get("hlc_counter")?,
-                device_id: row.try_get("hlc_device_id")?,
-            },
-            deleted_at_ms_utc: row.try_get("deleted_at_ms_utc")?,
-            schema_version: row.try_get("schema_version")?,
-            dek_id: row.try_get("dek_id")?,
-            payload_algo: row.try_get("algo")?,
-            nonce: row.try_get("nonce")?,
-            ciphertext: row.try_get("ciphertext")?,
-        });
-    }
-
-    // Ensure metadata is committed before chunks so clients can initialize
-    // local state before applying chunk payloads.
-    rows.sort_by(|a, b| {
-        let a_is_chunk = a.r#type == TYPE_TODO_ATTACHMENT_CHUNK;
-        let b_is_chunk = b.r#type == TYPE_TODO_ATTACHMENT_CHUNK;
-        match (a_is_chunk, b_is_chunk) {
-            (false, true) => std::cmp::Ordering::Less,
-            (true, false) => std::cmp::Ordering::Greater,
-            (false, false) => std::cmp::Ordering::Equal,
-            (true, true) => {
-                let ai = parse_chunk_index(&a.record_id).unwrap_or(i64::MAX);
-                let bi = parse_chunk_index(&b.record_id).unwrap_or(i64::MAX);
-                ai.cmp(&bi)
-            }
-        }
-    });
-
-    for row in &rows {
-        let existing = sqlx::query(
-            r#"SELECT
-                 hlc_wall_ms_utc, hlc_counter, hlc_device_id
-         FROM records
-         WHERE user_id = ? AND type = ? AND record_id = ?"#,
-        )
-        .bind(user_id)
-        .bind(&row.r#type)
-        .bind(&row.record_id)
-        .fetch_optional(&mut **tx)
-        .await?;
-
-        if let Some(existing) = existing {
-            let stored = Hlc {
-                wall_time_ms_utc: existing.try_get("hlc_wall_ms_utc")?,
-                counter: existing.try_get("hlc_counter")?,
-                device_id: existing.try_get("hlc_device_id")?,
-            };
-            if !hlc_is_newer(&row.hlc, &stored) {
-                continue;
-            }
-        }
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let Some(expires_at_ms_utc) = expires_at_ms_utc else {
+        tx.rollback().await.ok();
+        return Err(json_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too many open pairings",
+        ));
+    };
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-        let server_seq = alloc_server_seq(tx, user_id).await?;
-        let now_ms = now_ms_utc();
-        sqlx::query(
-            r#"INSERT INTO records (
-           user_id, type, record_id,
-           hlc_wall_ms_utc, hlc_counter, hlc_device_id,
-           deleted_at_ms_utc,
-           schema_version, dek_id,
-           algo, nonce, ciphertext,
-           server_seq, updated_at_ms_utc
-         ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
-         ON CONFLICT(user_id, type, record_id) DO UPDATE SET
-           hlc_wall_ms_utc = excluded.hlc_wall_ms_utc,
-           hlc_counter = excluded.hlc_counter,
-           hlc_device_id = excluded.hlc_device_id,
-           deleted_at_ms_utc = excluded.deleted_at_ms_utc,
-           schema_version = excluded.schema_version,
-           dek_id = excluded.dek_id,
-           algo = excluded.algo,
-           nonce = excluded.nonce,
-           ciphertext = excluded.ciphertext,
-           server_seq = excluded.server_seq,
-           updated_at_ms_utc = excluded.updated_at_ms_utc"#,
-        )
-        .bind(user_id)
-        .bind(&row.r#type)
-        .bind(&row.record_id)
-        .bind(row.hlc.wall_time_ms_utc)
-        .bind(row.hlc.counter)
-        .bind(&row.hlc.device_id)
-        .bind(row.deleted_at_ms_utc)
-        .bind(row.schema_version)
-        .bind(&row.dek_id)
-        .bind(&row.payload_algo)
-        .bind(&row.nonce)
-        .bind(&row.ciphertext)
-        .bind(server_seq)
-        .bind(now_ms)
-        .execute(&mut **tx)
-        .await?;
-    }
+    state.metrics.record_active_user(now_ms, user.account_id);
+    Ok(Json(CreatePairingResponse {
+        pairing_code: code,
+        expires_at_ms_utc,
+    }))
+}
 
-    if !rows.is_empty() {
-        // Clean up staged rows even if already committed, to keep commits idempotent.
-        let pattern = format!("{attachment_id}:%");
-        sqlx::query(
-            r#"DELETE FROM staged_records
-               WHERE user_id = ?
-                 AND (
-                   (type = ? AND record_id = ?)
-                   OR (type = ? AND record_id LIKE ?)
-                 )"#,
-        )
-        .bind(user_id)
-        .bind(TYPE_TODO_ATTACHMENT)
-        .bind(attachment_id)
-        .bind(TYPE_TODO_ATTACHMENT_CHUNK)
-        .bind(pattern)
-        .execute(&mut **tx)
-        .await?;
-    }
+async fn get_pairing(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+    Path(code): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    user.require_owner()?;
+    let code_hash = pairing_code_hash(&state, &code)?;
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let status = pairing::status(&mut tx, user.user_id, &code_hash, now_ms_utc())
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    tx.rollback().await.ok();
+    Ok(Json(pairing_found(status)?))
+}
 
-    Ok(())
+#[derive(Debug, Deserialize)]
+struct PairingPublicKeyRequest {
+    #[serde(rename = "publicKey")]
+    public_key: String,
 }
 
-async fn push_sync(
+/// The new device joins the session with its ephemeral public key.
+async fn put_pairing_public_key(
     State(state): State<AppState>,
     user: auth::AuthedUser,
-    Json(req): Json<PushRequest>,
+    Path(code): Path<String>,
+    Json(req): Json<PairingPublicKeyRequest>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
-    if req.records.len() > state.max_push_records {
-        return Err(json_error(StatusCode::BAD_REQUEST, "too many records"));
+    user.require_owner()?;
+    let code_hash = pairing_code_hash(&state, &code)?;
+    if !pairing::is_valid_blob(&req.public_key, pairing::MAX_PUBLIC_KEY_LEN) {
+        return Err(json_error(StatusCode::BAD_REQUEST, "invalid public key"));
     }
-
-    let now_ms = now_ms_utc();
-
-    let mut accepted = Vec::new();
-    let mut rejected = Vec::new();
-    let mut did_compact_records = false;
-
     let mut tx = state
         .db
         .begin()
         .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-
-    reset_user_api_outbound_if_new_month(&mut *tx, user.user_id, now_ms)
+    let stored = pairing::put_public_key(
+        &mut tx,
+        user.user_id,
+        &code_hash,
+        &req.public_key,
+        now_ms_utc(),
+    )
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    pairing_found(stored)?;
+    tx.commit()
         .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    Ok(Json(OkResponse { ok: true }))
+}
 
-    let billing_row = sqlx::query(
-        r#"SELECT
-             base_storage_b64,
-             base_outbound_bytes,
-             subscription_plan_id,
-             subscription_expires_at_ms_utc,
-             banned_at_ms_utc,
-             stored_b64,
-             api_outbound_bytes
-           FROM users
-           WHERE id = ?"#,
+#[derive(Debug, Deserialize)]
+struct PairingPayloadRequest {
+    payload: String,
+}
+
+/// The old device answers with the data key encrypted to the public key.
+async fn put_pairing_payload(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+    Path(code): Path<String>,
+    Json(req): Json<PairingPayloadRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    user.require_owner()?;
+    let code_hash = pairing_code_hash(&state, &code)?;
+    if !pairing::is_valid_blob(&req.payload, pairing::MAX_PAYLOAD_LEN) {
+        return Err(json_error(StatusCode::BAD_REQUEST, "invalid payload"));
+    }
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let stored = pairing::put_payload(
+        &mut tx,
+        user.user_id,
+        &code_hash,
+        &req.payload,
+        now_ms_utc(),
     )
-    .bind(user.user_id)
-    .fetch_optional(&mut *tx)
     .await
     .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-    let Some(billing_row) = billing_row else {
-        tx.rollback().await.ok();
-        return Err(json_error(StatusCode::UNAUTHORIZED, "unauthorized"));
-    };
+    pairing_found(stored)?;
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    Ok(Json(OkResponse { ok: true }))
+}
 
-    let mut subscription_plan_id: Option<String> = billing_row
-        .try_get("subscription_plan_id")
+#[derive(Debug, Serialize)]
+struct ConsumePairingResponse {
+    payload: String,
+}
+
+/// The new device picks up the answer; works once per session.
+async fn consume_pairing(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+    Path(code): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    user.require_owner()?;
+    let code_hash = pairing_code_hash(&state, &code)?;
+    let mut tx = state
+        .db
+        .begin()
+        .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-    let mut subscription_expires_at_ms_utc: Option<i64> = billing_row
-        .try_get("subscription_expires_at_ms_utc")
+    let payload = pairing::consume(&mut tx, user.user_id, &code_hash, now_ms_utc())
+        .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let payload = pairing_found(payload)?;
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    Ok(Json(ConsumePairingResponse { payload }))
+}
 
-    let has_plan = subscription_plan_id
-        .as_deref()
-        .map(|s| !s.trim().is_empty())
-        .unwrap_or(false);
-    let expires_at = subscription_expires_at_ms_utc.unwrap_or(0);
-    if has_plan && expires_at <= now_ms {
-        clear_subscription_if_expired(
-            &mut *tx,
-            user.user_id,
-            &subscription_plan_id,
-            subscription_expires_at_ms_utc,
-            now_ms,
-        )
+#[derive(Debug, Serialize)]
+struct SpacesResponse {
+    spaces: Vec<spaces::Space>,
+}
+
+async fn list_spaces(
+    State(state): State<AppState>,
+    account: auth::AuthedAccount,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let spaces = spaces::list_for_account(&state.db, account.user_id)
         .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-        subscription_plan_id = None;
-        subscription_expires_at_ms_utc = None;
-    }
+    Ok(Json(SpacesResponse { spaces }))
+}
 
-    let user_billing = UserBillingRow {
-        base_storage_b64: billing_row
-            .try_get("base_storage_b64")
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-        base_outbound_bytes: billing_row
-            .try_get("base_outbound_bytes")
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-        subscription_plan_id,
-        subscription_expires_at_ms_utc,
-        banned_at_ms_utc: billing_row
-            .try_get("banned_at_ms_utc")
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-        stored_b64: billing_row
-            .try_get("stored_b64")
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-        api_outbound_bytes: billing_row
-            .try_get("api_outbound_bytes")
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+async fn create_space(
+    State(state): State<AppState>,
+    account: auth::AuthedAccount,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let now_ms = now_ms_utc();
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let space = spaces::create(&mut tx, account.user_id, now_ms)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let Some(space) = space else {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::CONFLICT, "too many spaces"));
     };
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-    if user_billing.banned_at_ms_utc.is_some_and(|ms| ms > 0) {
-        tx.rollback().await.ok();
-        return Err(json_error(StatusCode::FORBIDDEN, "banned"));
+    state.metrics.record_active_user(now_ms, account.user_id);
+    Ok(Json(space))
+}
+
+fn space_change_result(change: spaces::MemberChange) -> Result<(), (StatusCode, Json<ErrorBody>)> {
+    match change {
+        spaces::MemberChange::Done => Ok(()),
+        spaces::MemberChange::NotFound => Err(json_error(StatusCode::NOT_FOUND, "space not found")),
+        spaces::MemberChange::Forbidden => Err(json_error(StatusCode::FORBIDDEN, "forbidden")),
+        spaces::MemberChange::UnknownUser => {
+            Err(json_error(StatusCode::NOT_FOUND, "user not found"))
+        }
+        spaces::MemberChange::TooManyMembers => {
+            Err(json_error(StatusCode::CONFLICT, "too many members"))
+        }
     }
+}
 
-    let quota = compute_effective_quota(&state.billing, &user_billing, now_ms);
+async fn delete_space(
+    State(state): State<AppState>,
+    account: auth::AuthedAccount,
+    Path(space_id): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let change = spaces::delete(&mut tx, &space_id, account.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    space_change_result(change)?;
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    Ok(Json(OkResponse { ok: true }))
+}
 
-    // Include staged attachment bytes in quota checks so users can't exceed
-    // storage limits by uploading uncommitted blobs.
-    let mut record_count: i64 = sqlx::query_scalar(
-        r#"SELECT
-             (SELECT COUNT(*) FROM records WHERE user_id = ?)
-           + (SELECT COUNT(*) FROM staged_records WHERE user_id = ?)"#,
-    )
-    .bind(user.user_id)
-    .bind(user.user_id)
-    .fetch_one(&mut *tx)
-    .await
-    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+#[derive(Debug, Serialize)]
+struct SpaceMembersResponse {
+    members: Vec<spaces::Member>,
+}
 
-    let mut total_b64: i64 = sqlx::query_scalar(
-        r#"SELECT
-             (SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext)), 0) FROM records WHERE user_id = ?)
-           + (SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext)), 0) FROM staged_records WHERE user_id = ?)"#,
+async fn list_space_members(
+    State(state): State<AppState>,
+    account: auth::AuthedAccount,
+    Path(space_id): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let member = spaces::resolve(&state.db, &space_id, account.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if member.is_none() {
+        return Err(json_error(StatusCode::NOT_FOUND, "space not found"));
+    }
+    let members = spaces::list_members(&state.db, &space_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    Ok(Json(SpaceMembersResponse { members }))
+}
+
+#[derive(Debug, Deserialize)]
+struct PutSpaceMemberRequest {
+    role: String,
+}
+
+async fn put_space_member(
+    State(state): State<AppState>,
+    account: auth::AuthedAccount,
+    Path((space_id, user_id)): Path<(String, i64)>,
+    Json(req): Json<PutSpaceMemberRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let Some(role) = spaces::Role::parse(&req.role) else {
+        return Err(json_error(StatusCode::BAD_REQUEST, "invalid role"));
+    };
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let change = spaces::set_member(
+        &mut tx,
+        &space_id,
+        account.user_id,
+        user_id,
+        role,
+        now_ms_utc(),
     )
-    .bind(user.user_id)
-    .bind(user.user_id)
-    .fetch_one(&mut *tx)
     .await
     .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    space_change_result(change)?;
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    Ok(Json(OkResponse { ok: true }))
+}
 
-    if let Some(max) = quota.allowed_outbound_bytes {
-        if user_billing.api_outbound_bytes > max {
-            tx.rollback().await.ok();
-            return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
-        }
+async fn delete_space_member(
+    State(state): State<AppState>,
+    account: auth::AuthedAccount,
+    Path((space_id, user_id)): Path<(String, i64)>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let change = spaces::remove_member(&mut tx, &space_id, account.user_id, user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    space_change_result(change)?;
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    Ok(Json(OkResponse { ok: true }))
+}
+
+async fn upsert_attachment_refs(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+    Json(req): Json<UpsertAttachmentRefsRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    if req.refs.len() > 5000 {
+        return Err(json_error(StatusCode::BAD_REQUEST, "too many refs"));
     }
 
-    let mut commit_requests: Vec<(String, Option<i64>)> = Vec::new();
+    let now_ms = now_ms_utc();
 
-    for r in req.records {
-        if r.nonce.len() > MAX_RECORD_B64_LEN || r.ciphertext.len() > MAX_RECORD_B64_LEN {
-            rejected.push(PushRejected {
-                r#type: r.r#type,
-                record_id: r.record_id,
-                reason: "record_too_large".to_string(),
-            });
-            continue;
-        }
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-        if r.r#type == TYPE_TODO_ATTACHMENT_COMMIT {
-            commit_requests.push((r.record_id, r.deleted_at_ms_utc));
+    for r in req.refs {
+        let attachment_id = r.attachment_id.trim();
+        let todo_id = r.todo_id.trim();
+        if attachment_id.is_empty() || todo_id.is_empty() {
             continue;
         }
 
-        let committed_existing = sqlx::query(
-            r#"SELECT
-                 hlc_wall_ms_utc, hlc_counter, hlc_device_id,
-                 LENGTH(nonce) AS nonce_len,
-                 LENGTH(ciphertext) AS ciphertext_len
-         FROM records
-         WHERE user_id = ? AND type = ? AND record_id = ?"#,
+        sqlx::query(
+            r#"INSERT INTO attachment_refs (user_id, attachment_id, todo_id, updated_at_ms_utc)
+               VALUES (?, ?, ?, ?)
+               ON CONFLICT(user_id, attachment_id) DO UPDATE SET
+                 todo_id = excluded.todo_id,
+                 updated_at_ms_utc = excluded.updated_at_ms_utc"#,
         )
         .bind(user.user_id)
-        .bind(&r.r#type)
-        .bind(&r.record_id)
-        .fetch_optional(&mut *tx)
+        .bind(attachment_id)
+        .bind(todo_id)
+        .bind(now_ms)
+        .execute(&mut *tx)
         .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    }
 
-        let is_staged_type = is_attachment_staged_type(&r.r#type);
-
-        // Once an attachment is deleted (tombstoned metadata in `records`), the
-        // server must reject any subsequent chunk/meta uploads regardless of
-        // HLC, otherwise stale/out-of-order clients could recreate staged data.
-        if is_staged_type && r.deleted_at_ms_utc.is_none() {
-            let attachment_id = if r.r#type == TYPE_TODO_ATTACHMENT {
-                Some(r.record_id.as_str())
-            } else {
-                r.record_id.rsplit_once(':').map(|(a, _)| a)
-            };
-
-            if let Some(attachment_id) = attachment_id {
-                let deleted: Option<i64> = sqlx::query_scalar(
-                    r#"SELECT deleted_at_ms_utc
-                       FROM records
-                       WHERE user_id = ? AND type = ? AND record_id = ?"#,
-                )
-                .bind(user.user_id)
-                .bind(TYPE_TODO_ATTACHMENT)
-                .bind(attachment_id)
-                .fetch_optional(&mut *tx)
-                .await
-                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-                if deleted.is_some_and(|ms| ms > 0) {
-                    rejected.push(PushRejected {
-                        r#type: r.r#type,
-                        record_id: r.record_id,
-                        reason: "attachment_deleted".to_string(),
-                    });
-                    continue;
-                }
-            }
-        }
+    Ok(Json(OkResponse { ok: true }))
+}
 
-        let staged_existing = if committed_existing.is_none() && is_staged_type {
-            sqlx::query(
-                r#"SELECT
-                     hlc_wall_ms_utc, hlc_counter, hlc_device_id,
-                     LENGTH(nonce) AS nonce_len,
-                     LENGTH(ciphertext) AS ciphertext_len
-             FROM staged_records
-             WHERE user_id = ? AND type = ? AND record_id = ?"#,
-            )
-            .bind(user.user_id)
-            .bind(&r.r#type)
-            .bind(&r.record_id)
-            .fetch_optional(&mut *tx)
-            .await
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
-        } else {
-            None
-        };
+#[derive(Debug, Serialize)]
+struct StagedChunk {
+    index: i64,
+    hlc: Hlc,
+    /// Length of the chunk's base64 ciphertext (0 when sent as a raw blob).
+    #[serde(rename = "ciphertextLen")]
+    ciphertext_len: i64,
+    /// Progress of the chunk's `/v1/blobs` upload, if one was started.
+    #[serde(rename = "blobReceivedBytes", skip_serializing_if = "Option::is_none")]
+    blob_received_bytes: Option<i64>,
+    #[serde(rename = "blobTotalBytes", skip_serializing_if = "Option::is_none")]
+    blob_total_bytes: Option<i64>,
+}
 
-        let mut existing_hlc: Option<Hlc> = None;
-        let mut existing_size: i64 = 0;
-        let mut exists_committed = false;
-        let mut exists_staged = false;
+#[derive(Debug, Serialize)]
+struct AttachmentStagingResponse {
+    #[serde(rename = "attachmentId")]
+    attachment_id: String,
+    /// The attachment metadata is already committed.
+    committed: bool,
+    /// HLC of the staged `todo_attachment` record, if it has arrived.
+    #[serde(rename = "metaHlc")]
+    meta_hlc: Option<Hlc>,
+    /// Manifest of the staged metadata, if it carries one.
+    #[serde(rename = "chunkCount")]
+    chunk_count: Option<i64>,
+    #[serde(rename = "totalCiphertextLen")]
+    total_ciphertext_len: Option<i64>,
+    /// What an `incomplete_attachment` rejection would list if the attachment
+    /// were committed now; `null` if the commit would go through.
+    #[serde(rename = "missingChunks")]
+    missing_chunks: Option<Vec<i64>>,
+    chunks: Vec<StagedChunk>,
+    /// When the staged upload cleanup will start dropping these rows (the
+    /// oldest row's expiry). `null` if nothing is staged or the cleanup is off.
+    #[serde(rename = "expiresAtMsUtc")]
+    expires_at_ms_utc: Option<i64>,
+}
+
+/// What of an uncommitted attachment upload has already reached the server,
+/// so an interrupted client can push just the missing chunks and then the
+/// `todo_attachment_commit` marker.
+async fn get_attachment_staging(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+    Path(attachment_id): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let committed: Option<Option<i64>> = sqlx::query_scalar(
+        r#"SELECT deleted_at_ms_utc
+           FROM records
+           WHERE user_id = ? AND type = ? AND record_id = ?"#,
+    )
+    .bind(user.user_id)
+    .bind(TYPE_TODO_ATTACHMENT)
+    .bind(&attachment_id)
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if committed.flatten().is_some_and(|ms| ms > 0) {
+        return Err(json_error(StatusCode::CONFLICT, "attachment_deleted"));
+    }
+
+    let meta = sqlx::query(
+        r#"SELECT
+             hlc_wall_ms_utc, hlc_counter, hlc_device_id, updated_at_ms_utc,
+             chunk_count, total_ciphertext_len
+           FROM staged_records
+           WHERE user_id = ? AND type = ? AND record_id = ? AND deleted_at_ms_utc IS NULL"#,
+    )
+    .bind(user.user_id)
+    .bind(TYPE_TODO_ATTACHMENT)
+    .bind(&attachment_id)
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    let rows = sqlx::query(
+        r#"SELECT
+             s.record_id,
+             s.hlc_wall_ms_utc, s.hlc_counter, s.hlc_device_id,
+             LENGTH(s.ciphertext) + IFNULL(s.ciphertext_len, 0)
+               + IFNULL(LENGTH(c.ciphertext) + IFNULL(c.ciphertext_len, 0), 0) AS ciphertext_len,
+             s.updated_at_ms_utc,
+             b.total_bytes AS blob_total_bytes,
+             CASE WHEN b.object_key IS NULL THEN LENGTH(b.data) ELSE b.total_bytes END
+               AS blob_received_bytes
+           FROM staged_records s
+           LEFT JOIN attachment_blobs b
+             ON b.user_id = s.user_id
+            AND b.attachment_id = ?
+            AND s.record_id = b.attachment_id || ':' || b.chunk_index
+           LEFT JOIN chunk_blobs c
+             ON c.user_id = s.user_id AND c.content_hash = s.content_hash
+           WHERE s.user_id = ? AND s.type = ? AND s.record_id LIKE ? ESCAPE '\'
+             AND s.deleted_at_ms_utc IS NULL"#,
+    )
+    .bind(&attachment_id)
+    .bind(user.user_id)
+    .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+    .bind(chunk_id_pattern(&attachment_id))
+    .fetch_all(&state.db)
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    let hlc_from_row = |row: &sqlx::sqlite::SqliteRow| -> Result<Hlc, sqlx::Error> {
+        Ok(Hlc {
+            wall_time_ms_utc: row.try_get("hlc_wall_ms_utc")?,
+            counter: row.try_get("hlc_counter")?,
+            device_id: row.try_get("hlc_device_id")?,
+        })
+    };
+    let mut oldest_update: Option<i64> = None;
+    let mut meta_hlc = None;
+    let mut manifest = (None, None);
+    if let Some(row) = &meta {
+        meta_hlc = Some(
+            hlc_from_row(row)
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+        );
+        oldest_update = row.try_get("updated_at_ms_utc").ok();
+        manifest = (
+            row.try_get("chunk_count")
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+            row.try_get("total_ciphertext_len")
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+        );
+    }
+
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let missing_chunks = incomplete_attachment_chunks(&mut tx, user.user_id, &attachment_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    tx.rollback().await.ok();
+
+    let mut chunks = Vec::with_capacity(rows.len());
+    for row in &rows {
+        let record_id: String = row
+            .try_get("record_id")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        let Some(index) = chunk_index_of(&record_id, &attachment_id) else {
+            continue;
+        };
+        let updated_at: i64 = row
+            .try_get("updated_at_ms_utc")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        oldest_update = Some(oldest_update.map_or(updated_at, |t| t.min(updated_at)));
+        chunks.push(StagedChunk {
+            index,
+            hlc: hlc_from_row(row)
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+            ciphertext_len: row
+                .try_get("ciphertext_len")
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+            blob_received_bytes: row
+                .try_get("blob_received_bytes")
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+            blob_total_bytes: row
+                .try_get("blob_total_bytes")
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+        });
+    }
+    chunks.sort_by_key(|c| c.index);
+
+    Ok(Json(AttachmentStagingResponse {
+        attachment_id,
+        committed: committed.is_some(),
+        meta_hlc,
+        chunk_count: manifest.0,
+        total_ciphertext_len: manifest.1,
+        missing_chunks,
+        chunks,
+        expires_at_ms_utc: oldest_update
+            .zip(state.staged_record_ttl_ms)
+            .map(|(t, ttl)| t.saturating_add(ttl)),
+    }))
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct Hlc {
+    #[serde(rename = "wallTimeMsUtc")]
+    wall_time_ms_utc: i64,
+    counter: i64,
+    #[serde(rename = "deviceId")]
+    device_id: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct SyncRecordEnvelope {
+    r#type: String,
+    #[serde(rename = "recordId")]
+    record_id: String,
+    hlc: Hlc,
+    #[serde(rename = "deletedAtMsUtc")]
+    deleted_at_ms_utc: Option<i64>,
+    #[serde(rename = "schemaVersion")]
+    schema_version: i64,
+    #[serde(rename = "dekId")]
+    dek_id: String,
+    #[serde(rename = "payloadAlgo")]
+    payload_algo: String,
+    #[serde(with = "wire::b64_bytes")]
+    nonce: String,
+    #[serde(with = "wire::b64_bytes")]
+    ciphertext: String,
+    /// Only set on server-provided copies (e.g. the winner in an `older_hlc` rejection).
+    #[serde(rename = "serverSeq", default, skip_serializing_if = "Option::is_none")]
+    server_seq: Option<i64>,
+    /// Plaintext manifest of a `todo_attachment`: its number of chunks and their
+    /// summed (decoded) ciphertext length, checked when the attachment commits.
+    #[serde(
+        rename = "chunkCount",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    chunk_count: Option<i64>,
+    #[serde(
+        rename = "totalCiphertextLen",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    total_ciphertext_len: Option<i64>,
+    /// Client-computed keyed hash of a `todo_attachment_chunk`; chunks with the
+    /// same hash are stored once per account (see `chunk_dedup`).
+    #[serde(
+        rename = "contentHash",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    content_hash: Option<String>,
+    /// Plaintext parent of the record (e.g. the todo of a pomodoro session);
+    /// live children of a deleted parent are tombstoned by the ghost collector.
+    #[serde(
+        rename = "parentType",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    parent_type: Option<String>,
+    #[serde(rename = "parentId", default, skip_serializing_if = "Option::is_none")]
+    parent_id: Option<String>,
+}
+
+fn record_envelope_from_row(
+    row: &sqlx::sqlite::SqliteRow,
+) -> Result<SyncRecordEnvelope, sqlx::Error> {
+    Ok(SyncRecordEnvelope {
+        r#type: row.try_get("type")?,
+        record_id: row.try_get("record_id")?,
+        hlc: Hlc {
+            wall_time_ms_utc: row.try_get("hlc_wall_ms_utc")?,
+            counter: row.try_get("hlc_counter")?,
+            device_id: row.try_get("hlc_device_id")?,
+        },
+        deleted_at_ms_utc: row.try_get("deleted_at_ms_utc")?,
+        schema_version: row.try_get("schema_version")?,
+        dek_id: row.try_get("dek_id")?,
+        payload_algo: row.try_get("algo")?,
+        nonce: row.try_get("nonce")?,
+        ciphertext: row.try_get("ciphertext")?,
+        server_seq: None,
+        chunk_count: None,
+        total_ciphertext_len: None,
+        content_hash: None,
+        parent_type: None,
+        parent_id: None,
+    })
+}
+
+impl SyncRecordEnvelope {
+    /// `(chunk_count, total_ciphertext_len)` to store; only attachment
+    /// metadata keeps a manifest.
+    fn stored_manifest(&self) -> (Option<i64>, Option<i64>) {
+        if self.r#type == TYPE_TODO_ATTACHMENT {
+            (self.chunk_count, self.total_ciphertext_len)
+        } else {
+            (None, None)
+        }
+    }
+
+    /// `parentType` and `parentId` come as a pair, name another record and are
+    /// only kept on committed types (attachments use `attachment_refs`).
+    fn has_valid_parent(&self) -> bool {
+        match (self.parent_type.as_deref(), self.parent_id.as_deref()) {
+            (None, None) => true,
+            (Some(parent_type), Some(parent_id)) => {
+                !is_attachment_staged_type(&self.r#type)
+                    && self.r#type != TYPE_TODO_ATTACHMENT_COMMIT
+                    && !parent_type.is_empty()
+                    && !parent_id.is_empty()
+                    && parent_type.len() <= MAX_PARENT_REF_LEN
+                    && parent_id.len() <= MAX_PARENT_REF_LEN
+                    && (parent_type, parent_id) != (self.r#type.as_str(), self.record_id.as_str())
+            }
+            _ => false,
+        }
+    }
+
+    /// Decoded length of an attachment chunk's inline ciphertext.
+    fn chunk_ciphertext_bytes(&self) -> Option<i64> {
+        (self.r#type == TYPE_TODO_ATTACHMENT_CHUNK && !self.ciphertext.is_empty())
+            .then(|| b64_decoded_len(&self.ciphertext))
+    }
+}
+
+/// Fill in the attachment manifest and chunk hash for rows that selected
+/// `chunk_count`, `total_ciphertext_len` and `content_hash` (only `records` /
+/// `staged_records` have them).
+fn set_attachment_fields_from_row(
+    envelope: &mut SyncRecordEnvelope,
+    row: &sqlx::sqlite::SqliteRow,
+) -> Result<(), sqlx::Error> {
+    envelope.chunk_count = row.try_get("chunk_count")?;
+    envelope.total_ciphertext_len = row.try_get("total_ciphertext_len")?;
+    envelope.content_hash = row.try_get("content_hash")?;
+    Ok(())
+}
+
+/// Fill in the parent for rows that joined `record_parents`.
+fn set_parent_from_row(
+    envelope: &mut SyncRecordEnvelope,
+    row: &sqlx::sqlite::SqliteRow,
+) -> Result<(), sqlx::Error> {
+    envelope.parent_type = row.try_get("parent_type")?;
+    envelope.parent_id = row.try_get("parent_id")?;
+    Ok(())
+}
+
+/// Record `child`'s parent in `record_parents`.
+async fn upsert_record_parent(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    child: &SyncRecordEnvelope,
+    now_ms: i64,
+) -> Result<(), sqlx::Error> {
+    let (Some(parent_type), Some(parent_id)) = (&child.parent_type, &child.parent_id) else {
+        return Ok(());
+    };
+    sqlx::query(
+        r#"INSERT INTO record_parents (
+             user_id, type, record_id, parent_type, parent_id, updated_at_ms_utc
+           ) VALUES (?, ?, ?, ?, ?, ?)
+           ON CONFLICT(user_id, type, record_id) DO UPDATE SET
+             parent_type = excluded.parent_type,
+             parent_id = excluded.parent_id,
+             updated_at_ms_utc = excluded.updated_at_ms_utc"#,
+    )
+    .bind(user_id)
+    .bind(&child.r#type)
+    .bind(&child.record_id)
+    .bind(parent_type)
+    .bind(parent_id)
+    .bind(now_ms)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct PushRequest {
+    records: Vec<SyncRecordEnvelope>,
+    /// Alternative to the `Idempotency-Key` header for clients that can't set headers.
+    #[serde(rename = "batchId", default)]
+    batch_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PushAccepted {
+    r#type: String,
+    #[serde(rename = "recordId")]
+    record_id: String,
+    #[serde(rename = "serverSeq")]
+    server_seq: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PushRejected {
+    r#type: String,
+    #[serde(rename = "recordId")]
+    record_id: String,
+    reason: String,
+    /// The stored winner for `older_hlc`, so clients can merge without a pull.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    current: Option<SyncRecordEnvelope>,
+    /// Chunk indexes still missing, for `incomplete_attachment`.
+    #[serde(
+        rename = "missingChunks",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    missing_chunks: Option<Vec<i64>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PushResponse {
+    accepted: Vec<PushAccepted>,
+    rejected: Vec<PushRejected>,
+}
+
+#[derive(Debug, Serialize)]
+struct OkResponse {
+    ok: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct AttachmentRefIn {
+    #[serde(rename = "attachmentId")]
+    attachment_id: String,
+    #[serde(rename = "todoId")]
+    todo_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpsertAttachmentRefsRequest {
+    refs: Vec<AttachmentRefIn>,
+}
+
+fn hlc_is_newer(a: &Hlc, b: &Hlc) -> bool {
+    (a.wall_time_ms_utc, a.counter, &a.device_id) > (b.wall_time_ms_utc, b.counter, &b.device_id)
+}
+
+fn is_attachment_staged_type(t: &str) -> bool {
+    t == TYPE_TODO_ATTACHMENT || t == TYPE_TODO_ATTACHMENT_CHUNK
+}
+
+/// Byte length of base64 (standard or URL-safe, padded or not) once decoded.
+fn b64_decoded_len(b64: &str) -> i64 {
+    (b64.trim_end_matches('=').len() * 3 / 4) as i64
+}
+
+fn parse_chunk_index(record_id: &str) -> Option<i64> {
+    record_id.rsplit_once(':')?.1.parse().ok()
+}
+
+/// `LIKE` pattern for the chunk record ids of an attachment, for use with
+/// `ESCAPE '\'`. Ids containing `:` can still match another attachment's
+/// chunks, so filter the rows with `chunk_index_of`.
+fn chunk_id_pattern(attachment_id: &str) -> String {
+    format!("{}:%", ghost_gc::escape_like_prefix(attachment_id))
+}
+
+/// Index of chunk `record_id` if it belongs to exactly `attachment_id`.
+fn chunk_index_of(record_id: &str, attachment_id: &str) -> Option<i64> {
+    let (owner, index) = record_id.rsplit_once(':')?;
+    if owner != attachment_id {
+        return None;
+    }
+    index.parse().ok()
+}
+
+/// Delete `attachment_id`'s chunk rows from `table` (`records` or
+/// `staged_records`). Returns how many rows went.
+async fn delete_attachment_chunk_rows(
+    tx: &mut Transaction<'_, Sqlite>,
+    table: &str,
+    user_id: i64,
+    attachment_id: &str,
+) -> Result<i64, sqlx::Error> {
+    let record_ids: Vec<String> = sqlx::query_scalar(&format!(
+        r#"SELECT record_id FROM {table}
+           WHERE user_id = ? AND type = ? AND record_id LIKE ? ESCAPE '\'"#
+    ))
+    .bind(user_id)
+    .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+    .bind(chunk_id_pattern(attachment_id))
+    .fetch_all(&mut **tx)
+    .await?;
+
+    let mut deleted = 0_i64;
+    for record_id in record_ids {
+        if chunk_index_of(&record_id, attachment_id).is_none() {
+            continue;
+        }
+        deleted += sqlx::query(&format!(
+            r#"DELETE FROM {table} WHERE user_id = ? AND type = ? AND record_id = ?"#
+        ))
+        .bind(user_id)
+        .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+        .bind(&record_id)
+        .execute(&mut **tx)
+        .await?
+        .rows_affected() as i64;
+    }
+    Ok(deleted)
+}
+
+async fn recompute_and_store_user_b64(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+) -> anyhow::Result<i64> {
+    let total_b64: i64 = sqlx::query_scalar(
+        r#"SELECT
+             (SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext) + IFNULL(ciphertext_len, 0)), 0) FROM records WHERE user_id = ?)
+           + (SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext) + IFNULL(ciphertext_len, 0)), 0) FROM staged_records WHERE user_id = ?)
+           + (SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext)), 0) FROM record_history WHERE user_id = ?)
+           + (SELECT IFNULL(SUM(CASE WHEN object_key IS NULL THEN LENGTH(data) ELSE total_bytes END), 0) FROM attachment_blobs WHERE user_id = ?)
+           + (SELECT IFNULL(SUM(LENGTH(ciphertext) + IFNULL(ciphertext_len, 0)), 0) FROM chunk_blobs WHERE user_id = ?)
+           + (SELECT IFNULL(SUM(size_b64), 0) FROM shares
+              WHERE user_id = ? AND ciphertext IS NOT NULL
+                AND expires_at_ms_utc > CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))"#,
+    )
+    .bind(user_id)
+    .bind(user_id)
+    .bind(user_id)
+    .bind(user_id)
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_one(&mut **tx)
+    .await?;
+
+    sqlx::query(r#"UPDATE users SET stored_b64 = ? WHERE id = ?"#)
+        .bind(total_b64)
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(total_b64)
+}
+
+async fn gc_staged_records(db: &Pool<Sqlite>, ttl_ms: i64) -> anyhow::Result<i64> {
+    if ttl_ms <= 0 {
+        return Ok(0);
+    }
+
+    let now_ms = now_ms_utc();
+    let cutoff = now_ms.saturating_sub(ttl_ms);
+
+    let mut tx = db.begin().await?;
+
+    let mut user_ids: Vec<i64> = sqlx::query_scalar(
+        r#"SELECT DISTINCT user_id
+           FROM staged_records
+           WHERE updated_at_ms_utc < ?"#,
+    )
+    .bind(cutoff)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let deleted = sqlx::query(
+        r#"DELETE FROM staged_records
+           WHERE updated_at_ms_utc < ?"#,
+    )
+    .bind(cutoff)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected() as i64;
+
+    // Blobs of the chunks just expired, and uploads whose chunk record never arrived.
+    user_ids.extend(blobs::gc_orphan_blobs(&mut tx, cutoff).await?);
+    user_ids.sort_unstable();
+    user_ids.dedup();
+    if user_ids.is_empty() {
+        tx.rollback().await.ok();
+        return Ok(0);
+    }
+
+    for user_id in user_ids {
+        let _ = recompute_and_store_user_b64(&mut tx, user_id).await?;
+    }
+
+    tx.commit().await?;
+    Ok(deleted)
+}
+
+async fn delete_staged_attachment(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    attachment_id: &str,
+) -> anyhow::Result<(i64, i64)> {
+    let meta_bytes: Option<i64> = sqlx::query_scalar(
+        r#"SELECT LENGTH(nonce) + LENGTH(ciphertext) + IFNULL(ciphertext_len, 0)
+           FROM staged_records
+           WHERE user_id = ? AND type = ? AND record_id = ?"#,
+    )
+    .bind(user_id)
+    .bind(TYPE_TODO_ATTACHMENT)
+    .bind(attachment_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+    let chunks: Vec<(String, i64)> = sqlx::query_as(
+        r#"SELECT record_id, LENGTH(nonce) + LENGTH(ciphertext) + IFNULL(ciphertext_len, 0)
+           FROM staged_records
+           WHERE user_id = ? AND type = ? AND record_id LIKE ? ESCAPE '\'"#,
+    )
+    .bind(user_id)
+    .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+    .bind(chunk_id_pattern(attachment_id))
+    .fetch_all(&mut **tx)
+    .await?;
+
+    let mut count = 0_i64;
+    let mut bytes = 0_i64;
+    if let Some(b) = meta_bytes {
+        sqlx::query(
+            r#"DELETE FROM staged_records WHERE user_id = ? AND type = ? AND record_id = ?"#,
+        )
+        .bind(user_id)
+        .bind(TYPE_TODO_ATTACHMENT)
+        .bind(attachment_id)
+        .execute(&mut **tx)
+        .await?;
+        count += 1;
+        bytes += b;
+    }
+    for (record_id, b) in chunks {
+        if chunk_index_of(&record_id, attachment_id).is_none() {
+            continue;
+        }
+        sqlx::query(
+            r#"DELETE FROM staged_records WHERE user_id = ? AND type = ? AND record_id = ?"#,
+        )
+        .bind(user_id)
+        .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+        .bind(&record_id)
+        .execute(&mut **tx)
+        .await?;
+        count += 1;
+        bytes += b;
+    }
+
+    Ok((count, bytes))
+}
+
+async fn compact_committed_attachment_chunks(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    attachment_id: &str,
+    deleted_at_ms_utc: i64,
+) -> anyhow::Result<i64> {
+    let now_ms = now_ms_utc();
+
+    let rows = sqlx::query(
+        r#"SELECT
+             record_id,
+             hlc_wall_ms_utc,
+             deleted_at_ms_utc,
+             LENGTH(nonce) AS nonce_len,
+             LENGTH(ciphertext) + IFNULL(ciphertext_len, 0) AS ciphertext_len
+           FROM records
+           WHERE user_id = ? AND type = ? AND record_id LIKE ? ESCAPE '\'"#,
+    )
+    .bind(user_id)
+    .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+    .bind(chunk_id_pattern(attachment_id))
+    .fetch_all(&mut **tx)
+    .await?;
+
+    let mut compacted = 0_i64;
+
+    for row in rows {
+        let record_id: String = row.try_get("record_id")?;
+        if chunk_index_of(&record_id, attachment_id).is_none() {
+            continue;
+        }
+        let existing_wall: i64 = row.try_get("hlc_wall_ms_utc")?;
+        let existing_deleted: Option<i64> = row.try_get("deleted_at_ms_utc")?;
+        let nonce_len: i64 = row.try_get("nonce_len")?;
+        let ciphertext_len: i64 = row.try_get("ciphertext_len")?;
+
+        // Already compacted tombstone; no need to bump server_seq repeatedly.
+        if existing_deleted.is_some() && nonce_len == 0 && ciphertext_len == 0 {
+            continue;
+        }
+
+        let server_seq = alloc_server_seq(tx, user_id).await?;
+        let new_wall = std::cmp::max(existing_wall, now_ms).saturating_add(1);
+
+        sqlx::query(
+            r#"UPDATE records
+               SET hlc_wall_ms_utc = ?,
+                   hlc_counter = 0,
+                   hlc_device_id = 'server',
+                   deleted_at_ms_utc = ?,
+                   nonce = '',
+                   ciphertext = '',
+                   ciphertext_ref = NULL,
+                   ciphertext_len = NULL,
+                   ciphertext_bytes = NULL,
+                   content_hash = NULL,
+                   server_seq = ?,
+                   updated_at_ms_utc = ?
+               WHERE user_id = ? AND type = ? AND record_id = ?"#,
+        )
+        .bind(new_wall)
+        .bind(deleted_at_ms_utc)
+        .bind(server_seq)
+        .bind(now_ms)
+        .bind(user_id)
+        .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+        .bind(record_id)
+        .execute(&mut **tx)
+        .await?;
+
+        compacted += 1;
+    }
+
+    blobs::delete_attachment_blobs(tx, user_id, attachment_id).await?;
+
+    Ok(compacted)
+}
+
+/// Last `serverSeq` allocated for the user (0 if nothing was ever written).
+async fn current_server_seq<'e, E>(executor: E, user_id: i64) -> Result<i64, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let seq: Option<i64> =
+        sqlx::query_scalar(r#"SELECT next_seq FROM server_seq WHERE user_id = ?"#)
+            .bind(user_id)
+            .fetch_optional(executor)
+            .await?;
+    Ok(seq.unwrap_or(0))
+}
+
+async fn alloc_server_seq(tx: &mut Transaction<'_, Sqlite>, user_id: i64) -> anyhow::Result<i64> {
+    sqlx::query(
+        r#"INSERT INTO server_seq (user_id, next_seq)
+       VALUES (?, 0)
+       ON CONFLICT(user_id) DO NOTHING"#,
+    )
+    .bind(user_id)
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query(r#"UPDATE server_seq SET next_seq = next_seq + 1 WHERE user_id = ?"#)
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+
+    let next_seq: i64 = sqlx::query_scalar(r#"SELECT next_seq FROM server_seq WHERE user_id = ?"#)
+        .bind(user_id)
+        .fetch_one(&mut **tx)
+        .await?;
+
+    Ok(next_seq)
+}
+
+#[derive(Debug, Clone)]
+struct StoredRow {
+    r#type: String,
+    record_id: String,
+    hlc: Hlc,
+    deleted_at_ms_utc: Option<i64>,
+    schema_version: i64,
+    dek_id: String,
+    payload_algo: String,
+    nonce: String,
+    ciphertext: String,
+    ciphertext_ref: Option<String>,
+    ciphertext_len: Option<i64>,
+    chunk_count: Option<i64>,
+    total_ciphertext_len: Option<i64>,
+    ciphertext_bytes: Option<i64>,
+    content_hash: Option<String>,
+}
+
+/// At most this many indexes are listed in an `incomplete_attachment` rejection.
+const MAX_REPORTED_MISSING_CHUNKS: usize = 1000;
+
+/// Check the chunks of `attachment_id` against the manifest on its metadata
+/// (the staged copy, else the committed one) before a commit.
+///
+/// Returns `None` if the attachment can be committed (or carries no manifest,
+/// as from older clients), otherwise the missing chunk indexes. The list is
+/// empty when every chunk is there but their lengths don't add up to
+/// `total_ciphertext_len`.
+async fn incomplete_attachment_chunks(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    attachment_id: &str,
+) -> anyhow::Result<Option<Vec<i64>>> {
+    let manifest: Option<(i64, Option<i64>, Option<i64>)> = sqlx::query_as(
+        r#"SELECT 0 AS p, chunk_count, total_ciphertext_len FROM staged_records
+           WHERE user_id = ? AND type = ? AND record_id = ?
+           UNION ALL
+           SELECT 1 AS p, chunk_count, total_ciphertext_len FROM records
+           WHERE user_id = ? AND type = ? AND record_id = ? AND deleted_at_ms_utc IS NULL
+           ORDER BY p
+           LIMIT 1"#,
+    )
+    .bind(user_id)
+    .bind(TYPE_TODO_ATTACHMENT)
+    .bind(attachment_id)
+    .bind(user_id)
+    .bind(TYPE_TODO_ATTACHMENT)
+    .bind(attachment_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+    let Some((_, Some(chunk_count), total_ciphertext_len)) = manifest else {
+        return Ok(None);
+    };
+
+    // Committed chunks first so staged ones (about to replace them) win.
+    // A chunk sent as a raw blob counts once its blob upload is complete.
+    let pattern = chunk_id_pattern(attachment_id);
+    let mut chunks: HashMap<i64, Option<i64>> = HashMap::new();
+    for table in ["records", "staged_records"] {
+        let rows = sqlx::query(&format!(
+            r#"SELECT
+                 c.record_id,
+                 c.ciphertext_bytes,
+                 c.content_hash IS NOT NULL
+                   OR LENGTH(c.ciphertext) + IFNULL(c.ciphertext_len, 0) > 0 AS has_ciphertext,
+                 b.total_bytes AS blob_total,
+                 IFNULL(b.object_key IS NOT NULL OR LENGTH(b.data) = b.total_bytes, 0)
+                   AS blob_complete
+               FROM {table} c
+               LEFT JOIN attachment_blobs b
+                 ON b.user_id = c.user_id
+                AND b.attachment_id = ?
+                AND c.record_id = b.attachment_id || ':' || b.chunk_index
+               WHERE c.user_id = ? AND c.type = ? AND c.record_id LIKE ? ESCAPE '\'
+                 AND c.deleted_at_ms_utc IS NULL"#
+        ))
+        .bind(attachment_id)
+        .bind(user_id)
+        .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+        .bind(&pattern)
+        .fetch_all(&mut **tx)
+        .await?;
+        for row in rows {
+            let record_id: String = row.try_get("record_id")?;
+            let Some(index) = chunk_index_of(&record_id, attachment_id) else {
+                continue;
+            };
+            if row.try_get::<bool, _>("has_ciphertext")? {
+                chunks.insert(index, row.try_get("ciphertext_bytes")?);
+            } else if row.try_get::<bool, _>("blob_complete")? {
+                chunks.insert(index, row.try_get("blob_total")?);
+            } else {
+                chunks.remove(&index);
+            }
+        }
+    }
+
+    let missing: Vec<i64> = (0..chunk_count)
+        .filter(|i| !chunks.contains_key(i))
+        .take(MAX_REPORTED_MISSING_CHUNKS)
+        .collect();
+    if !missing.is_empty() {
+        return Ok(Some(missing));
+    }
+
+    // Lengths are only compared when every chunk's is known.
+    let lengths: Option<i64> = (0..chunk_count).map(|i| chunks[&i]).sum();
+    match (total_ciphertext_len, lengths) {
+        (Some(expected), Some(actual)) if expected != actual => Ok(Some(Vec::new())),
+        _ => Ok(None),
+    }
+}
+
+async fn commit_staged_attachment(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    attachment_id: &str,
+) -> anyhow::Result<()> {
+    let staged_meta = sqlx::query(
+        r#"SELECT
+         type,
+         record_id,
+         hlc_wall_ms_utc,
+         hlc_counter,
+         hlc_device_id,
+         deleted_at_ms_utc,
+         schema_version,
+         dek_id,
+         algo,
+         nonce,
+         ciphertext,
+         ciphertext_ref,
+         ciphertext_len,
+         chunk_count,
+         total_ciphertext_len,
+         ciphertext_bytes,
+         content_hash
+       FROM staged_records
+       WHERE user_id = ? AND type = ? AND record_id = ?"#,
+    )
+    .bind(user_id)
+    .bind(TYPE_TODO_ATTACHMENT)
+    .bind(attachment_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    let staged_chunks = sqlx::query(
+        r#"SELECT
+         type,
+         record_id,
+         hlc_wall_ms_utc,
+         hlc_counter,
+         hlc_device_id,
+         deleted_at_ms_utc,
+         schema_version,
+         dek_id,
+         algo,
+         nonce,
+         ciphertext,
+         ciphertext_ref,
+         ciphertext_len,
+         chunk_count,
+         total_ciphertext_len,
+         ciphertext_bytes,
+         content_hash
+       FROM staged_records
+       WHERE user_id = ? AND type = ? AND record_id LIKE ? ESCAPE '\'"#,
+    )
+    .bind(user_id)
+    .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+    .bind(chunk_id_pattern(attachment_id))
+    .fetch_all(&mut **tx)
+    .await?;
+
+    let mut rows: Vec<StoredRow> = Vec::with_capacity(staged_chunks.len() + 1);
+    if let Some(row) = staged_meta {
+        rows.push(StoredRow {
+            r#type: row.try_get("type")?,
+            record_id: row.try_get("record_id")?,
+            hlc: Hlc {
+                wall_time_ms_utc: row.try_get("hlc_wall_ms_utc")?,
+                counter: row.try_get("hlc_counter")?,
+                device_id: row.try_get("hlc_device_id")?,
+            },
+            deleted_at_ms_utc: row.try_get("deleted_at_ms_utc")?,
+            schema_version: row.try_get("schema_version")?,
+            dek_id: row.try_get("dek_id")?,
+            payload_algo: row.try_get("algo")?,
+            nonce: row.try_get("nonce")?,
+            ciphertext: row.try_get("ciphertext")?,
+            ciphertext_ref: row.try_get("ciphertext_ref")?,
+            ciphertext_len: row.try_get("ciphertext_len")?,
+            chunk_count: row.try_get("chunk_count")?,
+            total_ciphertext_len: row.try_get("total_ciphertext_len")?,
+            ciphertext_bytes: row.try_get("ciphertext_bytes")?,
+            content_hash: row.try_get("content_hash")?,
+        });
+    }
+
+    for row in staged_chunks {
+        let record_id: String = row.try_get("record_id")?;
+        if chunk_index_of(&record_id, attachment_id).is_none() {
+            continue;
+        }
+        rows.push(StoredRow {
+            r#type: row.try_get("type")?,
+            record_id,
+            hlc: Hlc {
+                wall_time_ms_utc: row.try_get("hlc_wall_ms_utc")?,
+                counter: row.try_get("hlc_counter")?,
+                device_id: row.try_get("hlc_device_id")?,
+            },
+            deleted_at_ms_utc: row.try_get("deleted_at_ms_utc")?,
+            schema_version: row.try_get("schema_version")?,
+            dek_id: row.try_get("dek_id")?,
+            payload_algo: row.try_get("algo")?,
+            nonce: row.try_get("nonce")?,
+            ciphertext: row.try_get("ciphertext")?,
+            ciphertext_ref: row.try_get("ciphertext_ref")?,
+            ciphertext_len: row.try_get("ciphertext_len")?,
+            chunk_count: row.try_get("chunk_count")?,
+            total_ciphertext_len: row.try_get("total_ciphertext_len")?,
+            ciphertext_bytes: row.try_get("ciphertext_bytes")?,
+            content_hash: row.try_get("content_hash")?,
+        });
+    }
+
+    // Ensure metadata is committed before chunks so clients can initialize
+    // local state before applying chunk payloads.
+    rows.sort_by(|a, b| {
+        let a_is_chunk = a.r#type == TYPE_TODO_ATTACHMENT_CHUNK;
+        let b_is_chunk = b.r#type == TYPE_TODO_ATTACHMENT_CHUNK;
+        match (a_is_chunk, b_is_chunk) {
+            (false, true) => std::cmp::Ordering::Less,
+            (true, false) => std::cmp::Ordering::Greater,
+            (false, false) => std::cmp::Ordering::Equal,
+            (true, true) => {
+                let ai = parse_chunk_index(&a.record_id).unwrap_or(i64::MAX);
+                let bi = parse_chunk_index(&b.record_id).unwrap_or(i64::MAX);
+                ai.cmp(&bi)
+            }
+        }
+    });
+
+    for row in &rows {
+        let existing = sqlx::query(
+            r#"SELECT
+                 hlc_wall_ms_utc, hlc_counter, hlc_device_id
+         FROM records
+         WHERE user_id = ? AND type = ? AND record_id = ?"#,
+        )
+        .bind(user_id)
+        .bind(&row.r#type)
+        .bind(&row.record_id)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        if let Some(existing) = existing {
+            let stored = Hlc {
+                wall_time_ms_utc: existing.try_get("hlc_wall_ms_utc")?,
+                counter: existing.try_get("hlc_counter")?,
+                device_id: existing.try_get("hlc_device_id")?,
+            };
+            if !hlc_is_newer(&row.hlc, &stored) {
+                continue;
+            }
+        }
+
+        let server_seq = alloc_server_seq(tx, user_id).await?;
+        let now_ms = now_ms_utc();
+        sqlx::query(
+            r#"INSERT INTO records (
+           user_id, type, record_id,
+           hlc_wall_ms_utc, hlc_counter, hlc_device_id,
+           deleted_at_ms_utc,
+           schema_version, dek_id,
+           algo, nonce, ciphertext, ciphertext_ref, ciphertext_len,
+           chunk_count, total_ciphertext_len, ciphertext_bytes, content_hash,
+           server_seq, updated_at_ms_utc, created_server_seq
+         ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+         ON CONFLICT(user_id, type, record_id) DO UPDATE SET
+           hlc_wall_ms_utc = excluded.hlc_wall_ms_utc,
+           hlc_counter = excluded.hlc_counter,
+           hlc_device_id = excluded.hlc_device_id,
+           deleted_at_ms_utc = excluded.deleted_at_ms_utc,
+           schema_version = excluded.schema_version,
+           dek_id = excluded.dek_id,
+           algo = excluded.algo,
+           nonce = excluded.nonce,
+           ciphertext = excluded.ciphertext,
+           ciphertext_ref = excluded.ciphertext_ref,
+           ciphertext_len = excluded.ciphertext_len,
+           chunk_count = excluded.chunk_count,
+           total_ciphertext_len = excluded.total_ciphertext_len,
+           ciphertext_bytes = excluded.ciphertext_bytes,
+           content_hash = excluded.content_hash,
+           server_seq = excluded.server_seq,
+           updated_at_ms_utc = excluded.updated_at_ms_utc"#,
+        )
+        .bind(user_id)
+        .bind(&row.r#type)
+        .bind(&row.record_id)
+        .bind(row.hlc.wall_time_ms_utc)
+        .bind(row.hlc.counter)
+        .bind(&row.hlc.device_id)
+        .bind(row.deleted_at_ms_utc)
+        .bind(row.schema_version)
+        .bind(&row.dek_id)
+        .bind(&row.payload_algo)
+        .bind(&row.nonce)
+        .bind(&row.ciphertext)
+        .bind(&row.ciphertext_ref)
+        .bind(row.ciphertext_len)
+        .bind(row.chunk_count)
+        .bind(row.total_ciphertext_len)
+        .bind(row.ciphertext_bytes)
+        .bind(&row.content_hash)
+        .bind(server_seq)
+        .bind(now_ms)
+        .bind(server_seq)
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    // Clean up staged rows even if already committed, to keep commits idempotent.
+    for row in &rows {
+        sqlx::query(
+            r#"DELETE FROM staged_records WHERE user_id = ? AND type = ? AND record_id = ?"#,
+        )
+        .bind(user_id)
+        .bind(&row.r#type)
+        .bind(&row.record_id)
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Upload a push's chunk ciphertexts to the blob store (if one is
+/// configured) before its write transaction opens, so no store round trip
+/// runs under SQLite's write lock. One entry per record; `None` keeps the
+/// ciphertext inline, as does a chunk whose `contentHash` is already stored.
+/// Uploads the push ends up not using stay queued in `blob_trash`.
+async fn offload_push_chunks(
+    state: &AppState,
+    user_id: i64,
+    records: &[SyncRecordEnvelope],
+    idempotency_key: Option<&str>,
+) -> Result<Vec<Option<(String, i64)>>, (StatusCode, Json<ErrorBody>)> {
+    let mut uploads = Vec::with_capacity(records.len());
+    let Some(store) = state.blob_store.as_deref() else {
+        uploads.resize_with(records.len(), || None);
+        return Ok(uploads);
+    };
+    // A replay writes nothing.
+    if let Some(key) = idempotency_key {
+        let stored: bool = sqlx::query_scalar(
+            r#"SELECT EXISTS (
+                 SELECT 1 FROM push_idempotency WHERE user_id = ? AND idem_key = ?
+               )"#,
+        )
+        .bind(user_id)
+        .bind(key)
+        .fetch_one(&state.db)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        if stored {
+            uploads.resize_with(records.len(), || None);
+            return Ok(uploads);
+        }
+    }
+
+    for r in records {
+        if r.ciphertext.len() > MAX_RECORD_B64_LEN {
+            uploads.push(None);
+            continue;
+        }
+        let known = match r
+            .content_hash
+            .as_deref()
+            .filter(|_| r.deleted_at_ms_utc.is_none())
+        {
+            Some(hash) => chunk_dedup::lookup(&state.db, user_id, hash)
+                .await
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+                .is_some(),
+            None => false,
+        };
+        if known {
+            uploads.push(None);
+            continue;
+        }
+        let upload = blob_store::offload_ciphertext(
+            &state.db,
+            Some(store),
+            user_id,
+            &r.r#type,
+            &r.ciphertext,
+        )
+        .await
+        .map_err(|e| {
+            error!(error = %e, "blob store put failed");
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "blob store error")
+        })?;
+        uploads.push(upload);
+    }
+    Ok(uploads)
+}
+
+/// The stored response for a repeated idempotency key, charged like any
+/// other response to `billing_user_id` (a space's owner). `None` if the key
+/// hasn't been used; a key reused with a different body is
+/// `422 idempotency_key_reused`.
+async fn replay_stored_push(
+    tx: &mut Transaction<'_, Sqlite>,
+    wire: wire::Negotiated,
+    user_id: i64,
+    billing_user_id: i64,
+    key: &str,
+    request_hash: &str,
+    allowed_outbound_bytes: Option<i64>,
+) -> Result<Option<Response>, (StatusCode, Json<ErrorBody>)> {
+    let stored = sqlx::query(
+        r#"SELECT request_hash, response_json
+           FROM push_idempotency
+           WHERE user_id = ? AND idem_key = ?"#,
+    )
+    .bind(user_id)
+    .bind(key)
+    .fetch_optional(&mut **tx)
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let Some(stored) = stored else {
+        return Ok(None);
+    };
+
+    let stored_hash: String = stored
+        .try_get("request_hash")
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if stored_hash != request_hash {
+        return Err(json_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "idempotency_key_reused",
+        ));
+    }
+    let response_json: String = stored
+        .try_get("response_json")
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let body: PushResponse = serde_json::from_str(&response_json)
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let (mut resp, bytes_len) = wire.encode(&body)?;
+    resp.headers_mut()
+        .insert("idempotent-replayed", HeaderValue::from_static("true"));
+
+    let updated = sqlx::query(
+        r#"UPDATE users
+           SET api_outbound_bytes = api_outbound_bytes + ?
+           WHERE id = ? AND (? IS NULL OR api_outbound_bytes + ? <= ?)"#,
+    )
+    .bind(bytes_len)
+    .bind(billing_user_id)
+    .bind(allowed_outbound_bytes)
+    .bind(bytes_len)
+    .bind(allowed_outbound_bytes)
+    .execute(&mut **tx)
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if updated.rows_affected() == 0 {
+        return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
+    }
+    Ok(Some(resp))
+}
+
+async fn push_sync(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+    wire: wire::Negotiated,
+    headers: axum::http::HeaderMap,
+    wire::WireBody(req): wire::WireBody<PushRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    if req.records.len() > state.max_push_records {
+        return Err(json_error(StatusCode::BAD_REQUEST, "too many records"));
+    }
+
+    let idempotency_key = headers
+        .get("idempotency-key")
+        .and_then(|v| v.to_str().ok())
+        .or(req.batch_id.as_deref())
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string());
+    if idempotency_key
+        .as_ref()
+        .is_some_and(|k| k.len() > MAX_IDEMPOTENCY_KEY_LEN)
+    {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "invalid idempotency key",
+        ));
+    }
+    let idempotency_key = idempotency_key.filter(|_| state.push_idempotency_ttl_ms > 0);
+
+    // Prefer the explicit header; otherwise a batch authored by a single
+    // device identifies the pusher.
+    let device_id = devices::device_id_from_headers(&headers).or_else(|| {
+        let mut ids = req
+            .records
+            .iter()
+            .filter_map(|r| devices::normalize_device_id(&r.hlc.device_id));
+        let first = ids.next()?;
+        ids.all(|id| id == first).then_some(first)
+    });
+    let request_hash = match idempotency_key {
+        Some(_) => {
+            let canonical = serde_json::to_vec(&req.records)
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "serialize error"))?;
+            format!("{:x}", Sha256::digest(&canonical))
+        }
+        None => String::new(),
+    };
+
+    let now_ms = now_ms_utc();
+
+    let uploads = offload_push_chunks(
+        &state,
+        user.user_id,
+        &req.records,
+        idempotency_key.as_deref(),
+    )
+    .await?;
+
+    let mut accepted = Vec::new();
+    let mut rejected = Vec::new();
+    let mut did_compact_records = false;
+
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    reset_user_api_outbound_if_new_month(&mut *tx, user.user_id, now_ms)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    let seq_before = current_server_seq(&mut *tx, user.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    let billing_row = sqlx::query(BILLING_ROW_SQL)
+        .bind(user.user_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let Some(billing_row) = billing_row else {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::UNAUTHORIZED, "unauthorized"));
+    };
+
+    let mut subscription_plan_id: Option<String> = billing_row
+        .try_get("subscription_plan_id")
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let mut subscription_expires_at_ms_utc: Option<i64> = billing_row
+        .try_get("subscription_expires_at_ms_utc")
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    let has_plan = subscription_plan_id
+        .as_deref()
+        .map(|s| !s.trim().is_empty())
+        .unwrap_or(false);
+    let expires_at = subscription_expires_at_ms_utc.unwrap_or(0);
+    let billing_user_id: i64 = billing_row
+        .try_get("billing_user_id")
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if has_plan && expires_at <= now_ms {
+        clear_subscription_if_expired(
+            &mut *tx,
+            billing_user_id,
+            &subscription_plan_id,
+            subscription_expires_at_ms_utc,
+            now_ms,
+        )
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        subscription_plan_id = None;
+        subscription_expires_at_ms_utc = None;
+    }
+
+    let user_billing = UserBillingRow {
+        base_storage_b64: billing_row
+            .try_get("base_storage_b64")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+        base_outbound_bytes: billing_row
+            .try_get("base_outbound_bytes")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+        subscription_plan_id,
+        subscription_expires_at_ms_utc,
+        banned_at_ms_utc: billing_row
+            .try_get("banned_at_ms_utc")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+        stored_b64: billing_row
+            .try_get("stored_b64")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+        api_outbound_bytes: billing_row
+            .try_get("api_outbound_bytes")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+        other_stored_b64: billing_row
+            .try_get("other_stored_b64")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+    };
+
+    if user_billing.banned_at_ms_utc.is_some_and(|ms| ms > 0) {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::FORBIDDEN, "banned"));
+    }
+
+    let quota = compute_effective_quota(&state.billing, &user_billing, now_ms);
+
+    // Include staged attachment bytes in quota checks so users can't exceed
+    // storage limits by uploading uncommitted blobs.
+    let mut record_count: i64 = sqlx::query_scalar(
+        r#"SELECT
+             (SELECT COUNT(*) FROM records WHERE user_id = ?)
+           + (SELECT COUNT(*) FROM staged_records WHERE user_id = ?)"#,
+    )
+    .bind(user.user_id)
+    .bind(user.user_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    let mut total_b64: i64 = sqlx::query_scalar(
+        r#"SELECT
+             (SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext) + IFNULL(ciphertext_len, 0)), 0) FROM records WHERE user_id = ?)
+           + (SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext) + IFNULL(ciphertext_len, 0)), 0) FROM staged_records WHERE user_id = ?)
+           + (SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext)), 0) FROM record_history WHERE user_id = ?)
+           + (SELECT IFNULL(SUM(CASE WHEN object_key IS NULL THEN LENGTH(data) ELSE total_bytes END), 0) FROM attachment_blobs WHERE user_id = ?)
+           + (SELECT IFNULL(SUM(LENGTH(ciphertext) + IFNULL(ciphertext_len, 0)), 0) FROM chunk_blobs WHERE user_id = ?)"#,
+    )
+    .bind(user.user_id)
+    .bind(user.user_id)
+    .bind(user.user_id)
+    .bind(user.user_id)
+    .bind(user.user_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    if let Some(max) = quota.allowed_outbound_bytes {
+        if user_billing.api_outbound_bytes > max {
+            tx.rollback().await.ok();
+            return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
+        }
+    }
+
+    if let Some(key) = idempotency_key.as_deref() {
+        sqlx::query(r#"DELETE FROM push_idempotency WHERE user_id = ? AND created_at_ms_utc < ?"#)
+            .bind(user.user_id)
+            .bind(now_ms.saturating_sub(state.push_idempotency_ttl_ms))
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+        let replayed = replay_stored_push(
+            &mut tx,
+            wire,
+            user.user_id,
+            billing_user_id,
+            key,
+            &request_hash,
+            quota.allowed_outbound_bytes,
+        )
+        .await;
+        match replayed {
+            Ok(Some(resp)) => {
+                tx.commit()
+                    .await
+                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+                state.metrics.record_active_user(now_ms, user.account_id);
+                return Ok(resp);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                tx.rollback().await.ok();
+                return Err(e);
+            }
+        }
+    }
+
+    let mut commit_requests: Vec<(String, Option<i64>)> = Vec::new();
+
+    for (r, upload) in req.records.into_iter().zip(uploads) {
+        if r.nonce.len() > MAX_RECORD_B64_LEN || r.ciphertext.len() > MAX_RECORD_B64_LEN {
+            rejected.push(PushRejected {
+                r#type: r.r#type,
+                record_id: r.record_id,
+                reason: "record_too_large".to_string(),
+                current: None,
+                missing_chunks: None,
+            });
+            continue;
+        }
+
+        if r.chunk_count.is_some_and(|n| n < 0) || r.total_ciphertext_len.is_some_and(|n| n < 0) {
+            rejected.push(PushRejected {
+                r#type: r.r#type,
+                record_id: r.record_id,
+                reason: "invalid_manifest".to_string(),
+                current: None,
+                missing_chunks: None,
+            });
+            continue;
+        }
+
+        if r.content_hash.as_deref().is_some_and(|h| {
+            r.r#type != TYPE_TODO_ATTACHMENT_CHUNK || !chunk_dedup::is_valid_content_hash(h)
+        }) {
+            rejected.push(PushRejected {
+                r#type: r.r#type,
+                record_id: r.record_id,
+                reason: "invalid_content_hash".to_string(),
+                current: None,
+                missing_chunks: None,
+            });
+            continue;
+        }
+
+        if !r.has_valid_parent() {
+            rejected.push(PushRejected {
+                r#type: r.r#type,
+                record_id: r.record_id,
+                reason: "invalid_parent".to_string(),
+                current: None,
+                missing_chunks: None,
+            });
+            continue;
+        }
+
+        // A far-future wall time would make the record unbeatable by `hlc_is_newer`.
+        if state.sync_policy.is_clock_skewed(&r.hlc, now_ms) {
+            rejected.push(PushRejected {
+                r#type: r.r#type,
+                record_id: r.record_id,
+                reason: "clock_skew".to_string(),
+                current: None,
+                missing_chunks: None,
+            });
+            continue;
+        }
+
+        if state
+            .sync_policy
+            .is_schema_too_old(&r.r#type, r.schema_version)
+        {
+            rejected.push(PushRejected {
+                r#type: r.r#type,
+                record_id: r.record_id,
+                reason: "schema_too_old".to_string(),
+                current: None,
+                missing_chunks: None,
+            });
+            continue;
+        }
+
+        if r.r#type == TYPE_TODO_ATTACHMENT_COMMIT {
+            commit_requests.push((r.record_id, r.deleted_at_ms_utc));
+            continue;
+        }
+
+        let committed_existing = sqlx::query(
+            r#"SELECT
+                 hlc_wall_ms_utc, hlc_counter, hlc_device_id,
+                 LENGTH(nonce) AS nonce_len,
+                 LENGTH(ciphertext) + IFNULL(ciphertext_len, 0) AS ciphertext_len,
+                 content_hash IS NOT NULL AS deduped
+         FROM records
+         WHERE user_id = ? AND type = ? AND record_id = ?"#,
+        )
+        .bind(user.user_id)
+        .bind(&r.r#type)
+        .bind(&r.record_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+        let is_staged_type = is_attachment_staged_type(&r.r#type);
+
+        // Once an attachment is deleted (tombstoned metadata in `records`), the
+        // server must reject any subsequent chunk/meta uploads regardless of
+        // HLC, otherwise stale/out-of-order clients could recreate staged data.
+        if is_staged_type && r.deleted_at_ms_utc.is_none() {
+            let attachment_id = if r.r#type == TYPE_TODO_ATTACHMENT {
+                Some(r.record_id.as_str())
+            } else {
+                r.record_id.rsplit_once(':').map(|(a, _)| a)
+            };
+
+            if let Some(attachment_id) = attachment_id {
+                let deleted: Option<i64> = sqlx::query_scalar(
+                    r#"SELECT deleted_at_ms_utc
+                       FROM records
+                       WHERE user_id = ? AND type = ? AND record_id = ?"#,
+                )
+                .bind(user.user_id)
+                .bind(TYPE_TODO_ATTACHMENT)
+                .bind(attachment_id)
+                .fetch_optional(&mut *tx)
+                .await
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+                if deleted.is_some_and(|ms| ms > 0) {
+                    rejected.push(PushRejected {
+                        r#type: r.r#type,
+                        record_id: r.record_id,
+                        reason: "attachment_deleted".to_string(),
+                        current: None,
+                        missing_chunks: None,
+                    });
+                    continue;
+                }
+            }
+        }
+
+        let staged_existing = if committed_existing.is_none() && is_staged_type {
+            sqlx::query(
+                r#"SELECT
+                     hlc_wall_ms_utc, hlc_counter, hlc_device_id,
+                     LENGTH(nonce) AS nonce_len,
+                     LENGTH(ciphertext) + IFNULL(ciphertext_len, 0) AS ciphertext_len,
+                     content_hash IS NOT NULL AS deduped
+             FROM staged_records
+             WHERE user_id = ? AND type = ? AND record_id = ?"#,
+            )
+            .bind(user.user_id)
+            .bind(&r.r#type)
+            .bind(&r.record_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+        } else {
+            None
+        };
+
+        let mut existing_hlc: Option<Hlc> = None;
+        let mut existing_size: i64 = 0;
+        let mut exists_committed = false;
+        let mut exists_staged = false;
+        let mut existing_deduped = false;
 
         if let Some(row) = &committed_existing {
             exists_committed = true;
@@ -1540,353 +3414,1850 @@ async fn push_sync(
                     .try_get("hlc_device_id")
                     .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
             });
-            let nonce_len: i64 = row
-                .try_get("nonce_len")
+            let nonce_len: i64 = row
+                .try_get("nonce_len")
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+            let ciphertext_len: i64 = row
+                .try_get("ciphertext_len")
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+            existing_size = nonce_len + ciphertext_len;
+            existing_deduped = row
+                .try_get("deduped")
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        } else if let Some(row) = &staged_existing {
+            exists_staged = true;
+            existing_hlc = Some(Hlc {
+                wall_time_ms_utc: row
+                    .try_get("hlc_wall_ms_utc")
+                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+                counter: row
+                    .try_get("hlc_counter")
+                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+                device_id: row
+                    .try_get("hlc_device_id")
+                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+            });
+            let nonce_len: i64 = row
+                .try_get("nonce_len")
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+            let ciphertext_len: i64 = row
+                .try_get("ciphertext_len")
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+            existing_size = nonce_len + ciphertext_len;
+            existing_deduped = row
+                .try_get("deduped")
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        }
+
+        // A purged delete still beats older writes, so a device that missed
+        // it can't bring the record back.
+        if existing_hlc.is_none() {
+            existing_hlc =
+                tombstone_gc::purged_tombstone_hlc(&mut tx, user.user_id, &r.r#type, &r.record_id)
+                    .await
+                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        }
+
+        let should_accept = existing_hlc
+            .as_ref()
+            .map(|stored| hlc_is_newer(&r.hlc, stored))
+            .unwrap_or(true);
+
+        if !should_accept {
+            // Hand back the committed winner, as pull would send it, so the
+            // client can merge right away. Chunks are skipped: they're large
+            // and never merged.
+            let current = if exists_committed && r.r#type != TYPE_TODO_ATTACHMENT_CHUNK {
+                let row = sqlx::query(
+                    r#"SELECT
+                         r.type, r.record_id,
+                         r.hlc_wall_ms_utc, r.hlc_counter, r.hlc_device_id,
+                         r.deleted_at_ms_utc, r.schema_version,
+                         IFNULL(c.dek_id, r.dek_id) AS dek_id, r.algo,
+                         IFNULL(c.nonce, r.nonce) AS nonce,
+                         IFNULL(c.ciphertext, r.ciphertext) AS ciphertext,
+                         r.chunk_count, r.total_ciphertext_len, r.content_hash,
+                         p.parent_type, p.parent_id,
+                         r.server_seq
+                       FROM records r
+                       LEFT JOIN chunk_blobs c
+                         ON c.user_id = r.user_id AND c.content_hash = r.content_hash
+                       LEFT JOIN record_parents p
+                         ON p.user_id = r.user_id AND p.type = r.type AND p.record_id = r.record_id
+                       WHERE r.user_id = ? AND r.type = ? AND r.record_id = ?"#,
+                )
+                .bind(user.user_id)
+                .bind(&r.r#type)
+                .bind(&r.record_id)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+                let mut envelope = record_envelope_from_row(&row)
+                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+                set_attachment_fields_from_row(&mut envelope, &row)
+                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+                set_parent_from_row(&mut envelope, &row)
+                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+                envelope.server_seq = Some(
+                    row.try_get("server_seq")
+                        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+                );
+                Some(envelope)
+            } else {
+                None
+            };
+            rejected.push(PushRejected {
+                r#type: r.r#type,
+                record_id: r.record_id,
+                reason: "older_hlc".to_string(),
+                current,
+                missing_chunks: None,
+            });
+            continue;
+        }
+
+        // For attachments/chunks, a tombstone before commit should simply
+        // remove any staged data and remain invisible to other devices.
+        if is_staged_type && r.deleted_at_ms_utc.is_some() && !exists_committed {
+            if r.r#type == TYPE_TODO_ATTACHMENT {
+                let attachment_id = r.record_id.clone();
+                let (deleted_count, deleted_bytes) =
+                    delete_staged_attachment(&mut tx, user.user_id, &attachment_id)
+                        .await
+                        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+                if deleted_count > 0 {
+                    total_b64 = (total_b64 - deleted_bytes).max(0);
+                    record_count = (record_count - deleted_count).max(0);
+                    did_compact_records = true;
+                }
+                let blob_bytes =
+                    blobs::delete_attachment_blobs(&mut tx, user.user_id, &attachment_id)
+                        .await
+                        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+                if blob_bytes > 0 {
+                    total_b64 = (total_b64 - blob_bytes).max(0);
+                    did_compact_records = true;
+                }
+
+                accepted.push(PushAccepted {
+                    r#type: r.r#type,
+                    record_id: r.record_id,
+                    server_seq: 0,
+                });
+                continue;
+            }
+
+            if exists_staged {
+                sqlx::query(
+                    r#"DELETE FROM staged_records WHERE user_id = ? AND type = ? AND record_id = ?"#,
+                )
+                .bind(user.user_id)
+                .bind(&r.r#type)
+                .bind(&r.record_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+                total_b64 = (total_b64 - existing_size).max(0);
+                record_count = (record_count - 1).max(0);
+                if existing_deduped {
+                    did_compact_records = true;
+                }
+            }
+            if r.r#type == TYPE_TODO_ATTACHMENT_CHUNK {
+                let blob_bytes = blobs::delete_chunk_blob(&mut tx, user.user_id, &r.record_id)
+                    .await
+                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+                if blob_bytes > 0 {
+                    total_b64 = (total_b64 - blob_bytes).max(0);
+                    did_compact_records = true;
+                }
+            }
+
+            accepted.push(PushAccepted {
+                r#type: r.r#type,
+                record_id: r.record_id,
+                server_seq: 0,
+            });
+            continue;
+        }
+
+        // A chunk with a `contentHash` the account already stores is only a
+        // reference; its ciphertext may then be left out of the push.
+        let content_hash = r
+            .content_hash
+            .as_deref()
+            .filter(|_| r.deleted_at_ms_utc.is_none());
+        let dedup_bytes = match content_hash {
+            Some(hash) => chunk_dedup::lookup(&mut *tx, user.user_id, hash)
+                .await
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+            None => None,
+        };
+        if content_hash.is_some() && dedup_bytes.is_none() && r.ciphertext.is_empty() {
+            rejected.push(PushRejected {
+                r#type: r.r#type,
+                record_id: r.record_id,
+                reason: "unknown_content_hash".to_string(),
+                current: None,
+                missing_chunks: None,
+            });
+            continue;
+        }
+
+        let new_size: i64 = if dedup_bytes.is_some() {
+            r.nonce.len() as i64
+        } else {
+            (r.nonce.len() + r.ciphertext.len()) as i64
+        };
+
+        let mut new_total_b64 = total_b64 + (new_size - existing_size);
+        let new_record_count = record_count
+            + if !exists_committed && !exists_staged {
+                1
+            } else {
+                0
+            };
+
+        if let Some(max) = state.max_records_per_user {
+            if new_record_count > max {
+                rejected.push(PushRejected {
+                    r#type: r.r#type,
+                    record_id: r.record_id,
+                    reason: "quota_exceeded".to_string(),
+                    current: None,
+                    missing_chunks: None,
+                });
+                continue;
+            }
+        }
+        if let Some(max) = quota.allowed_storage_b64 {
+            // Allow deletes/size reductions even when the user is already over
+            // quota, so they can free space. Block only mutations that would
+            // increase stored bytes while remaining above the limit.
+            let increases_storage = new_total_b64 > total_b64;
+            if new_total_b64 > max && increases_storage {
+                rejected.push(PushRejected {
+                    r#type: r.r#type,
+                    record_id: r.record_id,
+                    reason: "quota_exceeded".to_string(),
+                    current: None,
+                    missing_chunks: None,
+                });
+                continue;
+            }
+        }
+
+        let mut ciphertext_bytes = r.chunk_ciphertext_bytes();
+        let offloaded = if let Some(hash) = content_hash {
+            // Deduplicated bytes live in `chunk_blobs`; the row keeps the hash.
+            match dedup_bytes {
+                Some(bytes) => ciphertext_bytes = Some(bytes),
+                None => chunk_dedup::insert(
+                    &mut tx,
+                    user.user_id,
+                    &chunk_dedup::NewChunk {
+                        content_hash: hash,
+                        nonce: &r.nonce,
+                        dek_id: &r.dek_id,
+                        ciphertext: &r.ciphertext,
+                        ciphertext_bytes: ciphertext_bytes.unwrap_or(0),
+                    },
+                    upload.as_ref(),
+                    now_ms,
+                )
+                .await
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+            }
+            None
+        } else {
+            // With a blob store configured, chunk ciphertext lives there and the
+            // row keeps only a pointer and the length.
+            if let Some((key, _)) = &upload {
+                blob_store::claim(&mut tx, key)
+                    .await
+                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+            }
+            upload
+        };
+        let (ciphertext, ciphertext_ref, ciphertext_len) = match &offloaded {
+            Some((key, len)) => ("", Some(key.as_str()), Some(*len)),
+            None if content_hash.is_some() => ("", None, None),
+            None => (r.ciphertext.as_str(), None, None),
+        };
+        let (chunk_count, total_ciphertext_len) = r.stored_manifest();
+        // Moving a reference may free a shared chunk, which the running total
+        // can't see; recount from the tables instead.
+        if content_hash.is_some() || existing_deduped {
+            did_compact_records = true;
+        }
+
+        let should_stage = is_staged_type && !exists_committed;
+        if should_stage {
+            let now_ms = now_ms_utc();
+            sqlx::query(
+                r#"INSERT INTO staged_records (
+               user_id, type, record_id,
+               hlc_wall_ms_utc, hlc_counter, hlc_device_id,
+               deleted_at_ms_utc,
+               schema_version, dek_id,
+               algo, nonce, ciphertext, ciphertext_ref, ciphertext_len,
+               chunk_count, total_ciphertext_len, ciphertext_bytes, content_hash,
+               updated_at_ms_utc
+             ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(user_id, type, record_id) DO UPDATE SET
+               hlc_wall_ms_utc = excluded.hlc_wall_ms_utc,
+               hlc_counter = excluded.hlc_counter,
+               hlc_device_id = excluded.hlc_device_id,
+               deleted_at_ms_utc = excluded.deleted_at_ms_utc,
+               schema_version = excluded.schema_version,
+               dek_id = excluded.dek_id,
+               algo = excluded.algo,
+               nonce = excluded.nonce,
+               ciphertext = excluded.ciphertext,
+               ciphertext_ref = excluded.ciphertext_ref,
+               ciphertext_len = excluded.ciphertext_len,
+               chunk_count = excluded.chunk_count,
+               total_ciphertext_len = excluded.total_ciphertext_len,
+               ciphertext_bytes = excluded.ciphertext_bytes,
+               content_hash = excluded.content_hash,
+               updated_at_ms_utc = excluded.updated_at_ms_utc"#,
+            )
+            .bind(user.user_id)
+            .bind(&r.r#type)
+            .bind(&r.record_id)
+            .bind(r.hlc.wall_time_ms_utc)
+            .bind(r.hlc.counter)
+            .bind(&r.hlc.device_id)
+            .bind(r.deleted_at_ms_utc)
+            .bind(r.schema_version)
+            .bind(&r.dek_id)
+            .bind(&r.payload_algo)
+            .bind(&r.nonce)
+            .bind(ciphertext)
+            .bind(ciphertext_ref)
+            .bind(ciphertext_len)
+            .bind(chunk_count)
+            .bind(total_ciphertext_len)
+            .bind(ciphertext_bytes)
+            .bind(content_hash)
+            .bind(now_ms)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+            accepted.push(PushAccepted {
+                r#type: r.r#type,
+                record_id: r.record_id,
+                server_seq: 0,
+            });
+        } else {
+            let server_seq = alloc_server_seq(&mut tx, user.user_id).await.map_err(|e| {
+                error!(error = %e, "alloc_server_seq failed");
+                json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error")
+            })?;
+
+            let now_ms = now_ms_utc();
+            // History is not part of the quota check above: an edit should
+            // never fail just because the previous version is being kept.
+            if exists_committed {
+                new_total_b64 += history::archive_current(
+                    &mut tx,
+                    user.user_id,
+                    &r.r#type,
+                    &r.record_id,
+                    server_seq,
+                    state.record_history_limit,
+                    now_ms,
+                )
+                .await
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+            }
+            sqlx::query(
+                r#"INSERT INTO records (
+               user_id, type, record_id,
+               hlc_wall_ms_utc, hlc_counter, hlc_device_id,
+               deleted_at_ms_utc,
+               schema_version, dek_id,
+               algo, nonce, ciphertext, ciphertext_ref, ciphertext_len,
+               chunk_count, total_ciphertext_len, ciphertext_bytes, content_hash,
+               server_seq, updated_at_ms_utc, created_server_seq
+             ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(user_id, type, record_id) DO UPDATE SET
+               hlc_wall_ms_utc = excluded.hlc_wall_ms_utc,
+               hlc_counter = excluded.hlc_counter,
+               hlc_device_id = excluded.hlc_device_id,
+               deleted_at_ms_utc = excluded.deleted_at_ms_utc,
+               schema_version = excluded.schema_version,
+               dek_id = excluded.dek_id,
+               algo = excluded.algo,
+               nonce = excluded.nonce,
+               ciphertext = excluded.ciphertext,
+               ciphertext_ref = excluded.ciphertext_ref,
+               ciphertext_len = excluded.ciphertext_len,
+               chunk_count = excluded.chunk_count,
+               total_ciphertext_len = excluded.total_ciphertext_len,
+               ciphertext_bytes = excluded.ciphertext_bytes,
+               content_hash = excluded.content_hash,
+               server_seq = excluded.server_seq,
+               updated_at_ms_utc = excluded.updated_at_ms_utc"#,
+            )
+            .bind(user.user_id)
+            .bind(&r.r#type)
+            .bind(&r.record_id)
+            .bind(r.hlc.wall_time_ms_utc)
+            .bind(r.hlc.counter)
+            .bind(&r.hlc.device_id)
+            .bind(r.deleted_at_ms_utc)
+            .bind(r.schema_version)
+            .bind(&r.dek_id)
+            .bind(&r.payload_algo)
+            .bind(&r.nonce)
+            .bind(ciphertext)
+            .bind(ciphertext_ref)
+            .bind(ciphertext_len)
+            .bind(chunk_count)
+            .bind(total_ciphertext_len)
+            .bind(ciphertext_bytes)
+            .bind(content_hash)
+            .bind(server_seq)
+            .bind(now_ms)
+            .bind(server_seq)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+            // A push without a parent leaves the stored link alone, so older
+            // clients don't drop it.
+            if r.deleted_at_ms_utc.is_none() {
+                upsert_record_parent(&mut tx, user.user_id, &r, now_ms)
+                    .await
+                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+            }
+
+            let record_type = r.r#type.clone();
+            let record_id = r.record_id.clone();
+            let is_attachment_delete =
+                r.r#type == TYPE_TODO_ATTACHMENT && r.deleted_at_ms_utc.is_some();
+            if r.r#type == TYPE_TODO_ATTACHMENT_CHUNK && r.deleted_at_ms_utc.is_some() {
+                let blob_bytes = blobs::delete_chunk_blob(&mut tx, user.user_id, &r.record_id)
+                    .await
+                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+                if blob_bytes > 0 {
+                    did_compact_records = true;
+                }
+            }
+            let deleted_at_ms_utc = r.deleted_at_ms_utc.unwrap_or(now_ms);
+
+            accepted.push(PushAccepted {
+                r#type: record_type,
+                record_id,
+                server_seq,
+            });
+
+            // Attachment deletion should compact all committed chunk payloads
+            // (so future pulls don't transfer old ciphertext) and clear any
+            // in-flight staged data without requiring per-chunk tombstones
+            // from the client.
+            if is_attachment_delete {
+                let attachment_id = r.record_id;
+
+                let _ = delete_staged_attachment(&mut tx, user.user_id, &attachment_id)
+                    .await
+                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+                compact_committed_attachment_chunks(
+                    &mut tx,
+                    user.user_id,
+                    &attachment_id,
+                    deleted_at_ms_utc,
+                )
+                .await
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+                did_compact_records = true;
+            }
+        }
+
+        total_b64 = new_total_b64;
+        record_count = new_record_count;
+    }
+
+    for (attachment_id, deleted_at_ms_utc) in commit_requests {
+        if deleted_at_ms_utc.is_some() {
+            // Tombstoning the commit marker is used by clients to cancel a
+            // pending commit while deleting an in-progress attachment upload.
+            accepted.push(PushAccepted {
+                r#type: TYPE_TODO_ATTACHMENT_COMMIT.to_string(),
+                record_id: attachment_id,
+                server_seq: 0,
+            });
+            continue;
+        }
+
+        let committed_deleted: Option<i64> = sqlx::query_scalar(
+            r#"SELECT deleted_at_ms_utc
+               FROM records
+               WHERE user_id = ? AND type = ? AND record_id = ?"#,
+        )
+        .bind(user.user_id)
+        .bind(TYPE_TODO_ATTACHMENT)
+        .bind(&attachment_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+        if committed_deleted.is_some_and(|ms| ms > 0) {
+            rejected.push(PushRejected {
+                r#type: TYPE_TODO_ATTACHMENT_COMMIT.to_string(),
+                record_id: attachment_id,
+                reason: "attachment_deleted".to_string(),
+                current: None,
+                missing_chunks: None,
+            });
+            continue;
+        }
+
+        let has_committed_meta: Option<i64> = sqlx::query_scalar(
+            r#"SELECT 1
+               FROM records
+               WHERE user_id = ? AND type = ? AND record_id = ? AND deleted_at_ms_utc IS NULL
+               LIMIT 1"#,
+        )
+        .bind(user.user_id)
+        .bind(TYPE_TODO_ATTACHMENT)
+        .bind(&attachment_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+        let has_staged_meta: Option<i64> = sqlx::query_scalar(
+            r#"SELECT 1 FROM staged_records WHERE user_id = ? AND type = ? AND record_id = ? LIMIT 1"#,
+        )
+        .bind(user.user_id)
+        .bind(TYPE_TODO_ATTACHMENT)
+        .bind(&attachment_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+        if has_committed_meta.is_none() && has_staged_meta.is_none() {
+            rejected.push(PushRejected {
+                r#type: TYPE_TODO_ATTACHMENT_COMMIT.to_string(),
+                record_id: attachment_id,
+                reason: "missing_attachment_meta".to_string(),
+                current: None,
+                missing_chunks: None,
+            });
+            continue;
+        }
+
+        let missing_chunks = incomplete_attachment_chunks(&mut tx, user.user_id, &attachment_id)
+            .await
+            .map_err(|e| {
+                error!(error = %e, "incomplete_attachment_chunks failed");
+                json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error")
+            })?;
+        if missing_chunks.is_some() {
+            rejected.push(PushRejected {
+                r#type: TYPE_TODO_ATTACHMENT_COMMIT.to_string(),
+                record_id: attachment_id,
+                reason: "incomplete_attachment".to_string(),
+                current: None,
+                missing_chunks,
+            });
+            continue;
+        }
+
+        commit_staged_attachment(&mut tx, user.user_id, &attachment_id)
+            .await
+            .map_err(|e| {
+                error!(error = %e, "commit_staged_attachment failed");
+                json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error")
+            })?;
+
+        accepted.push(PushAccepted {
+            r#type: TYPE_TODO_ATTACHMENT_COMMIT.to_string(),
+            record_id: attachment_id,
+            server_seq: 0,
+        });
+    }
+
+    let body = PushResponse { accepted, rejected };
+    let (resp, bytes_len) = wire.encode(&body)?;
+
+    if let Some(key) = idempotency_key.as_deref() {
+        let response_json = serde_json::to_string(&body)
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "serialize error"))?;
+        let inserted = sqlx::query(
+            r#"INSERT INTO push_idempotency (user_id, idem_key, request_hash, response_json, created_at_ms_utc)
+               VALUES (?, ?, ?, ?, ?)
+               ON CONFLICT(user_id, idem_key) DO NOTHING"#,
+        )
+        .bind(user.user_id)
+        .bind(key)
+        .bind(&request_hash)
+        .bind(response_json)
+        .bind(now_ms)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+        // A concurrent push with the same key committed first: drop this one
+        // and answer with that push's response instead.
+        if inserted.rows_affected() == 0 {
+            tx.rollback().await.ok();
+            let mut tx = state
+                .db
+                .begin()
+                .await
                 .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-            let ciphertext_len: i64 = row
-                .try_get("ciphertext_len")
+            let resp = replay_stored_push(
+                &mut tx,
+                wire,
+                user.user_id,
+                billing_user_id,
+                key,
+                &request_hash,
+                quota.allowed_outbound_bytes,
+            )
+            .await?
+            .ok_or_else(|| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+            tx.commit()
+                .await
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+            state.metrics.record_active_user(now_ms, user.account_id);
+            return Ok(resp);
+        }
+    }
+
+    if did_compact_records {
+        total_b64 = recompute_and_store_user_b64(&mut tx, user.user_id)
+            .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    }
+
+    sqlx::query(r#"UPDATE users SET stored_b64 = ?, api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#)
+        .bind(total_b64)
+        .bind(bytes_len)
+        .bind(user.user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    let seq_after = current_server_seq(&mut *tx, user.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    // Wake `/v1/sync/stream` listeners only after the new rows are visible.
+    if seq_after > seq_before {
+        state.notifier.publish(user.user_id, seq_after);
+    }
+
+    if let Some(device_id) = device_id.as_deref() {
+        let name = devices::device_name_from_headers(&headers);
+        if let Err(e) = devices::record_sighting(
+            &state.db,
+            user.user_id,
+            device_id,
+            name.as_deref(),
+            user.session_id,
+            None,
+            now_ms,
+        )
+        .await
+        {
+            error!(error = %e, "record device sighting failed");
+        }
+    }
+
+    state.metrics.record_active_user(now_ms, user.account_id);
+    Ok(resp)
+}
+
+/// Record filters shared by every query a single pull (or long-poll wakeup) runs.
+struct PullFilter {
+    exclude_device_id: Option<String>,
+    types: Vec<String>,
+    exclude_types: Vec<String>,
+    /// Stop the page once summed `nonce` + `ciphertext` length would exceed this.
+    max_bytes: Option<i64>,
+}
+
+fn parse_type_list(raw: Option<&str>) -> Vec<String> {
+    raw.unwrap_or("")
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .take(MAX_PULL_TYPE_FILTERS)
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// Returns the page rows and whether more matching rows exist after them.
+///
+/// The first row is always returned, even if it alone exceeds `max_bytes`,
+/// so a small budget can't stall a client forever.
+async fn fetch_pull_rows(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    since: i64,
+    filter: &PullFilter,
+    limit: i64,
+) -> Result<(Vec<sqlx::sqlite::SqliteRow>, bool), sqlx::Error> {
+    use tokio_stream::StreamExt as _;
+
+    // Deduplicated chunks take their payload from `chunk_blobs`.
+    let mut qb = QueryBuilder::<Sqlite>::new(
+        r#"SELECT
+         r.type,
+         r.record_id,
+         r.hlc_wall_ms_utc,
+         r.hlc_counter,
+         r.hlc_device_id,
+         r.deleted_at_ms_utc,
+         r.schema_version,
+         IFNULL(c.dek_id, r.dek_id) AS dek_id,
+         r.algo,
+         IFNULL(c.nonce, r.nonce) AS nonce,
+         IFNULL(c.ciphertext, r.ciphertext) AS ciphertext,
+         IFNULL(c.ciphertext_ref, r.ciphertext_ref) AS ciphertext_ref,
+         r.chunk_count,
+         r.total_ciphertext_len,
+         r.content_hash,
+         p.parent_type,
+         p.parent_id,
+         r.server_seq,
+         LENGTH(IFNULL(c.nonce, r.nonce)) + LENGTH(IFNULL(c.ciphertext, r.ciphertext))
+           + IFNULL(IFNULL(c.ciphertext_len, r.ciphertext_len), 0) AS payload_len
+       FROM records r
+       LEFT JOIN chunk_blobs c
+         ON c.user_id = r.user_id AND c.content_hash = r.content_hash
+       LEFT JOIN record_parents p
+         ON p.user_id = r.user_id AND p.type = r.type AND p.record_id = r.record_id
+       WHERE r.user_id = "#,
+    );
+    qb.push_bind(user_id);
+    qb.push(" AND r.server_seq > ");
+    qb.push_bind(since);
+    if let Some(device_id) = filter.exclude_device_id.as_deref() {
+        qb.push(" AND r.hlc_device_id != ");
+        qb.push_bind(device_id.to_string());
+    }
+    if !filter.types.is_empty() {
+        qb.push(" AND r.type IN (");
+        let mut sep = qb.separated(", ");
+        for t in &filter.types {
+            sep.push_bind(t.clone());
+        }
+        qb.push(")");
+    }
+    if !filter.exclude_types.is_empty() {
+        qb.push(" AND r.type NOT IN (");
+        let mut sep = qb.separated(", ");
+        for t in &filter.exclude_types {
+            sep.push_bind(t.clone());
+        }
+        qb.push(")");
+    }
+    qb.push(" ORDER BY r.server_seq ASC LIMIT ");
+    qb.push_bind(limit + 1);
+
+    // Stream rows so a tight byte budget doesn't materialize a full page of chunks.
+    let mut stream = qb.build().fetch(db);
+    let mut rows = Vec::new();
+    let mut used_bytes: i64 = 0;
+    while let Some(row) = stream.next().await {
+        let row = row?;
+        if rows.len() as i64 >= limit {
+            return Ok((rows, true));
+        }
+        let payload_len: i64 = row.try_get("payload_len")?;
+        if let Some(max) = filter.max_bytes {
+            if !rows.is_empty() && used_bytes + payload_len > max {
+                return Ok((rows, true));
+            }
+        }
+        used_bytes += payload_len;
+        rows.push(row);
+    }
+    Ok((rows, false))
+}
+
+/// Highest `serverSeq` still backed by data: the newest record, or the purge
+/// horizon if the newest rows were purged tombstones.
+async fn records_head(db: &Pool<Sqlite>, user_id: i64) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"SELECT MAX(
+             (SELECT COALESCE(MAX(server_seq), 0) FROM records WHERE user_id = ?),
+             (SELECT COALESCE(MAX(purge_horizon_seq), 0) FROM server_seq WHERE user_id = ?)
+           )"#,
+    )
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_one(db)
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct PullQuery {
+    since: Option<i64>,
+    limit: Option<i64>,
+    #[serde(rename = "excludeDeviceId")]
+    exclude_device_id: Option<String>,
+    /// Long-poll: park up to this many ms when the client is already at the head.
+    #[serde(rename = "waitMs")]
+    wait_ms: Option<i64>,
+    /// Comma-separated record types to include (default: all).
+    types: Option<String>,
+    /// Comma-separated record types to skip.
+    #[serde(rename = "excludeTypes")]
+    exclude_types: Option<String>,
+    #[serde(rename = "maxBytes")]
+    max_bytes: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct PullResponse {
+    records: Vec<SyncRecordEnvelope>,
+    #[serde(rename = "nextSince")]
+    next_since: i64,
+    /// More matching records exist past `nextSince` (page limit or byte budget hit).
+    #[serde(rename = "hasMore")]
+    has_more: bool,
+}
+
+/// Billing checks a read runs before doing any work: resets the monthly
+/// outbound counter, then refuses banned users and users over either quota.
+/// Returns the quota and the user outbound bytes are charged to (the owner
+/// when reading a space).
+async fn read_billing_preamble(
+    state: &AppState,
+    user_id: i64,
+    now_ms: i64,
+) -> Result<(EffectiveQuota, i64), (StatusCode, Json<ErrorBody>)> {
+    reset_user_api_outbound_if_new_month(&state.db, user_id, now_ms)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    let billing_row = sqlx::query(BILLING_ROW_SQL)
+        .bind(user_id)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let Some(billing_row) = billing_row else {
+        return Err(json_error(StatusCode::UNAUTHORIZED, "unauthorized"));
+    };
+
+    let mut subscription_plan_id: Option<String> = billing_row
+        .try_get("subscription_plan_id")
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let mut subscription_expires_at_ms_utc: Option<i64> = billing_row
+        .try_get("subscription_expires_at_ms_utc")
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    // Outbound is charged to the owner's row when pulling a space.
+    let billing_user_id: i64 = billing_row
+        .try_get("billing_user_id")
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    let has_plan = subscription_plan_id
+        .as_deref()
+        .map(|s| !s.trim().is_empty())
+        .unwrap_or(false);
+    let expires_at = subscription_expires_at_ms_utc.unwrap_or(0);
+    if has_plan && expires_at <= now_ms {
+        clear_subscription_if_expired(
+            &state.db,
+            billing_user_id,
+            &subscription_plan_id,
+            subscription_expires_at_ms_utc,
+            now_ms,
+        )
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        subscription_plan_id = None;
+        subscription_expires_at_ms_utc = None;
+    }
+
+    let user_billing = UserBillingRow {
+        base_storage_b64: billing_row
+            .try_get("base_storage_b64")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+        base_outbound_bytes: billing_row
+            .try_get("base_outbound_bytes")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+        subscription_plan_id,
+        subscription_expires_at_ms_utc,
+        banned_at_ms_utc: billing_row
+            .try_get("banned_at_ms_utc")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+        stored_b64: billing_row
+            .try_get("stored_b64")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+        api_outbound_bytes: billing_row
+            .try_get("api_outbound_bytes")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+        other_stored_b64: billing_row
+            .try_get("other_stored_b64")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+    };
+
+    if user_billing.banned_at_ms_utc.is_some_and(|ms| ms > 0) {
+        return Err(json_error(StatusCode::FORBIDDEN, "banned"));
+    }
+
+    let quota = compute_effective_quota(&state.billing, &user_billing, now_ms);
+    if let Some(max) = quota.allowed_storage_b64 {
+        if user_billing.stored_b64 > max {
+            return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
+        }
+    }
+    if let Some(max) = quota.allowed_outbound_bytes {
+        if user_billing.api_outbound_bytes > max {
+            return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
+        }
+    }
+
+    Ok((quota, billing_user_id))
+}
+
+/// Charge a read's response to `billing_user_id`, refusing it with
+/// `402 quota_exceeded` if it would go over `allowed_outbound_bytes`.
+async fn charge_read_outbound(
+    db: &Pool<Sqlite>,
+    billing_user_id: i64,
+    bytes_len: i64,
+    allowed_outbound_bytes: Option<i64>,
+) -> Result<(), (StatusCode, Json<ErrorBody>)> {
+    if let Some(limit) = allowed_outbound_bytes {
+        let updated = sqlx::query(
+            r#"UPDATE users
+               SET api_outbound_bytes = api_outbound_bytes + ?
+               WHERE id = ? AND api_outbound_bytes + ? <= ?"#,
+        )
+        .bind(bytes_len)
+        .bind(billing_user_id)
+        .bind(bytes_len)
+        .bind(limit)
+        .execute(db)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        if updated.rows_affected() == 0 {
+            return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
+        }
+    } else {
+        sqlx::query(r#"UPDATE users SET api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#)
+            .bind(bytes_len)
+            .bind(billing_user_id)
+            .execute(db)
+            .await
+            .ok();
+    }
+    Ok(())
+}
+
+async fn pull_sync(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+    wire: wire::Negotiated,
+    headers: axum::http::HeaderMap,
+    Query(q): Query<PullQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let since = q.since.unwrap_or(0).max(0);
+    let limit = q.limit.unwrap_or(200).clamp(1, MAX_PULL_LIMIT);
+    let filter = PullFilter {
+        exclude_device_id: q
+            .exclude_device_id
+            .as_deref()
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string()),
+        types: parse_type_list(q.types.as_deref()),
+        exclude_types: parse_type_list(q.exclude_types.as_deref()),
+        max_bytes: q.max_bytes.map(|v| v.max(1)),
+    };
+
+    let now_ms = now_ms_utc();
+
+    let (quota, billing_user_id) = read_billing_preamble(&state, user.user_id, now_ms).await?;
+
+    // Deletes at or below the horizon are gone; a cursor inside that range
+    // can't be caught up incrementally.
+    let purge_horizon = tombstone_gc::purge_horizon(&state.db, user.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if since > 0 && since < purge_horizon {
+        return Err(json_error(StatusCode::GONE, "resync_required"));
+    }
+
+    // Pulling from `since` means the device has applied everything up to it.
+    let device_id = devices::device_id_from_headers(&headers).or_else(|| {
+        filter
+            .exclude_device_id
+            .as_deref()
+            .and_then(devices::normalize_device_id)
+    });
+    if let Some(device_id) = device_id.as_deref() {
+        let name = devices::device_name_from_headers(&headers);
+        let head = current_server_seq(&state.db, user.user_id)
+            .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        if let Err(e) = devices::record_sighting(
+            &state.db,
+            user.user_id,
+            device_id,
+            name.as_deref(),
+            user.session_id,
+            Some(since.min(head)),
+            now_ms,
+        )
+        .await
+        {
+            error!(error = %e, "record device sighting failed");
+        }
+    }
+
+    let wait_ms = q.wait_ms.unwrap_or(0).clamp(0, MAX_PULL_WAIT_MS);
+    let deadline = tokio::time::Instant::now() + Duration::from_millis(wait_ms as u64);
+    // Subscribe before the first query so a commit landing in between still wakes us.
+    let mut wakeups = (wait_ms > 0).then(|| state.notifier.subscribe(user.user_id));
+    let mut timed_out = false;
+
+    // Parked requests hold no DB connection and are not re-checked against the
+    // rate limiter; only the initial request counts.
+    let (rows, has_more) = loop {
+        if let Some(rx) = wakeups.as_mut() {
+            rx.borrow_and_update();
+        }
+        let (rows, has_more) = fetch_pull_rows(&state.db, user.user_id, since, &filter, limit)
+            .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        let Some(rx) = wakeups.as_mut().filter(|_| rows.is_empty()) else {
+            break (rows, has_more);
+        };
+
+        // Only park a client that is caught up; a cursor ahead of the head
+        // (rollback) or behind it (excluded own writes) is answered right away.
+        let head = records_head(&state.db, user.user_id)
+            .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        if head != since {
+            break (rows, has_more);
+        }
+        match tokio::time::timeout_at(deadline, rx.changed()).await {
+            Ok(Ok(())) => continue,
+            Ok(Err(_)) | Err(_) => {
+                timed_out = true;
+                break (rows, has_more);
+            }
+        }
+    };
+
+    // `nextSince` should help clients detect server rollbacks / DB resets.
+    //
+    // If we return `nextSince = since` on an empty page, a client with a cursor
+    // ahead of the current server head (e.g. server DB reset) will never detect
+    // the rollback and will appear "stuck" at a higher `lastServerSeq`.
+    //
+    // A long-poll that timed out has just confirmed `since == head`, so the
+    // cursor is echoed back unchanged.
+    if rows.is_empty() {
+        let head = if timed_out {
+            since
+        } else {
+            records_head(&state.db, user.user_id)
+                .await
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+        };
+
+        let body = PullResponse {
+            records: Vec::new(),
+            next_since: head,
+            has_more: false,
+        };
+        let (resp, bytes_len) = wire.encode(&body)?;
+        charge_read_outbound(
+            &state.db,
+            billing_user_id,
+            bytes_len,
+            quota.allowed_outbound_bytes,
+        )
+        .await?;
+        state.metrics.record_active_user(now_ms, user.account_id);
+        return Ok(resp);
+    }
+
+    let mut next_since = since;
+    let mut records = Vec::with_capacity(rows.len());
+
+    for row in rows {
+        let server_seq: i64 = row
+            .try_get("server_seq")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        next_since = next_since.max(server_seq);
+
+        let mut envelope = record_envelope_from_row(&row)
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        set_attachment_fields_from_row(&mut envelope, &row)
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        set_parent_from_row(&mut envelope, &row)
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        let ciphertext_ref: Option<String> = row
+            .try_get("ciphertext_ref")
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        if let Some(key) = ciphertext_ref {
+            envelope.ciphertext = blob_store::load_ciphertext(state.blob_store.as_deref(), &key)
+                .await
+                .map_err(|e| {
+                    error!(error = %e, "blob store get failed");
+                    json_error(StatusCode::INTERNAL_SERVER_ERROR, "blob store error")
+                })?;
+        }
+        records.push(envelope);
+    }
+
+    let body = PullResponse {
+        records,
+        next_since,
+        has_more,
+    };
+    let (resp, bytes_len) = wire.encode(&body)?;
+    charge_read_outbound(
+        &state.db,
+        billing_user_id,
+        bytes_len,
+        quota.allowed_outbound_bytes,
+    )
+    .await?;
+
+    state.metrics.record_active_user(now_ms, user.account_id);
+    Ok(resp)
+}
+
+#[derive(Debug, Serialize)]
+struct StreamSeqEvent {
+    #[serde(rename = "serverSeq")]
+    server_seq: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct DevicesResponse {
+    #[serde(rename = "serverSeq")]
+    server_seq: i64,
+    devices: Vec<devices::Device>,
+}
+
+async fn list_devices(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let server_seq = current_server_seq(&state.db, user.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let devices = devices::list_devices(&state.db, user.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    Ok(Json(DevicesResponse {
+        server_seq,
+        devices,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceAckRequest {
+    #[serde(rename = "serverSeq")]
+    server_seq: i64,
+}
+
+async fn ack_device(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+    Path(device_id): Path<String>,
+    headers: axum::http::HeaderMap,
+    Json(req): Json<DeviceAckRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let Some(device_id) = devices::normalize_device_id(&device_id) else {
+        return Err(json_error(StatusCode::BAD_REQUEST, "invalid device id"));
+    };
+    let now_ms = now_ms_utc();
+
+    // Can't acknowledge what hasn't been allocated yet.
+    let head = current_server_seq(&state.db, user.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let name = devices::device_name_from_headers(&headers);
+    devices::record_sighting(
+        &state.db,
+        user.user_id,
+        &device_id,
+        name.as_deref(),
+        user.session_id,
+        Some(req.server_seq.clamp(0, head)),
+        now_ms,
+    )
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    Ok(Json(OkResponse { ok: true }))
+}
+
+#[derive(Debug, Serialize)]
+struct RecordHistoryResponse {
+    current: Option<SyncRecordEnvelope>,
+    versions: Vec<history::HistoryVersion>,
+}
+
+async fn get_record_history(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+    wire: wire::Negotiated,
+    Path((record_type, record_id)): Path<(String, String)>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let now_ms = now_ms_utc();
+    let (quota, billing_user_id) = read_billing_preamble(&state, user.user_id, now_ms).await?;
+
+    let row = sqlx::query(
+        r#"SELECT
+             r.type, r.record_id,
+             r.hlc_wall_ms_utc, r.hlc_counter, r.hlc_device_id,
+             r.deleted_at_ms_utc, r.schema_version,
+             IFNULL(c.dek_id, r.dek_id) AS dek_id, r.algo,
+             IFNULL(c.nonce, r.nonce) AS nonce,
+             IFNULL(c.ciphertext, r.ciphertext) AS ciphertext,
+             IFNULL(c.ciphertext_ref, r.ciphertext_ref) AS ciphertext_ref,
+             r.server_seq
+           FROM records r
+           LEFT JOIN chunk_blobs c
+             ON c.user_id = r.user_id AND c.content_hash = r.content_hash
+           WHERE r.user_id = ? AND r.type = ? AND r.record_id = ?"#,
+    )
+    .bind(user.user_id)
+    .bind(&record_type)
+    .bind(&record_id)
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let current = match row {
+        Some(row) => {
+            let mut envelope = record_envelope_from_row(&row)
                 .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-            existing_size = nonce_len + ciphertext_len;
-        } else if let Some(row) = &staged_existing {
-            exists_staged = true;
-            existing_hlc = Some(Hlc {
-                wall_time_ms_utc: row
-                    .try_get("hlc_wall_ms_utc")
-                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-                counter: row
-                    .try_get("hlc_counter")
-                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-                device_id: row
-                    .try_get("hlc_device_id")
+            envelope.server_seq = Some(
+                row.try_get("server_seq")
                     .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-            });
-            let nonce_len: i64 = row
-                .try_get("nonce_len")
+            );
+            let ciphertext_ref: Option<String> = row
+                .try_get("ciphertext_ref")
                 .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-            let ciphertext_len: i64 = row
-                .try_get("ciphertext_len")
-                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-            existing_size = nonce_len + ciphertext_len;
+            if let Some(key) = ciphertext_ref {
+                envelope.ciphertext =
+                    blob_store::load_ciphertext(state.blob_store.as_deref(), &key)
+                        .await
+                        .map_err(|e| {
+                            error!(error = %e, "blob store get failed");
+                            json_error(StatusCode::INTERNAL_SERVER_ERROR, "blob store error")
+                        })?;
+            }
+            Some(envelope)
         }
+        None => None,
+    };
 
-        let should_accept = existing_hlc
-            .as_ref()
-            .map(|stored| hlc_is_newer(&r.hlc, stored))
-            .unwrap_or(true);
+    let versions = history::list_versions(&state.db, user.user_id, &record_type, &record_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if current.is_none() && versions.is_empty() {
+        return Err(json_error(StatusCode::NOT_FOUND, "record not found"));
+    }
 
-        if !should_accept {
-            rejected.push(PushRejected {
-                r#type: r.r#type,
-                record_id: r.record_id,
-                reason: "older_hlc".to_string(),
-            });
-            continue;
-        }
+    let (resp, bytes_len) = wire.encode(&RecordHistoryResponse { current, versions })?;
+    charge_read_outbound(
+        &state.db,
+        billing_user_id,
+        bytes_len,
+        quota.allowed_outbound_bytes,
+    )
+    .await?;
 
-        // For attachments/chunks, a tombstone before commit should simply
-        // remove any staged data and remain invisible to other devices.
-        if is_staged_type && r.deleted_at_ms_utc.is_some() && !exists_committed {
-            if r.r#type == TYPE_TODO_ATTACHMENT {
-                let attachment_id = r.record_id.clone();
-                let (deleted_count, deleted_bytes) =
-                    delete_staged_attachment(&mut tx, user.user_id, &attachment_id)
-                        .await
-                        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-                if deleted_count > 0 {
-                    total_b64 = (total_b64 - deleted_bytes).max(0);
-                    record_count = (record_count - deleted_count).max(0);
-                    did_compact_records = true;
-                }
+    state.metrics.record_active_user(now_ms, user.account_id);
+    Ok(resp)
+}
 
-                accepted.push(PushAccepted {
-                    r#type: r.r#type,
-                    record_id: r.record_id,
-                    server_seq: 0,
-                });
-                continue;
+/// Re-publish an old version as the current record.
+///
+/// The restored envelope gets a server-minted HLC just ahead of the current
+/// one and a fresh `serverSeq`, so every device pulls it like a normal edit.
+/// The version being replaced goes into history itself, so a restore can be
+/// undone the same way.
+async fn restore_record_version(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+    wire: wire::Negotiated,
+    Path((record_type, record_id, version_id)): Path<(String, String, i64)>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    user.require_owner()?;
+    let now_ms = now_ms_utc();
+
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    let Some(user_billing) = load_user_billing(&mut tx, user.user_id, now_ms)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+    else {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::UNAUTHORIZED, "unauthorized"));
+    };
+    if user_billing.banned_at_ms_utc.is_some_and(|ms| ms > 0) {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::FORBIDDEN, "banned"));
+    }
+    let quota = compute_effective_quota(&state.billing, &user_billing, now_ms);
+
+    let Some(version) =
+        history::fetch_version(&mut tx, user.user_id, &record_type, &record_id, version_id)
+            .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+    else {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::NOT_FOUND, "version not found"));
+    };
+
+    let current = sqlx::query(
+        r#"SELECT hlc_wall_ms_utc, hlc_counter, hlc_device_id
+           FROM records
+           WHERE user_id = ? AND type = ? AND record_id = ?"#,
+    )
+    .bind(user.user_id)
+    .bind(&record_type)
+    .bind(&record_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let current_hlc = match &current {
+        Some(row) => Some(Hlc {
+            wall_time_ms_utc: row
+                .try_get("hlc_wall_ms_utc")
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+            counter: row
+                .try_get("hlc_counter")
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+            device_id: row
+                .try_get("hlc_device_id")
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+        }),
+        None => None,
+    };
+
+    // The record may have been purged by tombstone GC; restoring recreates it.
+    if current_hlc.is_none() {
+        if let Some(max) = state.max_records_per_user {
+            let record_count: i64 = sqlx::query_scalar(
+                r#"SELECT
+                     (SELECT COUNT(*) FROM records WHERE user_id = ?)
+                   + (SELECT COUNT(*) FROM staged_records WHERE user_id = ?)"#,
+            )
+            .bind(user.user_id)
+            .bind(user.user_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+            if record_count + 1 > max {
+                tx.rollback().await.ok();
+                return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
             }
+        }
+    }
 
-            if exists_staged {
-                sqlx::query(
-                    r#"DELETE FROM staged_records WHERE user_id = ? AND type = ? AND record_id = ?"#,
-                )
-                .bind(user.user_id)
-                .bind(&r.r#type)
-                .bind(&r.record_id)
-                .execute(&mut *tx)
-                .await
-                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let mut record = version.record;
+    let server_seq = history::republish(
+        &mut tx,
+        user.user_id,
+        &mut record,
+        current_hlc.as_ref(),
+        state.record_history_limit,
+        now_ms,
+    )
+    .await
+    .map_err(|e| {
+        error!(error = %e, "republish record version failed");
+        json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error")
+    })?;
+
+    let total_b64 = recompute_and_store_user_b64(&mut tx, user.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if let Some(max) = quota.allowed_storage_b64 {
+        if total_b64 > max && total_b64 > user_billing.stored_b64 {
+            tx.rollback().await.ok();
+            return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
+        }
+    }
+
+    let (resp, bytes_len) = wire.encode(&record)?;
+    sqlx::query(r#"UPDATE users SET api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#)
+        .bind(bytes_len)
+        .bind(user.user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-                total_b64 = (total_b64 - existing_size).max(0);
-                record_count = (record_count - 1).max(0);
-            }
+    state.notifier.publish(user.user_id, server_seq);
+    state.metrics.record_active_user(now_ms, user.account_id);
+    Ok(resp)
+}
 
-            accepted.push(PushAccepted {
-                r#type: r.r#type,
-                record_id: r.record_id,
-                server_seq: 0,
-            });
-            continue;
+/// Stream the account as a JSONL archive (see `archive::ArchiveLine`).
+///
+/// Payloads stay encrypted; together with the key bundle the archive is
+/// enough to rebuild the account on another server.
+async fn export_account(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    let now_ms = now_ms_utc();
+
+    reset_user_api_outbound_if_new_month(&state.db, user.user_id, now_ms)
+        .await
+        .ok();
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let Some(user_billing) = load_user_billing(&mut tx, user.user_id, now_ms)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+    else {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::UNAUTHORIZED, "unauthorized"));
+    };
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if user_billing.banned_at_ms_utc.is_some_and(|ms| ms > 0) {
+        return Err(json_error(StatusCode::FORBIDDEN, "banned"));
+    }
+    let quota = compute_effective_quota(&state.billing, &user_billing, now_ms);
+    if let Some(max) = quota.allowed_outbound_bytes {
+        if user_billing.api_outbound_bytes >= max {
+            return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
         }
+    }
 
-        let new_size: i64 = (r.nonce.len() + r.ciphertext.len()) as i64;
+    state.metrics.record_active_user(now_ms, user.account_id);
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"easy_todo_export.jsonl\"",
+            ),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        archive::export_body(
+            state.db.clone(),
+            state.blob_store.clone(),
+            user.user_id,
+            now_ms,
+        ),
+    ))
+}
 
-        let new_total_b64 = total_b64 + (new_size - existing_size);
-        let new_record_count = record_count
-            + if !exists_committed && !exists_staged {
-                1
-            } else {
-                0
-            };
+#[derive(Debug, Serialize)]
+struct ImportAccountResponse {
+    ok: bool,
+    records: i64,
+    #[serde(rename = "attachmentRefs")]
+    attachment_refs: i64,
+    blobs: i64,
+    #[serde(rename = "keyBundle")]
+    key_bundle: bool,
+    #[serde(rename = "keySlots")]
+    key_slots: i64,
+    #[serde(rename = "serverSeq")]
+    server_seq: i64,
+}
 
-        if let Some(max) = state.max_records_per_user {
-            if new_record_count > max {
-                rejected.push(PushRejected {
-                    r#type: r.r#type,
-                    record_id: r.record_id,
-                    reason: "quota_exceeded".to_string(),
-                });
-                continue;
-            }
-        }
-        if let Some(max) = quota.allowed_storage_b64 {
-            // Allow deletes/size reductions even when the user is already over
-            // quota, so they can free space. Block only mutations that would
-            // increase stored bytes while remaining above the limit.
-            let increases_storage = new_total_b64 > total_b64;
-            if new_total_b64 > max && increases_storage {
-                rejected.push(PushRejected {
-                    r#type: r.r#type,
-                    record_id: r.record_id,
-                    reason: "quota_exceeded".to_string(),
-                });
-                continue;
-            }
-        }
+/// Replay an export archive into an empty account.
+///
+/// Records keep their HLCs and ciphertext but get fresh `serverSeq`s in
+/// archive order, so devices of the new account simply pull from 0. The
+/// whole archive is applied in one transaction or not at all.
+async fn import_account(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+    req: axum::extract::Request,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    user.require_owner()?;
+    let now_ms = now_ms_utc();
 
-        let should_stage = is_staged_type && !exists_committed;
-        if should_stage {
-            let now_ms = now_ms_utc();
-            sqlx::query(
-                r#"INSERT INTO staged_records (
-               user_id, type, record_id,
-               hlc_wall_ms_utc, hlc_counter, hlc_device_id,
-               deleted_at_ms_utc,
-               schema_version, dek_id,
-               algo, nonce, ciphertext,
-               updated_at_ms_utc
-             ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
-             ON CONFLICT(user_id, type, record_id) DO UPDATE SET
-               hlc_wall_ms_utc = excluded.hlc_wall_ms_utc,
-               hlc_counter = excluded.hlc_counter,
-               hlc_device_id = excluded.hlc_device_id,
-               deleted_at_ms_utc = excluded.deleted_at_ms_utc,
-               schema_version = excluded.schema_version,
-               dek_id = excluded.dek_id,
-               algo = excluded.algo,
-               nonce = excluded.nonce,
-               ciphertext = excluded.ciphertext,
-               updated_at_ms_utc = excluded.updated_at_ms_utc"#,
-            )
-            .bind(user.user_id)
-            .bind(&r.r#type)
-            .bind(&r.record_id)
-            .bind(r.hlc.wall_time_ms_utc)
-            .bind(r.hlc.counter)
-            .bind(&r.hlc.device_id)
-            .bind(r.deleted_at_ms_utc)
-            .bind(r.schema_version)
-            .bind(&r.dek_id)
-            .bind(&r.payload_algo)
-            .bind(&r.nonce)
-            .bind(&r.ciphertext)
-            .bind(now_ms)
-            .execute(&mut *tx)
-            .await
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let bytes = wire::decoded_body(req, &state, state.import_body_limit_bytes).await?;
+    let parsed =
+        archive::parse_archive(&bytes).map_err(|code| json_error(StatusCode::BAD_REQUEST, code))?;
+    drop(bytes);
 
-            accepted.push(PushAccepted {
-                r#type: r.r#type,
-                record_id: r.record_id,
-                server_seq: 0,
-            });
+    let mut seen = HashSet::with_capacity(parsed.records.len());
+    let mut seen_hashes = HashSet::new();
+    let mut import_b64: i64 = 0;
+    for r in &parsed.records {
+        if r.nonce.len() > MAX_RECORD_B64_LEN || r.ciphertext.len() > MAX_RECORD_B64_LEN {
+            return Err(json_error(StatusCode::BAD_REQUEST, "record_too_large"));
+        }
+        if !seen.insert((r.r#type.as_str(), r.record_id.as_str())) {
+            return Err(json_error(StatusCode::BAD_REQUEST, "invalid_archive"));
+        }
+        if r.content_hash.as_deref().is_some_and(|h| {
+            r.r#type != TYPE_TODO_ATTACHMENT_CHUNK || !chunk_dedup::is_valid_content_hash(h)
+        }) || !r.has_valid_parent()
+        {
+            return Err(json_error(StatusCode::BAD_REQUEST, "invalid_archive"));
+        }
+        // Exports repeat a deduplicated chunk's bytes on every reference.
+        let shared = r.deleted_at_ms_utc.is_none()
+            && r.content_hash
+                .as_deref()
+                .is_some_and(|h| !seen_hashes.insert(h));
+        import_b64 += if shared {
+            r.nonce.len() as i64
         } else {
-            let server_seq = alloc_server_seq(&mut tx, user.user_id).await.map_err(|e| {
-                error!(error = %e, "alloc_server_seq failed");
-                json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error")
-            })?;
+            (r.nonce.len() + r.ciphertext.len()) as i64
+        };
+    }
+    let mut seen_slots = HashSet::with_capacity(parsed.key_slots.len());
+    for (slot_id, kind, _, slot) in &parsed.key_slots {
+        if !key_slots::is_valid_slot_id(slot_id)
+            || !key_slots::is_valid_kind(kind)
+            || !slot.is_object()
+            || !seen_slots.insert(slot_id.as_str())
+        {
+            return Err(json_error(StatusCode::BAD_REQUEST, "invalid_archive"));
+        }
+    }
+    let mut seen_blobs = HashSet::with_capacity(parsed.blobs.len());
+    for (attachment_id, chunk_index, data) in &parsed.blobs {
+        if *chunk_index < 0 || !seen_blobs.insert((attachment_id.as_str(), *chunk_index)) {
+            return Err(json_error(StatusCode::BAD_REQUEST, "invalid_archive"));
+        }
+        if data.len() > state.blob_max_chunk_bytes {
+            return Err(json_error(StatusCode::BAD_REQUEST, "record_too_large"));
+        }
+        import_b64 += data.len() as i64;
+    }
 
-            let now_ms = now_ms_utc();
-            sqlx::query(
-                r#"INSERT INTO records (
-               user_id, type, record_id,
-               hlc_wall_ms_utc, hlc_counter, hlc_device_id,
-               deleted_at_ms_utc,
-               schema_version, dek_id,
-               algo, nonce, ciphertext,
-               server_seq, updated_at_ms_utc
-             ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
-             ON CONFLICT(user_id, type, record_id) DO UPDATE SET
-               hlc_wall_ms_utc = excluded.hlc_wall_ms_utc,
-               hlc_counter = excluded.hlc_counter,
-               hlc_device_id = excluded.hlc_device_id,
-               deleted_at_ms_utc = excluded.deleted_at_ms_utc,
-               schema_version = excluded.schema_version,
-               dek_id = excluded.dek_id,
-               algo = excluded.algo,
-               nonce = excluded.nonce,
-               ciphertext = excluded.ciphertext,
-               server_seq = excluded.server_seq,
-               updated_at_ms_utc = excluded.updated_at_ms_utc"#,
+    // Bytes bound for the blob store are uploaded before the write
+    // transaction opens (see `blob_store::upload_pending`); a failed import
+    // leaves them queued for the sweeper.
+    let mut record_uploads = Vec::with_capacity(parsed.records.len());
+    let mut blob_uploads = Vec::with_capacity(parsed.blobs.len());
+    let mut uploaded_hashes = HashSet::new();
+    for r in &parsed.records {
+        let shared = r.deleted_at_ms_utc.is_none()
+            && r.content_hash
+                .as_deref()
+                .is_some_and(|h| !uploaded_hashes.insert(h));
+        let upload = if shared {
+            None
+        } else {
+            blob_store::offload_ciphertext(
+                &state.db,
+                state.blob_store.as_deref(),
+                user.user_id,
+                &r.r#type,
+                &r.ciphertext,
             )
-            .bind(user.user_id)
-            .bind(&r.r#type)
-            .bind(&r.record_id)
-            .bind(r.hlc.wall_time_ms_utc)
-            .bind(r.hlc.counter)
-            .bind(&r.hlc.device_id)
-            .bind(r.deleted_at_ms_utc)
-            .bind(r.schema_version)
-            .bind(&r.dek_id)
-            .bind(&r.payload_algo)
-            .bind(&r.nonce)
-            .bind(&r.ciphertext)
-            .bind(server_seq)
-            .bind(now_ms)
-            .execute(&mut *tx)
             .await
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-
-            let record_type = r.r#type.clone();
-            let record_id = r.record_id.clone();
-            let is_attachment_delete =
-                r.r#type == TYPE_TODO_ATTACHMENT && r.deleted_at_ms_utc.is_some();
-            let deleted_at_ms_utc = r.deleted_at_ms_utc.unwrap_or(now_ms);
-
-            accepted.push(PushAccepted {
-                r#type: record_type,
-                record_id,
-                server_seq,
-            });
-
-            // Attachment deletion should compact all committed chunk payloads
-            // (so future pulls don't transfer old ciphertext) and clear any
-            // in-flight staged data without requiring per-chunk tombstones
-            // from the client.
-            if is_attachment_delete {
-                let attachment_id = r.record_id;
-
-                let _ = delete_staged_attachment(&mut tx, user.user_id, &attachment_id)
+            .map_err(|e| {
+                error!(error = %e, "blob store put failed");
+                json_error(StatusCode::INTERNAL_SERVER_ERROR, "blob store error")
+            })?
+        };
+        record_uploads.push(upload);
+    }
+    for (_, _, data) in &parsed.blobs {
+        let object_key = match state.blob_store.as_deref() {
+            Some(store) => Some(
+                blob_store::upload_pending(&state.db, store, user.user_id, data.clone())
                     .await
-                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+                    .map_err(|e| {
+                        error!(error = %e, "blob store put failed");
+                        json_error(StatusCode::INTERNAL_SERVER_ERROR, "blob store error")
+                    })?,
+            ),
+            None => None,
+        };
+        blob_uploads.push(object_key);
+    }
 
-                compact_committed_attachment_chunks(
-                    &mut tx,
-                    user.user_id,
-                    &attachment_id,
-                    deleted_at_ms_utc,
-                )
-                .await
-                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-                did_compact_records = true;
-            }
-        }
+    let Some(user_billing) = load_user_billing(&mut tx, user.user_id, now_ms)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+    else {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::UNAUTHORIZED, "unauthorized"));
+    };
+    if user_billing.banned_at_ms_utc.is_some_and(|ms| ms > 0) {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::FORBIDDEN, "banned"));
+    }
+    let quota = compute_effective_quota(&state.billing, &user_billing, now_ms);
 
-        total_b64 = new_total_b64;
-        record_count = new_record_count;
+    let not_empty: bool = sqlx::query_scalar(
+        r#"SELECT
+             EXISTS (SELECT 1 FROM records WHERE user_id = ?)
+          OR EXISTS (SELECT 1 FROM staged_records WHERE user_id = ?)
+          OR EXISTS (SELECT 1 FROM key_bundles WHERE user_id = ?)
+          OR EXISTS (SELECT 1 FROM key_slots WHERE user_id = ?)
+          OR EXISTS (SELECT 1 FROM attachment_refs WHERE user_id = ?)
+          OR EXISTS (SELECT 1 FROM attachment_blobs WHERE user_id = ?)"#,
+    )
+    .bind(user.user_id)
+    .bind(user.user_id)
+    .bind(user.user_id)
+    .bind(user.user_id)
+    .bind(user.user_id)
+    .bind(user.user_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if not_empty {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::CONFLICT, "account_not_empty"));
     }
 
-    for (attachment_id, deleted_at_ms_utc) in commit_requests {
-        if deleted_at_ms_utc.is_some() {
-            // Tombstoning the commit marker is used by clients to cancel a
-            // pending commit while deleting an in-progress attachment upload.
-            accepted.push(PushAccepted {
-                r#type: TYPE_TODO_ATTACHMENT_COMMIT.to_string(),
-                record_id: attachment_id,
-                server_seq: 0,
-            });
-            continue;
+    // History of an empty account can only be left over from purged records.
+    let history_b64: i64 = sqlx::query_scalar(
+        r#"SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext)), 0)
+           FROM record_history WHERE user_id = ?"#,
+    )
+    .bind(user.user_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if let Some(max) = state.max_records_per_user {
+        if parsed.records.len() as i64 > max {
+            tx.rollback().await.ok();
+            return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
+        }
+    }
+    if let Some(max) = quota.allowed_storage_b64 {
+        if history_b64 + import_b64 > max {
+            tx.rollback().await.ok();
+            return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
         }
+    }
 
-        let committed_deleted: Option<i64> = sqlx::query_scalar(
-            r#"SELECT deleted_at_ms_utc
-               FROM records
-               WHERE user_id = ? AND type = ? AND record_id = ?"#,
+    let has_key_bundle = parsed.key_bundle.is_some();
+    if let Some((bundle_version, mut bundle)) = parsed.key_bundle {
+        let bundle_version = bundle_version.max(1);
+        bundle["bundleVersion"] = serde_json::Value::from(bundle_version);
+        let bundle_json = serde_json::to_string(&bundle)
+            .map_err(|_| json_error(StatusCode::BAD_REQUEST, "invalid bundle"))?;
+        sqlx::query(
+            r#"INSERT INTO key_bundles (user_id, bundle_version, bundle_json, updated_at_ms_utc)
+               VALUES (?, ?, ?, ?)"#,
         )
         .bind(user.user_id)
-        .bind(TYPE_TODO_ATTACHMENT)
-        .bind(&attachment_id)
-        .fetch_optional(&mut *tx)
+        .bind(bundle_version)
+        .bind(bundle_json)
+        .bind(now_ms)
+        .execute(&mut *tx)
         .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    }
 
-        if committed_deleted.is_some_and(|ms| ms > 0) {
-            rejected.push(PushRejected {
-                r#type: TYPE_TODO_ATTACHMENT_COMMIT.to_string(),
-                record_id: attachment_id,
-                reason: "attachment_deleted".to_string(),
-            });
-            continue;
-        }
-
-        let has_committed_meta: Option<i64> = sqlx::query_scalar(
-            r#"SELECT 1
-               FROM records
-               WHERE user_id = ? AND type = ? AND record_id = ? AND deleted_at_ms_utc IS NULL
-               LIMIT 1"#,
+    for (slot_id, kind, slot_version, slot) in &parsed.key_slots {
+        sqlx::query(
+            r#"INSERT INTO key_slots (
+                 user_id, slot_id, kind, slot_version, slot_json,
+                 created_at_ms_utc, updated_at_ms_utc
+               ) VALUES (?, ?, ?, ?, ?, ?, ?)"#,
         )
         .bind(user.user_id)
-        .bind(TYPE_TODO_ATTACHMENT)
-        .bind(&attachment_id)
-        .fetch_optional(&mut *tx)
+        .bind(slot_id)
+        .bind(kind)
+        .bind((*slot_version).max(1))
+        .bind(slot.to_string())
+        .bind(now_ms)
+        .bind(now_ms)
+        .execute(&mut *tx)
         .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    }
 
-        let has_staged_meta: Option<i64> = sqlx::query_scalar(
-            r#"SELECT 1 FROM staged_records WHERE user_id = ? AND type = ? AND record_id = ? LIMIT 1"#,
+    let mut server_seq = current_server_seq(&mut *tx, user.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    for (r, upload) in parsed.records.iter().zip(record_uploads) {
+        server_seq += 1;
+        let mut ciphertext_bytes = r.chunk_ciphertext_bytes();
+        let mut content_hash = r
+            .content_hash
+            .as_deref()
+            .filter(|_| r.deleted_at_ms_utc.is_none());
+        if let Some(hash) = content_hash {
+            let stored = chunk_dedup::lookup(&mut *tx, user.user_id, hash)
+                .await
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+            match stored {
+                Some(bytes) => ciphertext_bytes = Some(bytes),
+                None if r.ciphertext.is_empty() => content_hash = None,
+                None => chunk_dedup::insert(
+                    &mut tx,
+                    user.user_id,
+                    &chunk_dedup::NewChunk {
+                        content_hash: hash,
+                        nonce: &r.nonce,
+                        dek_id: &r.dek_id,
+                        ciphertext: &r.ciphertext,
+                        ciphertext_bytes: ciphertext_bytes.unwrap_or(0),
+                    },
+                    upload.as_ref(),
+                    now_ms,
+                )
+                .await
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+            }
+        }
+        let offloaded = match content_hash {
+            Some(_) => None,
+            None => {
+                if let Some((key, _)) = &upload {
+                    blob_store::claim(&mut tx, key)
+                        .await
+                        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+                }
+                upload
+            }
+        };
+        let (ciphertext, ciphertext_ref, ciphertext_len) = match &offloaded {
+            Some((key, len)) => ("", Some(key.as_str()), Some(*len)),
+            None if content_hash.is_some() => ("", None, None),
+            None => (r.ciphertext.as_str(), None, None),
+        };
+        let (chunk_count, total_ciphertext_len) = r.stored_manifest();
+        sqlx::query(
+            r#"INSERT INTO records (
+                 user_id, type, record_id,
+                 hlc_wall_ms_utc, hlc_counter, hlc_device_id,
+                 deleted_at_ms_utc,
+                 schema_version, dek_id,
+                 algo, nonce, ciphertext, ciphertext_ref, ciphertext_len,
+                 chunk_count, total_ciphertext_len, ciphertext_bytes, content_hash,
+                 server_seq, updated_at_ms_utc, created_server_seq
+               ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
         )
         .bind(user.user_id)
-        .bind(TYPE_TODO_ATTACHMENT)
-        .bind(&attachment_id)
-        .fetch_optional(&mut *tx)
+        .bind(&r.r#type)
+        .bind(&r.record_id)
+        .bind(r.hlc.wall_time_ms_utc)
+        .bind(r.hlc.counter)
+        .bind(&r.hlc.device_id)
+        .bind(r.deleted_at_ms_utc)
+        .bind(r.schema_version)
+        .bind(&r.dek_id)
+        .bind(&r.payload_algo)
+        .bind(&r.nonce)
+        .bind(ciphertext)
+        .bind(ciphertext_ref)
+        .bind(ciphertext_len)
+        .bind(chunk_count)
+        .bind(total_ciphertext_len)
+        .bind(ciphertext_bytes)
+        .bind(content_hash)
+        .bind(server_seq)
+        .bind(now_ms)
+        .bind(server_seq)
+        .execute(&mut *tx)
         .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-
-        if has_committed_meta.is_none() && has_staged_meta.is_none() {
-            rejected.push(PushRejected {
-                r#type: TYPE_TODO_ATTACHMENT_COMMIT.to_string(),
-                record_id: attachment_id,
-                reason: "missing_attachment_meta".to_string(),
-            });
-            continue;
+        if r.deleted_at_ms_utc.is_none() {
+            upsert_record_parent(&mut tx, user.user_id, r, now_ms)
+                .await
+                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
         }
-
-        commit_staged_attachment(&mut tx, user.user_id, &attachment_id)
-            .await
-            .map_err(|e| {
-                error!(error = %e, "commit_staged_attachment failed");
-                json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error")
-            })?;
-
-        accepted.push(PushAccepted {
-            r#type: TYPE_TODO_ATTACHMENT_COMMIT.to_string(),
-            record_id: attachment_id,
-            server_seq: 0,
-        });
     }
+    sqlx::query(
+        r#"INSERT INTO server_seq (user_id, next_seq)
+           VALUES (?, ?)
+           ON CONFLICT(user_id) DO UPDATE SET next_seq = excluded.next_seq"#,
+    )
+    .bind(user.user_id)
+    .bind(server_seq)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-    let body = PushResponse { accepted, rejected };
-    let (resp, bytes_len) = json_bytes(&body)?;
+    for (attachment_id, todo_id) in &parsed.attachment_refs {
+        sqlx::query(
+            r#"INSERT INTO attachment_refs (user_id, attachment_id, todo_id, updated_at_ms_utc)
+               VALUES (?, ?, ?, ?)
+               ON CONFLICT(user_id, attachment_id) DO UPDATE SET
+                 todo_id = excluded.todo_id,
+                 updated_at_ms_utc = excluded.updated_at_ms_utc"#,
+        )
+        .bind(user.user_id)
+        .bind(attachment_id)
+        .bind(todo_id)
+        .bind(now_ms)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    }
 
-    if did_compact_records {
-        total_b64 = recompute_and_store_user_b64(&mut tx, user.user_id)
-            .await
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    for ((attachment_id, chunk_index, data), object_key) in parsed.blobs.iter().zip(blob_uploads) {
+        let key = blobs::BlobKey {
+            user_id: user.user_id,
+            attachment_id,
+            chunk_index: *chunk_index,
+        };
+        let total_bytes = data.len() as i64;
+        let written = match &object_key {
+            Some(object_key) => {
+                blobs::insert_stored_blob(&mut tx, key, total_bytes, object_key, now_ms).await
+            }
+            None => blobs::write_blob(&mut tx, key, 0, total_bytes, data, now_ms)
+                .await
+                .map(|_| ()),
+        };
+        written.map_err(|e| {
+            error!(error = %e, "import blob write failed");
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error")
+        })?;
     }
 
-    sqlx::query(r#"UPDATE users SET stored_b64 = ?, api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#)
-        .bind(total_b64)
+    recompute_and_store_user_b64(&mut tx, user.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    let body = ImportAccountResponse {
+        ok: true,
+        records: parsed.records.len() as i64,
+        attachment_refs: parsed.attachment_refs.len() as i64,
+        blobs: parsed.blobs.len() as i64,
+        key_bundle: has_key_bundle,
+        key_slots: parsed.key_slots.len() as i64,
+        server_seq,
+    };
+    let (resp, bytes_len) = json_bytes(&body)?;
+    sqlx::query(r#"UPDATE users SET api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#)
         .bind(bytes_len)
         .bind(user.user_id)
         .execute(&mut *tx)
@@ -1897,285 +5268,381 @@ async fn push_sync(
         .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-    state.metrics.record_active_user(now_ms, user.user_id);
+    state.notifier.publish(user.user_id, server_seq);
+    state.metrics.record_active_user(now_ms, user.account_id);
     Ok(resp)
 }
 
-#[derive(Debug, Deserialize)]
-struct PullQuery {
-    since: Option<i64>,
-    limit: Option<i64>,
-    #[serde(rename = "excludeDeviceId")]
-    exclude_device_id: Option<String>,
+#[derive(Debug, Serialize)]
+struct BlobUploadResponse {
+    #[serde(rename = "receivedBytes")]
+    received_bytes: i64,
+    #[serde(rename = "totalBytes")]
+    total_bytes: i64,
+    complete: bool,
 }
 
-#[derive(Debug, Serialize)]
-struct PullResponse {
-    records: Vec<SyncRecordEnvelope>,
-    #[serde(rename = "nextSince")]
-    next_since: i64,
+fn upload_progress_headers(status: &blobs::BlobStatus) -> [(&'static str, String); 2] {
+    [
+        (
+            blobs::UPLOAD_OFFSET_HEADER,
+            status.received_bytes.to_string(),
+        ),
+        (blobs::UPLOAD_LENGTH_HEADER, status.total_bytes.to_string()),
+    ]
 }
 
-async fn pull_sync(
+/// Upload the raw ciphertext of attachment chunk `<attachmentId>:<chunk>`.
+///
+/// The whole chunk can be sent at once, or in parts with
+/// `Content-Range: bytes <start>-<end>/<total>`; each part must start where
+/// the previous one ended (`Upload-Offset`), or at 0 to start over. The
+/// chunk's envelope is still pushed (with an empty `ciphertext`) and follows
+/// the usual staging/commit flow.
+async fn put_blob(
     State(state): State<AppState>,
     user: auth::AuthedUser,
-    Query(q): Query<PullQuery>,
-) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
-    let since = q.since.unwrap_or(0).max(0);
-    let limit = q.limit.unwrap_or(200).clamp(1, MAX_PULL_LIMIT) as i64;
-    let exclude_device_id = q
-        .exclude_device_id
-        .as_deref()
-        .map(|s| s.trim())
-        .filter(|s| !s.is_empty())
-        .map(|s| s.to_string());
-
+    Path((attachment_id, chunk_index)): Path<(String, i64)>,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<Response, (StatusCode, Json<ErrorBody>)> {
     let now_ms = now_ms_utc();
+    if attachment_id.trim().is_empty() || chunk_index < 0 {
+        return Err(json_error(StatusCode::BAD_REQUEST, "invalid blob id"));
+    }
 
-    reset_user_api_outbound_if_new_month(&state.db, user.user_id, now_ms)
+    let len = body.len() as i64;
+    let (start, total_bytes) = match headers.get(header::CONTENT_RANGE) {
+        None => (0, len),
+        Some(value) => {
+            let (start, end, total) = value
+                .to_str()
+                .ok()
+                .and_then(blobs::parse_content_range)
+                .ok_or_else(|| json_error(StatusCode::BAD_REQUEST, "invalid content range"))?;
+            if end - start + 1 != len {
+                return Err(json_error(
+                    StatusCode::BAD_REQUEST,
+                    "content range does not match body",
+                ));
+            }
+            (start, total)
+        }
+    };
+    if total_bytes > state.blob_max_chunk_bytes as i64 {
+        return Err(json_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload too large",
+        ));
+    }
+
+    let mut tx = state
+        .db
+        .begin()
         .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-    let billing_row = sqlx::query(
-        r#"SELECT
-             base_storage_b64,
-             base_outbound_bytes,
-             subscription_plan_id,
-             subscription_expires_at_ms_utc,
-             banned_at_ms_utc,
-             stored_b64,
-             api_outbound_bytes
-           FROM users
-           WHERE id = ?"#,
+    let Some(user_billing) = load_user_billing(&mut tx, user.user_id, now_ms)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+    else {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::UNAUTHORIZED, "unauthorized"));
+    };
+    if user_billing.banned_at_ms_utc.is_some_and(|ms| ms > 0) {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::FORBIDDEN, "banned"));
+    }
+    let quota = compute_effective_quota(&state.billing, &user_billing, now_ms);
+
+    // Same rule as chunk pushes: a deleted attachment can't get new data.
+    let deleted: Option<i64> = sqlx::query_scalar(
+        r#"SELECT deleted_at_ms_utc
+           FROM records
+           WHERE user_id = ? AND type = ? AND record_id = ?"#,
     )
     .bind(user.user_id)
-    .fetch_optional(&state.db)
+    .bind(TYPE_TODO_ATTACHMENT)
+    .bind(&attachment_id)
+    .fetch_optional(&mut *tx)
     .await
-    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-    let Some(billing_row) = billing_row else {
-        return Err(json_error(StatusCode::UNAUTHORIZED, "unauthorized"));
+    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+    .flatten();
+    if deleted.is_some_and(|ms| ms > 0) {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::CONFLICT, "attachment_deleted"));
+    }
+
+    let key = blobs::BlobKey {
+        user_id: user.user_id,
+        attachment_id: &attachment_id,
+        chunk_index,
     };
+    // Devices may already hold a committed chunk's bytes; replacing them needs
+    // a new chunk version staged by a push first.
+    let committed = blobs::chunk_is_committed(&mut *tx, key)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if committed {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::CONFLICT, "chunk_committed"));
+    }
+    if start != 0 {
+        let existing = blobs::blob_status(&mut *tx, key)
+            .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        let resumes =
+            existing.is_some_and(|s| s.total_bytes == total_bytes && s.received_bytes == start);
+        if !resumes {
+            tx.rollback().await.ok();
+            let error = Json(ErrorBody {
+                error: "offset_mismatch".to_string(),
+            });
+            return Ok(match existing {
+                Some(status) => (
+                    StatusCode::CONFLICT,
+                    upload_progress_headers(&status),
+                    error,
+                )
+                    .into_response(),
+                None => (StatusCode::CONFLICT, error).into_response(),
+            });
+        }
+    }
 
-    let mut subscription_plan_id: Option<String> = billing_row
-        .try_get("subscription_plan_id")
+    let status = blobs::write_blob(&mut tx, key, start, total_bytes, &body, now_ms)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "blob write failed");
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error")
+        })?;
+
+    let total_b64 = recompute_and_store_user_b64(&mut tx, user.user_id)
+        .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-    let mut subscription_expires_at_ms_utc: Option<i64> = billing_row
-        .try_get("subscription_expires_at_ms_utc")
+    if let Some(max) = quota.allowed_storage_b64 {
+        if total_b64 > max && total_b64 > user_billing.stored_b64 {
+            tx.rollback().await.ok();
+            return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
+        }
+    }
+
+    let (resp, bytes_len) = json_bytes(&BlobUploadResponse {
+        received_bytes: status.received_bytes,
+        total_bytes: status.total_bytes,
+        complete: status.is_complete(),
+    })?;
+    sqlx::query(r#"UPDATE users SET api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#)
+        .bind(bytes_len)
+        .bind(user.user_id)
+        .execute(&mut *tx)
+        .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-    let has_plan = subscription_plan_id
-        .as_deref()
-        .map(|s| !s.trim().is_empty())
-        .unwrap_or(false);
-    let expires_at = subscription_expires_at_ms_utc.unwrap_or(0);
-    if has_plan && expires_at <= now_ms {
-        clear_subscription_if_expired(
-            &state.db,
-            user.user_id,
-            &subscription_plan_id,
-            subscription_expires_at_ms_utc,
-            now_ms,
-        )
+    tx.commit()
         .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-        subscription_plan_id = None;
-        subscription_expires_at_ms_utc = None;
+
+    // The store upload runs after the commit so it never holds the write
+    // lock; until it succeeds the blob is served from SQLite.
+    if let Some(store) = state.blob_store.as_deref().filter(|_| status.is_complete()) {
+        if let Err(e) = blobs::offload_blob(&state.db, store, key).await {
+            error!(error = %e, "blob offload failed");
+        }
     }
 
-    let user_billing = UserBillingRow {
-        base_storage_b64: billing_row
-            .try_get("base_storage_b64")
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-        base_outbound_bytes: billing_row
-            .try_get("base_outbound_bytes")
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-        subscription_plan_id,
-        subscription_expires_at_ms_utc,
-        banned_at_ms_utc: billing_row
-            .try_get("banned_at_ms_utc")
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-        stored_b64: billing_row
-            .try_get("stored_b64")
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-        api_outbound_bytes: billing_row
-            .try_get("api_outbound_bytes")
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
+    state.metrics.record_active_user(now_ms, user.account_id);
+    let code = if status.is_complete() {
+        StatusCode::OK
+    } else {
+        StatusCode::ACCEPTED
     };
+    Ok((code, upload_progress_headers(&status), resp).into_response())
+}
 
+/// Download a chunk blob, honouring a single `Range`. `HEAD` reports the
+/// size (or, for an unfinished upload, `Upload-Offset`) without a body.
+async fn get_blob(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+    Path((attachment_id, chunk_index)): Path<(String, i64)>,
+    method: Method,
+    headers: axum::http::HeaderMap,
+) -> Result<Response, (StatusCode, Json<ErrorBody>)> {
+    let now_ms = now_ms_utc();
+
+    reset_user_api_outbound_if_new_month(&state.db, user.user_id, now_ms)
+        .await
+        .ok();
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let Some(user_billing) = load_user_billing(&mut tx, user.user_id, now_ms)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+    else {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::UNAUTHORIZED, "unauthorized"));
+    };
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
     if user_billing.banned_at_ms_utc.is_some_and(|ms| ms > 0) {
         return Err(json_error(StatusCode::FORBIDDEN, "banned"));
     }
-
     let quota = compute_effective_quota(&state.billing, &user_billing, now_ms);
-    if let Some(max) = quota.allowed_storage_b64 {
-        if user_billing.stored_b64 > max {
-            return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
-        }
-    }
     if let Some(max) = quota.allowed_outbound_bytes {
-        if user_billing.api_outbound_bytes > max {
+        if user_billing.api_outbound_bytes >= max {
             return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
         }
     }
 
-    let rows = sqlx::query(
-        r#"SELECT
-         type,
-         record_id,
-         hlc_wall_ms_utc,
-         hlc_counter,
-         hlc_device_id,
-         deleted_at_ms_utc,
-         schema_version,
-         dek_id,
-         algo,
-         nonce,
-         ciphertext,
-         server_seq
-       FROM records
-       WHERE user_id = ? AND server_seq > ? AND (? IS NULL OR hlc_device_id != ?)
-       ORDER BY server_seq ASC
-       LIMIT ?"#,
-    )
-    .bind(user.user_id)
-    .bind(since)
-    .bind(&exclude_device_id)
-    .bind(&exclude_device_id)
-    .bind(limit)
-    .fetch_all(&state.db)
-    .await
-    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-
-    // `nextSince` should help clients detect server rollbacks / DB resets.
-    //
-    // If we return `nextSince = since` on an empty page, a client with a cursor
-    // ahead of the current server head (e.g. server DB reset) will never detect
-    // the rollback and will appear "stuck" at a higher `lastServerSeq`.
-    if rows.is_empty() {
-        let head: i64 = sqlx::query_scalar(
-            r#"SELECT COALESCE(MAX(server_seq), 0) FROM records WHERE user_id = ?"#,
-        )
-        .bind(user.user_id)
-        .fetch_one(&state.db)
+    let key = blobs::BlobKey {
+        user_id: user.user_id,
+        attachment_id: &attachment_id,
+        chunk_index,
+    };
+    let Some(status) = blobs::blob_status(&state.db, key)
         .await
-        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+    else {
+        return Err(json_error(StatusCode::NOT_FOUND, "blob not found"));
+    };
+    if !status.is_complete() {
+        let error = Json(ErrorBody {
+            error: "blob_incomplete".to_string(),
+        });
+        return Ok((
+            StatusCode::CONFLICT,
+            upload_progress_headers(&status),
+            error,
+        )
+            .into_response());
+    }
 
-        let body = PullResponse {
-            records: Vec::new(),
-            next_since: head,
-        };
-        let (resp, bytes_len) = json_bytes(&body)?;
-        if let Some(limit) = quota.allowed_outbound_bytes {
-            let updated = sqlx::query(
-                r#"UPDATE users
-                   SET api_outbound_bytes = api_outbound_bytes + ?
-                   WHERE id = ? AND api_outbound_bytes + ? <= ?"#,
+    let len = status.total_bytes;
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| blobs::parse_range(v, len))
+        .unwrap_or(blobs::RangeRequest::Full);
+    let (code, start, end) = match range {
+        blobs::RangeRequest::Full => (StatusCode::OK, 0, len - 1),
+        blobs::RangeRequest::Partial(start, end) => (StatusCode::PARTIAL_CONTENT, start, end),
+        blobs::RangeRequest::Unsatisfiable => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{len}"))],
             )
-            .bind(bytes_len)
-            .bind(user.user_id)
-            .bind(bytes_len)
-            .bind(limit)
-            .execute(&state.db)
+                .into_response());
+        }
+    };
+    let count = end - start + 1;
+
+    let mut resp = if method == Method::HEAD {
+        let mut resp = code.into_response();
+        resp.headers_mut()
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(count));
+        resp
+    } else {
+        let data = blobs::read_blob(&state.db, state.blob_store.as_deref(), key, start, count)
             .await
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-            if updated.rows_affected() == 0 {
-                return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
-            }
-        } else {
-            sqlx::query(
-                r#"UPDATE users SET api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#,
-            )
-            .bind(bytes_len)
+            .map_err(|e| {
+                error!(error = %e, "blob read failed");
+                json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error")
+            })?
+            .ok_or_else(|| json_error(StatusCode::NOT_FOUND, "blob not found"))?;
+        sqlx::query(r#"UPDATE users SET api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#)
+            .bind(data.len() as i64)
             .bind(user.user_id)
             .execute(&state.db)
             .await
             .ok();
+        (code, data).into_response()
+    };
+    let resp_headers = resp.headers_mut();
+    resp_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    resp_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    resp_headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    if code == StatusCode::PARTIAL_CONTENT {
+        if let Ok(value) = HeaderValue::from_str(&format!("bytes {start}-{end}/{len}")) {
+            resp_headers.insert(header::CONTENT_RANGE, value);
         }
-        state.metrics.record_active_user(now_ms, user.user_id);
-        return Ok(resp);
     }
 
-    let mut next_since = since;
-    let mut records = Vec::with_capacity(rows.len());
-
-    for row in rows {
-        let server_seq: i64 = row
-            .try_get("server_seq")
-            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-        next_since = next_since.max(server_seq);
-
-        records.push(SyncRecordEnvelope {
-            r#type: row
-                .try_get("type")
-                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-            record_id: row
-                .try_get("record_id")
-                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-            hlc: Hlc {
-                wall_time_ms_utc: row
-                    .try_get("hlc_wall_ms_utc")
-                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-                counter: row
-                    .try_get("hlc_counter")
-                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-                device_id: row
-                    .try_get("hlc_device_id")
-                    .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-            },
-            deleted_at_ms_utc: row
-                .try_get("deleted_at_ms_utc")
-                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-            schema_version: row
-                .try_get("schema_version")
-                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-            dek_id: row
-                .try_get("dek_id")
-                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-            payload_algo: row
-                .try_get("algo")
-                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-            nonce: row
-                .try_get("nonce")
-                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-            ciphertext: row
-                .try_get("ciphertext")
-                .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?,
-        });
-    }
+    state.metrics.record_active_user(now_ms, user.account_id);
+    Ok(resp)
+}
 
-    let body = PullResponse {
-        records,
-        next_since,
-    };
-    let (resp, bytes_len) = json_bytes(&body)?;
+/// Server-Sent Events feed of the user's `serverSeq` head.
+///
+/// Emits the current head right away, then one `seq` event per committed
+/// push. Clients pull with their own cursor whenever the head moves.
+async fn stream_sync(
+    State(state): State<AppState>,
+    user: auth::AuthedUser,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    use axum::response::sse::{Event, KeepAlive, Sse};
+    use tokio_stream::wrappers::WatchStream;
+    use tokio_stream::StreamExt as _;
 
-    if let Some(limit) = quota.allowed_outbound_bytes {
-        let updated = sqlx::query(
-            r#"UPDATE users
-               SET api_outbound_bytes = api_outbound_bytes + ?
-               WHERE id = ? AND api_outbound_bytes + ? <= ?"#,
-        )
-        .bind(bytes_len)
+    // A space is banned with its owner; the member's own account counts too.
+    let billing_row = sqlx::query(BILLING_ROW_SQL)
         .bind(user.user_id)
-        .bind(bytes_len)
-        .bind(limit)
-        .execute(&state.db)
+        .fetch_optional(&state.db)
         .await
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
-        if updated.rows_affected() == 0 {
-            return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
-        }
-    } else {
-        sqlx::query(r#"UPDATE users SET api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#)
-            .bind(bytes_len)
-            .bind(user.user_id)
-            .execute(&state.db)
+    let owner_banned_at_ms_utc: Option<i64> = billing_row
+        .map(|row| row.try_get("banned_at_ms_utc"))
+        .transpose()
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+        .flatten();
+    let account_banned_at_ms_utc: Option<i64> =
+        sqlx::query_scalar(r#"SELECT banned_at_ms_utc FROM users WHERE id = ?"#)
+            .bind(user.account_id)
+            .fetch_optional(&state.db)
             .await
-            .ok();
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+            .flatten();
+    if [owner_banned_at_ms_utc, account_banned_at_ms_utc]
+        .into_iter()
+        .flatten()
+        .any(|ms| ms > 0)
+    {
+        return Err(json_error(StatusCode::FORBIDDEN, "banned"));
     }
 
-    state.metrics.record_active_user(now_ms, user.user_id);
-    Ok(resp)
+    // Subscribe before reading the head so a commit in between is not lost.
+    let rx = state.notifier.subscribe(user.user_id);
+    let head = current_server_seq(&state.db, user.user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    state
+        .metrics
+        .record_active_user(now_ms_utc(), user.account_id);
+
+    let mut last_sent = -1_i64;
+    let events = tokio_stream::once(head)
+        .chain(WatchStream::from_changes(rx))
+        .filter_map(move |server_seq| {
+            if server_seq <= last_sent {
+                return None;
+            }
+            last_sent = server_seq;
+            Some(
+                Event::default()
+                    .event("seq")
+                    .json_data(StreamSeqEvent { server_seq }),
+            )
+        });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
 }
 
 async fn health() -> impl IntoResponse {
@@ -2229,12 +5696,42 @@ async fn main() -> anyhow::Result<()> {
         .ok()
         .and_then(|s| s.parse().ok());
 
+    let push_idempotency_ttl_ms: i64 =
+        env_i64("PUSH_IDEMPOTENCY_TTL_MS").unwrap_or(24 * 60 * 60 * 1000);
+
+    let record_history_limit: i64 = env_i64("RECORD_HISTORY_LIMIT").unwrap_or(10).max(0);
+    let key_bundle_history_limit: i64 = env_i64("KEY_BUNDLE_HISTORY_LIMIT").unwrap_or(10).max(0);
+    let pairing_ttl_secs: i64 = env_i64("PAIRING_TTL_SECS").unwrap_or(5 * 60).max(1);
+    let share_max_ttl_secs: i64 = env_i64("SHARE_MAX_TTL_SECS")
+        .unwrap_or(30 * 24 * 60 * 60)
+        .max(60);
+    let staged_record_ttl_ms: i64 = env_i64("STAGED_RECORD_TTL_MS").unwrap_or(24 * 60 * 60 * 1000);
+    let staged_gc_interval_secs: i64 = env_i64("STAGED_GC_INTERVAL_SECS").unwrap_or(60 * 60);
+
     let body_limit_bytes: usize = std::env::var("BODY_LIMIT_BYTES")
         .ok()
         .and_then(|s| s.parse().ok())
         .unwrap_or(DEFAULT_BODY_LIMIT_BYTES);
 
+    let import_body_limit_bytes: usize = std::env::var("IMPORT_BODY_LIMIT_BYTES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_IMPORT_BODY_LIMIT_BYTES);
+
+    let blob_max_chunk_bytes: usize = std::env::var("BLOB_MAX_CHUNK_BYTES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_BLOB_MAX_CHUNK_BYTES);
+
+    let share_max_b64: usize = std::env::var("SHARE_MAX_B64")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_SHARE_MAX_B64);
+
+    let blob_store = blob_store::from_env().context("load blob store config")?;
+
     let billing = Arc::new(BillingConfig::load_from_env().context("load billing config")?);
+    let sync_policy = Arc::new(SyncPolicy::load_from_env().context("load sync policy")?);
     let admin = AdminConfig::load_from_env();
 
     let site_created_at_ms_utc: Option<i64> = std::env::var("SITE_CREATED_AT_MS_UTC")
@@ -2281,14 +5778,26 @@ async fn main() -> anyhow::Result<()> {
         max_push_records,
         max_records_per_user,
         billing,
+        sync_policy,
         admin,
         metrics,
+        notifier: Arc::new(notify::SyncNotifier::new()),
+        body_limit_bytes,
+        import_body_limit_bytes,
+        blob_max_chunk_bytes,
+        blob_store: blob_store.clone(),
+        push_idempotency_ttl_ms,
+        record_history_limit,
+        key_bundle_history_limit,
+        pairing_ttl_ms: pairing_ttl_secs * 1000,
+        share_max_b64,
+        share_max_ttl_ms: share_max_ttl_secs * 1000,
+        staged_record_ttl_ms: (staged_record_ttl_ms > 0 && staged_gc_interval_secs > 0)
+            .then_some(staged_record_ttl_ms),
         started_at: Instant::now(),
         site_created_at_ms_utc,
     };
 
-    let staged_record_ttl_ms: i64 = env_i64("STAGED_RECORD_TTL_MS").unwrap_or(24 * 60 * 60 * 1000);
-    let staged_gc_interval_secs: i64 = env_i64("STAGED_GC_INTERVAL_SECS").unwrap_or(60 * 60);
     if staged_record_ttl_ms > 0 && staged_gc_interval_secs > 0 {
         let db = state.db.clone();
         tokio::spawn(async move {
@@ -2310,19 +5819,81 @@ async fn main() -> anyhow::Result<()> {
         });
     }
 
+    let blob_trash_sweep_interval_secs: i64 =
+        env_i64("BLOB_TRASH_SWEEP_INTERVAL_SECS").unwrap_or(60);
+    if let Some(store) = blob_store.filter(|_| blob_trash_sweep_interval_secs > 0) {
+        let db = state.db.clone();
+        tokio::spawn(async move {
+            let mut ticker =
+                tokio::time::interval(Duration::from_secs(blob_trash_sweep_interval_secs as u64));
+            loop {
+                ticker.tick().await;
+                match blob_store::sweep_trash(&db, store.as_ref(), now_ms_utc()).await {
+                    Ok(deleted) => {
+                        if deleted > 0 {
+                            info!(deleted, "blob store trash sweep");
+                        }
+                    }
+                    Err(e) => {
+                        error!(error = %e, "blob store trash sweep failed");
+                    }
+                }
+            }
+        });
+    }
+
+    let tombstone_retention_ms: i64 = env_i64("TOMBSTONE_RETENTION_MS").unwrap_or(0);
+    let tombstone_gc_interval_secs: i64 = env_i64("TOMBSTONE_GC_INTERVAL_SECS").unwrap_or(60 * 60);
+    let tombstone_gc_max_users_per_run: i64 =
+        env_i64("TOMBSTONE_GC_MAX_USERS_PER_RUN").unwrap_or(500);
+    if tombstone_retention_ms > 0 && tombstone_gc_interval_secs > 0 {
+        let db = state.db.clone();
+        tokio::spawn(async move {
+            let mut ticker =
+                tokio::time::interval(Duration::from_secs(tombstone_gc_interval_secs as u64));
+            loop {
+                ticker.tick().await;
+                match tombstone_gc::purge_tombstones(
+                    &db,
+                    tombstone_retention_ms,
+                    tombstone_gc_max_users_per_run.max(1),
+                    now_ms_utc(),
+                )
+                .await
+                {
+                    Ok(stats) => {
+                        if stats.purged_records > 0 {
+                            info!(
+                                users = stats.users,
+                                purged_records = stats.purged_records,
+                                "tombstone GC"
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        error!(error = %e, "tombstone GC failed");
+                    }
+                }
+            }
+        });
+    }
+
     let ghost_gc_interval_secs: i64 = env_i64("GHOST_GC_INTERVAL_SECS").unwrap_or(0);
     let ghost_gc_min_ref_age_ms: i64 = env_i64("GHOST_GC_MIN_REF_AGE_MS").unwrap_or(30 * 60 * 1000);
     let ghost_gc_max_users_per_run: i64 = env_i64("GHOST_GC_MAX_USERS_PER_RUN").unwrap_or(200);
+    let ghost_gc_min_parent_age_ms: i64 =
+        env_i64("GHOST_GC_MIN_PARENT_AGE_MS").unwrap_or(30 * 60 * 1000);
 
     if ghost_gc_interval_secs > 0 {
         let db = state.db.clone();
+        let notifier = state.notifier.clone();
         tokio::spawn(async move {
             let mut ticker =
                 tokio::time::interval(Duration::from_secs(ghost_gc_interval_secs as u64));
             loop {
                 ticker.tick().await;
 
-                let user_ids = match ghost_gc::select_users_with_orphan_attachment_refs(
+                let mut user_ids = match ghost_gc::select_users_with_orphan_attachment_refs(
                     &db,
                     ghost_gc_min_ref_age_ms,
                     ghost_gc_max_users_per_run,
@@ -2335,6 +5906,20 @@ async fn main() -> anyhow::Result<()> {
                         continue;
                     }
                 };
+                match ghost_gc::select_users_with_orphan_children(
+                    &db,
+                    ghost_gc_min_parent_age_ms,
+                    ghost_gc_max_users_per_run,
+                )
+                .await
+                {
+                    Ok(ids) => user_ids.extend(ids),
+                    Err(e) => {
+                        error!(error = %e, "orphan children GC list users failed");
+                    }
+                }
+                user_ids.sort_unstable();
+                user_ids.dedup();
 
                 if user_ids.is_empty() {
                     continue;
@@ -2367,11 +5952,40 @@ async fn main() -> anyhow::Result<()> {
                         }
                     };
 
+                    let tombstoned = match ghost_gc::tombstone_orphan_children_for_user(
+                        &mut tx,
+                        user_id,
+                        ghost_gc_min_parent_age_ms,
+                        record_history_limit,
+                    )
+                    .await
+                    {
+                        Ok(n) => n,
+                        Err(e) => {
+                            tx.rollback().await.ok();
+                            error!(user_id, error = %e, "orphan children GC failed");
+                            continue;
+                        }
+                    };
+                    let server_seq = match current_server_seq(&mut *tx, user_id).await {
+                        Ok(seq) => seq,
+                        Err(e) => {
+                            tx.rollback().await.ok();
+                            error!(user_id, error = %e, "ghost files GC failed");
+                            continue;
+                        }
+                    };
+
                     if let Err(e) = tx.commit().await {
                         error!(user_id, error = %e, "ghost files GC commit failed");
                         continue;
                     }
 
+                    if tombstoned > 0 {
+                        notifier.publish(user_id, server_seq);
+                        info!(user_id, tombstoned, "orphan children GC");
+                    }
+
                     if stats.deleted_records > 0 {
                         let freed_bytes = (stats.stored_before - stats.stored_after).max(0);
                         info!(
@@ -2397,10 +6011,76 @@ async fn main() -> anyhow::Result<()> {
         .route("/v1/health", get(health))
         .nest("/v1/auth", auth::AuthService::auth_router())
         .route("/v1/key-bundle", get(get_key_bundle).put(put_key_bundle))
+        .route("/v1/key-bundle/versions", get(list_key_bundle_versions))
+        .route(
+            "/v1/key-bundle/rotation-status",
+            get(get_key_rotation_status),
+        )
+        .route("/v1/key-bundle/slots", get(list_key_slots))
+        .route("/v1/spaces", get(list_spaces).post(create_space))
+        .route("/v1/spaces/:space_id", axum::routing::delete(delete_space))
+        .route("/v1/spaces/:space_id/members", get(list_space_members))
+        .route(
+            "/v1/spaces/:space_id/members/:user_id",
+            axum::routing::put(put_space_member).delete(delete_space_member),
+        )
+        .route("/v1/shares", get(list_shares))
+        .route("/v1/shares/:share_id", axum::routing::delete(revoke_share))
+        .route("/v1/pairing", post(create_pairing))
+        .route("/v1/pairing/:code", get(get_pairing))
+        .route(
+            "/v1/pairing/:code/public-key",
+            axum::routing::put(put_pairing_public_key),
+        )
+        .route(
+            "/v1/pairing/:code/payload",
+            axum::routing::put(put_pairing_payload),
+        )
+        .route("/v1/pairing/:code/consume", post(consume_pairing))
+        .route(
+            "/v1/key-bundle/slots/:slot_id",
+            axum::routing::put(put_key_slot).delete(delete_key_slot),
+        )
         .route("/v1/sync/push", post(push_sync))
         .route("/v1/sync/pull", get(pull_sync))
+        .route("/v1/sync/stream", get(stream_sync))
+        .route("/v1/devices", get(list_devices))
+        .route("/v1/devices/:device_id/ack", post(ack_device))
+        .route(
+            "/v1/records/:type/:record_id/history",
+            get(get_record_history),
+        )
+        .route(
+            "/v1/records/:type/:record_id/history/:version_id/restore",
+            post(restore_record_version),
+        )
         .route("/v1/attachments/refs", post(upsert_attachment_refs))
+        .route(
+            "/v1/attachments/:attachment_id/staging",
+            get(get_attachment_staging),
+        )
+        .route("/v1/account/export", get(export_account))
         .layer(RequestBodyLimitLayer::new(body_limit_bytes))
+        // Archives and blobs are far larger than any sync request, so they
+        // get their own limits instead of the global one above.
+        .route(
+            "/v1/account/import",
+            post(import_account).layer(axum::extract::DefaultBodyLimit::max(
+                import_body_limit_bytes,
+            )),
+        )
+        .route(
+            "/v1/shares",
+            post(create_share).layer(axum::extract::DefaultBodyLimit::max(
+                share_max_b64.saturating_add(4096),
+            )),
+        )
+        .route(
+            "/v1/blobs/:attachment_id/:chunk",
+            get(get_blob)
+                .put(put_blob)
+                .layer(axum::extract::DefaultBodyLimit::max(blob_max_chunk_bytes)),
+        )
         .layer(
             TraceLayer::new_for_http().make_span_with(|req: &axum::http::Request<_>| {
                 tracing::info_span!(
@@ -2410,10 +6090,15 @@ async fn main() -> anyhow::Result<()> {
                 )
             }),
         )
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            require_min_client_version,
+        ))
         .layer(axum::middleware::from_fn_with_state(
             state.clone(),
             track_api_metrics,
         ))
+        .layer(axum::middleware::from_fn(add_server_time_header))
         .with_state(state);
 
     let app = if let Some(cors) = cors {
@@ -2433,3 +6118,41 @@ async fn main() -> anyhow::Result<()> {
     .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_versions_compare_numerically() {
+        let min = parse_client_version("2.4").unwrap();
+        for (v, too_old) in [
+            ("2.4.0", false),
+            ("v2.10", false),
+            ("2.4.0-beta+7", false),
+            ("2.3.9", true),
+        ] {
+            let parsed = parse_client_version(v).unwrap();
+            assert_eq!(compare_versions(&parsed, &min).is_lt(), too_old, "{v}");
+        }
+        assert!(parse_client_version("abc").is_err());
+    }
+
+    #[test]
+    fn decoded_chunk_lengths() {
+        assert_eq!(b64_decoded_len(""), 0);
+        assert_eq!(b64_decoded_len("YWJj"), 3);
+        assert_eq!(b64_decoded_len("YWJjZA=="), 4);
+        assert_eq!(b64_decoded_len("YWJjZA"), 4);
+        assert_eq!(b64_decoded_len("YWJjZGU="), 5);
+    }
+
+    #[test]
+    fn chunk_ids_match_their_own_attachment_only() {
+        assert_eq!(chunk_id_pattern("a_1%"), r"a\_1\%:%");
+        assert_eq!(chunk_index_of("a_1:3", "a_1"), Some(3));
+        assert_eq!(chunk_index_of("ab1:3", "a_1"), None);
+        assert_eq!(chunk_index_of("a:b:0", "a"), None);
+        assert_eq!(chunk_index_of("a:x", "a"), None);
+    }
+}
@@ -279,7 +279,7 @@ fn parse_ymd(s: &str) -> Option<(i32, u32, u32)> {
 
 // Date conversions without external deps (proleptic Gregorian calendar).
 // Based on Howard Hinnant's "civil_from_days/days_from_civil".
-fn civil_from_days(days_since_epoch: i64) -> (i32, u32, u32) {
+pub(crate) fn civil_from_days(days_since_epoch: i64) -> (i32, u32, u32) {
     let z = days_since_epoch + 719_468;
     let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
     let doe = z - era * 146_097;
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use tokio::sync::watch;
+
+/// Fan-out of per-user `serverSeq` heads to live listeners (`/v1/sync/stream`).
+///
+/// Channels are created lazily when a listener subscribes and dropped again
+/// once the last receiver goes away, so idle users cost nothing.
+#[derive(Debug, Default)]
+pub(crate) struct SyncNotifier {
+    channels: Mutex<HashMap<i64, watch::Sender<i64>>>,
+}
+
+impl SyncNotifier {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn subscribe(&self, user_id: i64) -> watch::Receiver<i64> {
+        let mut channels = self.channels.lock().unwrap_or_else(|e| e.into_inner());
+        channels.retain(|_, tx| tx.receiver_count() > 0);
+        channels
+            .entry(user_id)
+            .or_insert_with(|| watch::channel(0).0)
+            .subscribe()
+    }
+
+    /// Publish a new head after the transaction that allocated it committed.
+    pub(crate) fn publish(&self, user_id: i64, server_seq: i64) {
+        let mut channels = self.channels.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(tx) = channels.get(&user_id) else {
+            return;
+        };
+        if tx.receiver_count() == 0 {
+            channels.remove(&user_id);
+            return;
+        }
+        tx.send_if_modified(|cur| {
+            if server_seq > *cur {
+                *cur = server_seq;
+                true
+            } else {
+                false
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publish_only_moves_forward() {
+        let notifier = SyncNotifier::new();
+        let rx = notifier.subscribe(1);
+        notifier.publish(1, 5);
+        notifier.publish(1, 3);
+        assert_eq!(*rx.borrow(), 5);
+
+        // Other users are unaffected.
+        notifier.publish(2, 9);
+        assert_eq!(*rx.borrow(), 5);
+    }
+
+    #[test]
+    fn channel_dropped_without_listeners() {
+        let notifier = SyncNotifier::new();
+        drop(notifier.subscribe(1));
+        notifier.publish(1, 1);
+        assert!(notifier.channels.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,284 @@
+use rand::Rng;
+use serde::Serialize;
+use sqlx::{Row, Sqlite, Transaction};
+
+/// Unambiguous when read aloud or typed (no 0/O, 1/I/L).
+const CODE_ALPHABET: &[u8] = b"23456789ABCDEFGHJKMNPQRSTUVWXYZ";
+pub(crate) const CODE_LEN: usize = 8;
+
+const MAX_OPEN_SESSIONS_PER_USER: i64 = 5;
+/// An X25519/P-256 public key is well under this even with framing.
+pub(crate) const MAX_PUBLIC_KEY_LEN: usize = 1024;
+/// A wrapped DEK plus a bit of metadata.
+pub(crate) const MAX_PAYLOAD_LEN: usize = 16 * 1024;
+
+pub(crate) const STATUS_WAITING: &str = "waiting";
+pub(crate) const STATUS_CLAIMED: &str = "claimed";
+pub(crate) const STATUS_READY: &str = "ready";
+pub(crate) const STATUS_CONSUMED: &str = "consumed";
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PairingStatus {
+    pub status: &'static str,
+    #[serde(rename = "expiresAtMsUtc")]
+    pub expires_at_ms_utc: i64,
+    #[serde(rename = "publicKey", default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+}
+
+pub(crate) enum Lookup<T> {
+    Found(T),
+    NotFound,
+    Expired,
+    /// The session isn't in the state this step needs.
+    Conflict,
+}
+
+pub(crate) fn new_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CODE_LEN)
+        .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+/// Accept codes the way people type them: any case, with dashes or spaces.
+pub(crate) fn normalize_code(raw: &str) -> Option<String> {
+    let code: String = raw
+        .chars()
+        .filter(|c| !matches!(c, '-' | ' '))
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    (code.len() == CODE_LEN && code.bytes().all(|b| CODE_ALPHABET.contains(&b))).then_some(code)
+}
+
+/// Opaque blobs are relayed as-is but must at least look like base64.
+pub(crate) fn is_valid_blob(blob: &str, max_len: usize) -> bool {
+    !blob.is_empty()
+        && blob.len() <= max_len
+        && blob
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'+' | b'/' | b'='))
+}
+
+/// Open a session; `None` when the user already has too many open ones.
+/// Expired sessions (of every user) are dropped on the way.
+pub(crate) async fn create(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    code_hash: &str,
+    now_ms: i64,
+    ttl_ms: i64,
+) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query(r#"DELETE FROM pairing_sessions WHERE expires_at_ms_utc <= ?"#)
+        .bind(now_ms)
+        .execute(&mut **tx)
+        .await?;
+
+    let open: i64 = sqlx::query_scalar(
+        r#"SELECT COUNT(*) FROM pairing_sessions
+           WHERE user_id = ? AND consumed_at_ms_utc IS NULL"#,
+    )
+    .bind(user_id)
+    .fetch_one(&mut **tx)
+    .await?;
+    if open >= MAX_OPEN_SESSIONS_PER_USER {
+        return Ok(None);
+    }
+
+    let expires_at_ms_utc = now_ms + ttl_ms;
+    sqlx::query(
+        r#"INSERT INTO pairing_sessions (code_hash, user_id, created_at_ms_utc, expires_at_ms_utc)
+           VALUES (?, ?, ?, ?)"#,
+    )
+    .bind(code_hash)
+    .bind(user_id)
+    .bind(now_ms)
+    .bind(expires_at_ms_utc)
+    .execute(&mut **tx)
+    .await?;
+    Ok(Some(expires_at_ms_utc))
+}
+
+struct SessionRow {
+    expires_at_ms_utc: i64,
+    public_key: Option<String>,
+    has_payload: bool,
+    consumed: bool,
+}
+
+impl SessionRow {
+    fn status(&self) -> &'static str {
+        if self.consumed {
+            STATUS_CONSUMED
+        } else if self.has_payload {
+            STATUS_READY
+        } else if self.public_key.is_some() {
+            STATUS_CLAIMED
+        } else {
+            STATUS_WAITING
+        }
+    }
+}
+
+/// Sessions of other users look exactly like unknown codes.
+async fn load(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    code_hash: &str,
+    now_ms: i64,
+) -> Result<Lookup<SessionRow>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"SELECT expires_at_ms_utc, public_key,
+             payload IS NOT NULL AS has_payload,
+             consumed_at_ms_utc IS NOT NULL AS consumed
+           FROM pairing_sessions
+           WHERE code_hash = ? AND user_id = ?"#,
+    )
+    .bind(code_hash)
+    .bind(user_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+    let Some(row) = row else {
+        return Ok(Lookup::NotFound);
+    };
+    let session = SessionRow {
+        expires_at_ms_utc: row.try_get("expires_at_ms_utc")?,
+        public_key: row.try_get("public_key")?,
+        has_payload: row.try_get("has_payload")?,
+        consumed: row.try_get("consumed")?,
+    };
+    if session.expires_at_ms_utc <= now_ms {
+        return Ok(Lookup::Expired);
+    }
+    Ok(Lookup::Found(session))
+}
+
+pub(crate) async fn status(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    code_hash: &str,
+    now_ms: i64,
+) -> Result<Lookup<PairingStatus>, sqlx::Error> {
+    Ok(match load(tx, user_id, code_hash, now_ms).await? {
+        Lookup::Found(session) => Lookup::Found(PairingStatus {
+            status: session.status(),
+            expires_at_ms_utc: session.expires_at_ms_utc,
+            public_key: session.public_key.filter(|_| !session.consumed),
+        }),
+        Lookup::NotFound => Lookup::NotFound,
+        Lookup::Expired => Lookup::Expired,
+        Lookup::Conflict => Lookup::Conflict,
+    })
+}
+
+/// The new device's ephemeral key; only the first one is accepted.
+pub(crate) async fn put_public_key(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    code_hash: &str,
+    public_key: &str,
+    now_ms: i64,
+) -> Result<Lookup<()>, sqlx::Error> {
+    match load(tx, user_id, code_hash, now_ms).await? {
+        Lookup::Found(session) if session.status() == STATUS_WAITING => {}
+        Lookup::Found(_) | Lookup::Conflict => return Ok(Lookup::Conflict),
+        Lookup::NotFound => return Ok(Lookup::NotFound),
+        Lookup::Expired => return Ok(Lookup::Expired),
+    }
+    let updated = sqlx::query(
+        r#"UPDATE pairing_sessions
+           SET public_key = ?, public_key_at_ms_utc = ?
+           WHERE code_hash = ? AND public_key IS NULL"#,
+    )
+    .bind(public_key)
+    .bind(now_ms)
+    .bind(code_hash)
+    .execute(&mut **tx)
+    .await?;
+    Ok(if updated.rows_affected() == 1 {
+        Lookup::Found(())
+    } else {
+        Lookup::Conflict
+    })
+}
+
+/// The old device's answer, encrypted to the posted public key.
+pub(crate) async fn put_payload(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    code_hash: &str,
+    payload: &str,
+    now_ms: i64,
+) -> Result<Lookup<()>, sqlx::Error> {
+    match load(tx, user_id, code_hash, now_ms).await? {
+        Lookup::Found(session) if session.status() == STATUS_CLAIMED => {}
+        Lookup::Found(_) | Lookup::Conflict => return Ok(Lookup::Conflict),
+        Lookup::NotFound => return Ok(Lookup::NotFound),
+        Lookup::Expired => return Ok(Lookup::Expired),
+    }
+    let updated = sqlx::query(
+        r#"UPDATE pairing_sessions
+           SET payload = ?, payload_at_ms_utc = ?
+           WHERE code_hash = ? AND payload IS NULL"#,
+    )
+    .bind(payload)
+    .bind(now_ms)
+    .bind(code_hash)
+    .execute(&mut **tx)
+    .await?;
+    Ok(if updated.rows_affected() == 1 {
+        Lookup::Found(())
+    } else {
+        Lookup::Conflict
+    })
+}
+
+/// Hand the payload out once and clear both blobs.
+pub(crate) async fn consume(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    code_hash: &str,
+    now_ms: i64,
+) -> Result<Lookup<String>, sqlx::Error> {
+    match load(tx, user_id, code_hash, now_ms).await? {
+        Lookup::Found(session) if session.status() == STATUS_READY => {}
+        Lookup::Found(_) | Lookup::Conflict => return Ok(Lookup::Conflict),
+        Lookup::NotFound => return Ok(Lookup::NotFound),
+        Lookup::Expired => return Ok(Lookup::Expired),
+    }
+    let payload: Option<String> =
+        sqlx::query_scalar(r#"SELECT payload FROM pairing_sessions WHERE code_hash = ?"#)
+            .bind(code_hash)
+            .fetch_one(&mut **tx)
+            .await?;
+    let updated = sqlx::query(
+        r#"UPDATE pairing_sessions
+           SET consumed_at_ms_utc = ?, payload = NULL, public_key = NULL
+           WHERE code_hash = ? AND consumed_at_ms_utc IS NULL"#,
+    )
+    .bind(now_ms)
+    .bind(code_hash)
+    .execute(&mut **tx)
+    .await?;
+    Ok(match payload {
+        Some(payload) if updated.rows_affected() == 1 => Lookup::Found(payload),
+        _ => Lookup::Conflict,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_round_trip() {
+        for _ in 0..100 {
+            let code = new_code();
+            assert_eq!(normalize_code(&code).as_deref(), Some(code.as_str()));
+        }
+        assert_eq!(normalize_code("abcd-efgh").as_deref(), Some("ABCDEFGH"));
+        assert_eq!(normalize_code("ABCD EFG"), None);
+        assert_eq!(normalize_code("ABCD0FGH"), None);
+        assert_eq!(normalize_code("ABCDEFGHJ"), None);
+    }
+}
@@ -0,0 +1,235 @@
+use serde::Serialize;
+use sqlx::{Pool, Row, Sqlite, Transaction};
+
+use crate::{
+    compact_committed_attachment_chunks, current_server_seq, delete_staged_attachment, history,
+    recompute_and_store_user_b64, record_envelope_from_row, Hlc, SyncRecordEnvelope,
+    TYPE_TODO_ATTACHMENT, TYPE_TODO_ATTACHMENT_CHUNK,
+};
+
+/// Keys listed individually in a report; the counts are always exact.
+const MAX_REPORTED_KEYS: usize = 200;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum RollbackTarget {
+    ServerSeq(i64),
+    Timestamp(i64),
+}
+
+impl RollbackTarget {
+    /// Exactly one of `serverSeq` / `atMsUtc` must be given.
+    pub(crate) fn from_parts(server_seq: Option<i64>, at_ms_utc: Option<i64>) -> Option<Self> {
+        match (server_seq, at_ms_utc) {
+            (Some(seq), None) if seq >= 0 => Some(Self::ServerSeq(seq)),
+            (None, Some(ms)) if ms >= 0 => Some(Self::Timestamp(ms)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RecordKey {
+    pub r#type: String,
+    #[serde(rename = "recordId")]
+    pub record_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RollbackReport {
+    #[serde(rename = "targetServerSeq")]
+    pub target_server_seq: i64,
+    pub republished: i64,
+    pub tombstoned: i64,
+    #[serde(rename = "unrestorableCount")]
+    pub unrestorable_count: i64,
+    /// Records changed after the target whose state at that point is no
+    /// longer known (history trimmed, or written before history existed).
+    pub unrestorable: Vec<RecordKey>,
+    /// Head after the rollback; devices converge by pulling up to it.
+    #[serde(rename = "serverSeq")]
+    pub server_seq: i64,
+}
+
+struct CurrentRow {
+    envelope: SyncRecordEnvelope,
+    server_seq: i64,
+    created_server_seq: Option<i64>,
+}
+
+/// Roll every record of the account back to its state as of `target`.
+///
+/// Nothing is rewritten in place: each changed record gets its old version
+/// re-published (or a tombstone, if it didn't exist yet) as a new write with
+/// a server HLC and a fresh `serverSeq`, so devices converge through pull and
+/// the rollback itself can be undone from history. Attachment chunks follow
+/// their attachment; attachment metadata is not versioned and can only be
+/// tombstoned.
+pub(crate) async fn rollback_account(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    target: RollbackTarget,
+    history_limit: i64,
+    now_ms: i64,
+) -> anyhow::Result<RollbackReport> {
+    let mut tx = db.begin().await?;
+
+    let head = current_server_seq(&mut *tx, user_id).await?;
+    let target_seq = match target {
+        RollbackTarget::ServerSeq(seq) => seq,
+        RollbackTarget::Timestamp(ms) => seq_at_time(&mut tx, user_id, ms).await?,
+    }
+    .clamp(0, head);
+
+    let mut report = RollbackReport {
+        target_server_seq: target_seq,
+        republished: 0,
+        tombstoned: 0,
+        unrestorable_count: 0,
+        unrestorable: Vec::new(),
+        server_seq: head,
+    };
+
+    // Records written after the target, plus purged ones that were live at it.
+    let keys: Vec<(String, String)> = sqlx::query_as(
+        r#"SELECT type, record_id FROM records
+           WHERE user_id = ? AND server_seq > ? AND type != ?
+           UNION
+           SELECT type, record_id FROM record_history
+           WHERE user_id = ? AND server_seq <= ? AND superseded_by_seq > ?
+           ORDER BY type, record_id"#,
+    )
+    .bind(user_id)
+    .bind(target_seq)
+    .bind(TYPE_TODO_ATTACHMENT_CHUNK)
+    .bind(user_id)
+    .bind(target_seq)
+    .bind(target_seq)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for (record_type, record_id) in keys {
+        let current = fetch_current(&mut tx, user_id, &record_type, &record_id).await?;
+        if current.as_ref().is_some_and(|c| c.server_seq <= target_seq) {
+            continue;
+        }
+
+        let version = if history::is_versioned_type(&record_type) {
+            history::version_at(&mut tx, user_id, &record_type, &record_id, target_seq).await?
+        } else {
+            None
+        };
+        if let Some(version) = version {
+            let mut record = version.record;
+            history::republish(
+                &mut tx,
+                user_id,
+                &mut record,
+                current.as_ref().map(|c| &c.envelope.hlc),
+                history_limit,
+                now_ms,
+            )
+            .await?;
+            report.republished += 1;
+            continue;
+        }
+
+        // No version current at the target: either the record didn't exist
+        // yet (tombstone it) or we no longer know what it looked like.
+        let Some(current) = current else {
+            continue;
+        };
+        if current
+            .created_server_seq
+            .is_none_or(|seq| seq <= target_seq)
+        {
+            report.unrestorable_count += 1;
+            if report.unrestorable.len() < MAX_REPORTED_KEYS {
+                report.unrestorable.push(RecordKey {
+                    r#type: record_type,
+                    record_id,
+                });
+            }
+            continue;
+        }
+        if current.envelope.deleted_at_ms_utc.is_some() {
+            continue;
+        }
+
+        let hlc: Hlc = current.envelope.hlc.clone();
+        let mut record = current.envelope;
+        record.deleted_at_ms_utc = Some(now_ms);
+        history::republish(
+            &mut tx,
+            user_id,
+            &mut record,
+            Some(&hlc),
+            history_limit,
+            now_ms,
+        )
+        .await?;
+        if record_type == TYPE_TODO_ATTACHMENT {
+            delete_staged_attachment(&mut tx, user_id, &record_id).await?;
+            compact_committed_attachment_chunks(&mut tx, user_id, &record_id, now_ms).await?;
+        }
+        report.tombstoned += 1;
+    }
+
+    recompute_and_store_user_b64(&mut tx, user_id).await?;
+    report.server_seq = current_server_seq(&mut *tx, user_id).await?;
+    tx.commit().await?;
+    Ok(report)
+}
+
+/// Highest `serverSeq` written at or before `at_ms` (0 if none).
+async fn seq_at_time(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    at_ms: i64,
+) -> Result<i64, sqlx::Error> {
+    let seq: Option<i64> = sqlx::query_scalar(
+        r#"SELECT MAX(s) FROM (
+             SELECT MAX(server_seq) AS s FROM records
+             WHERE user_id = ? AND updated_at_ms_utc <= ?
+             UNION ALL
+             SELECT MAX(server_seq) AS s FROM record_history
+             WHERE user_id = ? AND updated_at_ms_utc <= ?
+           )"#,
+    )
+    .bind(user_id)
+    .bind(at_ms)
+    .bind(user_id)
+    .bind(at_ms)
+    .fetch_one(&mut **tx)
+    .await?;
+    Ok(seq.unwrap_or(0))
+}
+
+async fn fetch_current(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    record_type: &str,
+    record_id: &str,
+) -> Result<Option<CurrentRow>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"SELECT
+             type, record_id,
+             hlc_wall_ms_utc, hlc_counter, hlc_device_id,
+             deleted_at_ms_utc, schema_version, dek_id, algo,
+             nonce, ciphertext, server_seq, created_server_seq
+           FROM records
+           WHERE user_id = ? AND type = ? AND record_id = ?"#,
+    )
+    .bind(user_id)
+    .bind(record_type)
+    .bind(record_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    Ok(Some(CurrentRow {
+        envelope: record_envelope_from_row(&row)?,
+        server_seq: row.try_get("server_seq")?,
+        created_server_seq: row.try_get("created_server_seq")?,
+    }))
+}
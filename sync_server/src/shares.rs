@@ -0,0 +1,298 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use serde::Serialize;
+use sqlx::{Pool, Row, Sqlite, Transaction};
+
+const MAX_SHARE_ID_LEN: usize = 64;
+const MAX_ACTIVE_SHARES_PER_USER: i64 = 50;
+/// An AES-GCM nonce is 12 bytes; leave room for other framings.
+pub(crate) const MAX_NONCE_LEN: usize = 64;
+
+pub(crate) const STATUS_ACTIVE: &str = "active";
+pub(crate) const STATUS_EXPIRED: &str = "expired";
+pub(crate) const STATUS_EXHAUSTED: &str = "exhausted";
+pub(crate) const STATUS_REVOKED: &str = "revoked";
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Share {
+    #[serde(rename = "shareId")]
+    pub share_id: String,
+    pub status: &'static str,
+    #[serde(rename = "sizeB64")]
+    pub size_b64: i64,
+    #[serde(rename = "createdAtMsUtc")]
+    pub created_at_ms_utc: i64,
+    #[serde(rename = "expiresAtMsUtc")]
+    pub expires_at_ms_utc: i64,
+    #[serde(rename = "maxViews")]
+    pub max_views: Option<i64>,
+    #[serde(rename = "viewCount")]
+    pub view_count: i64,
+    #[serde(rename = "revokedAtMsUtc")]
+    pub revoked_at_ms_utc: Option<i64>,
+}
+
+/// What an anonymous viewer gets: the snapshot and nothing about its owner.
+#[derive(Debug, Serialize)]
+pub(crate) struct Snapshot {
+    pub nonce: String,
+    pub ciphertext: String,
+    #[serde(rename = "expiresAtMsUtc")]
+    pub expires_at_ms_utc: i64,
+    #[serde(rename = "viewsLeft")]
+    pub views_left: Option<i64>,
+}
+
+pub(crate) enum Open {
+    /// The view was counted; `user_id` is the owner to bill.
+    Found {
+        user_id: i64,
+        snapshot: Snapshot,
+    },
+    NotFound,
+    /// Expired, revoked or out of views.
+    Gone,
+}
+
+pub(crate) fn new_share_id() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub(crate) fn is_valid_share_id(share_id: &str) -> bool {
+    !share_id.is_empty()
+        && share_id.len() <= MAX_SHARE_ID_LEN
+        && share_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_'))
+}
+
+pub(crate) fn status(
+    now_ms: i64,
+    expires_at_ms_utc: i64,
+    max_views: Option<i64>,
+    view_count: i64,
+    revoked_at_ms_utc: Option<i64>,
+) -> &'static str {
+    if revoked_at_ms_utc.is_some() {
+        STATUS_REVOKED
+    } else if expires_at_ms_utc <= now_ms {
+        STATUS_EXPIRED
+    } else if max_views.is_some_and(|max| view_count >= max) {
+        STATUS_EXHAUSTED
+    } else {
+        STATUS_ACTIVE
+    }
+}
+
+/// Store a snapshot; `None` when the user already has too many live shares.
+/// Expired shares (of every user) are dropped on the way.
+pub(crate) async fn create(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    nonce: &str,
+    ciphertext: &str,
+    now_ms: i64,
+    expires_at_ms_utc: i64,
+    max_views: Option<i64>,
+) -> Result<Option<Share>, sqlx::Error> {
+    sqlx::query(r#"DELETE FROM shares WHERE expires_at_ms_utc <= ?"#)
+        .bind(now_ms)
+        .execute(&mut **tx)
+        .await?;
+
+    let live: i64 = sqlx::query_scalar(
+        r#"SELECT COUNT(*) FROM shares WHERE user_id = ? AND ciphertext IS NOT NULL"#,
+    )
+    .bind(user_id)
+    .fetch_one(&mut **tx)
+    .await?;
+    if live >= MAX_ACTIVE_SHARES_PER_USER {
+        return Ok(None);
+    }
+
+    let share_id = new_share_id();
+    let size_b64 = (nonce.len() + ciphertext.len()) as i64;
+    sqlx::query(
+        r#"INSERT INTO shares (
+             share_id, user_id, nonce, ciphertext, size_b64,
+             created_at_ms_utc, expires_at_ms_utc, max_views
+           ) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"#,
+    )
+    .bind(&share_id)
+    .bind(user_id)
+    .bind(nonce)
+    .bind(ciphertext)
+    .bind(size_b64)
+    .bind(now_ms)
+    .bind(expires_at_ms_utc)
+    .bind(max_views)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(Some(Share {
+        share_id,
+        status: STATUS_ACTIVE,
+        size_b64,
+        created_at_ms_utc: now_ms,
+        expires_at_ms_utc,
+        max_views,
+        view_count: 0,
+        revoked_at_ms_utc: None,
+    }))
+}
+
+/// The user's shares that haven't expired yet, newest first.
+pub(crate) async fn list(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    now_ms: i64,
+) -> Result<Vec<Share>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"SELECT share_id, size_b64, created_at_ms_utc, expires_at_ms_utc,
+             max_views, view_count, revoked_at_ms_utc
+           FROM shares
+           WHERE user_id = ? AND expires_at_ms_utc > ?
+           ORDER BY created_at_ms_utc DESC, share_id"#,
+    )
+    .bind(user_id)
+    .bind(now_ms)
+    .fetch_all(db)
+    .await?;
+
+    let mut out = Vec::with_capacity(rows.len());
+    for row in rows {
+        let expires_at_ms_utc: i64 = row.try_get("expires_at_ms_utc")?;
+        let max_views: Option<i64> = row.try_get("max_views")?;
+        let view_count: i64 = row.try_get("view_count")?;
+        let revoked_at_ms_utc: Option<i64> = row.try_get("revoked_at_ms_utc")?;
+        out.push(Share {
+            share_id: row.try_get("share_id")?,
+            status: status(
+                now_ms,
+                expires_at_ms_utc,
+                max_views,
+                view_count,
+                revoked_at_ms_utc,
+            ),
+            size_b64: row.try_get("size_b64")?,
+            created_at_ms_utc: row.try_get("created_at_ms_utc")?,
+            expires_at_ms_utc,
+            max_views,
+            view_count,
+            revoked_at_ms_utc,
+        });
+    }
+    Ok(out)
+}
+
+/// Revoke one of the user's shares and drop its snapshot. Revoking twice is
+/// fine; `false` only when the share isn't theirs (or doesn't exist).
+pub(crate) async fn revoke(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    share_id: &str,
+    now_ms: i64,
+) -> anyhow::Result<bool> {
+    let mut tx = db.begin().await?;
+    let updated = sqlx::query(
+        r#"UPDATE shares
+           SET revoked_at_ms_utc = IFNULL(revoked_at_ms_utc, ?), nonce = NULL, ciphertext = NULL
+           WHERE share_id = ? AND user_id = ?"#,
+    )
+    .bind(now_ms)
+    .bind(share_id)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Ok(false);
+    }
+    crate::recompute_and_store_user_b64(&mut tx, user_id).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Count one view and return the snapshot. The last allowed view clears it.
+pub(crate) async fn open(
+    tx: &mut Transaction<'_, Sqlite>,
+    share_id: &str,
+    now_ms: i64,
+) -> anyhow::Result<Open> {
+    let updated = sqlx::query(
+        r#"UPDATE shares
+           SET view_count = view_count + 1
+           WHERE share_id = ?
+             AND ciphertext IS NOT NULL
+             AND revoked_at_ms_utc IS NULL
+             AND expires_at_ms_utc > ?
+             AND (max_views IS NULL OR view_count < max_views)"#,
+    )
+    .bind(share_id)
+    .bind(now_ms)
+    .execute(&mut **tx)
+    .await?;
+
+    let row = sqlx::query(
+        r#"SELECT user_id, nonce, ciphertext, expires_at_ms_utc, max_views, view_count
+           FROM shares
+           WHERE share_id = ?"#,
+    )
+    .bind(share_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+    let Some(row) = row else {
+        return Ok(Open::NotFound);
+    };
+    if updated.rows_affected() == 0 {
+        return Ok(Open::Gone);
+    }
+
+    let nonce: Option<String> = row.try_get("nonce")?;
+    let ciphertext: Option<String> = row.try_get("ciphertext")?;
+    let (Some(nonce), Some(ciphertext)) = (nonce, ciphertext) else {
+        return Ok(Open::Gone);
+    };
+    let max_views: Option<i64> = row.try_get("max_views")?;
+    let view_count: i64 = row.try_get("view_count")?;
+    let views_left = max_views.map(|max| (max - view_count).max(0));
+    let user_id: i64 = row.try_get("user_id")?;
+    if views_left == Some(0) {
+        sqlx::query(r#"UPDATE shares SET nonce = NULL, ciphertext = NULL WHERE share_id = ?"#)
+            .bind(share_id)
+            .execute(&mut **tx)
+            .await?;
+        crate::recompute_and_store_user_b64(tx, user_id).await?;
+    }
+
+    Ok(Open::Found {
+        user_id,
+        snapshot: Snapshot {
+            nonce,
+            ciphertext,
+            expires_at_ms_utc: row.try_get("expires_at_ms_utc")?,
+            views_left,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn share_status() {
+        assert_eq!(status(10, 20, None, 5, None), STATUS_ACTIVE);
+        assert_eq!(status(10, 20, Some(3), 2, None), STATUS_ACTIVE);
+        assert_eq!(status(10, 20, Some(3), 3, None), STATUS_EXHAUSTED);
+        assert_eq!(status(20, 20, Some(3), 0, None), STATUS_EXPIRED);
+        assert_eq!(status(30, 20, Some(3), 3, Some(5)), STATUS_REVOKED);
+
+        let id = new_share_id();
+        assert!(is_valid_share_id(&id));
+        assert!(!is_valid_share_id(""));
+        assert!(!is_valid_share_id("a/b"));
+    }
+}
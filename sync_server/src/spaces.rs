@@ -0,0 +1,364 @@
+use axum::http::request::Parts;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use serde::Serialize;
+use sqlx::{Pool, Row, Sqlite, Transaction};
+
+/// `users.oauth_provider` of the rows backing spaces.
+pub(crate) const SPACE_OAUTH_PROVIDER: &str = "_space";
+pub(crate) const SPACE_ID_HEADER: &str = "x-space-id";
+const SPACE_ID_QUERY: &str = "spaceId";
+
+const MAX_SPACE_ID_LEN: usize = 64;
+const MAX_SPACES_PER_OWNER: i64 = 20;
+const MAX_MEMBERS_PER_SPACE: i64 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Owner,
+    Editor,
+    Viewer,
+}
+
+impl Role {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Role::Owner => "owner",
+            Role::Editor => "editor",
+            Role::Viewer => "viewer",
+        }
+    }
+
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        match s {
+            "owner" => Some(Role::Owner),
+            "editor" => Some(Role::Editor),
+            "viewer" => Some(Role::Viewer),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn can_write(self) -> bool {
+        self != Role::Viewer
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Space {
+    #[serde(rename = "spaceId")]
+    pub space_id: String,
+    pub role: &'static str,
+    #[serde(rename = "ownerUserId")]
+    pub owner_user_id: i64,
+    #[serde(rename = "createdAtMsUtc")]
+    pub created_at_ms_utc: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Member {
+    #[serde(rename = "userId")]
+    pub user_id: i64,
+    pub role: &'static str,
+    #[serde(rename = "addedAtMsUtc")]
+    pub added_at_ms_utc: i64,
+}
+
+pub(crate) enum MemberChange {
+    Done,
+    NotFound,
+    Forbidden,
+    UnknownUser,
+    TooManyMembers,
+}
+
+pub(crate) fn is_valid_space_id(space_id: &str) -> bool {
+    !space_id.is_empty()
+        && space_id.len() <= MAX_SPACE_ID_LEN
+        && space_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_'))
+}
+
+/// `spaceId` from the `X-Space-Id` header or the query string, if any.
+pub(crate) fn space_id_from_request(parts: &Parts) -> Option<String> {
+    if let Some(v) = parts.headers.get(SPACE_ID_HEADER) {
+        return v.to_str().ok().map(|s| s.trim().to_string());
+    }
+    url::form_urlencoded::parse(parts.uri.query()?.as_bytes())
+        .find(|(k, _)| k == SPACE_ID_QUERY)
+        .map(|(_, v)| v.trim().to_string())
+}
+
+/// The backing `user_id` and the caller's role, if `account_id` is a member.
+pub(crate) async fn resolve(
+    db: &Pool<Sqlite>,
+    space_id: &str,
+    account_id: i64,
+) -> Result<Option<(i64, Role)>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"SELECT s.user_id, m.role
+           FROM spaces s
+           JOIN space_members m ON m.space_id = s.space_id
+           WHERE s.space_id = ? AND m.user_id = ?"#,
+    )
+    .bind(space_id)
+    .bind(account_id)
+    .fetch_optional(db)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let user_id: i64 = row.try_get("user_id")?;
+    let role: String = row.try_get("role")?;
+    Ok(Role::parse(&role).map(|role| (user_id, role)))
+}
+
+/// Create a space owned by `owner_id`; `None` past the per-owner limit.
+pub(crate) async fn create(
+    tx: &mut Transaction<'_, Sqlite>,
+    owner_id: i64,
+    now_ms: i64,
+) -> Result<Option<Space>, sqlx::Error> {
+    let owned: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM spaces WHERE owner_user_id = ?"#)
+        .bind(owner_id)
+        .fetch_one(&mut **tx)
+        .await?;
+    if owned >= MAX_SPACES_PER_OWNER {
+        return Ok(None);
+    }
+
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let space_id = URL_SAFE_NO_PAD.encode(bytes);
+
+    let backing_id: i64 = sqlx::query_scalar(
+        r#"INSERT INTO users (oauth_provider, oauth_sub, created_at_ms_utc)
+           VALUES (?, ?, ?)
+           RETURNING id"#,
+    )
+    .bind(SPACE_OAUTH_PROVIDER)
+    .bind(&space_id)
+    .bind(now_ms)
+    .fetch_one(&mut **tx)
+    .await?;
+    sqlx::query(
+        r#"INSERT INTO spaces (space_id, user_id, owner_user_id, created_at_ms_utc)
+           VALUES (?, ?, ?, ?)"#,
+    )
+    .bind(&space_id)
+    .bind(backing_id)
+    .bind(owner_id)
+    .bind(now_ms)
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query(
+        r#"INSERT INTO space_members (space_id, user_id, role, added_at_ms_utc)
+           VALUES (?, ?, 'owner', ?)"#,
+    )
+    .bind(&space_id)
+    .bind(owner_id)
+    .bind(now_ms)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(Some(Space {
+        space_id,
+        role: Role::Owner.as_str(),
+        owner_user_id: owner_id,
+        created_at_ms_utc: now_ms,
+    }))
+}
+
+/// Spaces `account_id` belongs to, oldest first.
+pub(crate) async fn list_for_account(
+    db: &Pool<Sqlite>,
+    account_id: i64,
+) -> Result<Vec<Space>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"SELECT s.space_id, m.role, s.owner_user_id, s.created_at_ms_utc
+           FROM space_members m
+           JOIN spaces s ON s.space_id = m.space_id
+           WHERE m.user_id = ?
+           ORDER BY s.created_at_ms_utc, s.space_id"#,
+    )
+    .bind(account_id)
+    .fetch_all(db)
+    .await?;
+
+    let mut out = Vec::with_capacity(rows.len());
+    for row in rows {
+        let role: String = row.try_get("role")?;
+        let Some(role) = Role::parse(&role) else {
+            continue;
+        };
+        out.push(Space {
+            space_id: row.try_get("space_id")?,
+            role: role.as_str(),
+            owner_user_id: row.try_get("owner_user_id")?,
+            created_at_ms_utc: row.try_get("created_at_ms_utc")?,
+        });
+    }
+    Ok(out)
+}
+
+pub(crate) async fn list_members(
+    db: &Pool<Sqlite>,
+    space_id: &str,
+) -> Result<Vec<Member>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"SELECT user_id, role, added_at_ms_utc
+           FROM space_members
+           WHERE space_id = ?
+           ORDER BY added_at_ms_utc, user_id"#,
+    )
+    .bind(space_id)
+    .fetch_all(db)
+    .await?;
+
+    let mut out = Vec::with_capacity(rows.len());
+    for row in rows {
+        let role: String = row.try_get("role")?;
+        let Some(role) = Role::parse(&role) else {
+            continue;
+        };
+        out.push(Member {
+            user_id: row.try_get("user_id")?,
+            role: role.as_str(),
+            added_at_ms_utc: row.try_get("added_at_ms_utc")?,
+        });
+    }
+    Ok(out)
+}
+
+async fn role_of(
+    tx: &mut Transaction<'_, Sqlite>,
+    space_id: &str,
+    user_id: i64,
+) -> Result<Option<Role>, sqlx::Error> {
+    let role: Option<String> =
+        sqlx::query_scalar(r#"SELECT role FROM space_members WHERE space_id = ? AND user_id = ?"#)
+            .bind(space_id)
+            .bind(user_id)
+            .fetch_optional(&mut **tx)
+            .await?;
+    Ok(role.as_deref().and_then(Role::parse))
+}
+
+/// Add `user_id` or change their role (owner only; the owner's own role is fixed).
+pub(crate) async fn set_member(
+    tx: &mut Transaction<'_, Sqlite>,
+    space_id: &str,
+    account_id: i64,
+    user_id: i64,
+    role: Role,
+    now_ms: i64,
+) -> Result<MemberChange, sqlx::Error> {
+    match role_of(tx, space_id, account_id).await? {
+        None => return Ok(MemberChange::NotFound),
+        Some(Role::Owner) => {}
+        Some(_) => return Ok(MemberChange::Forbidden),
+    }
+    if role == Role::Owner || user_id == account_id {
+        return Ok(MemberChange::Forbidden);
+    }
+
+    let exists: bool = sqlx::query_scalar(
+        r#"SELECT EXISTS (SELECT 1 FROM users WHERE id = ? AND oauth_provider != ?)"#,
+    )
+    .bind(user_id)
+    .bind(SPACE_OAUTH_PROVIDER)
+    .fetch_one(&mut **tx)
+    .await?;
+    if !exists {
+        return Ok(MemberChange::UnknownUser);
+    }
+
+    if role_of(tx, space_id, user_id).await?.is_none() {
+        let members: i64 =
+            sqlx::query_scalar(r#"SELECT COUNT(*) FROM space_members WHERE space_id = ?"#)
+                .bind(space_id)
+                .fetch_one(&mut **tx)
+                .await?;
+        if members >= MAX_MEMBERS_PER_SPACE {
+            return Ok(MemberChange::TooManyMembers);
+        }
+    }
+
+    sqlx::query(
+        r#"INSERT INTO space_members (space_id, user_id, role, added_at_ms_utc)
+           VALUES (?, ?, ?, ?)
+           ON CONFLICT(space_id, user_id) DO UPDATE SET role = excluded.role"#,
+    )
+    .bind(space_id)
+    .bind(user_id)
+    .bind(role.as_str())
+    .bind(now_ms)
+    .execute(&mut **tx)
+    .await?;
+    Ok(MemberChange::Done)
+}
+
+/// The owner removes a member, or a member leaves. The owner can't leave;
+/// they delete the space instead.
+pub(crate) async fn remove_member(
+    tx: &mut Transaction<'_, Sqlite>,
+    space_id: &str,
+    account_id: i64,
+    user_id: i64,
+) -> Result<MemberChange, sqlx::Error> {
+    let Some(caller) = role_of(tx, space_id, account_id).await? else {
+        return Ok(MemberChange::NotFound);
+    };
+    let Some(target) = role_of(tx, space_id, user_id).await? else {
+        return Ok(MemberChange::UnknownUser);
+    };
+    if target == Role::Owner || (caller != Role::Owner && user_id != account_id) {
+        return Ok(MemberChange::Forbidden);
+    }
+
+    sqlx::query(r#"DELETE FROM space_members WHERE space_id = ? AND user_id = ?"#)
+        .bind(space_id)
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+    Ok(MemberChange::Done)
+}
+
+/// Delete the space and everything in it (owner only).
+pub(crate) async fn delete(
+    tx: &mut Transaction<'_, Sqlite>,
+    space_id: &str,
+    account_id: i64,
+) -> Result<MemberChange, sqlx::Error> {
+    match role_of(tx, space_id, account_id).await? {
+        None => return Ok(MemberChange::NotFound),
+        Some(Role::Owner) => {}
+        Some(_) => return Ok(MemberChange::Forbidden),
+    }
+    sqlx::query(r#"DELETE FROM spaces WHERE space_id = ?"#)
+        .bind(space_id)
+        .execute(&mut **tx)
+        .await?;
+    Ok(MemberChange::Done)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_and_space_ids() {
+        for role in [Role::Owner, Role::Editor, Role::Viewer] {
+            assert_eq!(Role::parse(role.as_str()), Some(role));
+        }
+        assert_eq!(Role::parse("Owner"), None);
+        assert!(Role::Editor.can_write());
+        assert!(!Role::Viewer.can_write());
+
+        assert!(is_valid_space_id("q3Zx-_09"));
+        assert!(!is_valid_space_id(""));
+        assert!(!is_valid_space_id("a b"));
+        assert!(!is_valid_space_id(&"a".repeat(MAX_SPACE_ID_LEN + 1)));
+    }
+}
@@ -0,0 +1,180 @@
+use sqlx::{Pool, Row, Sqlite, Transaction};
+
+use crate::{recompute_and_store_user_b64, Hlc};
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TombstoneGcStats {
+    pub users: i64,
+    pub purged_records: i64,
+}
+
+/// Physically delete tombstones older than `retention_ms` for every user.
+///
+/// A tombstone is only purged once every device seen within the retention
+/// window has acknowledged its `serverSeq`; devices idle for longer than that
+/// have to resync anyway. Each run takes the users with the oldest purgeable
+/// tombstones, so users held back by a lagging device don't use up the batch.
+pub(crate) async fn purge_tombstones(
+    db: &Pool<Sqlite>,
+    retention_ms: i64,
+    max_users: i64,
+    now_ms: i64,
+) -> anyhow::Result<TombstoneGcStats> {
+    let cutoff = now_ms.saturating_sub(retention_ms);
+
+    let user_ids: Vec<i64> = sqlx::query_scalar(
+        r#"SELECT r.user_id
+           FROM records r
+           LEFT JOIN (
+             SELECT user_id, MIN(last_ack_server_seq) AS min_ack
+             FROM devices
+             WHERE last_seen_at_ms_utc >= ?
+             GROUP BY user_id
+           ) d ON d.user_id = r.user_id
+           WHERE r.deleted_at_ms_utc IS NOT NULL
+             AND r.updated_at_ms_utc < ?
+             AND (d.min_ack IS NULL OR r.server_seq <= d.min_ack)
+           GROUP BY r.user_id
+           ORDER BY MIN(r.updated_at_ms_utc), r.user_id
+           LIMIT ?"#,
+    )
+    .bind(cutoff)
+    .bind(cutoff)
+    .bind(max_users)
+    .fetch_all(db)
+    .await?;
+
+    let mut stats = TombstoneGcStats {
+        users: 0,
+        purged_records: 0,
+    };
+    for user_id in user_ids {
+        let mut tx = db.begin().await?;
+        let purged = purge_tombstones_for_user(&mut tx, user_id, cutoff).await?;
+        tx.commit().await?;
+        if purged > 0 {
+            stats.users += 1;
+            stats.purged_records += purged;
+        }
+    }
+    Ok(stats)
+}
+
+async fn purge_tombstones_for_user(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    cutoff_ms: i64,
+) -> anyhow::Result<i64> {
+    let min_ack: Option<i64> = sqlx::query_scalar(
+        r#"SELECT MIN(last_ack_server_seq)
+           FROM devices
+           WHERE user_id = ? AND last_seen_at_ms_utc >= ?"#,
+    )
+    .bind(user_id)
+    .bind(cutoff_ms)
+    .fetch_one(&mut **tx)
+    .await?;
+    let max_seq = min_ack.unwrap_or(i64::MAX);
+
+    let horizon: Option<i64> = sqlx::query_scalar(
+        r#"SELECT MAX(server_seq)
+           FROM records
+           WHERE user_id = ?
+             AND deleted_at_ms_utc IS NOT NULL
+             AND updated_at_ms_utc < ?
+             AND server_seq <= ?"#,
+    )
+    .bind(user_id)
+    .bind(cutoff_ms)
+    .bind(max_seq)
+    .fetch_one(&mut **tx)
+    .await?;
+    let Some(horizon) = horizon else {
+        return Ok(0);
+    };
+
+    sqlx::query(
+        r#"INSERT INTO purged_tombstones (
+             user_id, type, record_id, hlc_wall_ms_utc, hlc_counter, hlc_device_id
+           )
+           SELECT user_id, type, record_id, hlc_wall_ms_utc, hlc_counter, hlc_device_id
+           FROM records
+           WHERE user_id = ?
+             AND deleted_at_ms_utc IS NOT NULL
+             AND updated_at_ms_utc < ?
+             AND server_seq <= ?
+           ON CONFLICT(user_id, type, record_id) DO UPDATE SET
+             hlc_wall_ms_utc = excluded.hlc_wall_ms_utc,
+             hlc_counter = excluded.hlc_counter,
+             hlc_device_id = excluded.hlc_device_id"#,
+    )
+    .bind(user_id)
+    .bind(cutoff_ms)
+    .bind(max_seq)
+    .execute(&mut **tx)
+    .await?;
+
+    let purged = sqlx::query(
+        r#"DELETE FROM records
+           WHERE user_id = ?
+             AND deleted_at_ms_utc IS NOT NULL
+             AND updated_at_ms_utc < ?
+             AND server_seq <= ?"#,
+    )
+    .bind(user_id)
+    .bind(cutoff_ms)
+    .bind(max_seq)
+    .execute(&mut **tx)
+    .await?
+    .rows_affected() as i64;
+
+    sqlx::query(
+        r#"UPDATE server_seq
+           SET purge_horizon_seq = MAX(purge_horizon_seq, ?)
+           WHERE user_id = ?"#,
+    )
+    .bind(horizon)
+    .bind(user_id)
+    .execute(&mut **tx)
+    .await?;
+
+    recompute_and_store_user_b64(tx, user_id).await?;
+    Ok(purged)
+}
+
+/// Highest purged tombstone `serverSeq` for the user (0 if nothing was purged).
+pub(crate) async fn purge_horizon(db: &Pool<Sqlite>, user_id: i64) -> Result<i64, sqlx::Error> {
+    let seq: Option<i64> =
+        sqlx::query_scalar(r#"SELECT purge_horizon_seq FROM server_seq WHERE user_id = ?"#)
+            .bind(user_id)
+            .fetch_optional(db)
+            .await?;
+    Ok(seq.unwrap_or(0))
+}
+
+/// HLC of the delete GC purged for this key, if any.
+pub(crate) async fn purged_tombstone_hlc(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    record_type: &str,
+    record_id: &str,
+) -> Result<Option<Hlc>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"SELECT hlc_wall_ms_utc, hlc_counter, hlc_device_id
+           FROM purged_tombstones
+           WHERE user_id = ? AND type = ? AND record_id = ?"#,
+    )
+    .bind(user_id)
+    .bind(record_type)
+    .bind(record_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+    row.map(|row| {
+        Ok(Hlc {
+            wall_time_ms_utc: row.try_get("hlc_wall_ms_utc")?,
+            counter: row.try_get("hlc_counter")?,
+            device_id: row.try_get("hlc_device_id")?,
+        })
+    })
+    .transpose()
+}
@@ -6,7 +6,9 @@ use rand::RngCore;
 use serde::de::Deserializer;
 use serde::{Deserialize, Serialize};
 use sqlx::{QueryBuilder, Row, Sqlite};
+use tracing::error;
 
+use crate::rollback::{rollback_account, RollbackTarget};
 use crate::{
     clear_subscription_if_expired, compute_effective_quota, json_error, now_ms_utc,
     reset_user_api_outbound_if_new_month, AppState, ErrorBody, UserBillingRow,
@@ -15,19 +17,14 @@ use crate::{
 use super::admin_session::authenticate_admin;
 use super::util::check_same_origin;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 enum PatchField<T> {
+    #[default]
     Missing,
     Clear,
     Value(T),
 }
 
-impl<T> Default for PatchField<T> {
-    fn default() -> Self {
-        Self::Missing
-    }
-}
-
 impl<'de, T> Deserialize<'de> for PatchField<T>
 where
     T: Deserialize<'de>,
@@ -405,7 +402,11 @@ pub(super) async fn admin_get_user(
              oauth_sub,
              created_at_ms_utc,
              banned_at_ms_utc,
-             stored_b64,
+             stored_b64 + (
+               SELECT IFNULL(SUM(x.stored_b64), 0)
+               FROM spaces sp JOIN users x ON x.id = sp.user_id
+               WHERE sp.owner_user_id = users.id
+             ) AS stored_b64,
              api_outbound_bytes,
              base_storage_b64,
              base_outbound_bytes,
@@ -482,6 +483,7 @@ pub(super) async fn admin_get_user(
         banned_at_ms_utc,
         stored_b64,
         api_outbound_bytes,
+        other_stored_b64: 0,
     };
     let quota = compute_effective_quota(&state.billing, &user_billing, now_ms);
 
@@ -754,3 +756,63 @@ pub(super) async fn admin_update_user(
 
     Ok(Json(OkResponse { ok: true }))
 }
+
+#[derive(Debug, Deserialize)]
+pub(super) struct AdminRollbackUserRequest {
+    #[serde(rename = "userId")]
+    user_id: i64,
+    #[serde(rename = "serverSeq")]
+    server_seq: Option<i64>,
+    #[serde(rename = "atMsUtc")]
+    at_ms_utc: Option<i64>,
+}
+
+pub(super) async fn admin_rollback_user(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<std::net::SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<AdminRollbackUserRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
+    {
+        let mut limiter = state.admin_limiter.lock().await;
+        if !limiter.check(&format!("admin:users:rollback:{}", addr.ip())) {
+            return Err(json_error(StatusCode::TOO_MANY_REQUESTS, "rate limited"));
+        }
+    }
+    authenticate_admin(&state, &headers)?;
+    if !check_same_origin(&state, &headers) {
+        return Err(json_error(StatusCode::FORBIDDEN, "forbidden"));
+    }
+
+    let Some(target) = RollbackTarget::from_parts(req.server_seq, req.at_ms_utc) else {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_rollback_target",
+        ));
+    };
+
+    let exists: Option<i64> = sqlx::query_scalar(r#"SELECT id FROM users WHERE id = ?"#)
+        .bind(req.user_id)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if exists.is_none() {
+        return Err(json_error(StatusCode::NOT_FOUND, "user_not_found"));
+    }
+
+    let report = rollback_account(
+        &state.db,
+        req.user_id,
+        target,
+        state.record_history_limit,
+        now_ms_utc(),
+    )
+    .await
+    .map_err(|e| {
+        error!(error = %e, "admin account rollback failed");
+        json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error")
+    })?;
+    state.notifier.publish(req.user_id, report.server_seq);
+
+    Ok(Json(report))
+}
@@ -8,7 +8,7 @@ use axum::routing::{get, post};
 use axum::{Form, Json, Router};
 use serde::Deserialize;
 
-use crate::{json_error, now_ms_utc, AppState, ErrorBody};
+use crate::{json_error, now_ms_utc, spaces, AppState, ErrorBody};
 
 use super::admin_api;
 use super::admin_cdkeys;
@@ -82,6 +82,10 @@ pub(super) fn admin_router(admin_entry_path: &str) -> Router<AppState> {
             &format!("{base}/api/users/update"),
             post(admin_api::admin_update_user),
         )
+        .route(
+            &format!("{base}/api/users/rollback"),
+            post(admin_api::admin_rollback_user),
+        )
 }
 
 pub(super) fn admin_nav(base: &str) -> String {
@@ -348,10 +352,12 @@ pub(super) async fn admin_dashboard_page(
     .await
     .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-    let users_count: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM users"#)
-        .fetch_one(&state.db)
-        .await
-        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let users_count: i64 =
+        sqlx::query_scalar(r#"SELECT COUNT(*) FROM users WHERE oauth_provider != ?"#)
+            .bind(spaces::SPACE_OAUTH_PROVIDER)
+            .fetch_one(&state.db)
+            .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
     let cdkeys_count: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM cdkeys"#)
         .fetch_one(&state.db)
@@ -359,7 +365,9 @@ pub(super) async fn admin_dashboard_page(
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
     let total_b64: i64 = sqlx::query_scalar(
-        r#"SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext)), 0) FROM records"#,
+        r#"SELECT
+             (SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext) + IFNULL(ciphertext_len, 0)), 0) FROM records)
+           + (SELECT IFNULL(SUM(LENGTH(ciphertext) + IFNULL(ciphertext_len, 0)), 0) FROM chunk_blobs)"#,
     )
     .fetch_one(&state.db)
     .await
@@ -8,7 +8,7 @@ use axum::Json;
 use sqlx::Row;
 
 use crate::{
-    json_error, now_ms_utc, reset_all_users_api_outbound_if_new_month, AppState, ErrorBody,
+    json_error, now_ms_utc, reset_all_users_api_outbound_if_new_month, spaces, AppState, ErrorBody,
 };
 
 use super::admin_pages::admin_nav;
@@ -59,13 +59,17 @@ pub(super) async fn admin_users_page(
     .await
     .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
-    let users_count: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM users"#)
-        .fetch_one(&state.db)
-        .await
-        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let users_count: i64 =
+        sqlx::query_scalar(r#"SELECT COUNT(*) FROM users WHERE oauth_provider != ?"#)
+            .bind(spaces::SPACE_OAUTH_PROVIDER)
+            .fetch_one(&state.db)
+            .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
     let total_b64: i64 = sqlx::query_scalar(
-        r#"SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext)), 0) FROM records"#,
+        r#"SELECT
+             (SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext) + IFNULL(ciphertext_len, 0)), 0) FROM records)
+           + (SELECT IFNULL(SUM(LENGTH(ciphertext) + IFNULL(ciphertext_len, 0)), 0) FROM chunk_blobs)"#,
     )
     .fetch_one(&state.db)
     .await
@@ -82,9 +86,11 @@ pub(super) async fn admin_users_page(
              subscription_plan_id,
              subscription_expires_at_ms_utc
            FROM users
+           WHERE oauth_provider != ?
            ORDER BY id DESC
            LIMIT 50"#,
     )
+    .bind(spaces::SPACE_OAUTH_PROVIDER)
     .fetch_all(&state.db)
     .await
     .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
@@ -157,7 +163,7 @@ pub(super) async fn admin_users_page(
 <main class="mx-auto max-w-6xl px-4 pb-20 pt-14">
   <div class="space-y-3">
     <h1 class="text-3xl font-semibold tracking-tight heading-grad">用户管理</h1>
-    <p class="text-sm muted">查询/修改用户配额、订阅与封禁状态</p>
+    <p class="text-sm muted">查询/修改用户配额、订阅与封禁状态，或回滚用户数据</p>
   </div>
 
   <div class="mt-10 grid gap-4 md:grid-cols-4">
@@ -222,6 +228,20 @@ pub(super) async fn admin_users_page(
       </label>
     </div>
 
+    <div id="rollback-form" class="mt-5 hidden grid gap-3 md:grid-cols-3">
+      <label class="block">
+        <span class="text-xs font-medium subtle">回滚到服务器序号</span>
+        <input id="rollback-seq" type="number" min="0" class="input mt-2 font-mono text-sm" />
+      </label>
+      <label class="block">
+        <span class="text-xs font-medium subtle">或回滚到时间点</span>
+        <input id="rollback-at" type="datetime-local" class="input mt-2 text-sm" />
+      </label>
+      <div class="flex items-end">
+        <button id="btn-rollback-user" class="btn btn-secondary h-11 w-full" type="button">回滚账户</button>
+      </div>
+    </div>
+
     <p id="user-hint" class="mt-4 hidden text-sm text-emerald-700 dark:text-emerald-300"></p>
     <p id="user-error" class="mt-4 hidden text-sm text-rose-600 dark:text-rose-400"></p>
     <pre id="user-raw" class="codeblock mt-4 hidden overflow-x-auto text-xs"></pre>
@@ -268,6 +288,10 @@ pub(super) async fn admin_users_page(
   const userHint = document.getElementById('user-hint');
   const userErr = document.getElementById('user-error');
   const userRaw = document.getElementById('user-raw');
+  const rollbackForm = document.getElementById('rollback-form');
+  const rollbackSeq = document.getElementById('rollback-seq');
+  const rollbackAt = document.getElementById('rollback-at');
+  const btnRollback = document.getElementById('btn-rollback-user');
 
   function show(el, on) {{
     el?.classList.toggle('hidden', !on);
@@ -410,6 +434,7 @@ pub(super) async fn admin_users_page(
     btnUpdate.disabled = true;
     btnUpdate.classList.add('opacity-50');
     userForm.classList.add('hidden');
+    rollbackForm.classList.add('hidden');
     const id = Number(userId?.value || '0');
     if (!id) {{
       userErr.textContent = 'user id required';
@@ -428,6 +453,7 @@ pub(super) async fn admin_users_page(
       userRaw.textContent = JSON.stringify(data, null, 2);
       show(userRaw, true);
       userForm.classList.remove('hidden');
+      rollbackForm.classList.remove('hidden');
       btnUpdate.disabled = false;
       btnUpdate.classList.remove('opacity-50');
     }} catch (e) {{
@@ -479,6 +505,46 @@ pub(super) async fn admin_users_page(
     }}
   }});
 
+  btnRollback?.addEventListener('click', async () => {{
+    show(userHint, false);
+    show(userErr, false);
+    const id = Number(userId?.value || '0');
+    if (!id) {{
+      userErr.textContent = 'user id required';
+      show(userErr, true);
+      return;
+    }}
+    const payload = {{ userId: id }};
+    if (String(rollbackSeq.value || '').trim() !== '') {{
+      payload.serverSeq = Number(rollbackSeq.value);
+    }} else {{
+      const atMs = localInputValueToMs(rollbackAt.value);
+      if (atMs === null) {{
+        userErr.textContent = '请输入服务器序号或时间点';
+        show(userErr, true);
+        return;
+      }}
+      payload.atMsUtc = atMs;
+    }}
+    if (!confirm(`确定要回滚用户 ${{id}} 的数据吗？`)) return;
+
+    btnRollback.disabled = true;
+    btnRollback.classList.add('opacity-50');
+    try {{
+      const data = await postJson(`${{base}}/api/users/rollback`, payload);
+      userHint.textContent = `已回滚到序号 ${{data.targetServerSeq}}：恢复 ${{data.republished}} 条，删除 ${{data.tombstoned}} 条，无法恢复 ${{data.unrestorableCount}} 条`;
+      show(userHint, true);
+      userRaw.textContent = JSON.stringify(data, null, 2);
+      show(userRaw, true);
+    }} catch (e) {{
+      userErr.textContent = e?.message || 'rollback failed';
+      show(userErr, true);
+    }} finally {{
+      btnRollback.disabled = false;
+      btnRollback.classList.remove('opacity-50');
+    }}
+  }});
+
   function fmtCells() {{
     for (const el of document.querySelectorAll('[data-ms]')) {{
       const ms = Number(el.dataset.ms || '0');
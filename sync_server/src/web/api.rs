@@ -1,13 +1,19 @@
 use std::net::SocketAddr;
 
-use axum::extract::{ConnectInfo, State};
-use axum::http::{HeaderMap, StatusCode};
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use serde::{Deserialize, Serialize};
 use sqlx::Row;
+use tracing::error;
 
-use crate::{clear_subscription_if_expired, json_error, now_ms_utc, AppState, ErrorBody};
+use crate::rollback::{rollback_account, RollbackTarget};
+use crate::{
+    clear_subscription_if_expired, compute_effective_quota, identities, json_error,
+    load_user_billing, now_ms_utc, reset_user_api_outbound_if_new_month, shares, AppState,
+    ErrorBody,
+};
 
 use super::session::{apply_set_cookie_headers, authenticate_web, clear_auth_cookies};
 use super::util::check_same_origin;
@@ -324,3 +330,223 @@ pub(super) async fn web_gc_ghost_files(
     }
     Ok(resp)
 }
+
+#[derive(Debug, Deserialize)]
+pub(super) struct RollbackRequest {
+    #[serde(rename = "serverSeq")]
+    server_seq: Option<i64>,
+    #[serde(rename = "atMsUtc")]
+    at_ms_utc: Option<i64>,
+}
+
+pub(super) async fn web_rollback(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<RollbackRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorBody>)> {
+    if !check_same_origin(&state, &headers) {
+        return Err(json_error(StatusCode::FORBIDDEN, "forbidden"));
+    }
+
+    let (user_id, maybe_set_cookies) = authenticate_web(&state, &headers, Some(addr.ip())).await?;
+
+    let Some(target) = RollbackTarget::from_parts(req.server_seq, req.at_ms_utc) else {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "invalid rollback target",
+        ));
+    };
+
+    let banned_at: Option<Option<i64>> =
+        sqlx::query_scalar(r#"SELECT banned_at_ms_utc FROM users WHERE id = ?"#)
+            .bind(user_id)
+            .fetch_optional(&state.db)
+            .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let Some(banned_at) = banned_at else {
+        return Err(json_error(StatusCode::UNAUTHORIZED, "unauthorized"));
+    };
+    if banned_at.is_some_and(|ms| ms > 0) {
+        return Err(json_error(StatusCode::FORBIDDEN, "banned"));
+    }
+
+    let report = rollback_account(
+        &state.db,
+        user_id,
+        target,
+        state.record_history_limit,
+        now_ms_utc(),
+    )
+    .await
+    .map_err(|e| {
+        error!(error = %e, "account rollback failed");
+        json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error")
+    })?;
+    state.notifier.publish(user_id, report.server_seq);
+
+    let mut resp = Json(report).into_response();
+    if let Some(set) = maybe_set_cookies {
+        apply_set_cookie_headers(resp.headers_mut(), set);
+    }
+    Ok(resp)
+}
+
+/// The encrypted snapshot behind a share link, for the public `/s/{shareId}`
+/// page. Each call counts as a view and the bytes count against the owner's
+/// outbound quota.
+pub(super) async fn web_share_snapshot(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(share_id): Path<String>,
+) -> Result<Response, (StatusCode, Json<ErrorBody>)> {
+    {
+        let mut limiter = state.auth_limiter.lock().await;
+        if !limiter.check(&format!("share_view:{}", addr.ip())) {
+            return Err(json_error(StatusCode::TOO_MANY_REQUESTS, "rate limited"));
+        }
+    }
+    if !shares::is_valid_share_id(&share_id) {
+        return Err(json_error(StatusCode::NOT_FOUND, "share not found"));
+    }
+
+    let now_ms = now_ms_utc();
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let (user_id, snapshot) = match shares::open(&mut tx, &share_id, now_ms)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+    {
+        shares::Open::Found { user_id, snapshot } => (user_id, snapshot),
+        shares::Open::NotFound => {
+            tx.rollback().await.ok();
+            return Err(json_error(StatusCode::NOT_FOUND, "share not found"));
+        }
+        shares::Open::Gone => {
+            tx.rollback().await.ok();
+            return Err(json_error(StatusCode::GONE, "share gone"));
+        }
+    };
+
+    reset_user_api_outbound_if_new_month(&mut *tx, user_id, now_ms)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let Some(user_billing) = load_user_billing(&mut tx, user_id, now_ms)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?
+    else {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::NOT_FOUND, "share not found"));
+    };
+    if user_billing.banned_at_ms_utc.is_some_and(|ms| ms > 0) {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::GONE, "share gone"));
+    }
+
+    let bytes = serde_json::to_vec(&snapshot)
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "serialize error"))?;
+    let bytes_len = bytes.len() as i64;
+    let quota = compute_effective_quota(&state.billing, &user_billing, now_ms);
+    if quota
+        .allowed_outbound_bytes
+        .is_some_and(|max| user_billing.api_outbound_bytes.saturating_add(bytes_len) > max)
+    {
+        tx.rollback().await.ok();
+        return Err(json_error(StatusCode::PAYMENT_REQUIRED, "quota_exceeded"));
+    }
+    sqlx::query(r#"UPDATE users SET api_outbound_bytes = api_outbound_bytes + ? WHERE id = ?"#)
+        .bind(bytes_len)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/json"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        bytes,
+    )
+        .into_response())
+}
+
+pub(super) async fn web_revoke_share(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(share_id): Path<String>,
+) -> Result<Response, (StatusCode, Json<ErrorBody>)> {
+    if !check_same_origin(&state, &headers) {
+        return Err(json_error(StatusCode::FORBIDDEN, "forbidden"));
+    }
+
+    let (user_id, maybe_set_cookies) = authenticate_web(&state, &headers, Some(addr.ip())).await?;
+
+    let revoked = shares::revoke(&state.db, user_id, &share_id, now_ms_utc())
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    if !revoked {
+        return Err(json_error(StatusCode::NOT_FOUND, "share not found"));
+    }
+
+    let mut resp = Json(OkResponse { ok: true }).into_response();
+    if let Some(set) = maybe_set_cookies {
+        apply_set_cookie_headers(resp.headers_mut(), set);
+    }
+    Ok(resp)
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct UnlinkIdentityRequest {
+    provider: String,
+    sub: String,
+}
+
+pub(super) async fn web_unlink_identity(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<UnlinkIdentityRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorBody>)> {
+    if !check_same_origin(&state, &headers) {
+        return Err(json_error(StatusCode::FORBIDDEN, "forbidden"));
+    }
+
+    let (user_id, maybe_set_cookies) = authenticate_web(&state, &headers, Some(addr.ip())).await?;
+
+    let mut tx = state
+        .db
+        .begin()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let outcome = identities::unlink(&mut tx, user_id, &req.provider, &req.sub)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    match outcome {
+        identities::UnlinkOutcome::Unlinked => {}
+        identities::UnlinkOutcome::NotFound => {
+            tx.rollback().await.ok();
+            return Err(json_error(StatusCode::NOT_FOUND, "identity not found"));
+        }
+        identities::UnlinkOutcome::LastIdentity => {
+            tx.rollback().await.ok();
+            return Err(json_error(StatusCode::CONFLICT, "last identity"));
+        }
+    }
+    tx.commit()
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+
+    let mut resp = Json(OkResponse { ok: true }).into_response();
+    if let Some(set) = maybe_set_cookies {
+        apply_set_cookie_headers(resp.headers_mut(), set);
+    }
+    Ok(resp)
+}
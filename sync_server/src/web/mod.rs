@@ -15,6 +15,8 @@ use axum::Router;
 
 use crate::AppState;
 
+pub(crate) use session::{apply_set_cookie_headers, authenticate_web, cookie_value};
+
 pub fn web_router(admin_entry_path: String) -> Router<AppState> {
     Router::new()
         .route("/favicon.png", get(pages::favicon_png))
@@ -26,8 +28,19 @@ pub fn web_router(admin_entry_path: String) -> Router<AppState> {
         .route("/web/api/me", get(api::web_me))
         .route("/web/api/me/activate-cdkey", post(api::web_activate_cdkey))
         .route("/web/api/me/gc-ghost-files", post(api::web_gc_ghost_files))
+        .route("/web/api/me/rollback", post(api::web_rollback))
         .route("/web/api/me/delete", post(api::web_delete_me))
+        .route(
+            "/web/api/me/identities/unlink",
+            post(api::web_unlink_identity),
+        )
+        .route(
+            "/web/api/me/shares/:share_id/revoke",
+            post(api::web_revoke_share),
+        )
         .route("/web/api/auth/refresh", post(api::web_refresh))
+        .route("/s/:share_id", get(pages::share_page))
+        .route("/web/api/shares/:share_id", get(api::web_share_snapshot))
         .merge(admin_pages::admin_router(&admin_entry_path))
         .fallback(pages::fallback_page)
 }
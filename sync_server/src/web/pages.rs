@@ -2,7 +2,7 @@ use std::net::SocketAddr;
 use std::time::Duration;
 
 use axum::body::Body;
-use axum::extract::{ConnectInfo, OriginalUri, Query, State};
+use axum::extract::{ConnectInfo, OriginalUri, Path, Query, State};
 use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::{Html, IntoResponse, Redirect, Response};
 use axum::Json;
@@ -10,8 +10,9 @@ use serde::Deserialize;
 use sqlx::Row;
 
 use crate::{
-    clear_subscription_if_expired, compute_effective_quota, json_error, now_ms_utc,
-    reset_user_api_outbound_if_new_month, AppState, ErrorBody, UserBillingRow,
+    clear_subscription_if_expired, compute_effective_quota, current_server_seq, devices,
+    identities, json_error, key_slots, now_ms_utc, reset_user_api_outbound_if_new_month, shares,
+    spaces, AppState, ErrorBody, UserBillingRow,
 };
 
 use super::layout::{nav_bar, page_shell, stat_card, stat_card_ms, stat_card_ms_opt};
@@ -41,10 +42,12 @@ pub(super) struct LoginQuery {
 pub(super) async fn home_page(
     State(state): State<AppState>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<ErrorBody>)> {
-    let users_count: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM users"#)
-        .fetch_one(&state.db)
-        .await
-        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let users_count: i64 =
+        sqlx::query_scalar(r#"SELECT COUNT(*) FROM users WHERE oauth_provider != ?"#)
+            .bind(spaces::SPACE_OAUTH_PROVIDER)
+            .fetch_one(&state.db)
+            .await
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
     let records_count: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM records"#)
         .fetch_one(&state.db)
@@ -52,7 +55,9 @@ pub(super) async fn home_page(
         .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
     let total_b64: i64 = sqlx::query_scalar(
-        r#"SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext)), 0) FROM records"#,
+        r#"SELECT
+             (SELECT IFNULL(SUM(LENGTH(nonce) + LENGTH(ciphertext) + IFNULL(ciphertext_len, 0)), 0) FROM records)
+           + (SELECT IFNULL(SUM(LENGTH(ciphertext) + IFNULL(ciphertext_len, 0)), 0) FROM chunk_blobs)"#,
     )
     .fetch_one(&state.db)
     .await
@@ -99,7 +104,7 @@ pub(super) async fn home_page(
             .into_iter()
             .map(|plan| {
                 let duration_secs = (plan.duration_ms / 1000).max(0) as u64;
-                let duration_display = if duration_secs % 86400 == 0 {
+                let duration_display = if duration_secs.is_multiple_of(86400) {
                     format!("{} 天", duration_secs / 86400)
                 } else {
                     format_uptime(Duration::from_secs(duration_secs))
@@ -324,9 +329,16 @@ pub(super) async fn dashboard_login_page(
     Ok(Html(page_shell("登录仪表盘", &body)))
 }
 
+#[derive(Debug, Deserialize)]
+pub(super) struct DashboardQuery {
+    /// Outcome of a "link another provider" round trip.
+    link: Option<String>,
+}
+
 pub(super) async fn dashboard_page(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(q): Query<DashboardQuery>,
     headers: HeaderMap,
 ) -> Result<Response, (StatusCode, Json<ErrorBody>)> {
     let auth = authenticate_web(&state, &headers, Some(addr.ip())).await;
@@ -351,7 +363,11 @@ pub(super) async fn dashboard_page(
              subscription_plan_id,
              subscription_expires_at_ms_utc,
              banned_at_ms_utc,
-             stored_b64,
+             stored_b64 + (
+               SELECT IFNULL(SUM(x.stored_b64), 0)
+               FROM spaces sp JOIN users x ON x.id = sp.user_id
+               WHERE sp.owner_user_id = users.id
+             ) AS stored_b64,
              api_outbound_bytes
            FROM users
            WHERE id = ?"#,
@@ -427,6 +443,39 @@ pub(super) async fn dashboard_page(
             .await
             .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
 
+    let server_seq = current_server_seq(&state.db, user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let device_list = devices::list_devices(&state.db, user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let identity_list = identities::list(&state.db, user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let share_list = shares::list(&state.db, user_id, now_ms)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let slot_kinds = key_slots::kind_counts(&state.db, user_id)
+        .await
+        .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "db error"))?;
+    let key_slots_display = if slot_kinds.is_empty() {
+        "未设置".to_string()
+    } else {
+        slot_kinds
+            .iter()
+            .map(|(kind, count)| {
+                let label = match kind.as_str() {
+                    key_slots::KIND_PASSPHRASE => "口令",
+                    key_slots::KIND_RECOVERY_CODE => "恢复码",
+                    key_slots::KIND_DEVICE => "设备密钥",
+                    other => other,
+                };
+                format!("{label} ×{count}")
+            })
+            .collect::<Vec<_>>()
+            .join(" · ")
+    };
+
     let user_billing = UserBillingRow {
         base_storage_b64,
         base_outbound_bytes,
@@ -435,6 +484,7 @@ pub(super) async fn dashboard_page(
         banned_at_ms_utc,
         stored_b64,
         api_outbound_bytes,
+        other_stored_b64: 0,
     };
     let quota = compute_effective_quota(&state.billing, &user_billing, now_ms);
 
@@ -723,8 +773,289 @@ pub(super) async fn dashboard_page(
         btn_attr = if cdkey_disabled { "disabled" } else { "" },
     );
 
-    let ghost_gc_section = format!(
+    let mut device_rows = String::new();
+    for d in &device_list {
+        let behind = server_seq.saturating_sub(d.last_ack_server_seq).max(0);
+        let name = d.name.as_deref().unwrap_or("—");
+        device_rows.push_str(&format!(
+            r#"<tr class="table-row">
+  <td class="px-3 py-2 text-xs">{name}</td>
+  <td class="px-3 py-2 font-mono text-xs">{device_id}</td>
+  <td class="px-3 py-2 text-xs font-mono" data-ms="{first_seen}">—</td>
+  <td class="px-3 py-2 text-xs font-mono" data-ms="{last_seen}">—</td>
+  <td class="px-3 py-2 text-xs font-mono">{ack}</td>
+  <td class="px-3 py-2 text-xs font-mono {behind_class}">{behind}</td>
+</tr>"#,
+            name = h(name),
+            device_id = h(&d.device_id),
+            first_seen = d.first_seen_at_ms_utc,
+            last_seen = d.last_seen_at_ms_utc,
+            ack = format_number(d.last_ack_server_seq),
+            behind = format_number(behind),
+            behind_class = if behind > 0 {
+                "text-amber-700 dark:text-amber-300"
+            } else {
+                ""
+            },
+        ));
+    }
+    if device_rows.is_empty() {
+        device_rows.push_str(
+            r#"<tr class="table-row"><td class="px-3 py-2 text-xs subtle" colspan="6">暂无设备</td></tr>"#,
+        );
+    }
+    let devices_section = format!(
+        r#"<div class="mt-6 card p-6" data-spotlight>
+  <h2 class="text-base font-semibold">设备</h2>
+  <p class="mt-1 text-sm muted">当前服务器序号：<span class="font-mono">{server_seq}</span>。「落后」为尚未确认同步的变更数。</p>
+  <div class="table-wrap mt-4 overflow-x-auto">
+    <table class="table w-full text-left text-xs">
+      <thead class="subtle">
+        <tr>
+          <th class="px-3 py-2">名称</th>
+          <th class="px-3 py-2">设备ID</th>
+          <th class="px-3 py-2">首次出现</th>
+          <th class="px-3 py-2">最近活动</th>
+          <th class="px-3 py-2">已确认序号</th>
+          <th class="px-3 py-2">落后</th>
+        </tr>
+      </thead>
+      <tbody>
+        {device_rows}
+      </tbody>
+    </table>
+  </div>
+</div>"#,
+        server_seq = format_number(server_seq),
+        device_rows = device_rows,
+    );
+
+    let can_unlink = identity_list.len() > 1;
+    let mut identity_rows = String::new();
+    for ident in &identity_list {
+        let action = if can_unlink {
+            format!(
+                r#"<button class="btn btn-secondary h-8 px-3 text-xs" type="button" data-unlink-provider="{provider}" data-unlink-sub="{sub}">解除关联</button>"#,
+                provider = h(&ident.provider),
+                sub = h(&ident.sub),
+            )
+        } else {
+            String::new()
+        };
+        identity_rows.push_str(&format!(
+            r#"<tr class="table-row">
+  <td class="px-3 py-2 text-xs">{provider}</td>
+  <td class="px-3 py-2 font-mono text-xs">{sub}</td>
+  <td class="px-3 py-2 text-xs font-mono" data-ms="{linked}">—</td>
+  <td class="px-3 py-2 text-xs">{action}</td>
+</tr>"#,
+            provider = h(&provider_display_name(&state, &ident.provider)),
+            sub = h(&ident.sub),
+            linked = ident.linked_at_ms_utc,
+            action = action,
+        ));
+    }
+
+    let mut link_providers = state
+        .auth
+        .config
+        .enabled_providers
+        .iter()
+        .filter(|p| state.auth.config.providers.contains_key(*p))
+        .cloned()
+        .collect::<Vec<_>>();
+    link_providers.sort();
+    link_providers.dedup();
+    let link_buttons = link_providers
+        .iter()
+        .map(|p| {
+            let href = format!(
+                "/v1/auth/web/start?provider={}&return_to={}&intent=link",
+                url_encode(p),
+                url_encode("/dashboard"),
+            );
+            format!(
+                r#"<a class="btn btn-secondary" href="{href}">关联 {display}</a>"#,
+                href = h(&href),
+                display = h(&provider_display_name(&state, p)),
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n    ");
+
+    let (link_notice, link_notice_class) = match q.link.as_deref() {
+        Some("linked") => ("已关联新的登录方式", "text-emerald-700 dark:text-emerald-300"),
+        Some("already_linked") => ("该登录方式已关联到当前账户", "muted"),
+        Some("other_account") => (
+            "该登录方式已属于另一个账户，无法关联。如需合并，请先在另一个账户中解除关联或删除该账户。",
+            "text-rose-600 dark:text-rose-400",
+        ),
+        _ => ("", ""),
+    };
+
+    let identities_section = format!(
+        r#"<div class="mt-6 card p-6" data-spotlight>
+  <h2 class="text-base font-semibold">登录方式</h2>
+  <p class="mt-1 text-sm muted">使用以下任一方式登录都会进入同一个账户。至少需要保留一个登录方式。</p>
+  <p class="mt-3 text-sm {notice_class} {notice_hide}">{notice}</p>
+  <div class="table-wrap mt-4 overflow-x-auto">
+    <table class="table w-full text-left text-xs">
+      <thead class="subtle">
+        <tr>
+          <th class="px-3 py-2">Provider</th>
+          <th class="px-3 py-2">账户标识</th>
+          <th class="px-3 py-2">关联时间</th>
+          <th class="px-3 py-2"></th>
+        </tr>
+      </thead>
+      <tbody>
+        {identity_rows}
+      </tbody>
+    </table>
+  </div>
+  <div class="mt-4 flex flex-wrap items-center gap-3">
+    {link_buttons}
+  </div>
+  <p id="identity-error" class="mt-3 hidden text-sm text-rose-600 dark:text-rose-400"></p>
+</div>
+<script>
+(() => {{
+  const err = document.getElementById('identity-error');
+  for (const btn of document.querySelectorAll('button[data-unlink-provider]')) {{
+    btn.addEventListener('click', async () => {{
+      err.classList.add('hidden');
+      if (!confirm('确定要解除该登录方式的关联吗？')) return;
+      btn.disabled = true;
+      btn.classList.add('opacity-50');
+      try {{
+        const resp = await fetch('/web/api/me/identities/unlink', {{
+          method: 'POST',
+          headers: {{ 'Content-Type': 'application/json' }},
+          credentials: 'same-origin',
+          body: JSON.stringify({{ provider: btn.dataset.unlinkProvider, sub: btn.dataset.unlinkSub }}),
+        }});
+        const data = await resp.json().catch(() => ({{}}));
+        if (!resp.ok) throw new Error(data.error || 'unlink failed');
+        window.location.href = '/dashboard';
+      }} catch (e) {{
+        err.textContent = e?.message || 'unlink failed';
+        err.classList.remove('hidden');
+        btn.disabled = false;
+        btn.classList.remove('opacity-50');
+      }}
+    }});
+  }}
+}})();
+</script>"#,
+        notice = h(link_notice),
+        notice_class = link_notice_class,
+        notice_hide = if link_notice.is_empty() { "hidden" } else { "" },
+        identity_rows = identity_rows,
+        link_buttons = link_buttons,
+    );
+
+    let mut share_rows = String::new();
+    for sh in &share_list {
+        let status_label = match sh.status {
+            shares::STATUS_ACTIVE => "有效",
+            shares::STATUS_EXHAUSTED => "次数已用完",
+            shares::STATUS_REVOKED => "已撤销",
+            _ => "已过期",
+        };
+        let views = match sh.max_views {
+            Some(max) => format!("{}/{}", format_number(sh.view_count), format_number(max)),
+            None => format_number(sh.view_count),
+        };
+        let action = if sh.status == shares::STATUS_ACTIVE {
+            format!(
+                r#"<button class="btn btn-secondary h-8 px-3 text-xs" type="button" data-revoke-share="{id}">撤销</button>"#,
+                id = h(&sh.share_id),
+            )
+        } else {
+            String::new()
+        };
+        share_rows.push_str(&format!(
+            r#"<tr class="table-row">
+  <td class="px-3 py-2 font-mono text-xs">{share_id}</td>
+  <td class="px-3 py-2 text-xs">{status}</td>
+  <td class="px-3 py-2 text-xs font-mono" data-ms="{created}">—</td>
+  <td class="px-3 py-2 text-xs font-mono" data-ms="{expires}">—</td>
+  <td class="px-3 py-2 text-xs font-mono">{views}</td>
+  <td class="px-3 py-2 text-xs font-mono">{size}</td>
+  <td class="px-3 py-2 text-xs">{action}</td>
+</tr>"#,
+            share_id = h(&sh.share_id),
+            status = status_label,
+            created = sh.created_at_ms_utc,
+            expires = sh.expires_at_ms_utc,
+            views = h(&views),
+            size = h(&format_bytes(sh.size_b64)),
+            action = action,
+        ));
+    }
+    if share_rows.is_empty() {
+        share_rows.push_str(
+            r#"<tr class="table-row"><td class="px-3 py-2 text-xs subtle" colspan="7">暂无分享</td></tr>"#,
+        );
+    }
+    let shares_section = format!(
         r#"<div class="mt-6 card p-6" data-spotlight>
+  <h2 class="text-base font-semibold">分享链接</h2>
+  <p class="mt-1 text-sm muted">在应用中分享的待办。内容经端到端加密，密钥只在链接中，服务器无法查看；撤销后链接立即失效。</p>
+  <div class="table-wrap mt-4 overflow-x-auto">
+    <table class="table w-full text-left text-xs">
+      <thead class="subtle">
+        <tr>
+          <th class="px-3 py-2">分享ID</th>
+          <th class="px-3 py-2">状态</th>
+          <th class="px-3 py-2">创建时间</th>
+          <th class="px-3 py-2">过期时间</th>
+          <th class="px-3 py-2">查看次数</th>
+          <th class="px-3 py-2">大小</th>
+          <th class="px-3 py-2"></th>
+        </tr>
+      </thead>
+      <tbody>
+        {share_rows}
+      </tbody>
+    </table>
+  </div>
+  <p id="share-error" class="mt-3 hidden text-sm text-rose-600 dark:text-rose-400"></p>
+</div>
+<script>
+(() => {{
+  const err = document.getElementById('share-error');
+  for (const btn of document.querySelectorAll('button[data-revoke-share]')) {{
+    btn.addEventListener('click', async () => {{
+      err.classList.add('hidden');
+      if (!confirm('确定要撤销该分享链接吗？')) return;
+      btn.disabled = true;
+      btn.classList.add('opacity-50');
+      try {{
+        const id = encodeURIComponent(btn.dataset.revokeShare);
+        const resp = await fetch(`/web/api/me/shares/${{id}}/revoke`, {{
+          method: 'POST',
+          headers: {{ 'Content-Type': 'application/json' }},
+          credentials: 'same-origin',
+          body: JSON.stringify({{}}),
+        }});
+        const data = await resp.json().catch(() => ({{}}));
+        if (!resp.ok) throw new Error(data.error || 'revoke failed');
+        window.location.reload();
+      }} catch (e) {{
+        err.textContent = e?.message || 'revoke failed';
+        err.classList.remove('hidden');
+        btn.disabled = false;
+        btn.classList.remove('opacity-50');
+      }}
+    }});
+  }}
+}})();
+</script>"#,
+        share_rows = share_rows,
+    );
+
+    let ghost_gc_section = r#"<div class="mt-6 card p-6" data-spotlight>
   <h2 class="text-base font-semibold">幽灵文件清理</h2>
   <p class="mt-1 text-sm muted">清理“文件存在但不再被任何待办引用”的附件数据以释放存储。若你正在上传附件，可能会导致上传失败，需要重新上传。</p>
   <div class="mt-4 flex flex-wrap items-center gap-3">
@@ -734,21 +1065,21 @@ pub(super) async fn dashboard_page(
   </div>
 </div>
 <script>
-(() => {{
+(() => {
   const btn = document.getElementById('ghost-gc-btn');
   const hint = document.getElementById('ghost-gc-hint');
   const err = document.getElementById('ghost-gc-error');
-  function show(el, on) {{ el?.classList.toggle('hidden', !on); }}
-  function fmt(bytes) {{
+  function show(el, on) { el?.classList.toggle('hidden', !on); }
+  function fmt(bytes) {
     const n = Number(bytes || 0);
     if (!Number.isFinite(n) || n <= 0) return '0B';
     const units = ['B','KB','MB','GB','TB'];
     let v = n;
     let i = 0;
-    while (v >= 1024 && i < units.length - 1) {{ v /= 1024; i++; }}
-    return `${{v.toFixed(i === 0 ? 0 : 2)}}${{units[i]}}`;
-  }}
-  btn?.addEventListener('click', async () => {{
+    while (v >= 1024 && i < units.length - 1) { v /= 1024; i++; }
+    return `${v.toFixed(i === 0 ? 0 : 2)}${units[i]}`;
+  }
+  btn?.addEventListener('click', async () => {
     show(hint, false);
     show(err, false);
     hint.textContent = '';
@@ -757,31 +1088,98 @@ pub(super) async fn dashboard_page(
 
     btn.disabled = true;
     btn.classList.add('opacity-50');
-    try {{
-      const resp = await fetch('/web/api/me/gc-ghost-files', {{
+    try {
+      const resp = await fetch('/web/api/me/gc-ghost-files', {
         method: 'POST',
-        headers: {{ 'Content-Type': 'application/json' }},
+        headers: { 'Content-Type': 'application/json' },
         credentials: 'same-origin',
-        body: JSON.stringify({{}}),
-      }});
-      const data = await resp.json().catch(() => ({{}}));
+        body: JSON.stringify({}),
+      });
+      const data = await resp.json().catch(() => ({}));
       if (!resp.ok) throw new Error(data.error || 'gc failed');
       const n = data.deletedAttachments || 0;
       const freed = data.freedBytes || 0;
-      hint.textContent = `已清理 ${{n}} 个附件，释放 ${{fmt(freed)}}`;
+      hint.textContent = `已清理 ${n} 个附件，释放 ${fmt(freed)}`;
       show(hint, true);
       window.setTimeout(() => window.location.reload(), 700);
-    }} catch (e) {{
+    } catch (e) {
       err.textContent = e?.message || 'gc failed';
       show(err, true);
-    }} finally {{
+    } finally {
       btn.disabled = false;
       btn.classList.remove('opacity-50');
-    }}
-  }});
-}})();
-</script>"#,
-    );
+    }
+  });
+})();
+</script>"#.to_string();
+
+    let rollback_section = r#"<div class="mt-6 card p-6" data-spotlight>
+  <h2 class="text-base font-semibold">账户回滚</h2>
+  <p class="mt-1 text-sm muted">将所有记录恢复到指定服务器序号或时间点时的状态。恢复内容会作为新的修改同步到所有设备；之后新建的记录会被删除。附件内容无法恢复。</p>
+  <div class="mt-4 grid gap-3 sm:grid-cols-3">
+    <label class="block">
+      <span class="text-xs font-medium subtle">服务器序号</span>
+      <input id="rollback-seq" type="number" min="0" class="input mt-2 font-mono text-sm" placeholder="例如 1200" />
+    </label>
+    <label class="block">
+      <span class="text-xs font-medium subtle">或时间点</span>
+      <input id="rollback-at" type="datetime-local" class="input mt-2 text-sm" />
+    </label>
+    <div class="flex items-end">
+      <button id="rollback-btn" class="btn btn-secondary h-11 w-full" type="button">回滚</button>
+    </div>
+  </div>
+  <p id="rollback-hint" class="mt-3 hidden text-sm text-emerald-700 dark:text-emerald-300"></p>
+  <p id="rollback-error" class="mt-3 hidden text-sm text-rose-600 dark:text-rose-400"></p>
+</div>
+<script>
+(() => {
+  const btn = document.getElementById('rollback-btn');
+  const seq = document.getElementById('rollback-seq');
+  const at = document.getElementById('rollback-at');
+  const hint = document.getElementById('rollback-hint');
+  const err = document.getElementById('rollback-error');
+  function show(el, on) { el?.classList.toggle('hidden', !on); }
+  btn?.addEventListener('click', async () => {
+    show(hint, false);
+    show(err, false);
+    const payload = {};
+    if (String(seq.value || '').trim() !== '') {
+      payload.serverSeq = Number(seq.value);
+    } else if (at.value) {
+      payload.atMsUtc = new Date(at.value).getTime();
+    } else {
+      err.textContent = '请输入服务器序号或时间点';
+      show(err, true);
+      return;
+    }
+    if (!confirm('确定要回滚账户数据吗？')) return;
+
+    btn.disabled = true;
+    btn.classList.add('opacity-50');
+    try {
+      const resp = await fetch('/web/api/me/rollback', {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        credentials: 'same-origin',
+        body: JSON.stringify(payload),
+      });
+      const data = await resp.json().catch(() => ({}));
+      if (!resp.ok) throw new Error(data.error || 'rollback failed');
+      let text = `已回滚到序号 ${data.targetServerSeq}：恢复 ${data.republished} 条，删除 ${data.tombstoned} 条`;
+      if (data.unrestorableCount) text += `，${data.unrestorableCount} 条因历史不足无法恢复`;
+      hint.textContent = text;
+      show(hint, true);
+    } catch (e) {
+      err.textContent = e?.message || 'rollback failed';
+      show(err, true);
+    } finally {
+      btn.disabled = false;
+      btn.classList.remove('opacity-50');
+    }
+  });
+})();
+</script>"#;
 
     let body = format!(
         r#"
@@ -812,13 +1210,21 @@ pub(super) async fn dashboard_page(
         <dt class="text-xs font-medium subtle">OAuth Provider</dt>
         <dd class="mt-1 font-mono">{provider}</dd>
       </div>
+      <div class="subcard">
+        <dt class="text-xs font-medium subtle">密钥槽</dt>
+        <dd class="mt-1">{key_slots}</dd>
+      </div>
     </dl>
   </div>
 
+  {identities_section}
   {subscription_section}
+  {devices_section}
+  {shares_section}
   {quota_section}
   {cdkey_section}
   {ghost_gc_section}
+  {rollback_section}
 
   <div class="mt-6 rounded-2xl border border-rose-500/20 bg-rose-500/5 p-6 shadow-[0_0_0_1px_rgba(244,63,94,0.12),0_18px_50px_rgba(0,0,0,0.18)]">
     <div class="flex flex-wrap items-start justify-between gap-4">
@@ -926,6 +1332,11 @@ pub(super) async fn dashboard_page(
   fmt('created-at');
   fmt('last-sync');
   fmt('sub-exp');
+  for (const el of document.querySelectorAll('td[data-ms]')) {{
+    const ms = Number(el.dataset.ms || '0');
+    if (!ms) continue;
+    try {{ el.textContent = new Date(ms).toLocaleString(); }} catch {{}}
+  }}
 }})();
 </script>
 "#,
@@ -936,10 +1347,15 @@ pub(super) async fn dashboard_page(
         usage_card = usage_card,
         stat_last_sync = stat_card_ms_opt("最近同步", last_sync_at_ms, "last-sync"),
         provider = h(&oauth_provider_display),
+        key_slots = h(&key_slots_display),
+        identities_section = identities_section,
         subscription_section = subscription_section,
+        devices_section = devices_section,
+        shares_section = shares_section,
         quota_section = quota_section,
         cdkey_section = cdkey_section,
         ghost_gc_section = ghost_gc_section,
+        rollback_section = rollback_section,
     );
 
     let mut resp = Html(page_shell("仪表盘", &body)).into_response();
@@ -949,6 +1365,134 @@ pub(super) async fn dashboard_page(
     Ok(resp)
 }
 
+/// Public page for a share link. It ships no data: the script fetches the
+/// snapshot and decrypts it with the key from the `#fragment`, which browsers
+/// never send to the server.
+pub(super) async fn share_page(Path(share_id): Path<String>) -> Response {
+    if !shares::is_valid_share_id(&share_id) {
+        return (StatusCode::NOT_FOUND, Html(share_shell(None))).into_response();
+    }
+    (
+        [
+            (header::CACHE_CONTROL, "no-store"),
+            (header::REFERRER_POLICY, "no-referrer"),
+        ],
+        Html(share_shell(Some(&share_id))),
+    )
+        .into_response()
+}
+
+fn share_shell(share_id: Option<&str>) -> String {
+    let body = format!(
+        r#"
+{nav}
+<main class="mx-auto max-w-2xl px-4 pb-20 pt-14">
+  <div class="space-y-3">
+    <h1 class="text-3xl font-semibold tracking-tight heading-grad">分享的待办</h1>
+    <p class="text-sm muted">内容在你的浏览器中解密，服务器无法读取。</p>
+  </div>
+
+  <div class="mt-8 card p-6" data-spotlight>
+    <p id="share-status" class="text-sm muted">{status}</p>
+    <div id="share-content" class="hidden">
+      <div class="flex items-start justify-between gap-4">
+        <h2 id="share-title" class="text-lg font-semibold break-words"></h2>
+        <span id="share-done" class="badge hidden">已完成</span>
+      </div>
+      <p id="share-due" class="mt-2 hidden text-xs font-mono subtle"></p>
+      <p id="share-notes" class="mt-4 whitespace-pre-wrap break-words text-sm"></p>
+      <div id="share-attachments" class="mt-6 hidden space-y-3"></div>
+      <p id="share-meta" class="mt-6 text-xs subtle"></p>
+    </div>
+  </div>
+</main>
+<script>
+(() => {{
+  const shareId = {share_id_js};
+  if (!shareId) return;
+  const statusEl = document.getElementById('share-status');
+  const fail = (text) => {{ statusEl.textContent = text; }};
+  const bytes = (b64) => {{
+    const s = String(b64 || '').replace(/-/g, '+').replace(/_/g, '/');
+    const bin = atob(s + '='.repeat((4 - s.length % 4) % 4));
+    const out = new Uint8Array(bin.length);
+    for (let i = 0; i < bin.length; i++) out[i] = bin.charCodeAt(i);
+    return out;
+  }};
+  const rawKey = location.hash.replace(/^#/, '').replace(/^k=/, '');
+  if (!rawKey) {{ fail('链接缺少解密密钥'); return; }}
+  if (!window.crypto?.subtle) {{ fail('当前页面不支持解密（需要 HTTPS）'); return; }}
+
+  (async () => {{
+    statusEl.textContent = '正在加载…';
+    const resp = await fetch(`/web/api/shares/${{encodeURIComponent(shareId)}}`, {{ credentials: 'omit' }});
+    if (resp.status === 404) return fail('分享不存在');
+    if (resp.status === 410) return fail('分享已过期、已撤销或查看次数已用完');
+    if (!resp.ok) return fail('加载失败');
+    const data = await resp.json();
+
+    let todo;
+    try {{
+      const key = await crypto.subtle.importKey('raw', bytes(rawKey), 'AES-GCM', false, ['decrypt']);
+      const plain = await crypto.subtle.decrypt({{ name: 'AES-GCM', iv: bytes(data.nonce) }}, key, bytes(data.ciphertext));
+      todo = JSON.parse(new TextDecoder().decode(plain));
+    }} catch {{
+      return fail('解密失败：密钥不正确');
+    }}
+
+    document.getElementById('share-title').textContent = String(todo.title || '');
+    document.getElementById('share-notes').textContent = String(todo.notes || '');
+    if (todo.completed) document.getElementById('share-done').classList.remove('hidden');
+    if (todo.dueAtMsUtc) {{
+      const due = document.getElementById('share-due');
+      due.textContent = `截止：${{new Date(Number(todo.dueAtMsUtc)).toLocaleString()}}`;
+      due.classList.remove('hidden');
+    }}
+
+    const list = document.getElementById('share-attachments');
+    for (const a of Array.isArray(todo.attachments) ? todo.attachments : []) {{
+      const type = String(a.mimeType || 'application/octet-stream');
+      const url = URL.createObjectURL(new Blob([bytes(a.data)], {{ type }}));
+      const row = document.createElement('div');
+      row.className = 'subcard';
+      if (type.startsWith('image/')) {{
+        const img = document.createElement('img');
+        img.src = url;
+        img.alt = String(a.name || '');
+        img.className = 'mb-2 max-h-80 rounded-lg';
+        row.appendChild(img);
+      }}
+      const link = document.createElement('a');
+      link.className = 'link text-sm';
+      link.href = url;
+      link.download = String(a.name || 'attachment');
+      link.textContent = String(a.name || 'attachment');
+      row.appendChild(link);
+      list.appendChild(row);
+      list.classList.remove('hidden');
+    }}
+
+    const meta = [`有效期至 ${{new Date(data.expiresAtMsUtc).toLocaleString()}}`];
+    if (data.viewsLeft !== null && data.viewsLeft !== undefined) meta.push(`剩余查看次数 ${{data.viewsLeft}}`);
+    document.getElementById('share-meta').textContent = meta.join(' · ');
+    statusEl.classList.add('hidden');
+    document.getElementById('share-content').classList.remove('hidden');
+  }})().catch(() => fail('加载失败'));
+}})();
+</script>
+"#,
+        nav = nav_bar(None),
+        status = if share_id.is_some() {
+            "正在加载…"
+        } else {
+            "链接无效"
+        },
+        share_id_js = serde_json::to_string(&share_id.unwrap_or_default())
+            .unwrap_or_else(|_| "\"\"".to_string()),
+    );
+    page_shell("分享的待办", &body)
+}
+
 pub(super) async fn dashboard_logout(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
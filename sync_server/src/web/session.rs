@@ -6,7 +6,7 @@ use crate::{json_error, now_ms_utc, AppState, ErrorBody};
 const ACCESS_COOKIE: &str = "easy_todo_access";
 const REFRESH_COOKIE: &str = "easy_todo_refresh";
 
-pub(super) fn apply_set_cookie_headers(
+pub(crate) fn apply_set_cookie_headers(
     headers: &mut HeaderMap,
     set_cookie_values: Vec<HeaderValue>,
 ) {
@@ -15,7 +15,7 @@ pub(super) fn apply_set_cookie_headers(
     }
 }
 
-pub(super) fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+pub(crate) fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
     let raw = headers.get(header::COOKIE)?.to_str().ok()?;
     for part in raw.split(';') {
         let part = part.trim();
@@ -38,7 +38,7 @@ pub(super) fn clear_auth_cookies(state: &AppState) -> Vec<HeaderValue> {
     ]
 }
 
-pub(super) async fn authenticate_web(
+pub(crate) async fn authenticate_web(
     state: &AppState,
     headers: &HeaderMap,
     remote_ip: Option<std::net::IpAddr>,
@@ -97,20 +97,10 @@ fn build_auth_cookies(
     let secure = cookie_secure_flag(&state.auth.config.base_url);
     let refresh_max_age = state.auth.config.refresh_token_ttl.as_secs() as i64;
 
-    let mut out = Vec::new();
-    out.push(set_cookie(
-        ACCESS_COOKIE,
-        access_token,
-        access_max_age_secs,
-        secure,
-    ));
-    out.push(set_cookie(
-        REFRESH_COOKIE,
-        refresh_token,
-        refresh_max_age,
-        secure,
-    ));
-    out
+    vec![
+        set_cookie(ACCESS_COOKIE, access_token, access_max_age_secs, secure),
+        set_cookie(REFRESH_COOKIE, refresh_token, refresh_max_age, secure),
+    ]
 }
 
 fn set_cookie(name: &str, value: &str, max_age_secs: i64, secure: bool) -> HeaderValue {
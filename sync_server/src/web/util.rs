@@ -62,7 +62,7 @@ pub(super) fn format_bytes(b64_len: i64) -> String {
 }
 
 pub(super) fn format_uptime(d: Duration) -> String {
-    let secs = d.as_secs().max(0);
+    let secs = d.as_secs();
     let days = secs / 86400;
     let hours = (secs % 86400) / 3600;
     let mins = (secs % 3600) / 60;
@@ -0,0 +1,407 @@
+use std::io::{Read, Write};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, FromRequestParts, Request};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::Response;
+use axum::Json;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{json_error, AppState, ErrorBody};
+
+/// Responses smaller than this are sent uncompressed; the framing overhead isn't worth it.
+const MIN_COMPRESS_BYTES: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl Format {
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type.trim().to_ascii_lowercase().as_str() {
+            "application/json" => Some(Self::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Self::MsgPack)
+            }
+            "application/cbor" => Some(Self::Cbor),
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::MsgPack => "application/msgpack",
+            Self::Cbor => "application/cbor",
+        }
+    }
+
+    fn serialize<T: Serialize>(self, value: &T) -> Option<Vec<u8>> {
+        match self {
+            Self::Json => serde_json::to_vec(value).ok(),
+            Self::MsgPack => rmp_serde::to_vec_named(value).ok(),
+            Self::Cbor => {
+                let mut out = Vec::new();
+                ciborium::into_writer(value, &mut out).ok()?;
+                Some(out)
+            }
+        }
+    }
+
+    fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Option<T> {
+        match self {
+            Self::Json => serde_json::from_slice(bytes).ok(),
+            Self::MsgPack => rmp_serde::from_slice(bytes).ok(),
+            Self::Cbor => ciborium::from_reader(bytes).ok(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Coding {
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+impl Coding {
+    fn from_token(token: &str) -> Option<Self> {
+        match token.trim().to_ascii_lowercase().as_str() {
+            "identity" => Some(Self::Identity),
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    fn header_value(self) -> Option<&'static str> {
+        match self {
+            Self::Identity => None,
+            Self::Gzip => Some("gzip"),
+            Self::Zstd => Some("zstd"),
+        }
+    }
+
+    fn compress(self, bytes: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Identity => Ok(bytes),
+            Self::Gzip => {
+                let mut enc =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                enc.write_all(&bytes)?;
+                enc.finish()
+            }
+            Self::Zstd => zstd::stream::encode_all(bytes.as_slice(), 3),
+        }
+    }
+
+    /// Decompress, refusing to inflate past `max_len` bytes.
+    fn decompress(self, bytes: Bytes, max_len: usize) -> Result<Bytes, DecodeError> {
+        let reader: Box<dyn Read> = match self {
+            Self::Identity => return Ok(bytes),
+            Self::Gzip => Box::new(flate2::read::GzDecoder::new(&bytes[..])),
+            Self::Zstd => Box::new(
+                zstd::stream::read::Decoder::new(&bytes[..]).map_err(|_| DecodeError::Invalid)?,
+            ),
+        };
+        let mut out = Vec::new();
+        reader
+            .take(max_len as u64 + 1)
+            .read_to_end(&mut out)
+            .map_err(|_| DecodeError::Invalid)?;
+        if out.len() > max_len {
+            return Err(DecodeError::TooLarge);
+        }
+        Ok(Bytes::from(out))
+    }
+}
+
+enum DecodeError {
+    Invalid,
+    TooLarge,
+}
+
+/// Weighted list entries (`a;q=0.5, b`) in preference order, dropping `q=0`.
+fn preferred(header_value: &str) -> Vec<&str> {
+    let mut entries: Vec<(&str, f32, usize)> = header_value
+        .split(',')
+        .enumerate()
+        .filter_map(|(idx, part)| {
+            let mut params = part.split(';');
+            let value = params.next()?.trim();
+            if value.is_empty() {
+                return None;
+            }
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (q > 0.0).then_some((value, q, idx))
+        })
+        .collect();
+    entries.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.2.cmp(&b.2)));
+    entries.into_iter().map(|(value, _, _)| value).collect()
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> &str {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+}
+
+/// Response representation negotiated from `Accept` / `Accept-Encoding`.
+///
+/// Anything unrecognised falls back to uncompressed JSON, so existing clients
+/// see no change.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Negotiated {
+    format: Format,
+    coding: Coding,
+}
+
+impl Negotiated {
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Self {
+        let format = preferred(header_str(headers, header::ACCEPT))
+            .into_iter()
+            .find_map(Format::from_media_type)
+            .unwrap_or(Format::Json);
+        let coding = preferred(header_str(headers, header::ACCEPT_ENCODING))
+            .into_iter()
+            .find_map(Coding::from_token)
+            .unwrap_or(Coding::Identity);
+        Self { format, coding }
+    }
+
+    /// Like `json_bytes`, but in the negotiated representation. The returned
+    /// length is what actually goes on the wire, for outbound quota accounting.
+    pub(crate) fn encode<T: Serialize>(
+        self,
+        value: &T,
+    ) -> Result<(Response, i64), (StatusCode, Json<ErrorBody>)> {
+        let bytes = self
+            .format
+            .serialize(value)
+            .ok_or_else(|| json_error(StatusCode::INTERNAL_SERVER_ERROR, "serialize error"))?;
+        let coding = if bytes.len() >= MIN_COMPRESS_BYTES {
+            self.coding
+        } else {
+            Coding::Identity
+        };
+        let bytes = coding
+            .compress(bytes)
+            .map_err(|_| json_error(StatusCode::INTERNAL_SERVER_ERROR, "serialize error"))?;
+
+        let len = bytes.len() as i64;
+        let mut resp = Response::new(axum::body::Body::from(bytes));
+        let headers = resp.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(self.format.content_type()),
+        );
+        if let Some(encoding) = coding.header_value() {
+            headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        }
+        headers.insert(
+            header::VARY,
+            HeaderValue::from_static("accept, accept-encoding"),
+        );
+        Ok((resp, len))
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Negotiated {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_headers(&parts.headers))
+    }
+}
+
+/// Request body in JSON, MessagePack or CBOR (by `Content-Type`), optionally
+/// gzip/zstd compressed (by `Content-Encoding`).
+pub(crate) struct WireBody<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned> FromRequest<AppState> for WireBody<T> {
+    type Rejection = (StatusCode, Json<ErrorBody>);
+
+    async fn from_request(req: Request, state: &AppState) -> Result<Self, Self::Rejection> {
+        let headers = req.headers();
+        let content_type = header_str(headers, header::CONTENT_TYPE);
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+        let format = if media_type.is_empty() {
+            Format::Json
+        } else {
+            Format::from_media_type(media_type).ok_or_else(|| {
+                json_error(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "unsupported content type",
+                )
+            })?
+        };
+        let bytes = decoded_body(req, state, state.body_limit_bytes).await?;
+        let value = format
+            .deserialize(&bytes)
+            .ok_or_else(|| json_error(StatusCode::BAD_REQUEST, "invalid body"))?;
+        Ok(Self(value))
+    }
+}
+
+/// The request body with any `Content-Encoding` undone, inflated to at most
+/// `max_len` bytes. For endpoints that parse their own body format.
+pub(crate) async fn decoded_body(
+    req: Request,
+    state: &AppState,
+    max_len: usize,
+) -> Result<Bytes, (StatusCode, Json<ErrorBody>)> {
+    let content_encoding = header_str(req.headers(), header::CONTENT_ENCODING);
+    let coding = if content_encoding.trim().is_empty() {
+        Coding::Identity
+    } else {
+        Coding::from_token(content_encoding).ok_or_else(|| {
+            json_error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported content encoding",
+            )
+        })?
+    };
+
+    let bytes = Bytes::from_request(req, state).await.map_err(|rejection| {
+        let status = rejection.status();
+        if status == StatusCode::PAYLOAD_TOO_LARGE {
+            json_error(status, "payload too large")
+        } else {
+            json_error(StatusCode::BAD_REQUEST, "invalid body")
+        }
+    })?;
+    coding.decompress(bytes, max_len).map_err(|e| match e {
+        DecodeError::Invalid => json_error(StatusCode::BAD_REQUEST, "invalid body"),
+        DecodeError::TooLarge => json_error(StatusCode::PAYLOAD_TOO_LARGE, "payload too large"),
+    })
+}
+
+/// Serde adapter for base64 payload fields (`nonce`, `ciphertext`).
+///
+/// JSON keeps the base64url string as-is; binary formats carry the raw bytes
+/// so clients don't pay the 4/3 base64 overhead on the wire. Storage and quota
+/// accounting still work on the base64url form. A stored value that isn't
+/// base64 is sent as a string, which `deserialize` accepts too, so one bad
+/// record can't fail a whole pull page.
+pub(crate) mod b64_bytes {
+    use std::fmt;
+
+    use serde::de::{self, Visitor};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::{Engine, URL_SAFE_NO_PAD};
+
+    pub(crate) fn serialize<S: Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return serializer.serialize_str(value);
+        }
+        // Accept standard / padded input too, in case an older client stored it.
+        let normalized: String = value
+            .trim_end_matches('=')
+            .chars()
+            .map(|c| match c {
+                '+' => '-',
+                '/' => '_',
+                c => c,
+            })
+            .collect();
+        match URL_SAFE_NO_PAD.decode(normalized) {
+            Ok(raw) => serializer.serialize_bytes(&raw),
+            Err(_) => serializer.serialize_str(value),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<String, D::Error> {
+        if deserializer.is_human_readable() {
+            return String::deserialize(deserializer);
+        }
+        deserializer.deserialize_any(B64Visitor)
+    }
+
+    struct B64Visitor;
+
+    impl<'de> Visitor<'de> for B64Visitor {
+        type Value = String;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("bytes or a base64url string")
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<String, E> {
+            Ok(URL_SAFE_NO_PAD.encode(v))
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<String, E> {
+            Ok(v.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Payload {
+        #[serde(with = "b64_bytes")]
+        nonce: String,
+    }
+
+    #[test]
+    fn binary_formats_carry_raw_bytes() {
+        let value = Payload {
+            nonce: URL_SAFE_NO_PAD.encode([0xfb, 0xff, 0x00]),
+        };
+        for format in [Format::MsgPack, Format::Cbor] {
+            let bytes = format.serialize(&value).unwrap();
+            assert!(bytes.windows(3).any(|w| w == [0xfb, 0xff, 0x00]));
+            assert_eq!(format.deserialize::<Payload>(&bytes).unwrap(), value);
+        }
+        let json = Format::Json.serialize(&value).unwrap();
+        assert_eq!(json, br#"{"nonce":"-_8A"}"#);
+
+        let invalid = Payload {
+            nonce: "not base64!".to_string(),
+        };
+        for format in [Format::MsgPack, Format::Cbor] {
+            let bytes = format.serialize(&invalid).unwrap();
+            assert_eq!(format.deserialize::<Payload>(&bytes).unwrap(), invalid);
+        }
+    }
+
+    #[test]
+    fn negotiation_falls_back_to_json() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static("text/html, */*"));
+        let n = Negotiated::from_headers(&headers);
+        assert_eq!(n.format, Format::Json);
+        assert_eq!(n.coding, Coding::Identity);
+
+        headers.insert(
+            header::ACCEPT,
+            HeaderValue::from_static("application/cbor, application/json;q=0.5"),
+        );
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let n = Negotiated::from_headers(&headers);
+        assert_eq!(n.format, Format::Cbor);
+        assert_eq!(n.coding, Coding::Gzip);
+    }
+}